@@ -0,0 +1,58 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 013_plans.sql
+--
+-- Plan catalog: squads, device limit, traffic limit, Remnawave tag and
+-- display name for every tariff. Replaces the literals that used to live in
+-- extend_subscription / toggle_pro / auto_register. vpn-api loads the table
+-- into memory at startup; /admin/plans writes go through the API so the
+-- cache is refreshed — a manual UPDATE here needs POST /admin/plans/reload.
+--
+-- kind:
+--   paid   — purchasable tariff. Applying it REVOKES squads that belong to
+--            other paid plans and are not part of this one (base after
+--            bsbase loses БС-обход).
+--   bonus  — trial / free / milestone grants. Adds its squads, never
+--            revokes anything, so a bonus extend can't strip a paid squad.
+--   addon  — toggled separately from the plan (PRO). Never revoked by a
+--            plan change.
+-- The 'free' row is also the baseline for freshly created users and for
+-- plan codes missing from the catalog.
+
+CREATE TABLE IF NOT EXISTS plans (
+    code                VARCHAR(16)  PRIMARY KEY,
+    display_name        TEXT         NOT NULL,
+    kind                VARCHAR(8)   NOT NULL DEFAULT 'paid'
+                        CHECK (kind IN ('paid', 'bonus', 'addon')),
+    squads              TEXT[]       NOT NULL DEFAULT '{}',
+    inbounds            TEXT[]       NOT NULL DEFAULT '{}',
+    device_limit        BIGINT       NOT NULL DEFAULT 2,
+    traffic_limit_bytes BIGINT       NOT NULL DEFAULT 0,   -- 0 = unlimited
+    tag                 VARCHAR(16)  NOT NULL DEFAULT 'PAID',
+    is_active           BOOLEAN      NOT NULL DEFAULT TRUE,
+    created_at          TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    updated_at          TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+-- Seed with the values that were hardcoded until now.
+INSERT INTO plans (code, display_name, kind, squads, inbounds, device_limit, tag) VALUES
+    ('base',     'Базовый',              'paid',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 2, 'PAID'),
+    ('family',   'Семейный',             'paid',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 10, 'PAID'),
+    ('bsbase',   'Обход БС (Базовый)',   'paid',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7', '9e60626e-32a8-4d91-a2f8-2aa3fecf7b23'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 2, 'PAID'),
+    ('bsfamily', 'Обход БС (Семейный)',  'paid',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7', '9e60626e-32a8-4d91-a2f8-2aa3fecf7b23'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 10, 'PAID'),
+    ('trial',    'Пробный',              'bonus',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 2, 'TRIAL'),
+    ('free',     'Бесплатный',           'bonus',
+        ARRAY['514a5e22-c599-4f72-81a5-e646f0391db7'],
+        ARRAY['d92c68b5-41e9-47d0-b7ee-89e7c8640a59'], 2, 'FREE'),
+    ('pro',      'PRO',                  'addon',
+        ARRAY['b6a4e86b-b769-4c86-a2d9-f31bbe645029'],
+        '{}', 0, 'PAID')
+ON CONFLICT (code) DO NOTHING;
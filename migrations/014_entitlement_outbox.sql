@@ -0,0 +1,49 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 014_entitlement_outbox.sql
+--
+-- Transactional outbox for panel syncs. extend_subscription writes the
+-- users UPDATE and an outbox row in one transaction, then the outbox worker
+-- (src/outbox.rs) pushes the user's current entitlement to Remnawave with
+-- retries/backoff. Before this the PATCH went out first and a failed UPDATE
+-- (or a crash in between) left panel time we had no record of.
+--
+-- status:
+--   pending — not yet applied; picked up once next_attempt_at <= NOW()
+--   done    — applied (or superseded by a later row for the same user)
+--   failed  — gave up after the max attempt count; see last_error and
+--             the attempts table. Re-queue with
+--             UPDATE entitlement_outbox SET status = 'pending', attempts = 0,
+--                    next_attempt_at = NOW() WHERE id = ...;
+
+CREATE TABLE IF NOT EXISTS entitlement_outbox (
+    id               BIGSERIAL    PRIMARY KEY,
+    telegram_id      BIGINT       NOT NULL,
+    source           VARCHAR(32)  NOT NULL,
+    -- Snapshot at enqueue time, for audit. The worker always applies the
+    -- users row as it is when the attempt runs.
+    plan             TEXT         NOT NULL,
+    expire_at        TIMESTAMPTZ  NOT NULL,
+    status           VARCHAR(8)   NOT NULL DEFAULT 'pending'
+                     CHECK (status IN ('pending', 'done', 'failed')),
+    attempts         INTEGER      NOT NULL DEFAULT 0,
+    next_attempt_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    last_error       TEXT,
+    created_at       TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    done_at          TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_entitlement_outbox_due
+    ON entitlement_outbox (next_attempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS idx_entitlement_outbox_user
+    ON entitlement_outbox (telegram_id, id);
+
+CREATE TABLE IF NOT EXISTS entitlement_outbox_attempts (
+    id          BIGSERIAL    PRIMARY KEY,
+    outbox_id   BIGINT       NOT NULL REFERENCES entitlement_outbox(id) ON DELETE CASCADE,
+    attempt     INTEGER      NOT NULL,
+    ok          BOOLEAN      NOT NULL,
+    error       TEXT,
+    created_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_entitlement_outbox_attempts_outbox
+    ON entitlement_outbox_attempts (outbox_id);
@@ -0,0 +1,16 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 015_payment_idempotency.sql
+--
+-- Idempotency keys for PATCH /users/{telegram_id}/extend. A caller passes
+-- idempotency_key (e.g. the YooKassa payment id); the extend writes its
+-- ledger row with the key and the JSON it returned, in the same transaction
+-- as the users UPDATE. A repeat with the same key returns `result` instead
+-- of adding the days again.
+--
+-- POST /internal/payments with the same key fills in amount/external_id/
+-- metadata on that row instead of inserting a duplicate.
+
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS idempotency_key VARCHAR(128);
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS result          JSONB;
+
+CREATE UNIQUE INDEX IF NOT EXISTS ux_payments_idempotency_key
+    ON payments (idempotency_key) WHERE idempotency_key IS NOT NULL;
@@ -0,0 +1,33 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 016_scheduled_jobs.sql
+--
+-- Persistent scheduled jobs, run by the in-process worker in src/jobs.rs.
+-- Replaces tokio::spawn sleeps that were lost on restart (a restart inside
+-- the 30-minute device-limit window left users at hwidDeviceLimit 0).
+--
+-- kind / payload (see jobs::Job):
+--   restore_device_limit  {"uuid": "...", "device_limit": 2}
+--   send_broadcast        {"broadcast_id": 17}
+--   send_email_batch      {"news_text": "..."}
+--
+-- status:
+--   pending  — waits for run_at
+--   running  — claimed; if locked_until passes (process died) it's
+--              picked up again, so delivery is at-least-once
+--   done / failed — failed after the max attempt count, see last_error
+
+CREATE TABLE IF NOT EXISTS scheduled_jobs (
+    id            BIGSERIAL    PRIMARY KEY,
+    kind          VARCHAR(32)  NOT NULL,
+    payload       JSONB        NOT NULL DEFAULT '{}',
+    run_at        TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    status        VARCHAR(8)   NOT NULL DEFAULT 'pending'
+                  CHECK (status IN ('pending', 'running', 'done', 'failed')),
+    attempts      INTEGER      NOT NULL DEFAULT 0,
+    locked_until  TIMESTAMPTZ,
+    last_error    TEXT,
+    created_at    TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    finished_at   TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_scheduled_jobs_due
+    ON scheduled_jobs (run_at) WHERE status IN ('pending', 'running');
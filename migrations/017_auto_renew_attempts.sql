@@ -0,0 +1,40 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 017_auto_renew_attempts.sql
+--
+-- Server-side auto-renew charges (src/autorenew.rs). One row per charge of
+-- the saved card: the subscription period it renews (period_end = the
+-- subscription_end being extended) and the dunning slot it belongs to
+-- (slot = index into AUTORENEW_SCHEDULE, i.e. users.auto_renew_fail_count
+-- at the time of the charge).
+--
+-- The unique key makes a slot chargeable once, and the YooKassa
+-- Idempotence-Key is derived from the same triple, so a crash between the
+-- INSERT and the API call re-sends the same request instead of a new one.
+--
+-- status:
+--   pending   — created (payment_id NULL until the API answered) or the
+--               payment is still pending at YooKassa; re-checked by the worker
+--   succeeded — charged and credited (payments row with external_id = payment_id)
+--   failed    — canceled by YooKassa or rejected by the API, see reason
+
+CREATE TABLE IF NOT EXISTS auto_renew_attempts (
+    id           BIGSERIAL       PRIMARY KEY,
+    telegram_id  BIGINT          NOT NULL,
+    period_end   TIMESTAMPTZ     NOT NULL,
+    slot         INTEGER         NOT NULL,
+    shop         VARCHAR(8)      NOT NULL,   -- 'bot' | 'web' (users.payment_method_shop)
+    plan         VARCHAR(16)     NOT NULL,
+    duration     VARCHAR(16)     NOT NULL,
+    amount_rub   NUMERIC(10, 2)  NOT NULL,
+    payment_id   VARCHAR(64),
+    status       VARCHAR(10)     NOT NULL DEFAULT 'pending'
+                 CHECK (status IN ('pending', 'succeeded', 'failed')),
+    reason       TEXT,
+    created_at   TIMESTAMPTZ     NOT NULL DEFAULT NOW(),
+    resolved_at  TIMESTAMPTZ,
+    UNIQUE (telegram_id, period_end, slot)
+);
+
+CREATE INDEX IF NOT EXISTS idx_auto_renew_attempts_pending
+    ON auto_renew_attempts (created_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS idx_auto_renew_attempts_telegram_id
+    ON auto_renew_attempts (telegram_id, created_at DESC);
@@ -0,0 +1,13 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 018_payment_refunds.sql
+--
+-- Refunds (POST /admin/payments/{id}/refund, src/refund.rs). A refund is a
+-- ledger row of its own: source = 'refund', negative amount_rub and
+-- days_added, external_id = the YooKassa refund id, refund_of = the
+-- payments.id it reverses. SUM(amount_rub) over the ledger is then net of
+-- refunds, and a payment's refundable remainder is its amount plus the
+-- (negative) amounts of its refund rows.
+
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS refund_of BIGINT REFERENCES payments (id);
+
+CREATE INDEX IF NOT EXISTS idx_payments_refund_of
+    ON payments (refund_of) WHERE refund_of IS NOT NULL;
@@ -0,0 +1,54 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 019_prices.sql
+--
+-- Price catalog. Replaces get_price_map() / web_get_prices(), which read
+-- BASE_MONTH, FAMILY_3_MONTH, ... from the environment with hardcoded
+-- defaults and only knew 1m / 3m / 1y.
+--
+-- Prices are versioned: a change is a new price_versions row with its full
+-- price list and an effective_from, never an UPDATE of an old one. The
+-- version in effect is the latest one whose effective_from has passed, so a
+-- price change can be scheduled ahead. vpn-api caches the table (src/prices.rs);
+-- write through POST /admin/prices, or call POST /admin/prices/reload after
+-- editing by hand.
+--
+-- payments.price_version records the version a payment was priced with;
+-- auto_renew_attempts.price_version the one a saved-card charge used.
+--
+-- Version 1 is seeded with the old code defaults plus 6m. If production
+-- overrode them in .env (BASE_MONTH etc.), add a version with the real
+-- values before deploying — vpn-api logs a warning at startup for every
+-- env price that disagrees with the catalog.
+
+CREATE TABLE IF NOT EXISTS price_versions (
+    id              SERIAL       PRIMARY KEY,
+    effective_from  TIMESTAMPTZ  NOT NULL UNIQUE,
+    note            TEXT,
+    created_at      TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS prices (
+    version_id  INTEGER      NOT NULL REFERENCES price_versions (id),
+    plan        VARCHAR(16)  NOT NULL REFERENCES plans (code),
+    duration    VARCHAR(16)  NOT NULL CHECK (duration IN ('1m', '3m', '6m', '1y')),
+    amount_rub  INTEGER      NOT NULL CHECK (amount_rub > 0),
+    PRIMARY KEY (version_id, plan, duration)
+);
+
+ALTER TABLE payments ADD COLUMN IF NOT EXISTS price_version INTEGER REFERENCES price_versions (id);
+ALTER TABLE auto_renew_attempts ADD COLUMN IF NOT EXISTS price_version INTEGER REFERENCES price_versions (id);
+
+-- Re-running is a no-op: the version insert conflicts and yields no id.
+WITH v AS (
+    INSERT INTO price_versions (effective_from, note)
+    VALUES ('2020-01-01T00:00:00Z', 'initial: env defaults + 6m')
+    ON CONFLICT (effective_from) DO NOTHING
+    RETURNING id
+)
+INSERT INTO prices (version_id, plan, duration, amount_rub)
+SELECT v.id, p.plan, p.duration, p.amount_rub
+FROM v, (VALUES
+    ('base',     '1m',  150), ('base',     '3m',  430), ('base',     '6m',  800), ('base',     '1y', 1500),
+    ('family',   '1m',  250), ('family',   '3m',  700), ('family',   '6m', 1250), ('family',   '1y', 2200),
+    ('bsbase',   '1m',  450), ('bsbase',   '3m', 1250), ('bsbase',   '6m', 2400), ('bsbase',   '1y', 4500),
+    ('bsfamily', '1m',  750), ('bsfamily', '3m', 2100), ('bsfamily', '6m', 4000), ('bsfamily', '1y', 7500)
+) AS p (plan, duration, amount_rub);
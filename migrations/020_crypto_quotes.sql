@@ -0,0 +1,36 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 020_crypto_quotes.sql
+--
+-- Locked RUB -> crypto quotes for CryptoPay invoices (src/rates.rs). When a
+-- crypto invoice is created, the cached rate is frozen here together with the
+-- crypto amount and an expiry; the invoice is created for exactly that amount
+-- and expires with the quote, so what the user saw is what they pay.
+--
+-- rate_rub is the RUB price of one unit of `asset` and `source` the provider
+-- it came from (cryptopay / coinmarketcap / coingecko).
+--
+-- status:
+--   open      — issued, invoice not created yet (or its creation failed)
+--   invoiced  — invoice_id set
+--   paid      — the invoice was paid and credited (cryptopay_webhook)
+
+CREATE TABLE IF NOT EXISTS crypto_quotes (
+    id             BIGSERIAL       PRIMARY KEY,
+    telegram_id    BIGINT          NOT NULL,
+    tariff         VARCHAR(16)     NOT NULL,
+    duration       VARCHAR(16)     NOT NULL,
+    price_version  INTEGER         REFERENCES price_versions (id),
+    amount_rub     NUMERIC(10, 2)  NOT NULL,
+    asset          VARCHAR(10)     NOT NULL,
+    rate_rub       NUMERIC(20, 8)  NOT NULL,
+    amount_crypto  NUMERIC(30, 8)  NOT NULL,
+    source         VARCHAR(16)     NOT NULL,
+    rate_at        TIMESTAMPTZ     NOT NULL,
+    expires_at     TIMESTAMPTZ     NOT NULL,
+    invoice_id     BIGINT          UNIQUE,
+    status         VARCHAR(8)      NOT NULL DEFAULT 'open'
+                   CHECK (status IN ('open', 'invoiced', 'paid')),
+    created_at     TIMESTAMPTZ     NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_crypto_quotes_telegram_id
+    ON crypto_quotes (telegram_id, created_at DESC);
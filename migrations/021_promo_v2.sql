@@ -0,0 +1,28 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 021_promo_v2.sql
+--
+-- Promo codes v2 (src/promo.rs). Until now a code could only take
+-- discount_percent off, for the tariffs in applicable_tariffs, max_uses
+-- times. New columns:
+--
+--   kind                  percent    — discount_percent % off (the old behaviour)
+--                         fixed      — amount_rub off
+--                         bonus_days — full price, bonus_days extra days
+--   expires_at            NULL = never
+--   applicable_durations  '{}' = any duration; e.g. '{1y}' for annual-only
+--   first_purchase_only   only for users with no paid row in `payments`
+--
+-- Existing codes become kind = 'percent' with no expiry, any duration.
+
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS kind VARCHAR(16) NOT NULL DEFAULT 'percent';
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS amount_rub INTEGER;
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS bonus_days INTEGER;
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS applicable_durations TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS first_purchase_only BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE promo_codes DROP CONSTRAINT IF EXISTS promo_codes_kind_check;
+ALTER TABLE promo_codes ADD CONSTRAINT promo_codes_kind_check CHECK (
+    (kind = 'percent'    AND discount_percent BETWEEN 0 AND 100) OR
+    (kind = 'fixed'      AND amount_rub > 0) OR
+    (kind = 'bonus_days' AND bonus_days > 0)
+);
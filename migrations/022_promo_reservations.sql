@@ -0,0 +1,37 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 022_promo_reservations.sql
+--
+-- Promo reservations (src/promo.rs). A web checkout with a promo code holds
+-- one use of the code while its payment is open, so max_uses and "once per
+-- user" hold even when several checkouts race for the last use:
+--
+--   held       — created with the payment; counts against max_uses until
+--                expires_at (PROMO_RESERVATION_TTL_SECS for YooKassa, the
+--                quote expiry for CryptoPay)
+--   committed  — the payment was credited: a promo_usages row was written
+--                and current_uses incremented
+--   released   — payment canceled, superseded by a newer checkout of the
+--                same user, or expired (swept by vpn-api)
+--
+-- payment_ref is the YooKassa payment id or the CryptoPay invoice id; NULL
+-- until the provider has answered. A payment that succeeds after its hold
+-- was released is still committed — the user paid the discounted price.
+
+CREATE TABLE IF NOT EXISTS promo_reservations (
+    id             BIGSERIAL    PRIMARY KEY,
+    promo_code_id  INTEGER      NOT NULL REFERENCES promo_codes (id),
+    telegram_id    BIGINT       NOT NULL,
+    provider       VARCHAR(16)  NOT NULL CHECK (provider IN ('yookassa', 'crypto')),
+    payment_ref    TEXT,
+    status         VARCHAR(16)  NOT NULL DEFAULT 'held'
+                   CHECK (status IN ('held', 'committed', 'released')),
+    expires_at     TIMESTAMPTZ  NOT NULL,
+    created_at     TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    resolved_at    TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_promo_reservations_payment
+    ON promo_reservations (provider, payment_ref);
+
+-- Live holds per code, for the max_uses count.
+CREATE INDEX IF NOT EXISTS idx_promo_reservations_held
+    ON promo_reservations (promo_code_id, expires_at) WHERE status = 'held';
@@ -0,0 +1,32 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 023_promo_campaigns.sql
+--
+-- Promo campaigns (POST /admin/promos/batch). A campaign is one discount
+-- policy handed out as many single-use codes, e.g. one per influencer
+-- follower. The policy columns mirror promo_codes (021_promo_v2.sql) and are
+-- copied into every generated code, so checkout logic only ever looks at
+-- promo_codes; the campaign row keeps them for reference and stats.
+--
+-- Generated codes are `<prefix>-<random>` with max_uses = 1 and
+-- promo_codes.campaign_id set. Revenue is joined from the payments ledger
+-- through committed promo_reservations (022_promo_reservations.sql).
+
+CREATE TABLE IF NOT EXISTS promo_campaigns (
+    id                    SERIAL       PRIMARY KEY,
+    name                  TEXT         NOT NULL,
+    prefix                VARCHAR(32)  NOT NULL,
+    kind                  VARCHAR(16)  NOT NULL,
+    discount_percent      INTEGER      NOT NULL DEFAULT 0,
+    amount_rub            INTEGER,
+    bonus_days            INTEGER,
+    applicable_tariffs    TEXT[]       NOT NULL DEFAULT '{}',
+    applicable_durations  TEXT[]       NOT NULL DEFAULT '{}',
+    expires_at            TIMESTAMPTZ,
+    first_purchase_only   BOOLEAN      NOT NULL DEFAULT FALSE,
+    codes_count           INTEGER      NOT NULL,
+    created_at            TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE promo_codes ADD COLUMN IF NOT EXISTS campaign_id INTEGER REFERENCES promo_campaigns (id);
+
+CREATE INDEX IF NOT EXISTS idx_promo_codes_campaign_id
+    ON promo_codes (campaign_id) WHERE campaign_id IS NOT NULL;
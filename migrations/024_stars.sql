@@ -0,0 +1,11 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 024_stars.sql
+--
+-- Telegram Stars (src/stars.rs). Stars payments are logged in `payments`
+-- with source = 'stars', amount_rub = the catalog price the invoice was
+-- converted from, external_id = telegram_payment_charge_id and the star
+-- amount in metadata. The only schema change is letting promo holds
+-- (022_promo_reservations.sql) belong to a Stars invoice.
+
+ALTER TABLE promo_reservations DROP CONSTRAINT IF EXISTS promo_reservations_provider_check;
+ALTER TABLE promo_reservations ADD CONSTRAINT promo_reservations_provider_check
+    CHECK (provider IN ('yookassa', 'crypto', 'stars'));
@@ -0,0 +1,43 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 025_wallet.sql
+--
+-- Ruble wallet (src/wallet.rs). Every change is a wallet_ledger row; the
+-- balance in `wallets` is updated in the same transaction and can never go
+-- below zero, so concurrent checkouts can't spend the same rubles twice.
+--
+-- kind:
+--   referral      — referral reward (instead of free days)
+--   compensation  — credited by support / admin
+--   topup         — paid top-up (reference = yookassa:<payment id>)
+--   spend         — used at checkout (negative; reference = the payment)
+--   reversal      — a spend given back: payment canceled or never created
+--   adjustment    — admin correction, either sign
+--
+-- idempotency_key makes retried credits (bot, webhooks) land once.
+--
+-- A checkout paid entirely from the wallet is logged in `payments` with
+-- source = 'wallet', external_id = 'wallet:<spend id>' and no amount_rub, since
+-- no money changed hands at checkout.
+
+CREATE TABLE IF NOT EXISTS wallets (
+    telegram_id  BIGINT         PRIMARY KEY,
+    balance_rub  NUMERIC(10, 2) NOT NULL DEFAULT 0 CHECK (balance_rub >= 0),
+    updated_at   TIMESTAMPTZ    NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS wallet_ledger (
+    id               BIGSERIAL      PRIMARY KEY,
+    telegram_id      BIGINT         NOT NULL,
+    amount_rub       NUMERIC(10, 2) NOT NULL CHECK (amount_rub <> 0),
+    kind             VARCHAR(16)    NOT NULL
+                     CHECK (kind IN ('referral', 'compensation', 'topup', 'spend', 'reversal', 'adjustment')),
+    reference        TEXT,
+    note             TEXT,
+    idempotency_key  VARCHAR(128)   UNIQUE,
+    balance_after    NUMERIC(10, 2) NOT NULL,
+    created_at       TIMESTAMPTZ    NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS idx_wallet_ledger_telegram_id
+    ON wallet_ledger (telegram_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_wallet_ledger_reference
+    ON wallet_ledger (reference) WHERE reference IS NOT NULL;
@@ -0,0 +1,40 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 026_pending_payments.sql
+--
+-- Checkouts started on the site (src/pending.rs): one row per YooKassa
+-- payment or CryptoPay invoice created by web_create_payment,
+-- web_create_crypto_payment and web_wallet_topup, keyed by the provider's
+-- id. Webhooks mark rows paid / canceled; a poller syncs the rest from the
+-- provider, so abandoned checkouts can be counted and reminded about.
+--
+-- status:
+--   pending   — created, not paid yet
+--   paid      — provider reports it paid
+--   canceled  — YooKassa canceled it (declined, timed out, ...)
+--   expired   — CryptoPay invoice expired, or never resolved in time
+--
+-- kind: subscription | wallet_topup
+
+CREATE TABLE IF NOT EXISTS pending_payments (
+    id           BIGSERIAL       PRIMARY KEY,
+    provider     VARCHAR(16)     NOT NULL CHECK (provider IN ('yookassa', 'crypto')),
+    provider_id  TEXT            NOT NULL,
+    telegram_id  BIGINT          NOT NULL,
+    kind         VARCHAR(16)     NOT NULL DEFAULT 'subscription',
+    tariff       VARCHAR(32),
+    duration     VARCHAR(8),
+    amount_rub   NUMERIC(10, 2)  NOT NULL,
+    status       VARCHAR(16)     NOT NULL DEFAULT 'pending'
+                 CHECK (status IN ('pending', 'paid', 'canceled', 'expired')),
+    created_at   TIMESTAMPTZ     NOT NULL DEFAULT NOW(),
+    checked_at   TIMESTAMPTZ,
+    resolved_at  TIMESTAMPTZ,
+    reminded_at  TIMESTAMPTZ,
+    UNIQUE (provider, provider_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_pending_payments_open
+    ON pending_payments (checked_at NULLS FIRST) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS idx_pending_payments_telegram_id
+    ON pending_payments (telegram_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS idx_pending_payments_created_at
+    ON pending_payments (created_at);
@@ -0,0 +1,32 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 027_sessions.sql
+--
+-- Revocable web sessions (src/sessions.rs). Every JWT issued from now on
+-- carries a `jti` that must match a non-revoked row here; the token itself
+-- still never expires, but it can be killed: one session
+-- (DELETE /web/me/sessions/{id}), every session of a user
+-- (DELETE /web/me/sessions, or `revoke_sessions` in PATCH /admin/users/{id}).
+--
+-- Tokens issued before this migration have no jti. They keep working until
+-- their user's sessions are revoked once: session_cutoffs.revoked_before is
+-- the cutoff their issue time (exp - 100 years) is compared against. It's a
+-- table of its own so `users` (SELECT * into models::User) stays as is.
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id             BIGSERIAL    PRIMARY KEY,
+    jti            UUID         NOT NULL UNIQUE,
+    telegram_id    BIGINT       NOT NULL,
+    user_agent     TEXT,
+    ip             TEXT,
+    created_at     TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    last_used_at   TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    revoked_at     TIMESTAMPTZ,
+    revoked_by     VARCHAR(16)
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_telegram_id
+    ON sessions (telegram_id, last_used_at DESC) WHERE revoked_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS session_cutoffs (
+    telegram_id     BIGINT       PRIMARY KEY,
+    revoked_before  TIMESTAMPTZ  NOT NULL
+);
@@ -0,0 +1,19 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 028_refresh_tokens.sql
+--
+-- Refresh tokens for the short-lived access JWTs (src/sessions.rs,
+-- POST /web/auth/refresh). A session holds one live refresh token, stored
+-- as its SHA-256; every refresh rotates it and keeps the previous hash, so
+-- a rotated token presented again (a copy used after the owner refreshed)
+-- revokes the whole session.
+--
+-- Sessions opened before this migration have no refresh token; their
+-- access tokens are the old 100-year ones and need none.
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS refresh_hash       TEXT;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS prev_refresh_hash  TEXT;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS refreshed_at       TIMESTAMPTZ;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_sessions_refresh_hash
+    ON sessions (refresh_hash) WHERE refresh_hash IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_sessions_prev_refresh_hash
+    ON sessions (prev_refresh_hash) WHERE prev_refresh_hash IS NOT NULL;
@@ -0,0 +1,41 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 029_totp.sql
+--
+-- Opt-in TOTP second factor for email accounts (src/totp.rs). Keyed by
+-- email rather than telegram_id: auth_claim_email moves user_credentials
+-- from the synthetic id to the real one, the email stays.
+--
+-- A row with confirmed_at NULL is an enrollment in progress (secret shown,
+-- no code entered yet) and doesn't affect login. last_step is the last
+-- accepted time step, so a code can't be replayed; `failures` consecutive
+-- wrong codes lock the factor until locked_until.
+
+CREATE TABLE IF NOT EXISTS user_totp (
+    email         TEXT         PRIMARY KEY,
+    secret        TEXT         NOT NULL,
+    confirmed_at  TIMESTAMPTZ,
+    last_step     BIGINT,
+    failures      INTEGER      NOT NULL DEFAULT 0,
+    locked_until  TIMESTAMPTZ,
+    created_at    TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
+
+-- One-time recovery codes, SHA-256 of the normalized code. Replaced as a
+-- set whenever 2FA is (re)confirmed.
+CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+    id         BIGSERIAL    PRIMARY KEY,
+    email      TEXT         NOT NULL,
+    code_hash  TEXT         NOT NULL,
+    used_at    TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_totp_recovery_codes_email
+    ON totp_recovery_codes (email) WHERE used_at IS NULL;
+
+-- Password-checked logins waiting for their second factor
+-- (POST /web/auth/totp). Token stored as SHA-256; a challenge is used once.
+CREATE TABLE IF NOT EXISTS totp_challenges (
+    token_hash  TEXT         PRIMARY KEY,
+    email       TEXT         NOT NULL,
+    expires_at  TIMESTAMPTZ  NOT NULL,
+    created_at  TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
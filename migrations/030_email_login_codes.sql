@@ -0,0 +1,15 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 030_email_login_codes.sql
+--
+-- Passwordless email login (POST /web/auth/email-code): the code lives in
+-- email_verification_codes like the register / reset ones, with purpose
+-- 'login'. The same email carries a magic link signed over the row
+-- (jwt::sign_link), redeemed through POST /web/auth/email-code/verify.
+--
+-- `attempts` counts wrong codes typed for an email; a login code stops
+-- working after a few, so the 6 digits can't be brute-forced.
+
+ALTER TABLE email_verification_codes DROP CONSTRAINT IF EXISTS email_verification_codes_purpose_check;
+ALTER TABLE email_verification_codes ADD CONSTRAINT email_verification_codes_purpose_check
+    CHECK (purpose IN ('register', 'reset_password', 'login'));
+
+ALTER TABLE email_verification_codes ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0;
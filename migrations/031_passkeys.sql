@@ -0,0 +1,33 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 031_passkeys.sql
+--
+-- WebAuthn passkeys (src/passkeys.rs, /web/auth/passkey/*). A credential
+-- belongs to a telegram_id (synthetic for email accounts; auth_claim_email
+-- moves them to the real id together with user_credentials). public_key is
+-- the COSE key exactly as the authenticator sent it.
+--
+-- webauthn_challenges holds the challenges handed out by the */options
+-- endpoints until the ceremony comes back; each is used once. Registration
+-- challenges are bound to the signed-in user, login ones to nobody (the
+-- browser picks the passkey).
+
+CREATE TABLE IF NOT EXISTS passkeys (
+    id             BIGSERIAL    PRIMARY KEY,
+    telegram_id    BIGINT       NOT NULL,
+    credential_id  TEXT         NOT NULL UNIQUE,
+    public_key     BYTEA        NOT NULL,
+    alg            INTEGER      NOT NULL,
+    sign_count     BIGINT       NOT NULL DEFAULT 0,
+    name           TEXT         NOT NULL,
+    created_at     TIMESTAMPTZ  NOT NULL DEFAULT NOW(),
+    last_used_at   TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_passkeys_telegram_id ON passkeys (telegram_id);
+
+CREATE TABLE IF NOT EXISTS webauthn_challenges (
+    challenge    TEXT         PRIMARY KEY,
+    purpose      VARCHAR(16)  NOT NULL CHECK (purpose IN ('register', 'login')),
+    telegram_id  BIGINT,
+    expires_at   TIMESTAMPTZ  NOT NULL,
+    created_at   TIMESTAMPTZ  NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,9 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 032_passkey_sessions.sql
+--
+-- The session a passkey was added in (src/passkeys.rs). A passkey added
+-- from a stolen session would outlive it, so "sign out everywhere"
+-- (DELETE /web/me/sessions) lists the passkeys added in the sessions it
+-- ends and, with ?delete_passkeys=true, removes them. NULL for passkeys
+-- added before this migration or with a pre-sessions token.
+
+ALTER TABLE passkeys ADD COLUMN IF NOT EXISTS session_id BIGINT;
@@ -0,0 +1,12 @@
+-- Apply: sudo -u postgres psql -d vpn_db -f 033_pending_create_request.sql
+--
+-- A site checkout whose POST /v3/payments got no answer (src/pending.rs).
+-- The payment may or may not exist, so web_create_payment records the row
+-- with provider_id set to the Idempotence-Key and create_request set to
+-- the body it sent. The poller re-sends that body with the same key while
+-- YooKassa still honours it (24h): the answer names the payment, and the
+-- row, the wallet spend and the promo hold are tied to it. If YooKassa
+-- rejects the body instead, no payment exists and both are given back.
+-- create_request is cleared once the payment id is known.
+
+ALTER TABLE pending_payments ADD COLUMN IF NOT EXISTS create_request JSONB;
@@ -0,0 +1,335 @@
+//! Revenue analytics over the payments ledger (migrations/005) for
+//! `GET /admin/analytics/revenue`.
+//!
+//! Money is `amount_rub` of `yookassa` / `crypto` / `stars` rows plus paid
+//! wallet top-ups (migrations/025_wallet.sql), net of `refund` rows. Rubles
+//! spent from the wallet were counted when they were topped up, so they are
+//! not revenue again; they do count towards the value of the subscription
+//! they paid for (`wallet_rub` in the row's metadata), and a `wallet` row is
+//! a paid one. A payment "covers" `[created_at, created_at + days_added)`;
+//! MRR, churn and retention are computed from that coverage, so an annual
+//! payment counts as one twelfth per month rather than a spike.
+//!
+//! Email users' rows may be stored under either sign of the id, so users
+//! are matched on either sign (or `ABS(telegram_id)` within the ledger).
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+/// Rows that carry real money in.
+const CASH: &str = "p.source IN ('yookassa', 'crypto', 'stars') AND p.amount_rub > 0";
+/// Rows that bought a subscription: cash, or wholly from the wallet.
+const PAID: &str = "(p.source IN ('yookassa', 'crypto', 'stars') AND p.amount_rub > 0 OR p.source = 'wallet')";
+/// What a paid row's subscription cost, card and wallet parts together.
+const VALUE: &str = "(COALESCE(p.amount_rub, 0) + COALESCE(NULLIF(p.metadata->>'wallet_rub', '')::numeric, 0))";
+const MAX_COHORT_MONTHS: i32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Granularity {
+    Day,
+    Week,
+    Month,
+}
+
+impl Granularity {
+    pub fn parse(s: Option<&str>) -> Option<Granularity> {
+        match s.unwrap_or("day") {
+            "day" | "daily" => Some(Granularity::Day),
+            "week" | "weekly" => Some(Granularity::Week),
+            "month" | "monthly" => Some(Granularity::Month),
+            _ => None,
+        }
+    }
+
+    /// `date_trunc` field; never user input.
+    fn trunc(self) -> &'static str {
+        match self {
+            Granularity::Day => "day",
+            Granularity::Week => "week",
+            Granularity::Month => "month",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct RevenuePoint {
+    pub bucket: DateTime<Utc>,
+    pub source: String,
+    pub plan: String,
+    pub amount_rub: f64,
+    pub payments: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Summary {
+    /// Net revenue in the window, wallet top-ups included.
+    pub revenue_rub: f64,
+    pub refunds_rub: f64,
+    pub paying_users: i64,
+    /// Users with an active subscription now.
+    pub active_users: i64,
+    /// Sum of `value * 30 / days_added` over paid rows covering now, the
+    /// wallet part of a checkout included.
+    pub mrr_rub: f64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct TrialConversion {
+    /// Trials started in the window.
+    pub trials: i64,
+    /// Of those, users who paid after the trial (at any time).
+    pub converted: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Churn {
+    /// Paying users whose paid coverage reached the window start.
+    pub paying_at_start: i64,
+    /// Of those, users whose coverage ended inside the window and who have
+    /// no coverage after it.
+    pub churned: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct CohortCell {
+    cohort: DateTime<Utc>,
+    size: i64,
+    month_offset: i32,
+    retained: i64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Cohort {
+    /// `YYYY-MM` of signup.
+    pub cohort: String,
+    pub size: i64,
+    /// Users with paid coverage in month 0, 1, ... after signup, up to now.
+    pub retained: Vec<i64>,
+    pub rates: Vec<f64>,
+}
+
+/// Fold `(cohort, offset, retained)` cells into one row per cohort.
+fn cohort_matrix(cells: Vec<CohortCell>) -> Vec<Cohort> {
+    let mut out: Vec<Cohort> = Vec::new();
+    for c in cells {
+        if out.last().map(|l| l.cohort.as_str()) != Some(c.cohort.format("%Y-%m").to_string().as_str()) {
+            out.push(Cohort {
+                cohort: c.cohort.format("%Y-%m").to_string(),
+                size: c.size,
+                retained: Vec::new(),
+                rates: Vec::new(),
+            });
+        }
+        let row = out.last_mut().expect("pushed above");
+        let at = c.month_offset as usize;
+        if row.retained.len() <= at {
+            row.retained.resize(at + 1, 0);
+            row.rates.resize(at + 1, 0.0);
+        }
+        row.retained[at] = c.retained;
+        row.rates[at] = ratio(c.retained, c.size);
+    }
+    out
+}
+
+fn ratio(n: i64, d: i64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        (n as f64 / d as f64 * 10_000.0).round() / 10_000.0
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub granularity: &'static str,
+    pub series: Vec<RevenuePoint>,
+    pub summary: Summary,
+    /// `revenue / active users`.
+    pub arpu_rub: f64,
+    /// `revenue / paying users`.
+    pub arppu_rub: f64,
+    pub trial_conversion: TrialConversion,
+    pub trial_conversion_rate: f64,
+    pub churn: Churn,
+    pub churn_rate: f64,
+    pub cohorts: Vec<Cohort>,
+}
+
+/// Default window: the last 30 days.
+pub fn default_window() -> (DateTime<Utc>, DateTime<Utc>) {
+    let to = Utc::now();
+    (to - Duration::days(30), to)
+}
+
+pub async fn report(
+    pool: &PgPool,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    granularity: Granularity,
+) -> Result<Report, sqlx::Error> {
+    let series = sqlx::query_as::<_, RevenuePoint>(&format!(
+        "SELECT date_trunc('{trunc}', p.created_at) AS bucket, p.source, p.plan, \
+                SUM(p.amount_rub)::float8 AS amount_rub, COUNT(*) AS payments \
+         FROM payments p \
+         WHERE p.amount_rub IS NOT NULL AND p.created_at >= $1 AND p.created_at < $2 \
+         GROUP BY 1, 2, 3 \
+         UNION ALL \
+         SELECT date_trunc('{trunc}', w.created_at), 'wallet_topup', '', \
+                SUM(w.amount_rub)::float8, COUNT(*) \
+         FROM wallet_ledger w \
+         WHERE w.kind = 'topup' AND w.created_at >= $1 AND w.created_at < $2 \
+         GROUP BY 1 ORDER BY 1, 2, 3",
+        trunc = granularity.trunc()
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let summary = sqlx::query_as::<_, Summary>(&format!(
+        "SELECT \
+            (COALESCE((SELECT SUM(p.amount_rub) FROM payments p WHERE p.amount_rub IS NOT NULL \
+                       AND p.created_at >= $1 AND p.created_at < $2), 0) \
+             + COALESCE((SELECT SUM(w.amount_rub) FROM wallet_ledger w WHERE w.kind = 'topup' \
+                       AND w.created_at >= $1 AND w.created_at < $2), 0))::float8 AS revenue_rub, \
+            COALESCE((SELECT -SUM(p.amount_rub) FROM payments p WHERE p.source = 'refund' \
+                      AND p.created_at >= $1 AND p.created_at < $2), 0)::float8 AS refunds_rub, \
+            (SELECT COUNT(DISTINCT uid) FROM ( \
+                 SELECT ABS(p.telegram_id) AS uid FROM payments p WHERE {cash} \
+                     AND p.created_at >= $1 AND p.created_at < $2 \
+                 UNION ALL \
+                 SELECT ABS(w.telegram_id) FROM wallet_ledger w WHERE w.kind = 'topup' \
+                     AND w.created_at >= $1 AND w.created_at < $2) payers) AS paying_users, \
+            (SELECT COUNT(*) FROM users WHERE subscription_end > NOW()) AS active_users, \
+            COALESCE((SELECT SUM(({value} + COALESCE(r.refunded, 0)) * 30 / NULLIF(p.days_added, 0)) \
+                      FROM payments p \
+                      LEFT JOIN (SELECT refund_of, SUM(amount_rub) AS refunded FROM payments \
+                                 WHERE refund_of IS NOT NULL GROUP BY refund_of) r ON r.refund_of = p.id \
+                      WHERE {paid} AND p.created_at <= NOW() \
+                        AND p.created_at + make_interval(days => p.days_added) > NOW()), 0)::float8 AS mrr_rub",
+        cash = CASH,
+        paid = PAID,
+        value = VALUE
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_one(pool)
+    .await?;
+
+    let trial_conversion = sqlx::query_as::<_, TrialConversion>(&format!(
+        "SELECT COUNT(*) AS trials, \
+                COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM payments p WHERE {paid} \
+                    AND p.telegram_id IN (t.telegram_id, -t.telegram_id) AND p.created_at > t.created_at)) AS converted \
+         FROM payments t WHERE t.source = 'trial' AND t.created_at >= $1 AND t.created_at < $2",
+        paid = PAID
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_one(pool)
+    .await?;
+
+    // Coverage end per user: last end reached by the window start, and
+    // whether anything covers past the window's churn point.
+    let churn = sqlx::query_as::<_, Churn>(&format!(
+        "WITH cov AS ( \
+             SELECT ABS(p.telegram_id) AS uid, p.created_at AS s, \
+                    p.created_at + make_interval(days => p.days_added) AS e \
+             FROM payments p WHERE {paid} \
+         ), at_start AS ( \
+             SELECT uid FROM cov WHERE s <= $1 AND e > $1 GROUP BY uid \
+         ), last_end AS ( \
+             SELECT c.uid, MAX(c.e) AS e FROM cov c JOIN at_start a ON a.uid = c.uid \
+             WHERE c.s < LEAST($2, NOW()) GROUP BY c.uid \
+         ) \
+         SELECT (SELECT COUNT(*) FROM at_start) AS paying_at_start, \
+                (SELECT COUNT(*) FROM last_end WHERE e <= LEAST($2, NOW())) AS churned",
+        paid = PAID
+    ))
+    .bind(from)
+    .bind(to)
+    .fetch_one(pool)
+    .await?;
+
+    let cells = sqlx::query_as::<_, CohortCell>(&format!(
+        "WITH cohorts AS ( \
+             SELECT telegram_id, date_trunc('month', created_at) AS cohort FROM users \
+             WHERE created_at >= date_trunc('month', NOW()) - make_interval(months => {max} - 1) \
+         ), sizes AS ( \
+             SELECT cohort, COUNT(*) AS size FROM cohorts GROUP BY cohort \
+         ), cov AS ( \
+             SELECT p.telegram_id, p.created_at AS s, p.created_at + make_interval(days => p.days_added) AS e \
+             FROM payments p WHERE {paid} \
+         ) \
+         SELECT c.cohort, s.size, k AS month_offset, COUNT(DISTINCT cov.telegram_id) AS retained \
+         FROM cohorts c \
+         JOIN sizes s ON s.cohort = c.cohort \
+         CROSS JOIN generate_series(0, {max} - 1) AS k \
+         LEFT JOIN cov ON cov.telegram_id IN (c.telegram_id, -c.telegram_id) \
+             AND cov.s < c.cohort + make_interval(months => k + 1) \
+             AND cov.e > c.cohort + make_interval(months => k) \
+         WHERE c.cohort + make_interval(months => k) <= NOW() \
+         GROUP BY c.cohort, s.size, k ORDER BY c.cohort, k",
+        max = MAX_COHORT_MONTHS,
+        paid = PAID
+    ))
+    .fetch_all(pool)
+    .await?;
+
+    Ok(Report {
+        from,
+        to,
+        granularity: granularity.trunc(),
+        series,
+        arpu_rub: money(summary.revenue_rub, summary.active_users),
+        arppu_rub: money(summary.revenue_rub, summary.paying_users),
+        summary,
+        trial_conversion_rate: ratio(trial_conversion.converted, trial_conversion.trials),
+        trial_conversion,
+        churn_rate: ratio(churn.churned, churn.paying_at_start),
+        churn,
+        cohorts: cohort_matrix(cells),
+    })
+}
+
+fn money(total: f64, users: i64) -> f64 {
+    if users == 0 {
+        0.0
+    } else {
+        (total / users as f64 * 100.0).round() / 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn cohort_cells_fold_into_rows() {
+        let jan = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2026, 2, 1, 0, 0, 0).unwrap();
+        let cell = |cohort, size, month_offset, retained| CohortCell { cohort, size, month_offset, retained };
+        let rows = cohort_matrix(vec![cell(jan, 4, 0, 2), cell(jan, 4, 1, 1), cell(feb, 3, 0, 0)]);
+        assert_eq!(
+            rows,
+            vec![
+                Cohort { cohort: "2026-01".into(), size: 4, retained: vec![2, 1], rates: vec![0.5, 0.25] },
+                Cohort { cohort: "2026-02".into(), size: 3, retained: vec![0], rates: vec![0.0] },
+            ]
+        );
+    }
+
+    #[test]
+    fn granularity_and_ratios() {
+        assert_eq!(Granularity::parse(None), Some(Granularity::Day));
+        assert_eq!(Granularity::parse(Some("monthly")), Some(Granularity::Month));
+        assert_eq!(Granularity::parse(Some("year")), None);
+        assert_eq!(ratio(1, 3), 0.3333);
+        assert_eq!(ratio(5, 0), 0.0);
+        assert_eq!(money(1000.0, 3), 333.33);
+    }
+}
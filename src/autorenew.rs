@@ -0,0 +1,508 @@
+//! Server-side auto-renew (migrations/017_auto_renew_attempts.sql).
+//!
+//! Charges the saved card (`users.payment_method_id`) through the shop that
+//! issued it (`payment_method_shop`) on a dunning schedule relative to
+//! `subscription_end`: AUTORENEW_SCHEDULE, default `-24h,-6h,+1d`. Slot N of
+//! a period is tried once the previous N slots failed; after the last one
+//! auto-renew is switched off. Each charge is an `auto_renew_attempts` row
+//! and the user is told about every failure.
+//!
+//! A succeeded payment is credited through credit.rs with the payment id as
+//! the idempotency key — the same key the YooKassa webhook uses, so
+//! whichever sees it first credits it.
+//!
+//! Off unless AUTORENEW_ENABLED=true; while it's on, `/users/auto_renew_due`
+//! returns nothing so the bot's own loop doesn't charge a second time.
+
+use crate::credit::{self, Credit};
+use crate::{plans, prices, push_web, yookassa};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const BATCH: i64 = 50;
+const DEFAULT_SCHEDULE: &str = "-24h,-6h,+1d";
+/// A period whose last slot passed longer ago than this isn't charged any
+/// more (the engine was off, the card was added late, ...).
+const STALE_AFTER_SECS: i64 = 24 * 3600;
+/// YooKassa forgets an Idempotence-Key after 24h; an unsent attempt older
+/// than this can't be resent safely.
+const RESEND_WINDOW_SECS: f64 = 23.0 * 3600.0;
+
+lazy_static::lazy_static! {
+    static ref HTTP: reqwest::Client = reqwest::Client::new();
+}
+
+pub fn enabled() -> bool {
+    std::env::var("AUTORENEW_ENABLED")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// `-24h,-6h,+1d` → offsets from `subscription_end`. Units: m, h, d.
+/// Offsets must be strictly increasing.
+pub fn parse_schedule(s: &str) -> Result<Vec<ChronoDuration>, String> {
+    let mut out: Vec<ChronoDuration> = Vec::new();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let unit_len = part.chars().last().map(char::len_utf8).unwrap_or(0);
+        let (num, unit) = part.split_at(part.len() - unit_len);
+        let n: i64 = num
+            .trim_start_matches('+')
+            .parse()
+            .map_err(|_| format!("bad offset {:?}", part))?;
+        let offset = match unit {
+            "m" => ChronoDuration::minutes(n),
+            "h" => ChronoDuration::hours(n),
+            "d" => ChronoDuration::days(n),
+            _ => return Err(format!("bad unit in {:?} (m/h/d)", part)),
+        };
+        if out.last().is_some_and(|prev| *prev >= offset) {
+            return Err(format!("offsets must increase: {:?}", s));
+        }
+        out.push(offset);
+    }
+    if out.is_empty() {
+        return Err("empty schedule".to_string());
+    }
+    Ok(out)
+}
+
+fn schedule() -> Vec<ChronoDuration> {
+    let raw = std::env::var("AUTORENEW_SCHEDULE").unwrap_or_else(|_| DEFAULT_SCHEDULE.to_string());
+    parse_schedule(&raw).unwrap_or_else(|e| {
+        error!("[autorenew] AUTORENEW_SCHEDULE {:?}: {}, using {}", raw, e, DEFAULT_SCHEDULE);
+        parse_schedule(DEFAULT_SCHEDULE).unwrap_or_default()
+    })
+}
+
+/// When slot `slot` of the period ending at `period_end` is due; `None`
+/// once the schedule is used up.
+pub fn slot_time(schedule: &[ChronoDuration], period_end: DateTime<Utc>, slot: usize) -> Option<DateTime<Utc>> {
+    schedule.get(slot).map(|offset| period_end + *offset)
+}
+
+/// YooKassa `cancellation_details.reason`, as told to the user.
+fn reason_text(reason: &str) -> &'static str {
+    match reason {
+        "insufficient_funds" => "недостаточно средств на карте",
+        "card_expired" => "истёк срок действия карты",
+        "permission_revoked" => "банк отозвал разрешение на списания",
+        "payment_method_restricted" | "payment_method_limit_exceeded" => "банк ограничил операции по карте",
+        "issuer_unavailable" | "general_decline" | "fraud_suspected" => "банк отклонил платёж",
+        _ => "платёж не прошёл",
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DueUser {
+    telegram_id: i64,
+    payment_method_shop: String,
+    auto_renew_plan: String,
+    auto_renew_duration: String,
+    subscription_end: DateTime<Utc>,
+    /// Failed attempts for this period so far.
+    slot: i64,
+}
+
+#[derive(sqlx::FromRow)]
+struct Attempt {
+    id: i64,
+    telegram_id: i64,
+    period_end: DateTime<Utc>,
+    slot: i32,
+    shop: String,
+    plan: String,
+    duration: String,
+    amount_rub: f64,
+    price_version: Option<i32>,
+    payment_id: Option<String>,
+    /// Card to charge, as of now.
+    payment_method_id: Option<String>,
+    card_last4: Option<String>,
+    /// Too old to resend with the same Idempotence-Key.
+    expired: bool,
+}
+
+impl Attempt {
+    fn idempotence_key(&self) -> String {
+        format!("autorenew:{}:{}:{}", self.telegram_id, self.period_end.timestamp(), self.slot)
+    }
+}
+
+const ATTEMPT_COLUMNS: &str = "a.id, a.telegram_id, a.period_end, a.slot, a.shop, a.plan, a.duration, \
+     a.amount_rub::float8 AS amount_rub, a.price_version, a.payment_id, u.payment_method_id, u.card_last4, \
+     a.created_at < NOW() - make_interval(secs => $1) AS expired";
+
+fn shop(name: &str) -> Option<yookassa::Shop> {
+    yookassa::shop_named(name, crate::web_handlers::web_yookassa_creds())
+}
+
+/// Users whose current slot may be due. The exact slot time is checked in
+/// [`charge_due`]; this only narrows the window.
+async fn due_users(pool: &PgPool, schedule: &[ChronoDuration]) -> Result<Vec<DueUser>, sqlx::Error> {
+    let lead = -schedule[0].num_seconds();
+    let lag = schedule[schedule.len() - 1].num_seconds() + STALE_AFTER_SECS;
+    sqlx::query_as::<_, DueUser>(
+        "SELECT u.telegram_id, COALESCE(u.payment_method_shop, 'bot') AS payment_method_shop, \
+                u.auto_renew_plan, u.auto_renew_duration, u.subscription_end, \
+                (SELECT COUNT(*) FROM auto_renew_attempts a \
+                  WHERE a.telegram_id = u.telegram_id AND a.period_end = u.subscription_end \
+                    AND a.status = 'failed') AS slot \
+         FROM users u \
+         WHERE u.auto_renew = TRUE \
+           AND u.payment_method_id IS NOT NULL \
+           AND u.auto_renew_plan IS NOT NULL AND u.auto_renew_duration IS NOT NULL \
+           AND u.subscription_end <= NOW() + make_interval(secs => $1) \
+           AND u.subscription_end > NOW() - make_interval(secs => $2) \
+           AND (u.auto_renew_last_attempt IS NULL OR u.auto_renew_last_attempt < NOW() - INTERVAL '50 minutes') \
+           AND NOT EXISTS (SELECT 1 FROM auto_renew_attempts p \
+                            WHERE p.telegram_id = u.telegram_id AND p.status = 'pending') \
+         ORDER BY u.subscription_end \
+         LIMIT $3",
+    )
+    .bind(lead as f64)
+    .bind(lag as f64)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+}
+
+/// Start the due slot of every candidate.
+async fn charge_due(pool: &PgPool, schedule: &[ChronoDuration]) {
+    let users = match due_users(pool, schedule).await {
+        Ok(u) => u,
+        Err(e) => {
+            error!("[autorenew] due query failed: {}", e);
+            return;
+        }
+    };
+    let now = Utc::now();
+    let price_list = prices::current();
+    for u in users {
+        let slot = u.slot as usize;
+        match slot_time(schedule, u.subscription_end, slot) {
+            Some(at) if at <= now => {}
+            _ => continue,
+        }
+        let Some(price) = price_list.get(&u.auto_renew_plan, &u.auto_renew_duration) else {
+            warn!("[autorenew] no price for {}_{} (user {})", u.auto_renew_plan, u.auto_renew_duration, u.telegram_id);
+            continue;
+        };
+        if shop(&u.payment_method_shop).is_none() {
+            error!("[autorenew] {} shop not configured, can't charge {}", u.payment_method_shop, u.telegram_id);
+            continue;
+        }
+
+        // Same 50-minute guard as the bot's loop used, so the two never
+        // overlap on one user while switching over.
+        let claimed = sqlx::query(
+            "UPDATE users SET auto_renew_last_attempt = NOW() WHERE telegram_id = $1 \
+             AND (auto_renew_last_attempt IS NULL OR auto_renew_last_attempt < NOW() - INTERVAL '50 minutes')",
+        )
+        .bind(u.telegram_id)
+        .execute(pool)
+        .await;
+        if !matches!(claimed, Ok(r) if r.rows_affected() == 1) {
+            continue;
+        }
+
+        let id: Option<i64> = match sqlx::query_scalar(
+            "INSERT INTO auto_renew_attempts (telegram_id, period_end, slot, shop, plan, duration, amount_rub, price_version) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8) \
+             ON CONFLICT (telegram_id, period_end, slot) DO NOTHING RETURNING id",
+        )
+        .bind(u.telegram_id)
+        .bind(u.subscription_end)
+        .bind(slot as i32)
+        .bind(&u.payment_method_shop)
+        .bind(&u.auto_renew_plan)
+        .bind(&u.auto_renew_duration)
+        .bind(price as f64)
+        .bind(price_list.version)
+        .fetch_optional(pool)
+        .await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                error!("[autorenew] insert attempt for {} failed: {}", u.telegram_id, e);
+                continue;
+            }
+        };
+        let Some(id) = id else { continue };
+        info!("[autorenew] #{} user {} slot {} {}_{} {}₽ via {} shop",
+            id, u.telegram_id, slot, u.auto_renew_plan, u.auto_renew_duration, price, u.payment_method_shop);
+        match load_attempt(pool, id).await {
+            Ok(a) => submit(pool, schedule, &a).await,
+            Err(e) => error!("[autorenew] load #{} failed: {}", id, e),
+        }
+    }
+}
+
+async fn load_attempt(pool: &PgPool, id: i64) -> Result<Attempt, sqlx::Error> {
+    sqlx::query_as::<_, Attempt>(&format!(
+        "SELECT {} FROM auto_renew_attempts a JOIN users u ON u.telegram_id = a.telegram_id WHERE a.id = $2",
+        ATTEMPT_COLUMNS
+    ))
+    .bind(RESEND_WINDOW_SECS)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Create the payment (or get the one already created for this key).
+async fn submit(pool: &PgPool, schedule: &[ChronoDuration], a: &Attempt) {
+    let (Some(method), Some(shop)) = (a.payment_method_id.as_deref(), shop(&a.shop)) else {
+        fail(pool, schedule, a, "card unlinked or shop not configured").await;
+        return;
+    };
+    let tariff_name = plans::catalog()
+        .get(&a.plan)
+        .map(|p| p.display_name.clone())
+        .unwrap_or_else(|| "Подписка".to_string());
+    let duration_name = prices::duration_name(&a.duration).unwrap_or_default();
+    let description = format!("SvoiVPN {} {} (автопродление)", tariff_name, duration_name);
+    let receipt_email = std::env::var("RECEIPT_EMAIL").unwrap_or_else(|_| "receipt@svoi-connect.ru".to_string());
+    let amount = json!({"value": format!("{:.2}", a.amount_rub), "currency": "RUB"});
+    let mut body = json!({
+        "amount": amount,
+        "capture": true,
+        "payment_method_id": method,
+        "description": description,
+        "receipt": {
+            "customer": {"email": receipt_email},
+            "items": [{
+                "description": description,
+                "quantity": "1.00",
+                "amount": amount,
+                "vat_code": 1,
+                "payment_subject": "service",
+                "payment_mode": "full_payment"
+            }]
+        },
+        "metadata": {
+            "telegram_id": a.telegram_id.to_string(),
+            "tariff": a.plan,
+            "plan": duration_name,
+            "duration": a.duration,
+            "auto_renew": "true",
+            "auto_renew_attempt": a.id.to_string(),
+        }
+    });
+    if let Some(v) = a.price_version {
+        body["metadata"]["price_version"] = json!(v.to_string());
+    }
+
+    match yookassa::create_payment(&HTTP, &shop, &a.idempotence_key(), &body).await {
+        Ok(payment) => {
+            if let Some(pid) = payment["id"].as_str() {
+                let _ = sqlx::query("UPDATE auto_renew_attempts SET payment_id = $2 WHERE id = $1")
+                    .bind(a.id)
+                    .bind(pid)
+                    .execute(pool)
+                    .await;
+            }
+            resolve(pool, schedule, a, &payment).await;
+        }
+        Err(yookassa::CreateError::Rejected(e)) => fail(pool, schedule, a, &e).await,
+        // Left pending without a payment_id; the sweep resends it.
+        Err(e) => warn!("[autorenew] #{} create failed, will resend: {}", a.id, e),
+    }
+}
+
+/// Act on the payment's current status; `pending` stays for the next sweep.
+async fn resolve(pool: &PgPool, schedule: &[ChronoDuration], a: &Attempt, payment: &Value) {
+    match payment["status"].as_str() {
+        Some("succeeded") => {
+            let order = match yookassa::parse_paid_order(payment) {
+                Ok(o) => o,
+                Err(e) => {
+                    error!("[autorenew] #{} succeeded but can't be credited: {}", a.id, e);
+                    return;
+                }
+            };
+            let c = Credit {
+                idempotency_key: Some(&order.payment_id),
+                amount_rub: Some(order.amount_rub),
+                duration: Some(&order.duration),
+                external_id: Some(&order.payment_id),
+                price_version: order.price_version,
+                metadata: Some(order.metadata.clone()),
+                ..Credit::new(order.telegram_id, order.days, &order.tariff, "yookassa")
+            };
+            // On error the row stays pending and the sweep credits it again.
+            if let Err(e) = credit::credit(pool, &c).await {
+                error!("[autorenew] #{} credit {} failed: {}", a.id, order.payment_id, e);
+                return;
+            }
+            let _ = sqlx::query(
+                "UPDATE auto_renew_attempts SET status = 'succeeded', resolved_at = NOW(), reason = NULL WHERE id = $1",
+            )
+            .bind(a.id)
+            .execute(pool)
+            .await;
+            let _ = sqlx::query("UPDATE users SET auto_renew_fail_count = 0 WHERE telegram_id = $1")
+                .bind(a.telegram_id)
+                .execute(pool)
+                .await;
+            info!("[autorenew] #{} user {} renewed ({})", a.id, a.telegram_id, order.payment_id);
+        }
+        Some("canceled") => {
+            let reason = payment["cancellation_details"]["reason"].as_str().unwrap_or("canceled");
+            fail(pool, schedule, a, reason).await;
+        }
+        _ => {}
+    }
+}
+
+/// Mark the attempt failed, move the user to the next slot (or switch
+/// auto-renew off after the last one) and tell them.
+async fn fail(pool: &PgPool, schedule: &[ChronoDuration], a: &Attempt, reason: &str) {
+    warn!("[autorenew] #{} user {} slot {} failed: {}", a.id, a.telegram_id, a.slot, reason);
+    let _ = sqlx::query(
+        "UPDATE auto_renew_attempts SET status = 'failed', reason = $2, resolved_at = NOW() WHERE id = $1",
+    )
+    .bind(a.id)
+    .bind(reason)
+    .execute(pool)
+    .await;
+
+    let next = slot_time(schedule, a.period_end, a.slot as usize + 1);
+    // Only while the period is still the one being renewed — after a manual
+    // payment the failure no longer matters.
+    let updated = sqlx::query(
+        "UPDATE users SET auto_renew_fail_count = $3, \
+         auto_renew = CASE WHEN $4 THEN auto_renew ELSE FALSE END \
+         WHERE telegram_id = $1 AND subscription_end = $2",
+    )
+    .bind(a.telegram_id)
+    .bind(a.period_end)
+    .bind(a.slot + 1)
+    .bind(next.is_some())
+    .execute(pool)
+    .await;
+    match updated {
+        Ok(r) if r.rows_affected() == 1 => {}
+        Ok(_) => return,
+        Err(e) => {
+            error!("[autorenew] updating user {} failed: {}", a.telegram_id, e);
+            return;
+        }
+    }
+
+    let card = a.card_last4.as_deref().map(|l| format!(" •••• {}", l)).unwrap_or_default();
+    let head = format!(
+        "Не удалось списать {:.0} ₽ с карты{} за продление подписки: {}.",
+        a.amount_rub, card, reason_text(reason)
+    );
+    let text = match next {
+        Some(at) => format!(
+            "{} Повторим попытку {}.",
+            head,
+            (at + ChronoDuration::hours(3)).format("%d.%m в %H:%M МСК")
+        ),
+        None => format!("{} Автопродление отключено — продлите подписку вручную.", head),
+    };
+    notify(pool, a.telegram_id, &text).await;
+}
+
+/// Telegram message from the main bot (Telegram users) and web push.
+async fn notify(pool: &PgPool, telegram_id: i64, text: &str) {
+    if telegram_id > 0 {
+        let token = std::env::var("BOT_TOKEN_TG").unwrap_or_default();
+        let sent = HTTP
+            .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+            .json(&json!({"chat_id": telegram_id, "text": text}))
+            .send()
+            .await;
+        match sent {
+            Ok(r) if r.status().is_success() => {}
+            Ok(r) => warn!("[autorenew] telegram notify {} failed: {}", telegram_id, r.status()),
+            Err(e) => warn!("[autorenew] telegram notify {} failed: {}", telegram_id, e),
+        }
+    }
+    push_web::send_to_telegram_id(pool.clone(), telegram_id, "Автопродление не прошло".to_string(), text.to_string())
+        .await;
+}
+
+/// Re-check pending attempts: resend the unsent ones, poll the rest.
+async fn sweep_pending(pool: &PgPool, schedule: &[ChronoDuration]) {
+    let rows = match sqlx::query_as::<_, Attempt>(&format!(
+        "SELECT {} FROM auto_renew_attempts a JOIN users u ON u.telegram_id = a.telegram_id \
+         WHERE a.status = 'pending' AND a.created_at < NOW() - INTERVAL '1 minute' \
+         ORDER BY a.created_at LIMIT $2",
+        ATTEMPT_COLUMNS
+    ))
+    .bind(RESEND_WINDOW_SECS)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("[autorenew] pending query failed: {}", e);
+            return;
+        }
+    };
+
+    for a in rows {
+        let Some(pid) = a.payment_id.as_deref() else {
+            if a.expired {
+                fail(pool, schedule, &a, "payment was never created").await;
+            } else {
+                submit(pool, schedule, &a).await;
+            }
+            continue;
+        };
+        let Some(shop) = shop(&a.shop) else { continue };
+        match yookassa::fetch_payment(&HTTP, &shop, pid).await {
+            Ok(payment) => resolve(pool, schedule, &a, &payment).await,
+            Err(e) => warn!("[autorenew] #{} fetch {} failed: {}", a.id, pid, e),
+        }
+    }
+}
+
+/// Background worker, started from main when AUTORENEW_ENABLED=true.
+pub fn spawn(pool: PgPool) {
+    if !enabled() {
+        info!("[autorenew] disabled (AUTORENEW_ENABLED)");
+        return;
+    }
+    let schedule = schedule();
+    info!("[autorenew] enabled, schedule {:?}", schedule.iter().map(|d| d.num_minutes()).collect::<Vec<_>>());
+    tokio::spawn(async move {
+        loop {
+            sweep_pending(&pool, &schedule).await;
+            charge_due(&pool, &schedule).await;
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_schedule_offsets() {
+        let s = parse_schedule(DEFAULT_SCHEDULE).unwrap();
+        assert_eq!(s, vec![ChronoDuration::hours(-24), ChronoDuration::hours(-6), ChronoDuration::days(1)]);
+        assert_eq!(parse_schedule(" -30m , 0h ").unwrap(), vec![ChronoDuration::minutes(-30), ChronoDuration::zero()]);
+
+        assert!(parse_schedule("").is_err());
+        assert!(parse_schedule("-24x").is_err());
+        assert!(parse_schedule("-6h,-24h").is_err());
+        assert!(parse_schedule("-6h,-6h").is_err());
+    }
+
+    #[test]
+    fn slots_are_offsets_from_period_end() {
+        let s = parse_schedule(DEFAULT_SCHEDULE).unwrap();
+        let end = DateTime::parse_from_rfc3339("2026-03-10T12:00:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(slot_time(&s, end, 0).unwrap().to_rfc3339(), "2026-03-09T12:00:00+00:00");
+        assert_eq!(slot_time(&s, end, 2).unwrap().to_rfc3339(), "2026-03-11T12:00:00+00:00");
+        assert_eq!(slot_time(&s, end, 3), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Subscription crediting: the core of `PATCH /users/{id}/extend`, shared
+//! with the payment webhooks so they don't loop back through HTTP.
+//!
+//! One call resolves the user (email users live under the negated id,
+//! users missing locally are imported from Remnawave), then in a single
+//! transaction extends `users`, writes the `payments` ledger row for an
+//! idempotency key and queues the panel sync (outbox.rs). The panel PATCH
+//! is attempted right after commit; the outbox worker retries on failure.
+
+use crate::models::User;
+use crate::remnawave::{self, RemnawaveApi};
+use crate::{outbox, plans};
+use log::{info, warn};
+use serde_json::json;
+use sqlx::PgPool;
+use std::fmt;
+
+/// One subscription extension. Ledger fields are only stored when
+/// `idempotency_key` is set — unkeyed callers log via /internal/payments.
+pub struct Credit<'a> {
+    pub telegram_id: i64,
+    pub days: i32,
+    pub plan: &'a str,
+    pub source: &'a str,
+    pub idempotency_key: Option<&'a str>,
+    pub amount_rub: Option<f64>,
+    pub duration: Option<&'a str>,
+    pub external_id: Option<&'a str>,
+    /// Price catalog version the amount came from; defaults to the version
+    /// in effect when an amount is given.
+    pub price_version: Option<i32>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl<'a> Credit<'a> {
+    pub fn new(telegram_id: i64, days: i32, plan: &'a str, source: &'a str) -> Self {
+        Credit {
+            telegram_id,
+            days,
+            plan,
+            source,
+            idempotency_key: None,
+            amount_rub: None,
+            duration: None,
+            external_id: None,
+            price_version: None,
+            metadata: None,
+        }
+    }
+}
+
+/// Response body of the extend (`telegram_id`, `uuid`, `subscription_end`,
+/// `is_active`, `plan`), and whether it was replayed from an earlier call
+/// with the same key.
+pub struct Credited {
+    pub body: serde_json::Value,
+    pub replayed: bool,
+}
+
+#[derive(Debug)]
+pub enum CreditError {
+    /// Neither in `users` nor in Remnawave.
+    UserNotFound,
+    /// Found in Remnawave but the local import failed.
+    Import(sqlx::Error),
+    /// The idempotency key was used for another user.
+    KeyConflict,
+    /// A concurrent call with the same key rolled back before committing.
+    InProgress,
+    Db(sqlx::Error),
+}
+
+impl fmt::Display for CreditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreditError::UserNotFound => write!(f, "user not found"),
+            CreditError::Import(e) => write!(f, "user import failed: {}", e),
+            CreditError::KeyConflict => write!(f, "idempotency key already used"),
+            CreditError::InProgress => write!(f, "duplicate request in progress"),
+            CreditError::Db(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for CreditError {
+    fn from(e: sqlx::Error) -> Self {
+        CreditError::Db(e)
+    }
+}
+
+/// Stored result for an already-applied key. Email users are stored under
+/// the negated id, so both signs match the caller's id.
+async fn replay(pool: &PgPool, key: &str, telegram_id: i64) -> Result<Option<serde_json::Value>, CreditError> {
+    let row: Option<(i64, Option<serde_json::Value>)> =
+        sqlx::query_as("SELECT telegram_id, result FROM payments WHERE idempotency_key = $1")
+            .bind(key)
+            .fetch_optional(pool)
+            .await?;
+    match row {
+        None => Ok(None),
+        Some((owner, _)) if owner.abs() != telegram_id.abs() => {
+            warn!("[credit] Key {} belongs to {}, not {}", key, owner, telegram_id);
+            Err(CreditError::KeyConflict)
+        }
+        Some((_, result)) => {
+            info!("[credit] Replaying key {} for {}", key, telegram_id);
+            Ok(Some(result.unwrap_or_else(|| json!({}))))
+        }
+    }
+}
+
+async fn user_exists(pool: &PgPool, telegram_id: i64) -> bool {
+    sqlx::query("SELECT 1 FROM users WHERE telegram_id = $1")
+        .bind(telegram_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+        .is_some()
+}
+
+/// Local telegram_id to credit: the id itself, its negation for email
+/// users, or the id after importing the user from Remnawave.
+async fn resolve_user(pool: &PgPool, telegram_id: i64) -> Result<i64, CreditError> {
+    if user_exists(pool, telegram_id).await {
+        return Ok(telegram_id);
+    }
+    if telegram_id > 0 && user_exists(pool, -telegram_id).await {
+        info!("[credit] User {} not found, but -{} exists (email user). Using negative ID.", telegram_id, telegram_id);
+        return Ok(-telegram_id);
+    }
+
+    info!("[credit] User {} not in local DB, checking Remnawave...", telegram_id);
+    let remna_user = match remnawave::client().get_user_by_telegram_id(telegram_id).await {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            warn!("[credit] User {} not found in Remnawave", telegram_id);
+            return Err(CreditError::UserNotFound);
+        }
+        Err(e) => {
+            warn!("[credit] User {} not found in Remnawave: {}", telegram_id, e);
+            return Err(CreditError::UserNotFound);
+        }
+    };
+    info!("[credit] Importing user {} from Remnawave (uuid={})", telegram_id, remna_user.uuid);
+    sqlx::query(
+        r#"INSERT INTO users (telegram_id, uuid, subscription_end, is_active, created_at, is_used_trial, game_points, is_used_ref_bonus, game_attempts, username, sub_link, payed_refs, is_pro)
+        VALUES ($1, $2, NOW(), 0, NOW(), false, 0, false, 0, $3, $4, 0, false)"#
+    )
+    .bind(telegram_id)
+    .bind(remna_user.uuid)
+    .bind(remna_user.username.as_deref())
+    .bind(remna_user.subscription_url.unwrap_or_default())
+    .execute(pool)
+    .await
+    .map_err(CreditError::Import)?;
+    info!("[credit] User {} imported to local DB", telegram_id);
+    Ok(telegram_id)
+}
+
+/// Extend a subscription. With an idempotency key, a repeat returns the
+/// first call's body instead of adding the days again.
+pub async fn credit(pool: &PgPool, c: &Credit<'_>) -> Result<Credited, CreditError> {
+    if let Some(key) = c.idempotency_key {
+        if let Some(body) = replay(pool, key, c.telegram_id).await? {
+            return Ok(Credited { body, replayed: true });
+        }
+    }
+
+    let telegram_id = resolve_user(pool, c.telegram_id).await?;
+    let previous_plan: String = sqlx::query_scalar("SELECT plan FROM users WHERE telegram_id = $1")
+        .bind(telegram_id)
+        .fetch_one(pool)
+        .await
+        .map_err(|_| CreditError::UserNotFound)?;
+
+    let catalog = plans::catalog();
+    let plan_def = catalog.resolve(c.plan);
+    let device_limit = plan_def.device_limit;
+
+    // Always extend from the later of (existing end, now). Never destroy paid time:
+    // plan upgrades (family <-> bsfamily), milestone bonuses, and downgrades keep
+    // all remaining days and add `days` more on top. Previous "plan_changed -> reset"
+    // branch was destructive — incident 2026-05-14: 180-day ref milestone reset
+    // active subscriptions to NOW+180.
+    let plan_changed = previous_plan != c.plan && c.plan != "trial" && c.plan != "free";
+    if plan_changed {
+        info!(
+            "[credit] User {} plan transition: {} -> {} (subscription_end preserved)",
+            telegram_id, previous_plan, c.plan
+        );
+    }
+
+    // The UPDATE and the panel sync are queued in one transaction; the PATCH
+    // itself goes through the outbox (see outbox.rs), so a failed PATCH or a
+    // crash after commit is retried instead of leaving the stores diverged.
+    let mut tx = pool.begin().await?;
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET
+            subscription_end = GREATEST(subscription_end, NOW()) + $1 * INTERVAL '1 day',
+            is_active = 1,
+            plan = $2,
+            device_limit = $4
+        WHERE telegram_id = $3
+        RETURNING *
+        "#,
+    )
+    .bind(f64::from(c.days))
+    .bind(c.plan)
+    .bind(telegram_id)
+    .bind(device_limit)
+    .fetch_one(&mut *tx)
+    .await?;
+    let body = json!({
+        "telegram_id": user.telegram_id,
+        "uuid": user.uuid,
+        "subscription_end": user.subscription_end,
+        "is_active": user.is_active,
+        "plan":user.plan
+    });
+
+    if let Some(key) = c.idempotency_key {
+        let price_version = c
+            .price_version
+            .or_else(|| c.amount_rub.map(|_| crate::prices::current().version).filter(|v| *v > 0));
+        // The unique index serialises concurrent calls with the same key:
+        // the loser blocks here until the winner commits, then gets no row.
+        let inserted: Option<i64> = sqlx::query_scalar(
+            "INSERT INTO payments (telegram_id, source, amount_rub, plan, duration, days_added, \
+                                   external_id, metadata, idempotency_key, result, price_version) \
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+             ON CONFLICT (idempotency_key) WHERE idempotency_key IS NOT NULL DO NOTHING \
+             RETURNING id",
+        )
+        .bind(telegram_id)
+        .bind(c.source)
+        .bind(c.amount_rub)
+        .bind(c.plan)
+        .bind(c.duration)
+        .bind(c.days)
+        .bind(c.external_id)
+        .bind(&c.metadata)
+        .bind(key)
+        .bind(&body)
+        .bind(price_version)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if inserted.is_none() {
+            drop(tx);
+            info!("[credit] Concurrent duplicate for key {}, replaying", key);
+            return match replay(pool, key, c.telegram_id).await? {
+                Some(body) => Ok(Credited { body, replayed: true }),
+                None => Err(CreditError::InProgress),
+            };
+        }
+    }
+
+    let outbox_id = outbox::enqueue(&mut tx, telegram_id, c.source).await?;
+    tx.commit().await?;
+
+    // Apply right away so the link works by the time the bot replies. The
+    // subscription is already recorded; on failure the outbox worker retries.
+    match outbox::deliver_now(pool, remnawave::client(), &catalog, outbox_id).await {
+        Ok(squad_list) => {
+            info!("[credit] User {} squads={:?}, is_pro={}, tag={}", telegram_id, squad_list, user.is_pro, plan_def.tag);
+        }
+        Err(e) => {
+            warn!("[credit] Remnawave sync for {} deferred to outbox (row {}): {}", telegram_id, outbox_id, e);
+        }
+    }
+
+    info!("[credit] Success for user {}: plan={}, sub_end={}", user.telegram_id, user.plan, user.subscription_end);
+    Ok(Credited { body, replayed: false })
+}
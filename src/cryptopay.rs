@@ -0,0 +1,151 @@
+//! CryptoPay (@CryptoBot) helpers for `POST /webhooks/cryptopay`.
+//!
+//! Updates are signed: `crypto-pay-api-signature` is the hex
+//! HMAC-SHA256 of the raw body, keyed with SHA256(CRYPTO_BOT_TOKEN).
+//! Invoices are created by `web_create_crypto_payment` with payload
+//! `telegram_id:tariff:duration:price_rub:price_version:days` (see
+//! [`invoice_payload`]). Invoices from before that still carry the older
+//! `telegram_id:tariff:duration` and `...:price_rub:price_version` forms;
+//! [`parse_update`] reads those too, taking `days` from the duration.
+
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+pub const SIGNATURE_HEADER: &str = "crypto-pay-api-signature";
+
+/// Constant-time check of the update signature.
+pub fn verify_signature(token: &str, body: &[u8], signature_hex: &str) -> bool {
+    let Ok(signature) = hex::decode(signature_hex.trim()) else {
+        return false;
+    };
+    let secret = Sha256::digest(token.as_bytes());
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(&secret) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Invoice payload as written by `web_create_crypto_payment`. `days` is the
+/// duration plus any promo bonus days.
+pub fn invoice_payload(telegram_id: i64, tariff: &str, duration: &str, price_rub: i64, price_version: i32, days: i32) -> String {
+    format!("{}:{}:{}:{}:{}:{}", telegram_id, tariff, duration, price_rub, price_version, days)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaidInvoice {
+    pub invoice_id: i64,
+    pub telegram_id: i64,
+    pub tariff: String,
+    pub duration: String,
+    pub days: i32,
+    /// RUB price at invoice time; `None` for invoices created before the
+    /// price was added to the payload.
+    pub amount_rub: Option<f64>,
+    /// Price catalog version the invoice was priced with (prices.rs).
+    pub price_version: Option<i32>,
+    /// asset / amount / paid_* fields, for the ledger row.
+    pub metadata: Value,
+}
+
+/// `Ok(None)` for updates that aren't a paid invoice.
+pub fn parse_update(update: &Value) -> Result<Option<PaidInvoice>, String> {
+    if update["update_type"] != "invoice_paid" {
+        return Ok(None);
+    }
+    let inv = &update["payload"];
+    let invoice_id = inv["invoice_id"].as_i64().ok_or("invoice_id missing")?;
+    if inv["status"] != "paid" {
+        return Ok(None);
+    }
+
+    let payload = inv["payload"].as_str().ok_or("invoice payload missing")?;
+    let parts: Vec<&str> = payload.split(':').collect();
+    if parts.len() < 3 {
+        return Err(format!("bad invoice payload {:?}", payload));
+    }
+    let telegram_id = parts[0].parse::<i64>().map_err(|_| format!("bad telegram_id in {:?}", payload))?;
+    let tariff = parts[1].to_string();
+    let duration = parts[2].to_string();
+    let days = parts
+        .get(5)
+        .and_then(|p| p.parse::<i32>().ok())
+        .or_else(|| crate::prices::duration_days(&duration))
+        .ok_or_else(|| format!("unknown duration {:?}", duration))?;
+    let amount_rub = parts.get(3).and_then(|p| p.parse::<f64>().ok());
+    let price_version = parts.get(4).and_then(|p| p.parse::<i32>().ok());
+
+    let mut metadata = serde_json::Map::new();
+    for key in ["asset", "amount", "paid_asset", "paid_amount", "paid_usd_rate", "paid_at"] {
+        if !inv[key].is_null() {
+            metadata.insert(key.to_string(), inv[key].clone());
+        }
+    }
+
+    Ok(Some(PaidInvoice {
+        invoice_id,
+        telegram_id,
+        tariff,
+        duration,
+        days,
+        amount_rub,
+        price_version,
+        metadata: Value::Object(metadata),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn signature_is_hmac_of_body_keyed_by_token_hash() {
+        let body = br#"{"update_type":"invoice_paid"}"#;
+        let secret = Sha256::digest(b"123:ABC");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+        mac.update(body);
+        let sig = hex::encode(mac.finalize().into_bytes());
+
+        assert!(verify_signature("123:ABC", body, &sig));
+        assert!(!verify_signature("123:ABD", body, &sig));
+        assert!(!verify_signature("123:ABC", b"{}", &sig));
+        assert!(!verify_signature("123:ABC", body, "not-hex"));
+    }
+
+    #[test]
+    fn parses_paid_invoice() {
+        let update = json!({
+            "update_type": "invoice_paid",
+            "payload": {
+                "invoice_id": 777, "status": "paid", "asset": "USDT", "amount": "4.71",
+                "payload": invoice_payload(-42, "family", "1y", 2200, 3, 365),
+            }
+        });
+        let inv = parse_update(&update).unwrap().unwrap();
+        assert_eq!(inv.invoice_id, 777);
+        assert_eq!(inv.telegram_id, -42);
+        assert_eq!(inv.tariff, "family");
+        assert_eq!(inv.days, 365);
+        assert_eq!(inv.amount_rub, Some(2200.0));
+        assert_eq!(inv.price_version, Some(3));
+        assert_eq!(inv.metadata["asset"], "USDT");
+
+        // Payloads from before the price was appended still parse.
+        let mut old = update.clone();
+        old["payload"]["payload"] = json!("42:base:1m");
+        let inv = parse_update(&old).unwrap().unwrap();
+        assert_eq!((inv.amount_rub, inv.price_version), (None, None));
+        assert_eq!(inv.days, 30);
+
+        // Promo bonus days ride along in the payload.
+        let mut bonus = update.clone();
+        bonus["payload"]["payload"] = json!(invoice_payload(42, "base", "3m", 430, 1, 104));
+        assert_eq!(parse_update(&bonus).unwrap().unwrap().days, 104);
+
+        let mut other = update;
+        other["update_type"] = json!("invoice_expired");
+        assert_eq!(parse_update(&other).unwrap(), None);
+    }
+}
@@ -159,6 +159,23 @@ pub async fn send_reset_code(to: &str, code: &str) -> Result<(), String> {
     send_email(to, "Сброс пароля SvoiVPN", &html).await
 }
 
+/// Passwordless login: the code plus a link that signs in without typing
+/// it.
+pub async fn send_login_code(to: &str, code: &str, link: &str) -> Result<(), String> {
+    let message = format!(
+        "Для входа в SvoiVPN введите этот код на сайте или в приложении \
+         либо <a href=\"{}\" style=\"color:#7C6BFF;\">нажмите сюда, чтобы войти</a>:",
+        html_escape(link)
+    );
+    let html = email_template(
+        "Вход в аккаунт",
+        code,
+        &message,
+        "Если вы не пытались войти в SvoiVPN, просто проигнорируйте это письмо. Без кода войти в аккаунт нельзя.",
+    );
+    send_email(to, "Код для входа в SvoiVPN", &html).await
+}
+
 pub async fn send_test_email(to: &str) -> Result<(), String> {
     let html = email_template(
         "Тестовое письмо",
@@ -0,0 +1,208 @@
+//! Persistent scheduled jobs (migrations/016_scheduled_jobs.sql).
+//!
+//! Anything that has to happen later, or takes too long for a request,
+//! goes through [`schedule`] instead of a bare `tokio::spawn`: the row
+//! survives a restart and the worker ([`spawn`]) picks it up once `run_at`
+//! passes. A claim is a lease — a job whose process died mid-run is claimed
+//! again after `locked_until`, so handlers must tolerate running twice.
+//! Fan-outs ([`Job::retryable`]) are the exception: they run at most once,
+//! since a rerun would message everyone already reached again.
+
+use crate::remnawave::{self, RemnawaveApi, UserUpdate};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use std::time::Duration;
+use uuid::Uuid;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+const BATCH: i64 = 10;
+/// Long enough for a full broadcast / email blast; a crashed run is retried
+/// after this.
+const LEASE_SECS: f64 = 30.0 * 60.0;
+const MAX_ATTEMPTS: i32 = 5;
+const RETRY_BASE_SECS: i64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum Job {
+    /// End of the temp_disable_device_limit window.
+    RestoreDeviceLimit { uuid: Uuid, device_limit: i64 },
+    /// FCM fan-out for a `broadcasts` row.
+    SendBroadcast { broadcast_id: i64 },
+    /// News email to every verified, opted-in address.
+    SendEmailBatch { news_text: String },
+}
+
+impl Job {
+    fn split(&self) -> (String, serde_json::Value) {
+        let v = serde_json::to_value(self).unwrap_or_default();
+        let kind = v["kind"].as_str().unwrap_or_default().to_string();
+        (kind, v.get("payload").cloned().unwrap_or_else(|| json!({})))
+    }
+
+    /// Whether a failed or interrupted run may start over. A broadcast or
+    /// email blast that fails partway has already reached part of its
+    /// recipients, and nothing records which.
+    pub fn retryable(&self) -> bool {
+        !matches!(self, Job::SendBroadcast { .. } | Job::SendEmailBatch { .. })
+    }
+
+    fn from_row(kind: &str, payload: serde_json::Value) -> Result<Job, String> {
+        serde_json::from_value(json!({ "kind": kind, "payload": payload }))
+            .map_err(|e| format!("bad {} payload: {}", kind, e))
+    }
+}
+
+/// Queue `job` to run at `run_at`. Returns the job id.
+pub async fn schedule(pool: &PgPool, job: &Job, run_at: DateTime<Utc>) -> Result<i64, sqlx::Error> {
+    let (kind, payload) = job.split();
+    sqlx::query_scalar("INSERT INTO scheduled_jobs (kind, payload, run_at) VALUES ($1, $2, $3) RETURNING id")
+        .bind(kind)
+        .bind(payload)
+        .bind(run_at)
+        .fetch_one(pool)
+        .await
+}
+
+/// Drop a job that hasn't started yet.
+pub async fn cancel(pool: &PgPool, id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM scheduled_jobs WHERE id = $1 AND status = 'pending'")
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+
+#[derive(sqlx::FromRow)]
+struct Claimed {
+    id: i64,
+    kind: String,
+    payload: serde_json::Value,
+    attempts: i32,
+}
+
+async fn claim_due(pool: &PgPool) -> Result<Vec<Claimed>, sqlx::Error> {
+    sqlx::query_as::<_, Claimed>(
+        "UPDATE scheduled_jobs \
+         SET status = 'running', attempts = attempts + 1, \
+             locked_until = NOW() + make_interval(secs => $2) \
+         WHERE id IN ( \
+             SELECT id FROM scheduled_jobs \
+             WHERE (status = 'pending' AND run_at <= NOW()) \
+                OR (status = 'running' AND locked_until < NOW()) \
+             ORDER BY run_at LIMIT $1 FOR UPDATE SKIP LOCKED) \
+         RETURNING id, kind, payload, attempts",
+    )
+    .bind(BATCH)
+    .bind(LEASE_SECS)
+    .fetch_all(pool)
+    .await
+}
+
+async fn execute(pool: &PgPool, job: Job) -> Result<(), String> {
+    match job {
+        Job::RestoreDeviceLimit { uuid, device_limit } => {
+            log::info!("[jobs] restoring device limit {} for uuid={}", device_limit, uuid);
+            let update = UserUpdate { hwid_device_limit: Some(device_limit), ..UserUpdate::new(uuid) };
+            match remnawave::client().update_user(&update).await {
+                Ok(_) => Ok(()),
+                // User deleted from the panel in the meantime — nothing to restore.
+                Err(e) if e.is_not_found() => Ok(()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Job::SendBroadcast { broadcast_id } => crate::web_handlers::run_broadcast(pool, broadcast_id).await,
+        Job::SendEmailBatch { news_text } => crate::web_handlers::blast_news_email(pool, &news_text).await,
+    }
+}
+
+async fn finish(pool: &PgPool, item: &Claimed, retryable: bool, result: Result<(), String>) {
+    let q = match &result {
+        Ok(()) => sqlx::query(
+            "UPDATE scheduled_jobs SET status = 'done', finished_at = NOW(), last_error = NULL WHERE id = $1",
+        )
+        .bind(item.id),
+        Err(e) if !retryable || item.attempts >= MAX_ATTEMPTS => {
+            log::error!("[jobs] #{} {} failed permanently: {}", item.id, item.kind, e);
+            sqlx::query(
+                "UPDATE scheduled_jobs SET status = 'failed', finished_at = NOW(), last_error = $2 WHERE id = $1",
+            )
+            .bind(item.id)
+            .bind(e.clone())
+        }
+        Err(e) => {
+            log::warn!("[jobs] #{} {} attempt {} failed: {}", item.id, item.kind, item.attempts, e);
+            sqlx::query(
+                "UPDATE scheduled_jobs SET status = 'pending', last_error = $2, \
+                 run_at = NOW() + make_interval(secs => $3) WHERE id = $1",
+            )
+            .bind(item.id)
+            .bind(e.clone())
+            .bind((RETRY_BASE_SECS << (item.attempts - 1).clamp(0, 10)) as f64)
+        }
+    };
+    if let Err(e) = q.execute(pool).await {
+        log::error!("[jobs] failed to update #{}: {}", item.id, e);
+    }
+}
+
+/// Background worker, started from main. Due jobs left over from before a
+/// restart are picked up on the first poll.
+pub fn spawn(pool: PgPool) {
+    tokio::spawn(async move {
+        loop {
+            let due = match claim_due(&pool).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    log::error!("[jobs] claim failed: {}", e);
+                    Vec::new()
+                }
+            };
+            for item in due {
+                let pool = pool.clone();
+                // One task per job so a long broadcast doesn't hold up a
+                // device-limit restore behind it.
+                tokio::spawn(async move {
+                    let (retryable, result) = match Job::from_row(&item.kind, item.payload.clone()) {
+                        // A second claim of a fan-out is a run that died
+                        // midway (lease expired); don't start it over.
+                        Ok(job) if !job.retryable() && item.attempts > 1 => {
+                            (false, Err("interrupted run, not retried".to_string()))
+                        }
+                        Ok(job) => (job.retryable(), execute(&pool, job).await),
+                        Err(e) => (true, Err(e)),
+                    };
+                    finish(&pool, &item, retryable, result).await;
+                });
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_round_trips_through_kind_and_payload() {
+        let job = Job::RestoreDeviceLimit { uuid: Uuid::nil(), device_limit: 10 };
+        let (kind, payload) = job.split();
+        assert_eq!(kind, "restore_device_limit");
+        assert_eq!(payload, json!({"uuid": Uuid::nil(), "device_limit": 10}));
+        assert_eq!(Job::from_row(&kind, payload).unwrap(), job);
+
+        let (kind, payload) = Job::SendBroadcast { broadcast_id: 7 }.split();
+        assert_eq!(kind, "send_broadcast");
+        assert!(Job::from_row("no_such_job", payload).is_err());
+    }
+
+    #[test]
+    fn fan_outs_are_not_retried() {
+        assert!(Job::RestoreDeviceLimit { uuid: Uuid::nil(), device_limit: 1 }.retryable());
+        assert!(!Job::SendBroadcast { broadcast_id: 7 }.retryable());
+        assert!(!Job::SendEmailBatch { news_text: "hi".into() }.retryable());
+    }
+}
@@ -1,45 +1,199 @@
-use actix_web::{HttpRequest, HttpResponse};
+use crate::sessions;
+use actix_web::{web, HttpRequest, HttpResponse};
 use hmac::{Hmac, Mac};
-use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey};
+use jsonwebtoken::{encode, decode, decode_header, Header, Validation, EncodingKey, DecodingKey};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sha2::Sha256;
+use sqlx::PgPool;
 use std::collections::HashMap;
+use uuid::Uuid;
 
 lazy_static::lazy_static! {
-    static ref JWT_SECRET: String = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    static ref KEYRING: Keyring = Keyring::from_env().unwrap_or_else(|e| panic!("JWT keys: {}", e));
     static ref BOT_TOKEN: String = std::env::var("BOT_TOKEN_TG").expect("BOT_TOKEN_TG must be set");
+    static ref ACCESS_TOKEN_TTL_MINUTES: i64 = std::env::var("ACCESS_TOKEN_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|m| *m > 0)
+        .unwrap_or(15);
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub telegram_id: i64,
     pub exp: usize,
+    /// Session row (sessions.rs); absent on tokens issued before sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
 }
 
-pub fn create_token(telegram_id: i64) -> Result<String, jsonwebtoken::errors::Error> {
-    // Sessions never expire by product decision. We still emit an `exp` claim
-    // (the Claims struct requires it and some JWT tooling expects it), but set
-    // it ~100 years out. Combined with `validate_exp = false` on decode below,
-    // no user is ever signed out due to token age — including holders of older
-    // 30-day tokens issued before this change.
+/// Lifetime of the access tokens issued before refresh tokens (~100 years);
+/// a jti-less one was issued this long before its `exp`.
+const LEGACY_TOKEN_LIFETIME_DAYS: i64 = 36_500;
+
+/// HMAC secrets by `kid`.
+///
+/// JWT_KEYS=`kid:secret,kid:secret` lists every key tokens may be signed
+/// with and JWT_KID picks the one new tokens are signed with; the others
+/// only verify, so a secret is rotated by adding a key, switching JWT_KID,
+/// and dropping the old key once the access tokens it signed have expired.
+/// JWT_SECRET verifies tokens without a `kid` (everything issued before
+/// the keyring) and signs when no JWT_KEYS are set.
+struct Keyring {
+    keys: HashMap<String, String>,
+    current: Option<String>,
+    legacy: Option<String>,
+}
+
+impl Keyring {
+    fn from_env() -> Result<Keyring, String> {
+        let var = |name| std::env::var(name).ok().filter(|v: &String| !v.trim().is_empty());
+        Keyring::parse(var("JWT_KEYS").as_deref(), var("JWT_KID").as_deref(), var("JWT_SECRET"))
+    }
+
+    fn parse(keys: Option<&str>, current: Option<&str>, legacy: Option<String>) -> Result<Keyring, String> {
+        let mut map = HashMap::new();
+        for (n, entry) in keys.unwrap_or("").split(',').map(str::trim).filter(|e| !e.is_empty()).enumerate() {
+            // The entry itself isn't echoed: it holds a secret.
+            let (kid, secret) = entry
+                .split_once(':')
+                .filter(|(kid, secret)| !kid.is_empty() && !secret.is_empty())
+                .ok_or_else(|| format!("JWT_KEYS entry #{} must be kid:secret", n + 1))?;
+            if map.insert(kid.to_string(), secret.to_string()).is_some() {
+                return Err(format!("duplicate kid {:?} in JWT_KEYS", kid));
+            }
+        }
+        let current = match current {
+            Some(kid) if map.contains_key(kid) => Some(kid.to_string()),
+            Some(kid) => return Err(format!("JWT_KID {:?} is not in JWT_KEYS", kid)),
+            None if map.is_empty() => None,
+            None => return Err("JWT_KID must name the signing key in JWT_KEYS".to_string()),
+        };
+        if current.is_none() && legacy.is_none() {
+            return Err("set JWT_SECRET or JWT_KEYS".to_string());
+        }
+        Ok(Keyring { keys: map, current, legacy })
+    }
+
+    /// Header and secret new tokens are signed with.
+    fn signing(&self) -> (Header, &str) {
+        match &self.current {
+            Some(kid) => (Header { kid: Some(kid.clone()), ..Header::default() }, &self.keys[kid]),
+            None => (Header::default(), self.legacy.as_deref().unwrap_or_default()),
+        }
+    }
+
+    fn verifying(&self, kid: Option<&str>) -> Option<&str> {
+        match kid {
+            Some(kid) => self.keys.get(kid).map(String::as_str),
+            None => self.legacy.as_deref(),
+        }
+    }
+
+    fn link_mac(secret: &str, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+        mac.update(b"link:");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    fn sign_link(&self, payload: &str) -> String {
+        let (_, secret) = self.signing();
+        hex::encode(Keyring::link_mac(secret, payload).finalize().into_bytes())
+    }
+
+    /// Checked against every key, so links mailed just before a rotation
+    /// still work.
+    fn verify_link(&self, payload: &str, sig: &str) -> bool {
+        let Ok(sig) = hex::decode(sig) else {
+            return false;
+        };
+        self.keys
+            .values()
+            .chain(self.legacy.as_ref())
+            .any(|secret| Keyring::link_mac(secret, payload).verify_slice(&sig).is_ok())
+    }
+}
+
+/// Signature for a link mailed to the user (magic login links), hex.
+pub fn sign_link(payload: &str) -> String {
+    KEYRING.sign_link(payload)
+}
+
+pub fn verify_link(payload: &str, sig: &str) -> bool {
+    KEYRING.verify_link(payload, sig)
+}
+
+/// Access token for session `jti`, valid ACCESS_TOKEN_TTL_MINUTES (15 by
+/// default). The "token itself still never expires" in 027_sessions.sql
+/// predates 028_refresh_tokens.sql: it only holds for tokens issued before
+/// that, see [`LEGACY_TOKEN_LIFETIME_DAYS`].
+pub fn create_token(telegram_id: i64, jti: Uuid) -> Result<String, jsonwebtoken::errors::Error> {
+    // Access tokens are short-lived; the session itself never expires by
+    // product decision — the client swaps its refresh token for a new access
+    // token (POST /web/auth/refresh) and sessions end only by revocation.
     let expiration = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::days(36_500))
+        .checked_add_signed(chrono::Duration::minutes(*ACCESS_TOKEN_TTL_MINUTES))
         .expect("valid timestamp")
         .timestamp() as usize;
 
     let claims = Claims {
         telegram_id,
         exp: expiration,
+        jti: Some(jti.to_string()),
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
-    )
+    let (header, secret) = KEYRING.signing();
+    encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes()))
+}
+
+/// A signed-in session's credentials, as returned by the auth endpoints.
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Access token lifetime, seconds.
+    pub expires_in: i64,
+}
+
+fn sign(grant: sessions::Grant) -> Result<Tokens, String> {
+    let access_token = create_token(grant.telegram_id, grant.jti).map_err(|e| e.to_string())?;
+    Ok(Tokens { access_token, refresh_token: grant.refresh_token, expires_in: *ACCESS_TOKEN_TTL_MINUTES * 60 })
+}
+
+/// Open a session for `telegram_id` (user agent and IP from `req`) and sign
+/// its tokens.
+pub async fn issue_token(pool: &PgPool, req: &HttpRequest, telegram_id: i64) -> Result<Tokens, String> {
+    let grant = sessions::create(pool, telegram_id, &sessions::client(req))
+        .await
+        .map_err(|e| e.to_string())?;
+    sign(grant)
 }
 
-pub fn extract_telegram_id(req: &HttpRequest) -> Result<i64, HttpResponse> {
+pub enum Refresh {
+    Issued(Tokens),
+    /// Already rotated by a concurrent request.
+    Raced,
+    Invalid,
+}
+
+/// Rotate a refresh token and sign a new access token for its session.
+pub async fn refresh_token(pool: &PgPool, req: &HttpRequest, refresh_token: &str) -> Result<Refresh, String> {
+    match sessions::refresh(pool, refresh_token, &sessions::client(req)).await.map_err(|e| e.to_string())? {
+        sessions::Refreshed::Rotated(grant) => sign(grant).map(Refresh::Issued),
+        sessions::Refreshed::Raced => Ok(Refresh::Raced),
+        sessions::Refreshed::Invalid => Ok(Refresh::Invalid),
+    }
+}
+
+/// The caller of a request with a valid, unrevoked token.
+pub struct Auth {
+    pub telegram_id: i64,
+    /// None for tokens issued before sessions.
+    pub session_id: Option<i64>,
+}
+
+fn decode_claims(req: &HttpRequest) -> Result<Claims, HttpResponse> {
     let auth_header = req
         .headers()
         .get("Authorization")
@@ -50,19 +204,68 @@ pub fn extract_telegram_id(req: &HttpRequest) -> Result<i64, HttpResponse> {
         .strip_prefix("Bearer ")
         .ok_or_else(|| HttpResponse::Unauthorized().body("Invalid Authorization format"))?;
 
-    // Do NOT reject tokens for being past their `exp` — sessions are permanent.
-    // Signature is still verified, so tampered/forged tokens are rejected.
+    let header = decode_header(token).map_err(|_| HttpResponse::Unauthorized().body("Invalid token"))?;
+    let secret = KEYRING
+        .verifying(header.kid.as_deref())
+        .ok_or_else(|| HttpResponse::Unauthorized().body("Invalid token"))?;
+
+    // `exp` is checked only on tokens with a session (`jti`): the first of
+    // those were issued ~100 years out, so nobody is signed out, and the
+    // short-lived ones answer "Token expired" so the client refreshes.
+    // Tokens from before sessions (some with 30-day `exp`) stay permanent.
     let mut validation = Validation::default();
     validation.validate_exp = false;
 
     let token_data = decode::<Claims>(
         token,
-        &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
+        &DecodingKey::from_secret(secret.as_bytes()),
         &validation,
     )
     .map_err(|_| HttpResponse::Unauthorized().body("Invalid token"))?;
 
-    Ok(token_data.claims.telegram_id)
+    let now = chrono::Utc::now().timestamp() as usize;
+    if token_data.claims.jti.is_some() && token_data.claims.exp < now.saturating_sub(validation.leeway as usize) {
+        return Err(HttpResponse::Unauthorized().body("Token expired"));
+    }
+
+    Ok(token_data.claims)
+}
+
+/// Verify the bearer token and that its session is still live.
+pub async fn authenticate(req: &HttpRequest) -> Result<Auth, HttpResponse> {
+    let claims = decode_claims(req)?;
+    let pool = req
+        .app_data::<web::Data<PgPool>>()
+        .ok_or_else(|| HttpResponse::InternalServerError().json(json!({"error": "internal server error"})))?;
+    let db_error = |e: sqlx::Error| {
+        log::error!("[jwt] session check failed: {}", e);
+        HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+    };
+
+    match claims.jti.as_deref() {
+        Some(jti) => {
+            let jti = Uuid::parse_str(jti).map_err(|_| HttpResponse::Unauthorized().body("Invalid token"))?;
+            let client = sessions::client(req);
+            match sessions::check(pool, jti, claims.telegram_id, &client).await.map_err(db_error)? {
+                Some(id) => Ok(Auth { telegram_id: claims.telegram_id, session_id: Some(id) }),
+                None => Err(HttpResponse::Unauthorized().body("Session revoked")),
+            }
+        }
+        None => {
+            let issued_at = chrono::DateTime::from_timestamp(claims.exp as i64, 0)
+                .map(|exp| exp - chrono::Duration::days(LEGACY_TOKEN_LIFETIME_DAYS))
+                .unwrap_or_default();
+            if sessions::legacy_allowed(pool, claims.telegram_id, issued_at).await.map_err(db_error)? {
+                Ok(Auth { telegram_id: claims.telegram_id, session_id: None })
+            } else {
+                Err(HttpResponse::Unauthorized().body("Session revoked"))
+            }
+        }
+    }
+}
+
+pub async fn extract_telegram_id(req: &HttpRequest) -> Result<i64, HttpResponse> {
+    authenticate(req).await.map(|a| a.telegram_id)
 }
 
 pub fn validate_init_data(init_data: &str) -> Option<i64> {
@@ -135,3 +338,49 @@ pub fn validate_telegram_login(data: &HashMap<String, String>) -> Option<i64> {
 
     data.get("id")?.parse().ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyring_signs_with_current_kid_and_verifies_all() {
+        let ring = Keyring::parse(Some("k1:old, k2:new"), Some("k2"), Some("legacy".to_string())).unwrap();
+        let (header, secret) = ring.signing();
+        assert_eq!(header.kid.as_deref(), Some("k2"));
+        assert_eq!(secret, "new");
+        assert_eq!(ring.verifying(Some("k1")), Some("old"));
+        assert_eq!(ring.verifying(None), Some("legacy"));
+        assert_eq!(ring.verifying(Some("k3")), None);
+    }
+
+    #[test]
+    fn link_signatures_survive_rotation() {
+        let old = Keyring::parse(Some("k1:old"), Some("k1"), None).unwrap();
+        let rotated = Keyring::parse(Some("k1:old,k2:new"), Some("k2"), None).unwrap();
+        let sig = old.sign_link("login:1");
+        assert!(rotated.verify_link("login:1", &sig));
+        assert!(!rotated.verify_link("login:2", &sig));
+        assert!(!rotated.verify_link("login:1", "zz"));
+        let dropped = Keyring::parse(Some("k2:new"), Some("k2"), None).unwrap();
+        assert!(!dropped.verify_link("login:1", &sig));
+    }
+
+    #[test]
+    fn keyring_falls_back_to_jwt_secret() {
+        let ring = Keyring::parse(None, None, Some("legacy".to_string())).unwrap();
+        let (header, secret) = ring.signing();
+        assert_eq!(header.kid, None);
+        assert_eq!(secret, "legacy");
+    }
+
+    #[test]
+    fn keyring_rejects_bad_config() {
+        assert!(Keyring::parse(None, None, None).is_err());
+        assert!(Keyring::parse(Some("k1:a"), None, None).is_err());
+        assert!(Keyring::parse(Some("k1:a"), Some("k2"), None).is_err());
+        assert!(Keyring::parse(Some("k1:a,k1:b"), Some("k1"), None).is_err());
+        let err = Keyring::parse(Some("k1:a,s3cret"), Some("k1"), None).err().unwrap();
+        assert!(!err.contains("s3cret"), "{}", err);
+    }
+}
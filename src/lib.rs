@@ -1,3 +1,6 @@
 //! Library exports for integration tests. The binary is built from main.rs.
 pub mod push_web;
 pub mod proxy;
+pub mod plans;
+pub mod remnawave;
+pub mod subscription;
@@ -1,16 +1,38 @@
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
 use serde_json::json;
 use sqlx::postgres::PgPool;
-use uuid::Uuid;
 use chrono::Utc;
 use log::{info, warn, error};
 mod models;
+mod analytics;
 mod jwt;
 mod web_handlers;
 mod email;
 mod push;
 mod push_web;
 mod proxy;
+mod autorenew;
+mod credit;
+mod cryptopay;
+mod jobs;
+mod outbox;
+mod passkeys;
+mod pending;
+mod plans;
+mod prices;
+mod promo;
+mod rates;
+mod receipts;
+mod reconcile;
+mod refund;
+mod remnawave;
+mod sessions;
+mod stars;
+mod subscription;
+mod totp;
+mod wallet;
+mod yookassa;
+use remnawave::{RemnawaveApi, CreateUser as CreateRemnawaveUser, UserUpdate};
 use models::{User, NewUser, AddReferralData, ExtendSubscriptionRequest, ExpiringUser, PromoCode, CreatePromoRequest, ValidatePromoRequest, UsePromoRequest, SavePaymentMethodRequest, ToggleAutoRenewRequest, AutoRenewUser, AutoRenewAttemptRequest, ToggleProRequest, SupportChatRequest, InternalSupportChatRequest, InternalSupportEscalateRequest, AppSupportMessageResponse};
 use sqlx::Row;
 use std::collections::HashMap;
@@ -19,8 +41,6 @@ use chrono::{Duration};
 
 lazy_static::lazy_static! {
     static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
-    static ref REMNAWAVE_API_BASE: String = std::env::var("REMNAWAVE_API_BASE").unwrap_or_else(|_| "http://localhost:3000/api".to_string());
-    static ref REMNAWAVE_API_KEY: String = std::env::var("REMNAWAVE_API_KEY").expect("REMNAWAVE_API_KEY must be set");
     static ref MASTER_KEY: String = std::env::var("MASTER_KEY").expect("MASTER_KEY must be set");
     static ref PROXY_HOST: String = std::env::var("PROXY_HOST").unwrap_or_else(|_| "svoiweb.ru".to_string());
     static ref PROXY_PORT: String = std::env::var("PROXY_PORT").unwrap_or_else(|_| "8444".to_string());
@@ -52,58 +72,30 @@ async fn create_user(pool: web::Data<PgPool>, data: web::Json<NewUser>) -> HttpR
         format!("user_{}", data.telegram_id)
     });
 
-    let api_response = match HTTP_CLIENT
-        .post(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .json(&json!({
-            "username": username,
-            "status": "DISABLED",
-            "trafficLimitBytes": 0,
-            "trafficLimitStrategy": "MONTH",
-            "expireAt": Utc::now(),
-            "createdAt": Utc::now(),
-            "telegramId": data.telegram_id,
-            "hwidDeviceLimit": 2,
-            "activeInternalSquads": [
-                "514a5e22-c599-4f72-81a5-e646f0391db7"
-            ],
-        }))
-        .send()
+    let baseline = plans::catalog().baseline();
+    let created = match remnawave::client()
+        .create_user(&CreateRemnawaveUser {
+            username: username.clone(),
+            status: "DISABLED".to_string(),
+            traffic_limit_bytes: 0,
+            traffic_limit_strategy: "MONTH".to_string(),
+            expire_at: Utc::now(),
+            created_at: Utc::now(),
+            telegram_id: data.telegram_id,
+            hwid_device_limit: baseline.device_limit,
+            active_internal_squads: baseline.squads,
+        })
         .await
     {
-        Ok(resp) => resp,
+        Ok(u) => u,
         Err(e) => {
             error!("[create_user] Remnawave API call failed for {}: {}", data.telegram_id, e);
             return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
         }
     };
 
-    if !api_response.status().is_success() {
-        error!("[create_user] Remnawave API error for {}: {}", data.telegram_id, api_response.status());
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match api_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
-        Err(e) => {
-            error!("[create_user] Failed to parse Remnawave response for {}: {}", data.telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
-        }
-    };
-
-    let uuid = Uuid::parse_str(
-        json_response["response"]["uuid"]
-        .as_str()
-        .unwrap()
-    ).unwrap();
-
-    let sub_url = json_response["response"]["subscriptionUrl"]
-        .as_str()
-        .unwrap()
-        .to_string();
+    let uuid = created.uuid;
+    let sub_url = created.subscription_url.unwrap_or_default();
 
     let mut tx = match pool.begin().await {
         Ok(tx) => tx,
@@ -178,286 +170,41 @@ async fn extend_subscription(
     telegram_id: web::Path<i64>,
     request: web::Json<ExtendSubscriptionRequest>,
 ) -> HttpResponse {
-    let mut telegram_id = telegram_id.into_inner();
-    let days = request.days;
-    let plan = request.plan.clone();
-    info!("[extend_subscription] telegram_id={}, days={}, plan={}", telegram_id, days, plan);
+    let telegram_id = telegram_id.into_inner();
+    info!("[extend_subscription] telegram_id={}, days={}, plan={}", telegram_id, request.days, request.plan);
 
-    // Check if user exists locally; if not found, try negative ID (email users)
-    let user_exists = sqlx::query("SELECT 1 FROM users WHERE telegram_id = $1")
-        .bind(telegram_id)
-        .fetch_optional(pool.get_ref())
-        .await
-        .unwrap_or(None);
-
-    if user_exists.is_none() && telegram_id > 0 {
-        let neg_id = -telegram_id;
-        let neg_exists = sqlx::query("SELECT 1 FROM users WHERE telegram_id = $1")
-            .bind(neg_id)
-            .fetch_optional(pool.get_ref())
-            .await
-            .unwrap_or(None);
-        if neg_exists.is_some() {
-            info!("[extend_subscription] User {} not found, but -{} exists (email user). Using negative ID.", telegram_id, telegram_id);
-            telegram_id = neg_id;
-        }
+    let idempotency_key = request.idempotency_key.as_deref().filter(|k| !k.is_empty());
+    if idempotency_key.is_some_and(|k| k.len() > 128) {
+        return HttpResponse::BadRequest().json(json!({"error": "idempotency_key too long"}));
     }
 
-    let user_exists = sqlx::query("SELECT 1 FROM users WHERE telegram_id = $1")
-        .bind(telegram_id)
-        .fetch_optional(pool.get_ref())
-        .await
-        .unwrap_or(None);
-
-    if user_exists.is_none() {
-        info!("[extend_subscription] User {} not in local DB, checking Remnawave...", telegram_id);
-        let remna_resp = match HTTP_CLIENT
-            .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-            .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-            .header("Content-Type", "application/json")
-            .header("X-Forwarded-For", "127.0.0.1")
-            .header("X-Forwarded-Proto", "https")
-            .send()
-            .await
-        {
-            Ok(resp) if resp.status().is_success() => match resp.json::<serde_json::Value>().await {
-                Ok(json) => json,
-                Err(_) => {
-                    warn!("[extend_subscription] User {} not found in Remnawave (parse error)", telegram_id);
-                    return HttpResponse::NotFound().body("User not found");
-                }
-            },
-            _ => {
-                warn!("[extend_subscription] User {} not found in Remnawave", telegram_id);
-                return HttpResponse::NotFound().body("User not found");
-            }
-        };
-
-        let remna_user = &remna_resp["response"][0];
-        let uuid_str = match remna_user["uuid"].as_str() {
-            Some(u) => u,
-            None => {
-                warn!("[extend_subscription] User {} not found in Remnawave (no uuid)", telegram_id);
-                return HttpResponse::NotFound().body("User not found");
-            }
-        };
-        let uuid = match Uuid::parse_str(uuid_str) {
-            Ok(u) => u,
-            Err(_) => return HttpResponse::InternalServerError().body("Invalid uuid from Remnawave"),
-        };
-        let sub_url = remna_user["subscriptionUrl"].as_str().unwrap_or("").to_string();
-        let username = remna_user["username"].as_str().map(|s| s.to_string());
-
-        info!("[extend_subscription] Importing user {} from Remnawave (uuid={})", telegram_id, uuid);
-        match sqlx::query(
-            r#"INSERT INTO users (telegram_id, uuid, subscription_end, is_active, created_at, is_used_trial, game_points, is_used_ref_bonus, game_attempts, username, sub_link, payed_refs, is_pro)
-            VALUES ($1, $2, NOW(), 0, NOW(), false, 0, false, 0, $3, $4, 0, false)"#
-        )
-        .bind(telegram_id)
-        .bind(uuid)
-        .bind(username.as_deref())
-        .bind(&sub_url)
-        .execute(pool.get_ref())
-        .await
-        {
-            Ok(_) => info!("[extend_subscription] User {} imported to local DB", telegram_id),
-            Err(e) => {
-                error!("[extend_subscription] Failed to import user {} to local DB: {}", telegram_id, e);
-                return HttpResponse::InternalServerError().body("Failed to import user");
-            }
-        }
+    let source = request.source.as_deref().unwrap_or("other");
+    // Paid to the wallet by ref_bonus / payed_refs instead.
+    if wallet::referral_reward_rub(source).is_some() {
+        return HttpResponse::Conflict().json(json!({"error": "referral reward is paid to the wallet"}));
     }
 
-    let user = match sqlx::query!(
-        "SELECT * FROM users WHERE telegram_id = $1",
-        telegram_id
-    )
-    .fetch_one(pool.get_ref())
-    .await
-    {
-        Ok(record) => record,
-        Err(_) => {
-            warn!("[extend_subscription] User {} not found", telegram_id);
-            return HttpResponse::NotFound().body("User not found");
-        }
-    };
-
-    let uuid = user.uuid;
-
-    let device_limit = match plan.as_str() {
-        "base" | "bsbase" => 2,
-        "family" | "bsfamily" => 10,
-        _ => 2,
+    let c = credit::Credit {
+        idempotency_key,
+        ..credit::Credit::new(telegram_id, request.days, &request.plan, source)
     };
-
-    let traffic_limit: u64 = 0; // unlimited for all plans
-
-    let tag = match plan.as_str(){
-        "base" | "bsbase" => "PAID",
-        "family" | "bsfamily" => "PAID",
-        "trial" => "TRIAL",
-        "free" => "FREE",
-        _ => "UNKNOWN",
-    };
-
-    let default_squad = "514a5e22-c599-4f72-81a5-e646f0391db7";
-    let bs_squad = "9e60626e-32a8-4d91-a2f8-2aa3fecf7b23";
-    let pro_squad = "b6a4e86b-b769-4c86-a2d9-f31bbe645029";
-
-    // Fetch current squads from Remnawave. Unmanaged squads (e.g. Claude,
-    // admin-granted) are preserved; managed squads (default/bs/pro) are then
-    // reconciled below according to the user's actual entitlement.
-    let get_response = match HTTP_CLIENT
-        .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
+    match credit::credit(pool.get_ref(), &c).await {
+        Ok(credited) => HttpResponse::Ok().json(credited.body),
         Err(e) => {
-            error!("[extend_subscription] Failed to get user {} from Remnawave: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
-        }
-    };
-
-    let mut squad_list: Vec<String> = if get_response.status().is_success() {
-        match get_response.json::<serde_json::Value>().await {
-            Ok(json) => json["response"][0]["activeInternalSquads"]
-                .as_array()
-                .map(|arr| {
-                    arr.iter()
-                        .filter_map(|s| s["uuid"].as_str().map(|v| v.to_string()))
-                        .collect()
-                })
-                .unwrap_or_default(),
-            Err(_) => vec![],
-        }
-    } else {
-        vec![]
-    };
-
-    // Ensure default squad is always present
-    if !squad_list.contains(&default_squad.to_string()) {
-        squad_list.push(default_squad.to_string());
-    }
-    // BS squad is entitlement-authoritative for REAL purchasable plans only:
-    //   bsbase | bsfamily -> grant BS
-    //   base   | family   -> REVOKE BS (fixes money-leak: a regular plan must
-    //                        not keep БС-обход that was paid for on an old plan)
-    //   trial | free | _  -> leave as-is. Bonus/trial extends (ref-milestone,
-    //                        trial grant, first-purchase) pass non-purchasable
-    //                        values and MUST NOT change BS entitlement, otherwise
-    //                        a paying BS user loses БС for earning a bonus.
-    match plan.as_str() {
-        "bsbase" | "bsfamily" => {
-            if !squad_list.contains(&bs_squad.to_string()) {
-                squad_list.push(bs_squad.to_string());
+            error!("[extend_subscription] {} failed: {}", telegram_id, e);
+            match e {
+                credit::CreditError::UserNotFound => HttpResponse::NotFound().body("User not found"),
+                credit::CreditError::Import(_) => HttpResponse::InternalServerError().body("Failed to import user"),
+                credit::CreditError::KeyConflict => {
+                    HttpResponse::Conflict().json(json!({"error": "idempotency_key already used"}))
+                }
+                credit::CreditError::InProgress => {
+                    HttpResponse::Conflict().json(json!({"error": "duplicate request in progress"}))
+                }
+                credit::CreditError::Db(_) => HttpResponse::InternalServerError().body("Failed to update database"),
             }
         }
-        "base" | "family" => {
-            squad_list.retain(|s| s.as_str() != bs_squad);
-        }
-        _ => {}
-    }
-    // Keep PRO squad if user has pro enabled
-    if user.is_pro && !squad_list.contains(&pro_squad.to_string()) {
-        squad_list.push(pro_squad.to_string());
-    }
-    let squads = json!(squad_list);
-    info!("[extend_subscription] User {} squads={:?}, is_pro={}, tag={}", telegram_id, squad_list, user.is_pro, tag);
-
-    let now_utc = Utc::now();
-    // Always extend from the later of (existing end, now). Never destroy paid time:
-    // plan upgrades (family <-> bsfamily), milestone bonuses, and downgrades keep
-    // all remaining days and add `days` more on top. Previous "plan_changed -> reset"
-    // branch was destructive — incident 2026-05-14: 180-day ref milestone reset
-    // active subscriptions to NOW+180.
-    let plan_changed = user.plan != plan && plan != "trial" && plan != "free";
-    let effective_start_time = std::cmp::max(user.subscription_end, now_utc);
-    let expire_at = effective_start_time + Duration::days(days.into());
-    let expire_at_str = expire_at.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-
-    let api_response = match HTTP_CLIENT
-        .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .json(&json!({
-            "uuid": uuid,
-            "status": "ACTIVE",
-            "trafficLimitBytes": traffic_limit,
-            "trafficLimitStrategy": "MONTH",
-            "activeUserInbounds": [
-                "d92c68b5-41e9-47d0-b7ee-89e7c8640a59"
-            ],
-            "activeInternalSquads": squads,
-            "tag": tag,
-            "expireAt": expire_at_str,
-            "telegramId": user.telegram_id,
-            "hwidDeviceLimit": device_limit
-        }))
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("[extend_subscription] Remnawave API call failed for {}: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
-        }
-    };
-
-    if !api_response.status().is_success() {
-        error!("[extend_subscription] Remnawave API error for {}: {}", telegram_id, api_response.status());
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    // Single non-destructive UPDATE path. plan_changed kept for logging only.
-    if plan_changed {
-        info!(
-            "[extend_subscription] User {} plan transition: {} -> {} (subscription_end preserved)",
-            telegram_id, user.plan, plan
-        );
-    }
-    let result = sqlx::query_as!(
-        User,
-        r#"
-        UPDATE users
-        SET
-            subscription_end = GREATEST(subscription_end, NOW()) + $1 * INTERVAL '1 day',
-            is_active = 1,
-            plan = $2,
-            device_limit = $4
-        WHERE telegram_id = $3
-        RETURNING *
-        "#,
-        days as i32,
-        plan,
-        telegram_id,
-        device_limit as i64
-    )
-    .fetch_one(pool.get_ref())
-    .await;
-    match result {
-        Ok(user) => {
-            info!("[extend_subscription] Success for user {}: plan={}, sub_end={}", user.telegram_id, user.plan, user.subscription_end);
-            HttpResponse::Ok().json(json!({
-                "telegram_id": user.telegram_id,
-                "uuid": uuid,
-                "subscription_end": user.subscription_end,
-                "is_active": user.is_active,
-                "plan":user.plan
-            }))
-        },
-        Err(e) => {
-            error!("[extend_subscription] DB update failed for {}: {}", telegram_id, e);
-            return HttpResponse::InternalServerError().body("Failed to update database");
-        }
     }
-
 }
 
 
@@ -750,6 +497,9 @@ async fn ref_bonus(pool: web::Data<PgPool>,telegram_id: web::Path<i64>, data: we
                 HttpResponse::NotFound().body("User not found")
             }   
             else {
+                if is_used_trial {
+                    reward_referrer(pool.get_ref(), telegram_id).await;
+                }
                 HttpResponse::Ok().body("Referral bonus status updated successfully")
             }
         }
@@ -758,40 +508,42 @@ async fn ref_bonus(pool: web::Data<PgPool>,telegram_id: web::Path<i64>, data: we
     result
 }
 
+/// The invited user took their bonus: their referrer's reward goes to the
+/// wallet, once per invited user, when REFERRAL_PARENT_RUB is set.
+async fn reward_referrer(pool: &PgPool, referred_id: i64) {
+    let referrer = sqlx::query_scalar::<_, Option<i64>>("SELECT referral_id FROM users WHERE telegram_id = $1")
+        .bind(referred_id)
+        .fetch_optional(pool)
+        .await;
+    let referrer = match referrer {
+        Ok(r) => r.flatten(),
+        Err(e) => {
+            error!("[ref_bonus] referrer of {}: {}", referred_id, e);
+            return;
+        }
+    };
+    let Some(referrer) = referrer else { return };
+    let key = format!("referral:{}", referred_id);
+    match wallet::reward_referral(pool, referrer, wallet::REFERRAL_PARENT, &key).await {
+        Ok(Some(posted)) if !posted.replayed => info!("[ref_bonus] {} rewarded for {} in the wallet", referrer, referred_id),
+        Ok(_) => {}
+        Err(e) => error!("[ref_bonus] rewarding {} for {}: {}", referrer, referred_id, e),
+    }
+}
+
 
 async fn check_connection(telegram_id: web::Path<i64>) -> HttpResponse {
     let telegram_id = telegram_id.into_inner();
     info!("[check_connection] telegram_id={}", telegram_id);
 
-    let api_response = match HTTP_CLIENT
-    .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-    .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-    .header("Content-Type", "application/json")
-    .header("X-Forwarded-For", "127.0.0.1")
-    .header("X-Forwarded-Proto", "https")
-    .send()
-    .await
-    {
-        Ok(resp) => resp,
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-    };
-
-    if !api_response.status().is_success() {
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match api_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
+    let remna_user = match remnawave::client().get_user_by_telegram_id(telegram_id).await {
+        Ok(Some(u)) => u,
+        Ok(None) => return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})),
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     };
 
-    let first_connected = json_response["response"][0]["firstConnectedAt"].as_str();
+    let connected = remna_user.first_connected_at.is_some();
 
-    let connected = match first_connected {
-        Some(_) => true,    
-        None => false      
-    };
-    
     HttpResponse::Ok().json(json!({ "connected": connected }))
 }
 
@@ -1005,6 +757,16 @@ async fn payed_refs(pool: web::Data<PgPool>,telegram_id: web::Path<i64>, data: w
                 HttpResponse::NotFound().body("User not found")
             }   
             else {
+                // Milestones reached pay to the wallet, once per referrer.
+                for (count, source) in wallet::REFERRAL_MILESTONES {
+                    if is_used_trial < count {
+                        continue;
+                    }
+                    let key = format!("{}:{}", source, telegram_id);
+                    if let Err(e) = wallet::reward_referral(pool.get_ref(), telegram_id, source, &key).await {
+                        error!("[payed_refs] {} for {}: {}", source, telegram_id, e);
+                    }
+                }
                 HttpResponse::Ok().body("Payed refs updated successfully")
             }
         }
@@ -1036,45 +798,28 @@ async fn temp_disable_device_limit(
     // Получаем uuid пользователя
     let uuid = user.uuid;
 
-    // Устанавливаем временный лимит в 0
-    let api_response = match HTTP_CLIENT
-        .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .json(&json!({
-            "uuid": uuid,
-            "hwidDeviceLimit": 0
-        }))
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
+    // Restore is scheduled before the limit is lifted, so no restart can
+    // leave the user at 0 for good.
+    let restore = jobs::Job::RestoreDeviceLimit { uuid, device_limit: original_limit };
+    let job_id = match jobs::schedule(pool.get_ref(), &restore, Utc::now() + Duration::minutes(30)).await {
+        Ok(id) => id,
+        Err(e) => {
+            error!("[temp_disable_device_limit] Failed to schedule restore for {}: {}", telegram_id, e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
+        }
     };
 
-    if !api_response.status().is_success() {
+    // Устанавливаем временный лимит в 0
+    let update = UserUpdate { hwid_device_limit: Some(0), ..UserUpdate::new(uuid) };
+    if let Err(e) = remnawave::client().update_user(&update).await {
+        error!("Internal error: {}", e);
+        if let Err(e) = jobs::cancel(pool.get_ref(), job_id).await {
+            warn!("[temp_disable_device_limit] Failed to cancel restore job #{}: {}", job_id, e);
+        }
         return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
     }
 
-    info!("[temp_disable_device_limit] Disabled limit for user {}, original={}, restoring in 30min", telegram_id, original_limit);
-    tokio::spawn(async move {
-        tokio::time::sleep(std::time::Duration::from_secs(30 * 60)).await;
-        info!("[temp_disable_device_limit] Restoring device limit {} for uuid={}", original_limit, uuid);
-        let _ = HTTP_CLIENT
-            .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-            .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-            .header("Content-Type", "application/json")
-            .header("X-Forwarded-For", "127.0.0.1")
-            .header("X-Forwarded-Proto", "https")
-            .json(&json!({
-                "uuid": uuid,
-                "hwidDeviceLimit": original_limit
-            }))
-            .send()
-            .await;
-    });
+    info!("[temp_disable_device_limit] Disabled limit for user {}, original={}, restore job #{} in 30min", telegram_id, original_limit, job_id);
 
     HttpResponse::Ok().json(json!({
         "message": "Device limit temporarily set to 0 for 30 minutes",
@@ -1087,66 +832,20 @@ async fn get_devices(telegram_id: web::Path<i64>) -> HttpResponse {
     let telegram_id = telegram_id.into_inner();
     info!("[get_devices] telegram_id={}", telegram_id);
 
-    let api_response = match HTTP_CLIENT
-    .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-    .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-    .header("Content-Type", "application/json")
-    .header("X-Forwarded-For", "127.0.0.1")
-    .header("X-Forwarded-Proto", "https")
-    .send()
-    .await
-    {
-        Ok(resp) => resp,
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-    };
-
-    if !api_response.status().is_success() {
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match api_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-    };
-
-    let uuid_str = match json_response["response"][0]["uuid"].as_str() {
-        Some(s) => s,
-        None => {
+    let remna_user = match remnawave::client().get_user_by_telegram_id(telegram_id).await {
+        Ok(Some(u)) => u,
+        Ok(None) => {
             return HttpResponse::InternalServerError()
                 .body("Failed to parse UUID from user API response");
         }
-    };
-
-    let api_response = match HTTP_CLIENT
-    .get(&format!("{}/hwid/devices/{}", *REMNAWAVE_API_BASE, uuid_str))
-    .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-    .header("Content-Type", "application/json")
-    .header("X-Forwarded-For", "127.0.0.1")
-    .header("X-Forwarded-Proto", "https")
-    .send()
-    .await
-    {
-        Ok(resp) => resp,
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     };
 
-    if !api_response.status().is_success() {
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match api_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
+    let devices_amount = match remnawave::client().list_devices(remna_user.uuid).await {
+        Ok(list) => list.total,
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     };
 
-    let devices_amount = match json_response["response"]["total"].as_u64() {
-        Some(n) => n,
-        None => {   
-            return HttpResponse::InternalServerError()
-                .body("Failed to parse devices amount from user API response");
-        }
-    };
-    
     HttpResponse::Ok().json(json!({ "devices_amount": devices_amount }))
 }
 
@@ -1159,32 +858,13 @@ async fn list_devices(pool: web::Data<PgPool>, telegram_id: web::Path<i64>) -> H
         .fetch_optional(pool.get_ref())
         .await
     {
-        Ok(Some(u)) => u.to_string(),
+        Ok(Some(u)) => u,
         Ok(None) => return HttpResponse::NotFound().body("User not found"),
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     };
 
-    let resp = HTTP_CLIENT
-        .get(&format!("{}/hwid/devices/{}", *REMNAWAVE_API_BASE, uuid))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .send()
-        .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => {
-                    let devices = json["response"]["devices"].clone();
-                    let total = json["response"]["total"].as_u64().unwrap_or(0);
-                    HttpResponse::Ok().json(json!({ "devices": devices, "total": total }))
-                }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-            }
-        }
-        Ok(r) => HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})),
+    match remnawave::client().list_devices(uuid).await {
+        Ok(list) => HttpResponse::Ok().json(json!({ "devices": list.devices, "total": list.total })),
         Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     }
 }
@@ -1198,37 +878,42 @@ async fn bot_delete_device(pool: web::Data<PgPool>, path: web::Path<(i64, String
         .fetch_optional(pool.get_ref())
         .await
     {
-        Ok(Some(u)) => u.to_string(),
+        Ok(Some(u)) => u,
         Ok(None) => return HttpResponse::NotFound().body("User not found"),
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     };
 
-    let resp = HTTP_CLIENT
-        .post(&format!("{}/hwid/devices/delete", *REMNAWAVE_API_BASE))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .json(&json!({ "userUuid": uuid, "hwid": hwid }))
-        .send()
-        .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => HttpResponse::Ok().json(json!({"status": "ok"})),
-        Ok(r) => HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})),
+    match remnawave::client().delete_device(uuid, &hwid).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"status": "ok"})),
         Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     }
 }
 
 async fn create_promo(pool: web::Data<PgPool>, data: web::Json<CreatePromoRequest>, req: HttpRequest) -> HttpResponse {
-    info!("[create_promo] code={}, discount={}%, tariffs={:?}, max_uses={}", data.code, data.discount_percent, data.applicable_tariffs, data.max_uses);
+    let kind = data.kind.clone().unwrap_or_else(|| promo::KIND_PERCENT.to_string());
+    info!(
+        "[create_promo] code={}, kind={}, discount={}%, amount={:?}, bonus_days={:?}, tariffs={:?}, durations={:?}, max_uses={}, expires_at={:?}, first_purchase_only={}",
+        data.code, kind, data.discount_percent, data.amount_rub, data.bonus_days, data.applicable_tariffs,
+        data.applicable_durations, data.max_uses, data.expires_at, data.first_purchase_only
+    );
+    if let Err(msg) = promo::validate_new(&data) {
+        return HttpResponse::BadRequest().json(json!({"error": msg}));
+    }
     let result = sqlx::query_as::<_, PromoCode>(
-        "INSERT INTO promo_codes (code, discount_percent, applicable_tariffs, max_uses) VALUES ($1, $2, $3, $4) RETURNING *"
+        "INSERT INTO promo_codes (code, kind, discount_percent, amount_rub, bonus_days, applicable_tariffs, \
+         applicable_durations, max_uses, expires_at, first_purchase_only) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *"
     )
     .bind(&data.code)
-    .bind(data.discount_percent)
+    .bind(&kind)
+    .bind(if kind == promo::KIND_PERCENT { data.discount_percent } else { 0 })
+    .bind(data.amount_rub.filter(|_| kind == promo::KIND_FIXED))
+    .bind(data.bonus_days.filter(|_| kind == promo::KIND_BONUS_DAYS))
     .bind(&data.applicable_tariffs)
+    .bind(&data.applicable_durations)
     .bind(data.max_uses)
+    .bind(data.expires_at)
+    .bind(data.first_purchase_only)
     .fetch_one(pool.get_ref())
     .await;
 
@@ -1272,44 +957,27 @@ async fn deactivate_promo(pool: web::Data<PgPool>, code: web::Path<String>, req:
 }
 
 async fn validate_promo(pool: web::Data<PgPool>, data: web::Json<ValidatePromoRequest>) -> HttpResponse {
-    info!("[validate_promo] code={}, tariff={}, telegram_id={}", data.code, data.tariff, data.telegram_id);
-    let promo = sqlx::query_as::<_, PromoCode>(
-        "SELECT * FROM promo_codes WHERE code = $1"
-    )
-    .bind(&data.code)
-    .fetch_optional(pool.get_ref())
-    .await;
-
-    let promo = match promo {
-        Ok(Some(p)) => p,
-        Ok(None) => return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод не найден"})),
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-    };
-
-    if !promo.is_active {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод деактивирован"}));
-    }
-
-    if promo.current_uses >= promo.max_uses {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод исчерпан"}));
-    }
-
-    if !data.tariff.is_empty() && !promo.applicable_tariffs.contains(&data.tariff) {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод не применим к этому тарифу"}));
-    }
-
-    let already_used: Option<(i32,)> = sqlx::query_as(
-        "SELECT id FROM promo_usages WHERE promo_code_id = $1 AND telegram_id = $2"
-    )
-    .bind(promo.id)
-    .bind(data.telegram_id)
-    .fetch_optional(pool.get_ref())
-    .await
-    .unwrap_or(None);
-
-    match already_used {
-        Some(_) => HttpResponse::Ok().json(json!({"valid": false, "reason": "Вы уже использовали этот промокод"})),
-        None => HttpResponse::Ok().json(json!({"valid": true, "discount_percent": promo.discount_percent, "applicable_tariffs": promo.applicable_tariffs})),
+    info!("[validate_promo] code={}, tariff={}, duration={:?}, telegram_id={}", data.code, data.tariff, data.duration, data.telegram_id);
+    let price = data.duration.as_deref().and_then(|d| prices::current().get(&data.tariff, d));
+    let checked = promo::check(pool.get_ref(), &data.code, data.telegram_id, &data.tariff, data.duration.as_deref(), price).await;
+
+    match checked {
+        Ok(Ok((promo, effect))) => HttpResponse::Ok().json(json!({
+            "valid": true,
+            "kind": promo.kind,
+            "discount_percent": promo.discount_percent,
+            "amount_rub": promo.amount_rub,
+            "bonus_days": promo.bonus_days,
+            "applicable_tariffs": promo.applicable_tariffs,
+            "applicable_durations": promo.applicable_durations,
+            "expires_at": promo.expires_at,
+            "first_purchase_only": promo.first_purchase_only,
+            "original_price": price,
+            "final_price": effect.price_rub,
+            "discount_rub": effect.discount_rub,
+        })),
+        Ok(Err(rejection)) => HttpResponse::Ok().json(json!({"valid": false, "reason": rejection.reason()})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     }
 }
 
@@ -1486,6 +1154,12 @@ async fn get_auto_renew_users(
     pool: web::Data<PgPool>,
     query: web::Query<HashMap<String, String>>,
 ) -> HttpResponse {
+    // The API charges saved cards itself (autorenew.rs) — nothing for the
+    // bot's loop to do.
+    if autorenew::enabled() {
+        return HttpResponse::Ok().json(Vec::<AutoRenewUser>::new());
+    }
+
     // Support both "hours" and "days" params, hours takes priority
     let threshold_date = if let Some(hours) = query.get("hours").and_then(|h| h.parse::<i64>().ok()) {
         Utc::now() + Duration::hours(hours)
@@ -1571,7 +1245,6 @@ async fn toggle_pro(
 ) -> HttpResponse {
     let mut telegram_id = telegram_id.into_inner();
     let enable = data.is_pro;
-    let pro_squad = "b6a4e86b-b769-4c86-a2d9-f31bbe645029";
     info!("[toggle_pro] telegram_id={}, enable={}", telegram_id, enable);
 
     let user = match sqlx::query_as!(
@@ -1600,79 +1273,13 @@ async fn toggle_pro(
         }
     };
 
-    // Получаем текущие сквады пользователя из Remnawave
-    let get_response = match HTTP_CLIENT
-        .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
+    // Добавляем или убираем только PRO сквад в Remnawave
+    match subscription::push_pro(remnawave::client(), &plans::catalog(), telegram_id, user.uuid, enable).await {
+        Ok(squads) => info!("[toggle_pro] User {} final squads: {:?}", telegram_id, squads),
         Err(e) => {
-            error!("[toggle_pro] Failed to get user {} from Remnawave: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
+            error!("[toggle_pro] Remnawave squad update failed for {}: {}", telegram_id, e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
         }
-    };
-
-    if !get_response.status().is_success() {
-        error!("[toggle_pro] Remnawave GET error for {}: {}", telegram_id, get_response.status());
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match get_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
-        Err(e) => {
-            error!("[toggle_pro] Failed to parse Remnawave response for {}: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
-        }
-    };
-
-    let mut current_squads: Vec<String> = json_response["response"][0]["activeInternalSquads"]
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|s| s["uuid"].as_str().map(|v| v.to_string()))
-                .collect()
-        })
-        .unwrap_or_default();
-
-    // Добавляем или убираем только PRO сквад
-    if enable {
-        if !current_squads.contains(&pro_squad.to_string()) {
-            current_squads.push(pro_squad.to_string());
-        }
-    } else {
-        current_squads.retain(|s| s != pro_squad);
-    }
-    info!("[toggle_pro] User {} final squads: {:?}", telegram_id, current_squads);
-
-    // Обновляем сквады в Remnawave
-    let api_response = match HTTP_CLIENT
-        .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .json(&json!({
-            "uuid": user.uuid,
-            "activeInternalSquads": json!(current_squads),
-        }))
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("[toggle_pro] Remnawave PATCH failed for {}: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
-        }
-    };
-
-    if !api_response.status().is_success() {
-        error!("[toggle_pro] Remnawave PATCH error for {}: {}", telegram_id, api_response.status());
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
     }
 
     // Обновляем is_pro в БД
@@ -1704,47 +1311,23 @@ async fn get_user_squads(telegram_id: web::Path<i64>) -> HttpResponse {
     let telegram_id = telegram_id.into_inner();
     info!("[get_user_squads] telegram_id={}", telegram_id);
 
-    let get_response = match HTTP_CLIENT
-        .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-        .header("Authorization", &format!("Bearer {}", *REMNAWAVE_API_KEY))
-        .header("Content-Type", "application/json")
-        .header("X-Forwarded-For", "127.0.0.1")
-        .header("X-Forwarded-Proto", "https")
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            error!("[get_user_squads] Remnawave API call failed for {}: {}", telegram_id, e);
-            return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
+    let remna_user = match remnawave::client().get_user_by_telegram_id(telegram_id).await {
+        Ok(Some(u)) => u,
+        Ok(None) => {
+            error!("[get_user_squads] User {} not found in Remnawave", telegram_id);
+            return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
         }
-    };
-
-    if !get_response.status().is_success() {
-        error!("[get_user_squads] Remnawave API error for {}: {}", telegram_id, get_response.status());
-        return HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"}));
-    }
-
-    let json_response = match get_response.json::<serde_json::Value>().await {
-        Ok(json) => json,
         Err(e) => {
-            error!("[get_user_squads] Failed to parse response for {}: {}", telegram_id, e);
+            error!("[get_user_squads] Remnawave API call failed for {}: {}", telegram_id, e);
             return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) };
         }
     };
 
-    let squads: Vec<serde_json::Value> = json_response["response"][0]["activeInternalSquads"]
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .map(|s| {
-                    let uuid = s["uuid"].as_str().unwrap_or("unknown").to_string();
-                    let name = s["name"].as_str().unwrap_or("unknown").to_string();
-                    json!({"uuid": uuid, "name": name})
-                })
-                .collect()
-        })
-        .unwrap_or_default();
+    let squads: Vec<serde_json::Value> = remna_user
+        .active_internal_squads
+        .iter()
+        .map(|s| json!({"uuid": s.uuid, "name": s.name.as_deref().unwrap_or("unknown")}))
+        .collect();
 
     info!("[get_user_squads] User {} has {} squads", telegram_id, squads.len());
     HttpResponse::Ok().json(json!({"squads": squads}))
@@ -1839,6 +1422,35 @@ async fn main() -> std::io::Result<()> {
         .unwrap();
     info!("DB connected.");
 
+    // Plan catalog (squads / limits / tags). Fail-closed: against an empty
+    // catalog every extend would push no squads and a device limit of 0.
+    match plans::reload(&pool).await {
+        Ok(0) => panic!("plans table is empty — apply migrations/013_plans.sql"),
+        Ok(_) => {}
+        Err(e) => panic!("Failed to load plan catalog: {}", e),
+    }
+    // Price catalog. Without a version nothing can be sold.
+    match prices::reload(&pool).await {
+        Ok(0) => panic!("price_versions is empty — apply migrations/019_prices.sql"),
+        Ok(_) => prices::warn_legacy_env(),
+        Err(e) => panic!("Failed to load price catalog: {}", e),
+    }
+
+    // Periodic users <-> Remnawave drift check (RECONCILE_INTERVAL_MINUTES).
+    reconcile::spawn(pool.clone());
+    // Retries panel syncs queued by extend_subscription.
+    outbox::spawn(pool.clone());
+    // Device-limit restores, broadcasts and email blasts (scheduled_jobs).
+    jobs::spawn(pool.clone());
+    // Saved-card charges on the dunning schedule (AUTORENEW_ENABLED).
+    autorenew::spawn(pool.clone());
+    // RUB -> crypto rate cache for CryptoPay invoices.
+    rates::spawn();
+    // Releases expired promo holds of abandoned checkouts.
+    promo::spawn(pool.clone());
+    // Syncs open site checkouts and reminds about unpaid ones.
+    pending::spawn(pool.clone());
+
     // Initialize SMTP for email verification
     email::init();
 
@@ -1911,10 +1523,26 @@ async fn main() -> std::io::Result<()> {
                 .route(web::post().to(web_handlers::auth_email_register)))
             .service(web::resource("/web/auth/login")
                 .route(web::post().to(web_handlers::auth_email_login)))
+            .service(web::resource("/web/auth/email-code")
+                .route(web::post().to(web_handlers::auth_email_code)))
+            .service(web::resource("/web/auth/email-code/verify")
+                .route(web::post().to(web_handlers::auth_email_code_verify)))
+            .service(web::resource("/web/auth/totp")
+                .route(web::post().to(web_handlers::auth_totp)))
+            .service(web::resource("/web/auth/passkey/register/options")
+                .route(web::post().to(web_handlers::web_passkey_register_options)))
+            .service(web::resource("/web/auth/passkey/register")
+                .route(web::post().to(web_handlers::web_passkey_register)))
+            .service(web::resource("/web/auth/passkey/login/options")
+                .route(web::post().to(web_handlers::auth_passkey_login_options)))
+            .service(web::resource("/web/auth/passkey/login")
+                .route(web::post().to(web_handlers::auth_passkey_login)))
             // Generic-client session: mint a token from an imported SvoiVPN
             // subscription link (iOS has no sign-in screen — see auth_sub_session).
             .service(web::resource("/web/auth/sub-session")
                 .route(web::post().to(web_handlers::auth_sub_session)))
+            .service(web::resource("/web/auth/refresh")
+                .route(web::post().to(web_handlers::auth_refresh)))
             .service(web::resource("/web/auth/link-email")
                 .route(web::post().to(web_handlers::auth_link_email)))
             .service(web::resource("/web/auth/claim-email")
@@ -1955,6 +1583,12 @@ async fn main() -> std::io::Result<()> {
                 .route(web::post().to(web_handlers::internal_notify_expiry)))
             .service(web::resource("/internal/payments")
                 .route(web::post().to(web_handlers::internal_log_payment)))
+            .service(web::resource("/internal/payments/stars")
+                .route(web::post().to(web_handlers::internal_stars_update)))
+            .service(web::resource("/internal/payments/stars/invoice")
+                .route(web::post().to(web_handlers::internal_create_stars_invoice)))
+            .service(web::resource("/internal/wallet/credit")
+                .route(web::post().to(web_handlers::internal_wallet_credit)))
             .service(web::resource("/web/me/notifications")
                 .route(web::get().to(web_handlers::web_get_notifications))
                 .route(web::patch().to(web_handlers::web_update_notifications)))
@@ -1974,16 +1608,49 @@ async fn main() -> std::io::Result<()> {
                 .route(web::delete().to(web_handlers::web_delete_device)))
             .service(web::resource("/web/me/connection")
                 .route(web::get().to(web_handlers::web_check_connection)))
+            .service(web::resource("/web/me/payments")
+                .route(web::get().to(web_handlers::web_my_payments)))
+            .service(web::resource("/web/me/payments/{id}/receipt")
+                .route(web::get().to(web_handlers::web_my_payment_receipt)))
+            .service(web::resource("/web/me/sessions")
+                .route(web::get().to(web_handlers::web_my_sessions))
+                .route(web::delete().to(web_handlers::web_revoke_all_sessions)))
+            .service(web::resource("/web/me/sessions/{id}")
+                .route(web::delete().to(web_handlers::web_revoke_session)))
+            .service(web::resource("/web/me/totp")
+                .route(web::get().to(web_handlers::web_totp_status)))
+            .service(web::resource("/web/me/totp/setup")
+                .route(web::post().to(web_handlers::web_totp_setup)))
+            .service(web::resource("/web/me/totp/confirm")
+                .route(web::post().to(web_handlers::web_totp_confirm)))
+            .service(web::resource("/web/me/totp/disable")
+                .route(web::post().to(web_handlers::web_totp_disable)))
+            .service(web::resource("/web/me/passkeys")
+                .route(web::get().to(web_handlers::web_my_passkeys)))
+            .service(web::resource("/web/me/passkeys/{id}")
+                .route(web::delete().to(web_handlers::web_delete_passkey)))
+            .service(web::resource("/web/me/wallet")
+                .route(web::get().to(web_handlers::web_my_wallet)))
+            .service(web::resource("/web/wallet/topup")
+                .route(web::post().to(web_handlers::web_wallet_topup)))
             .service(web::resource("/web/subscription/prices")
                 .route(web::get().to(web_handlers::web_get_prices)))
             .service(web::resource("/web/subscription/trial")
                 .route(web::post().to(web_handlers::web_activate_trial)))
+            .service(web::resource("/webhooks/yookassa")
+                .route(web::post().to(web_handlers::yookassa_webhook)))
+            .service(web::resource("/webhooks/cryptopay")
+                .route(web::post().to(web_handlers::cryptopay_webhook)))
             .service(web::resource("/web/payment/create")
                 .route(web::post().to(web_handlers::web_create_payment)))
             .service(web::resource("/web/payment/{payment_id}/status")
                 .route(web::get().to(web_handlers::web_payment_status)))
             .service(web::resource("/web/payment/crypto/create")
                 .route(web::post().to(web_handlers::web_create_crypto_payment)))
+            .service(web::resource("/web/payment/crypto/rates")
+                .route(web::get().to(web_handlers::web_crypto_rates)))
+            .service(web::resource("/web/payment/stars")
+                .route(web::post().to(web_handlers::web_create_stars_payment)))
             .service(web::resource("/web/promo/validate")
                 .route(web::post().to(web_handlers::web_validate_promo)))
             .service(web::resource("/web/settings/auto-renew")
@@ -2043,8 +1710,41 @@ async fn main() -> std::io::Result<()> {
             .service(web::resource("/admin/users/{telegram_id}")
                 .route(web::get().to(web_handlers::admin_get_user))
                 .route(web::patch().to(web_handlers::admin_update_user)))
+            .service(web::resource("/admin/plans")
+                .route(web::get().to(web_handlers::admin_list_plans))
+                .route(web::post().to(web_handlers::admin_create_plan)))
+            .service(web::resource("/admin/plans/reload")
+                .route(web::post().to(web_handlers::admin_reload_plans)))
+            .service(web::resource("/admin/plans/{code}")
+                .route(web::patch().to(web_handlers::admin_update_plan)))
+            .service(web::resource("/admin/prices")
+                .route(web::get().to(web_handlers::admin_list_prices))
+                .route(web::post().to(web_handlers::admin_create_price_version)))
+            .service(web::resource("/admin/prices/reload")
+                .route(web::post().to(web_handlers::admin_reload_prices)))
+            .service(web::resource("/admin/reconcile/report")
+                .route(web::get().to(web_handlers::admin_reconcile_report)))
+            .service(web::resource("/admin/reconcile/run")
+                .route(web::post().to(web_handlers::admin_reconcile_run)))
+            .service(web::resource("/admin/payments/{id}/refund")
+                .route(web::post().to(web_handlers::admin_refund_payment)))
+            .service(web::resource("/admin/users/{telegram_id}/wallet")
+                .route(web::get().to(web_handlers::admin_get_wallet))
+                .route(web::post().to(web_handlers::admin_post_wallet)))
+            .service(web::resource("/admin/promos/batch")
+                .route(web::post().to(web_handlers::admin_create_promo_batch)))
+            .service(web::resource("/admin/promos/campaigns")
+                .route(web::get().to(web_handlers::admin_list_promo_campaigns)))
+            .service(web::resource("/admin/promos/campaigns/{id}")
+                .route(web::get().to(web_handlers::admin_get_promo_campaign)))
+            .service(web::resource("/admin/promos/campaigns/{id}/codes.csv")
+                .route(web::get().to(web_handlers::admin_export_promo_campaign)))
             .service(web::resource("/admin/stats")
                 .route(web::get().to(web_handlers::admin_stats)))
+            .service(web::resource("/admin/analytics/revenue")
+                .route(web::get().to(web_handlers::admin_revenue_analytics)))
+            .service(web::resource("/admin/analytics/checkouts")
+                .route(web::get().to(web_handlers::admin_checkout_funnel)))
             .service(web::resource("/admin/broadcast")
                 .route(web::post().to(web_handlers::admin_broadcast)))
             .service(web::resource("/admin/broadcast/preview")
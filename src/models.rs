@@ -56,6 +56,11 @@ pub struct AddReferralData {
 pub struct ExtendSubscriptionRequest {
     pub days: i32,
     pub plan: String,
+    /// Repeat-safe key (e.g. the YooKassa payment id). A second call with the
+    /// same key returns the first result without extending again.
+    pub idempotency_key: Option<String>,
+    /// Ledger `source` for the row written with the key (default 'other').
+    pub source: Option<String>,
 }
 
 
@@ -67,24 +72,44 @@ pub struct ExpiringUser {
     pub plan: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct PromoCode {
     pub id: i32,
     pub code: String,
+    /// "percent" | "fixed" | "bonus_days" (see promo.rs)
+    pub kind: String,
     pub discount_percent: i32,
+    pub amount_rub: Option<i32>,
+    pub bonus_days: Option<i32>,
     pub applicable_tariffs: Vec<String>,
+    /// Empty = any duration.
+    pub applicable_durations: Vec<String>,
     pub max_uses: i32,
     pub current_uses: i32,
     pub is_active: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub first_purchase_only: bool,
+    /// Set for codes generated by `/admin/promos/batch`.
+    pub campaign_id: Option<i32>,
     pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreatePromoRequest {
     pub code: String,
+    /// Defaults to "percent".
+    pub kind: Option<String>,
+    #[serde(default)]
     pub discount_percent: i32,
+    pub amount_rub: Option<i32>,
+    pub bonus_days: Option<i32>,
     pub applicable_tariffs: Vec<String>,
+    #[serde(default)]
+    pub applicable_durations: Vec<String>,
     pub max_uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub first_purchase_only: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -92,6 +117,8 @@ pub struct ValidatePromoRequest {
     pub code: String,
     pub tariff: String,
     pub telegram_id: i64,
+    /// When set, duration limits are checked and the result carries prices.
+    pub duration: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
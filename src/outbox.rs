@@ -0,0 +1,232 @@
+//! Transactional outbox for Remnawave entitlement syncs
+//! (migrations/014_entitlement_outbox.sql).
+//!
+//! A subscription change commits the `users` UPDATE together with an
+//! `entitlement_outbox` row ([`enqueue`]). The caller then tries to apply it
+//! right away ([`deliver_now`]) so the happy path stays synchronous; if the
+//! panel is down the background worker ([`spawn`]) retries with exponential
+//! backoff and every attempt lands in `entitlement_outbox_attempts`.
+//!
+//! An attempt always pushes the users row as it is *now*, not the snapshot
+//! from enqueue time, so retries and out-of-order delivery can't roll a
+//! user back. A successful attempt also closes older pending rows for the
+//! same user — they're covered by the state just applied.
+
+use crate::plans::Catalog;
+use crate::reconcile::LocalUser;
+use crate::remnawave::RemnawaveApi;
+use crate::subscription::{self, Entitlement};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+const BATCH: i64 = 20;
+/// After this many failed attempts the row is parked as `failed`.
+const MAX_ATTEMPTS: i32 = 12;
+/// Backoff is 30s · 2^(attempt-1), capped at one hour.
+const BACKOFF_BASE_SECS: i64 = 30;
+const BACKOFF_MAX_SECS: i64 = 3600;
+/// A claimed row is invisible to other claimers for this long, so a worker
+/// that dies mid-attempt doesn't strand it.
+const LEASE_SECS: i64 = 120;
+
+#[derive(Debug, sqlx::FromRow)]
+struct Claimed {
+    id: i64,
+    telegram_id: i64,
+    attempts: i32,
+}
+
+/// Queue a panel sync for `telegram_id` inside the caller's transaction.
+/// Must run after the users UPDATE so the snapshot reflects it. The row is
+/// leased for the caller's immediate [`deliver_now`]; the worker only picks
+/// it up if that attempt doesn't finish.
+pub async fn enqueue(
+    tx: &mut Transaction<'_, Postgres>,
+    telegram_id: i64,
+    source: &str,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "INSERT INTO entitlement_outbox (telegram_id, source, plan, expire_at, next_attempt_at) \
+         SELECT telegram_id, $2, plan, subscription_end, NOW() + make_interval(secs => $3) \
+         FROM users WHERE telegram_id = $1 \
+         RETURNING id",
+    )
+    .bind(telegram_id)
+    .bind(source)
+    .bind(LEASE_SECS as f64)
+    .fetch_one(&mut **tx)
+    .await
+}
+
+/// Apply one outbox row now. The row stays queued for the worker on error.
+pub async fn deliver_now(
+    pool: &PgPool,
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    id: i64,
+) -> Result<Vec<String>, String> {
+    let claimed = sqlx::query_as::<_, Claimed>(
+        "UPDATE entitlement_outbox SET attempts = attempts + 1 \
+         WHERE id = $1 AND status = 'pending' \
+         RETURNING id, telegram_id, attempts",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| e.to_string())?;
+    match claimed {
+        Some(item) => attempt(pool, api, catalog, &item).await,
+        None => Err(format!("outbox row {} is not pending", id)),
+    }
+}
+
+/// Lease up to [`BATCH`] due rows.
+async fn claim_due(pool: &PgPool) -> Result<Vec<Claimed>, sqlx::Error> {
+    sqlx::query_as::<_, Claimed>(
+        "UPDATE entitlement_outbox \
+         SET attempts = attempts + 1, next_attempt_at = NOW() + make_interval(secs => $2) \
+         WHERE id IN ( \
+             SELECT id FROM entitlement_outbox \
+             WHERE status = 'pending' AND next_attempt_at <= NOW() \
+             ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED) \
+         RETURNING id, telegram_id, attempts",
+    )
+    .bind(BATCH)
+    .bind(LEASE_SECS as f64)
+    .fetch_all(pool)
+    .await
+}
+
+/// Push the user's current entitlement and record the outcome.
+async fn attempt(
+    pool: &PgPool,
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    item: &Claimed,
+) -> Result<Vec<String>, String> {
+    let result = push(pool, api, catalog, item.telegram_id).await;
+    if let Err(e) = record(pool, item, &result).await {
+        log::error!("[outbox] failed to record attempt for row {}: {}", item.id, e);
+    }
+    result
+}
+
+async fn push(
+    pool: &PgPool,
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    telegram_id: i64,
+) -> Result<Vec<String>, String> {
+    let local = sqlx::query_as::<_, LocalUser>(&format!(
+        "SELECT {} FROM users WHERE telegram_id = $1",
+        LocalUser::COLUMNS
+    ))
+    .bind(telegram_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or_else(|| format!("user {} not found", telegram_id))?;
+
+    let plan = local.effective_plan(catalog);
+    let entitlement = Entitlement {
+        telegram_id: local.telegram_id,
+        uuid: local.uuid,
+        plan: &plan,
+        is_pro: local.is_pro,
+        expire_at: local.subscription_end,
+    };
+    subscription::push_entitlement(api, catalog, &entitlement)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn backoff_secs(attempts: i32) -> i64 {
+    let exp = attempts.clamp(1, 20) as u32 - 1;
+    (BACKOFF_BASE_SECS.saturating_mul(1 << exp)).min(BACKOFF_MAX_SECS)
+}
+
+async fn record(pool: &PgPool, item: &Claimed, result: &Result<Vec<String>, String>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT INTO entitlement_outbox_attempts (outbox_id, attempt, ok, error) VALUES ($1, $2, $3, $4)")
+        .bind(item.id)
+        .bind(item.attempts)
+        .bind(result.is_ok())
+        .bind(result.as_ref().err())
+        .execute(&mut *tx)
+        .await?;
+
+    match result {
+        Ok(_) => {
+            sqlx::query(
+                "UPDATE entitlement_outbox SET status = 'done', done_at = NOW(), last_error = NULL \
+                 WHERE id = $1 OR (telegram_id = $2 AND status = 'pending' AND id < $1)",
+            )
+            .bind(item.id)
+            .bind(item.telegram_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        Err(e) if item.attempts >= MAX_ATTEMPTS => {
+            log::error!("[outbox] row {} (user {}) failed permanently: {}", item.id, item.telegram_id, e);
+            sqlx::query("UPDATE entitlement_outbox SET status = 'failed', last_error = $2 WHERE id = $1")
+                .bind(item.id)
+                .bind(e)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Err(e) => {
+            sqlx::query(
+                "UPDATE entitlement_outbox \
+                 SET last_error = $2, next_attempt_at = NOW() + make_interval(secs => $3) \
+                 WHERE id = $1",
+            )
+            .bind(item.id)
+            .bind(e)
+            .bind(backoff_secs(item.attempts) as f64)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+    tx.commit().await
+}
+
+/// Background worker, started from main.
+pub fn spawn(pool: PgPool) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let due = match claim_due(&pool).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    log::error!("[outbox] claim failed: {}", e);
+                    continue;
+                }
+            };
+            let catalog = crate::plans::catalog();
+            for item in &due {
+                match attempt(&pool, crate::remnawave::client(), &catalog, item).await {
+                    Ok(_) => log::info!("[outbox] row {} applied for user {}", item.id, item.telegram_id),
+                    Err(e) => log::warn!(
+                        "[outbox] row {} attempt {} for user {} failed: {}",
+                        item.id, item.attempts, item.telegram_id, e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::backoff_secs;
+
+    #[test]
+    fn backoff_doubles_and_caps() {
+        assert_eq!(backoff_secs(1), 30);
+        assert_eq!(backoff_secs(2), 60);
+        assert_eq!(backoff_secs(5), 480);
+        assert_eq!(backoff_secs(8), 3600);
+        assert_eq!(backoff_secs(12), 3600);
+    }
+}
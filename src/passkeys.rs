@@ -0,0 +1,603 @@
+//! WebAuthn passkeys for web accounts (migrations/031_passkeys.sql).
+//!
+//! Registration: a signed-in user gets creation options from
+//! POST /web/auth/passkey/register/options (after a step-up check, see
+//! `web_passkey_register_options`), the browser runs
+//! `navigator.credentials.create()` and posts the result to
+//! POST /web/auth/passkey/register. Sign-in: POST
+//! /web/auth/passkey/login/options hands out a challenge without
+//! `allowCredentials` (passkeys are discoverable, the browser offers the
+//! ones it has for the site) and the `navigator.credentials.get()` result
+//! goes to POST /web/auth/passkey/login, which opens a session like any
+//! other login. Each passkey remembers the session it was added in, for
+//! "sign out everywhere" (migrations/032_passkey_sessions.sql).
+//!
+//! User verification (PIN, fingerprint, face) is required in both
+//! ceremonies and checked in the authenticator data: a passkey login is
+//! something the user has and is or knows, so it skips the TOTP step other
+//! logins go through.
+//!
+//! Attestation is not requested or checked: any authenticator the user
+//! picks is fine. Keys may be ES256 or RS256, which covers the platform
+//! authenticators and security keys in use. WEBAUTHN_RP_ID is the site's
+//! domain, WEBAUTHN_ORIGINS the origins ceremonies may come from.
+
+use ciborium::Value;
+use p256::ecdsa::signature::Verifier;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value as Json};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::convert::TryFrom;
+use std::fmt;
+
+const RP_NAME: &str = "SvoiVPN";
+const CHALLENGE_TTL_SECS: i64 = 300;
+const PURPOSE_REGISTER: &str = "register";
+const PURPOSE_LOGIN: &str = "login";
+const MAX_NAME: usize = 64;
+
+/// COSE algorithm ids.
+const ES256: i64 = -7;
+const RS256: i64 = -257;
+
+/// Authenticator data flags: user present, user verified, credential data
+/// attached.
+const FLAG_UP: u8 = 0x01;
+const FLAG_UV: u8 = 0x04;
+const FLAG_AT: u8 = 0x40;
+
+lazy_static::lazy_static! {
+    static ref RP: RelyingParty = RelyingParty::from_env();
+}
+
+struct RelyingParty {
+    id: String,
+    origins: Vec<String>,
+}
+
+impl RelyingParty {
+    fn from_env() -> RelyingParty {
+        let id = std::env::var("WEBAUTHN_RP_ID")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| "svoiweb.ru".to_string());
+        let origins: Vec<String> = std::env::var("WEBAUTHN_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/').to_string())
+            .filter(|o| !o.is_empty())
+            .collect();
+        let origins = if origins.is_empty() { vec![format!("https://{}", id)] } else { origins };
+        RelyingParty { id, origins }
+    }
+}
+
+#[derive(Debug)]
+pub enum PasskeyError {
+    /// The browser's response doesn't check out; the reason is for logs.
+    Invalid(&'static str),
+    /// Challenge unknown, expired or already used.
+    ChallengeExpired,
+    /// The credential is registered already.
+    Duplicate,
+    UnknownCredential,
+    Db(sqlx::Error),
+}
+
+impl fmt::Display for PasskeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasskeyError::Invalid(reason) => write!(f, "invalid passkey response: {}", reason),
+            PasskeyError::ChallengeExpired => write!(f, "challenge expired"),
+            PasskeyError::Duplicate => write!(f, "passkey already registered"),
+            PasskeyError::UnknownCredential => write!(f, "unknown passkey"),
+            PasskeyError::Db(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for PasskeyError {
+    fn from(e: sqlx::Error) -> Self {
+        PasskeyError::Db(e)
+    }
+}
+
+fn b64url(bytes: &[u8]) -> String {
+    data_encoding::BASE64URL_NOPAD.encode(bytes)
+}
+
+fn b64url_decode(s: &str) -> Result<Vec<u8>, PasskeyError> {
+    data_encoding::BASE64URL_NOPAD
+        .decode(s.trim_end_matches('=').as_bytes())
+        .map_err(|_| PasskeyError::Invalid("bad base64url"))
+}
+
+/// WebAuthn user handle: the telegram_id, big-endian.
+fn user_handle(telegram_id: i64) -> String {
+    b64url(&telegram_id.to_be_bytes())
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// The challenge a clientDataJSON answers, once its type and origin check
+/// out.
+fn check_client_data(rp: &RelyingParty, raw: &[u8], kind: &str) -> Result<String, PasskeyError> {
+    let data: ClientData = serde_json::from_slice(raw).map_err(|_| PasskeyError::Invalid("bad clientDataJSON"))?;
+    if data.kind != kind {
+        return Err(PasskeyError::Invalid("wrong ceremony type"));
+    }
+    if !rp.origins.contains(&data.origin) {
+        return Err(PasskeyError::Invalid("origin not allowed"));
+    }
+    Ok(data.challenge)
+}
+
+struct AuthData {
+    sign_count: u32,
+    /// Credential id and COSE public key; registration only.
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Parse authenticator data and check it's for this RP with the user
+/// present and verified.
+fn parse_auth_data(rp: &RelyingParty, data: &[u8]) -> Result<AuthData, PasskeyError> {
+    let short = PasskeyError::Invalid("authenticatorData too short");
+    if data.len() < 37 {
+        return Err(short);
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(PasskeyError::Invalid("wrong RP id"));
+    }
+    let flags = data[32];
+    if flags & FLAG_UP == 0 {
+        return Err(PasskeyError::Invalid("user not present"));
+    }
+    if flags & FLAG_UV == 0 {
+        return Err(PasskeyError::Invalid("user not verified"));
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+    let credential = if flags & FLAG_AT != 0 {
+        // AAGUID (16 bytes), credential id length (2), credential id, COSE
+        // key; extensions may follow the key.
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err(short);
+        }
+        let len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let id = rest.get(18..18 + len).ok_or(short)?.to_vec();
+        let key = &rest[18 + len..];
+        let mut cursor = std::io::Cursor::new(key);
+        let _: Value = ciborium::de::from_reader(&mut cursor)
+            .map_err(|_| PasskeyError::Invalid("bad credential public key"))?;
+        Some((id, key[..cursor.position() as usize].to_vec()))
+    } else {
+        None
+    };
+    Ok(AuthData { sign_count, credential })
+}
+
+enum PublicKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    Rs256(rsa::RsaPublicKey),
+}
+
+impl PublicKey {
+    /// The key in a COSE_Key and its algorithm.
+    fn from_cose(bytes: &[u8]) -> Result<(PublicKey, i64), PasskeyError> {
+        let bad = || PasskeyError::Invalid("bad credential public key");
+        let value: Value = ciborium::de::from_reader(bytes).map_err(|_| bad())?;
+        let map = value.as_map().ok_or_else(bad)?;
+        let get = |label: i64| map.iter().find(|(k, _)| k.as_integer() == Some(label.into())).map(|(_, v)| v);
+        let int = |label: i64| get(label).and_then(Value::as_integer).and_then(|i| i64::try_from(i).ok());
+        let bytes = |label: i64| get(label).and_then(Value::as_bytes).ok_or_else(bad);
+
+        match (int(1), int(3)) {
+            // EC2 on P-256.
+            (Some(2), Some(ES256)) => {
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                if int(-1) != Some(1) || x.len() != 32 || y.len() != 32 {
+                    return Err(bad());
+                }
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    p256::FieldBytes::from_slice(x),
+                    p256::FieldBytes::from_slice(y),
+                    false,
+                );
+                let key = p256::ecdsa::VerifyingKey::from_encoded_point(&point).map_err(|_| bad())?;
+                Ok((PublicKey::Es256(key), ES256))
+            }
+            (Some(3), Some(RS256)) => {
+                let n = rsa::BigUint::from_bytes_be(bytes(-1)?);
+                let e = rsa::BigUint::from_bytes_be(bytes(-2)?);
+                let key = rsa::RsaPublicKey::new(n, e).map_err(|_| bad())?;
+                Ok((PublicKey::Rs256(key), RS256))
+            }
+            _ => Err(PasskeyError::Invalid("unsupported key type")),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            PublicKey::Es256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|sig| key.verify(message, &sig).is_ok()),
+            PublicKey::Rs256(key) => key
+                .verify(rsa::Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(message), signature)
+                .is_ok(),
+        }
+    }
+}
+
+/// `navigator.credentials.create()` result, as PublicKeyCredential.toJSON().
+#[derive(Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// `navigator.credentials.get()` result, as PublicKeyCredential.toJSON().
+#[derive(Deserialize)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+struct NewCredential {
+    credential_id: String,
+    public_key: Vec<u8>,
+    alg: i64,
+    sign_count: u32,
+}
+
+/// Check a registration response; its challenge and the new credential.
+fn verify_registration(rp: &RelyingParty, cred: &RegistrationCredential) -> Result<(String, NewCredential), PasskeyError> {
+    let challenge = check_client_data(rp, &b64url_decode(&cred.response.client_data_json)?, "webauthn.create")?;
+
+    let object: Value = ciborium::de::from_reader(&b64url_decode(&cred.response.attestation_object)?[..])
+        .map_err(|_| PasskeyError::Invalid("bad attestationObject"))?;
+    let auth_data = object
+        .as_map()
+        .and_then(|m| m.iter().find(|(k, _)| k.as_text() == Some("authData")))
+        .and_then(|(_, v)| v.as_bytes())
+        .ok_or(PasskeyError::Invalid("attestationObject without authData"))?;
+    let auth_data = parse_auth_data(rp, auth_data)?;
+    let (id, public_key) = auth_data.credential.ok_or(PasskeyError::Invalid("no credential data"))?;
+    let credential_id = b64url(&id);
+    if credential_id != cred.id.trim_end_matches('=') {
+        return Err(PasskeyError::Invalid("credential id mismatch"));
+    }
+    let (_, alg) = PublicKey::from_cose(&public_key)?;
+    Ok((challenge, NewCredential { credential_id, public_key, alg, sign_count: auth_data.sign_count }))
+}
+
+/// Check an assertion against the stored key; its challenge and the
+/// authenticator's signature counter.
+fn verify_assertion(rp: &RelyingParty, public_key: &[u8], cred: &AssertionCredential) -> Result<(String, u32), PasskeyError> {
+    let client_data = b64url_decode(&cred.response.client_data_json)?;
+    let challenge = check_client_data(rp, &client_data, "webauthn.get")?;
+    let raw_auth_data = b64url_decode(&cred.response.authenticator_data)?;
+    let auth_data = parse_auth_data(rp, &raw_auth_data)?;
+
+    let (key, _) = PublicKey::from_cose(public_key)?;
+    let mut signed = raw_auth_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data));
+    if !key.verify(&signed, &b64url_decode(&cred.response.signature)?) {
+        return Err(PasskeyError::Invalid("bad signature"));
+    }
+    Ok((challenge, auth_data.sign_count))
+}
+
+async fn new_challenge(pool: &PgPool, purpose: &str, telegram_id: Option<i64>) -> Result<String, sqlx::Error> {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    let challenge = b64url(&bytes);
+    sqlx::query("DELETE FROM webauthn_challenges WHERE expires_at < NOW()")
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO webauthn_challenges (challenge, purpose, telegram_id, expires_at) \
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))",
+    )
+    .bind(&challenge)
+    .bind(purpose)
+    .bind(telegram_id)
+    .bind(CHALLENGE_TTL_SECS as f64)
+    .execute(pool)
+    .await?;
+    Ok(challenge)
+}
+
+/// Use up a live challenge; false when there's none to use.
+async fn take_challenge(pool: &PgPool, challenge: &str, purpose: &str, telegram_id: Option<i64>) -> Result<bool, sqlx::Error> {
+    let r = sqlx::query(
+        "DELETE FROM webauthn_challenges \
+         WHERE challenge = $1 AND purpose = $2 AND telegram_id IS NOT DISTINCT FROM $3 AND expires_at > NOW()",
+    )
+    .bind(challenge)
+    .bind(purpose)
+    .bind(telegram_id)
+    .execute(pool)
+    .await?;
+    Ok(r.rows_affected() > 0)
+}
+
+/// PublicKeyCredentialCreationOptions for `navigator.credentials.create()`.
+pub async fn registration_options(pool: &PgPool, telegram_id: i64, user_name: &str) -> Result<Json, PasskeyError> {
+    let existing: Vec<String> = sqlx::query_scalar("SELECT credential_id FROM passkeys WHERE telegram_id = $1")
+        .bind(telegram_id)
+        .fetch_all(pool)
+        .await?;
+    let challenge = new_challenge(pool, PURPOSE_REGISTER, Some(telegram_id)).await?;
+    Ok(json!({
+        "challenge": challenge,
+        "rp": { "id": RP.id, "name": RP_NAME },
+        "user": { "id": user_handle(telegram_id), "name": user_name, "displayName": user_name },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": ES256 },
+            { "type": "public-key", "alg": RS256 },
+        ],
+        "timeout": CHALLENGE_TTL_SECS * 1000,
+        "attestation": "none",
+        "authenticatorSelection": { "residentKey": "required", "requireResidentKey": true, "userVerification": "required" },
+        "excludeCredentials": existing.iter().map(|id| json!({ "type": "public-key", "id": id })).collect::<Vec<_>>(),
+    }))
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Passkey {
+    pub id: i64,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Session it was added in; None for pre-sessions tokens.
+    pub session_id: Option<i64>,
+}
+
+/// Finish registration: store the new passkey under `name`, added in
+/// `session_id`.
+pub async fn register(
+    pool: &PgPool,
+    telegram_id: i64,
+    session_id: Option<i64>,
+    cred: &RegistrationCredential,
+    name: &str,
+) -> Result<Passkey, PasskeyError> {
+    let (challenge, new) = verify_registration(&RP, cred)?;
+    if !take_challenge(pool, &challenge, PURPOSE_REGISTER, Some(telegram_id)).await? {
+        return Err(PasskeyError::ChallengeExpired);
+    }
+    let name: String = name.trim().chars().take(MAX_NAME).collect();
+    let name = if name.is_empty() { "Passkey".to_string() } else { name };
+    let row: Option<Passkey> = sqlx::query_as(
+        "INSERT INTO passkeys (telegram_id, credential_id, public_key, alg, sign_count, name, session_id) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (credential_id) DO NOTHING \
+         RETURNING id, name, created_at, last_used_at, session_id",
+    )
+    .bind(telegram_id)
+    .bind(&new.credential_id)
+    .bind(&new.public_key)
+    .bind(new.alg as i32)
+    .bind(new.sign_count as i64)
+    .bind(&name)
+    .bind(session_id)
+    .fetch_optional(pool)
+    .await?;
+    row.ok_or(PasskeyError::Duplicate)
+}
+
+/// PublicKeyCredentialRequestOptions for `navigator.credentials.get()`.
+pub async fn login_options(pool: &PgPool) -> Result<Json, PasskeyError> {
+    let challenge = new_challenge(pool, PURPOSE_LOGIN, None).await?;
+    Ok(json!({
+        "challenge": challenge,
+        "rpId": RP.id,
+        "timeout": CHALLENGE_TTL_SECS * 1000,
+        "userVerification": "required",
+        "allowCredentials": [],
+    }))
+}
+
+/// Finish sign-in; the passkey owner's telegram_id.
+pub async fn authenticate(pool: &PgPool, cred: &AssertionCredential) -> Result<i64, PasskeyError> {
+    let stored: Option<(i64, i64, Vec<u8>, i64)> = sqlx::query_as(
+        "SELECT id, telegram_id, public_key, sign_count FROM passkeys WHERE credential_id = $1",
+    )
+    .bind(cred.id.trim_end_matches('='))
+    .fetch_optional(pool)
+    .await?;
+    let (id, telegram_id, public_key, stored_count) = stored.ok_or(PasskeyError::UnknownCredential)?;
+    if cred.response.user_handle.as_deref().is_some_and(|h| h.trim_end_matches('=') != user_handle(telegram_id)) {
+        return Err(PasskeyError::Invalid("user handle mismatch"));
+    }
+
+    let (challenge, sign_count) = verify_assertion(&RP, &public_key, cred)?;
+    if !take_challenge(pool, &challenge, PURPOSE_LOGIN, None).await? {
+        return Err(PasskeyError::ChallengeExpired);
+    }
+    // Authenticators that count (most passkeys sync and send 0) must move
+    // forward; going back means the key was cloned.
+    let sign_count = sign_count as i64;
+    if (sign_count != 0 || stored_count != 0) && sign_count <= stored_count {
+        log::warn!("[passkeys] sign count went back for passkey {} (telegram_id={})", id, telegram_id);
+        return Err(PasskeyError::Invalid("sign count went back"));
+    }
+
+    sqlx::query("UPDATE passkeys SET sign_count = $2, last_used_at = NOW() WHERE id = $1")
+        .bind(id)
+        .bind(sign_count)
+        .execute(pool)
+        .await?;
+    Ok(telegram_id)
+}
+
+/// The user's passkeys, newest first.
+pub async fn list(pool: &PgPool, telegram_id: i64) -> Result<Vec<Passkey>, sqlx::Error> {
+    sqlx::query_as::<_, Passkey>(
+        "SELECT id, name, created_at, last_used_at, session_id FROM passkeys \
+         WHERE telegram_id = $1 ORDER BY created_at DESC",
+    )
+    .bind(telegram_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// The user's passkeys added in sessions that are still live, newest first.
+pub async fn added_in_live_sessions(pool: &PgPool, telegram_id: i64) -> Result<Vec<Passkey>, sqlx::Error> {
+    sqlx::query_as::<_, Passkey>(
+        "SELECT p.id, p.name, p.created_at, p.last_used_at, p.session_id FROM passkeys p \
+         JOIN sessions s ON s.id = p.session_id \
+         WHERE p.telegram_id = $1 AND s.revoked_at IS NULL ORDER BY p.created_at DESC",
+    )
+    .bind(telegram_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Remove one of the user's passkeys. Returns whether it existed.
+pub async fn delete(pool: &PgPool, telegram_id: i64, id: i64) -> Result<bool, sqlx::Error> {
+    let r = sqlx::query("DELETE FROM passkeys WHERE id = $1 AND telegram_id = $2")
+        .bind(id)
+        .bind(telegram_id)
+        .execute(pool)
+        .await?;
+    Ok(r.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, SigningKey};
+
+    fn rp() -> RelyingParty {
+        RelyingParty { id: "svoiweb.ru".into(), origins: vec!["https://svoiweb.ru".into()] }
+    }
+
+    fn cbor(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::ser::into_writer(value, &mut out).unwrap();
+        out
+    }
+
+    fn cose_key(key: &SigningKey) -> Vec<u8> {
+        let point = key.verifying_key().to_encoded_point(false);
+        cbor(&Value::Map(vec![
+            (1.into(), 2.into()),
+            (3.into(), ES256.into()),
+            ((-1).into(), 1.into()),
+            ((-2).into(), Value::Bytes(point.x().unwrap().to_vec())),
+            ((-3).into(), Value::Bytes(point.y().unwrap().to_vec())),
+        ]))
+    }
+
+    fn auth_data(flags: u8, sign_count: u32, credential: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Sha256::digest(b"svoiweb.ru").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        if let Some((id, key)) = credential {
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&(id.len() as u16).to_be_bytes());
+            data.extend_from_slice(id);
+            data.extend_from_slice(key);
+        }
+        data
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> String {
+        b64url(json!({ "type": kind, "challenge": challenge, "origin": origin }).to_string().as_bytes())
+    }
+
+    #[test]
+    fn registration_extracts_credential() {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let cose = cose_key(&key);
+        let object = cbor(&Value::Map(vec![
+            ("fmt".into(), "none".into()),
+            ("attStmt".into(), Value::Map(vec![])),
+            ("authData".into(), Value::Bytes(auth_data(FLAG_UP | FLAG_UV | FLAG_AT, 0, Some((b"cred-1", &cose))))),
+        ]));
+        let mut cred = RegistrationCredential {
+            id: b64url(b"cred-1"),
+            response: AttestationResponse {
+                client_data_json: client_data("webauthn.create", "chal", "https://svoiweb.ru"),
+                attestation_object: b64url(&object),
+            },
+        };
+        let (challenge, new) = verify_registration(&rp(), &cred).unwrap();
+        assert_eq!(challenge, "chal");
+        assert_eq!(new.public_key, cose);
+        assert_eq!(new.alg, ES256);
+
+        cred.response.client_data_json = client_data("webauthn.create", "chal", "https://evil.example");
+        assert!(verify_registration(&rp(), &cred).is_err());
+        cred.response.client_data_json = client_data("webauthn.get", "chal", "https://svoiweb.ru");
+        assert!(verify_registration(&rp(), &cred).is_err());
+    }
+
+    #[test]
+    fn assertion_checks_signature() {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let data = auth_data(FLAG_UP | FLAG_UV, 7, None);
+        let client = client_data("webauthn.get", "chal", "https://svoiweb.ru");
+        let mut signed = data.clone();
+        signed.extend_from_slice(&Sha256::digest(b64url_decode(&client).unwrap()));
+        let sig: p256::ecdsa::Signature = key.sign(&signed);
+        let mut cred = AssertionCredential {
+            id: b64url(b"cred-1"),
+            response: AssertionResponse {
+                client_data_json: client,
+                authenticator_data: b64url(&data),
+                signature: b64url(sig.to_der().as_bytes()),
+                user_handle: None,
+            },
+        };
+        assert_eq!(verify_assertion(&rp(), &cose_key(&key), &cred).unwrap(), ("chal".to_string(), 7));
+
+        let other = SigningKey::random(&mut rand::rngs::OsRng);
+        assert!(verify_assertion(&rp(), &cose_key(&other), &cred).is_err());
+        cred.response.authenticator_data = b64url(&auth_data(0, 7, None));
+        assert!(verify_assertion(&rp(), &cose_key(&key), &cred).is_err());
+        // Signed, but no PIN or biometric was asked for.
+        let unverified = auth_data(FLAG_UP, 7, None);
+        let mut signed = unverified.clone();
+        signed.extend_from_slice(&Sha256::digest(b64url_decode(&cred.response.client_data_json).unwrap()));
+        let sig: p256::ecdsa::Signature = key.sign(&signed);
+        cred.response.authenticator_data = b64url(&unverified);
+        cred.response.signature = b64url(sig.to_der().as_bytes());
+        assert!(verify_assertion(&rp(), &cose_key(&key), &cred).is_err());
+    }
+
+    #[test]
+    fn auth_data_rejects_other_rp() {
+        let mut data = auth_data(FLAG_UP | FLAG_UV, 0, None);
+        data[0] ^= 1;
+        assert!(parse_auth_data(&rp(), &data).is_err());
+        assert!(parse_auth_data(&rp(), &data[..20]).is_err());
+    }
+}
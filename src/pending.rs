@@ -0,0 +1,628 @@
+//! Checkouts started on the site (migrations/026_pending_payments.sql).
+//!
+//! Every YooKassa payment and CryptoPay invoice the site creates is
+//! [`record`]ed as `pending`. Webhooks [`resolve`] it; the worker
+//! ([`spawn`]) polls the provider for rows still open, gives up on them
+//! after OPEN_HOURS, and sends one reminder per user for a subscription
+//! checkout left unpaid PENDING_REMIND_AFTER_HOURS (default 3, 0 = off).
+//! Crediting stays with the webhooks — the poller only tracks status, and
+//! gives back the promo hold and wallet part of a checkout the provider
+//! reports canceled or expired, as the cancel webhook does. One it gives up
+//! on without such a status is only marked expired.
+//!
+//! A YooKassa create call that got no answer is [`record_unconfirmed`]
+//! under its Idempotence-Key (migrations/033_pending_create_request.sql);
+//! the poller re-sends it with that key to learn the payment.
+//!
+//! [`funnel`] is the created → paid breakdown for
+//! `GET /admin/analytics/checkouts`.
+
+use crate::{promo, push_web, wallet, yookassa};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::time::Duration;
+
+pub const PROVIDER_YOOKASSA: &str = "yookassa";
+pub const PROVIDER_CRYPTO: &str = "crypto";
+
+pub const KIND_SUBSCRIPTION: &str = "subscription";
+pub const KIND_WALLET_TOPUP: &str = "wallet_topup";
+
+pub const STATUS_PAID: &str = "paid";
+pub const STATUS_CANCELED: &str = "canceled";
+pub const STATUS_EXPIRED: &str = "expired";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const BATCH: i64 = 50;
+/// A row isn't re-polled sooner than this.
+const RECHECK_SECS: f64 = 10.0 * 60.0;
+/// Still unresolved after this — stop polling: resolved from the
+/// provider's final status if it has one, otherwise counted as expired.
+const OPEN_HOURS: f64 = 48.0;
+/// YooKassa answers a resent Idempotence-Key for 24h; an unconfirmed
+/// checkout is re-sent a little less than that.
+const KEY_VALID_HOURS: f64 = 23.0;
+/// Checkouts older than this aren't reminded about.
+const REMIND_WINDOW_HOURS: f64 = 72.0;
+
+lazy_static::lazy_static! {
+    static ref HTTP: reqwest::Client = reqwest::Client::new();
+}
+
+/// Hours after which an unpaid checkout is reminded about; None when off.
+pub fn remind_after_hours() -> Option<f64> {
+    let h = std::env::var("PENDING_REMIND_AFTER_HOURS")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(3.0);
+    (h > 0.0).then_some(h)
+}
+
+pub struct NewPending<'a> {
+    pub provider: &'a str,
+    pub provider_id: &'a str,
+    pub telegram_id: i64,
+    pub kind: &'a str,
+    pub tariff: Option<&'a str>,
+    pub duration: Option<&'a str>,
+    pub amount_rub: f64,
+}
+
+/// Remember a checkout the provider just created.
+pub async fn record(pool: &PgPool, p: &NewPending<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO pending_payments (provider, provider_id, telegram_id, kind, tariff, duration, amount_rub) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7::numeric) ON CONFLICT (provider, provider_id) DO NOTHING",
+    )
+    .bind(p.provider)
+    .bind(p.provider_id)
+    .bind(p.telegram_id)
+    .bind(p.kind)
+    .bind(p.tariff)
+    .bind(p.duration)
+    .bind(p.amount_rub)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Remember a YooKassa checkout whose create call got no answer:
+/// `p.provider_id` is the Idempotence-Key, `request` the body sent with it.
+pub async fn record_unconfirmed(pool: &PgPool, p: &NewPending<'_>, request: &Value) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO pending_payments \
+         (provider, provider_id, telegram_id, kind, tariff, duration, amount_rub, create_request) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7::numeric, $8::jsonb) ON CONFLICT (provider, provider_id) DO NOTHING",
+    )
+    .bind(p.provider)
+    .bind(p.provider_id)
+    .bind(p.telegram_id)
+    .bind(p.kind)
+    .bind(p.tariff)
+    .bind(p.duration)
+    .bind(p.amount_rub)
+    .bind(request.to_string())
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Final status from a webhook or a poll. Paid is never overwritten; a
+/// payment reported paid after being canceled locally is still paid. No-op
+/// for payments that weren't recorded.
+pub async fn resolve(pool: &PgPool, provider: &str, provider_id: &str, status: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE pending_payments SET status = $3, resolved_at = NOW(), checked_at = NOW() \
+         WHERE provider = $1 AND provider_id = $2 AND status <> 'paid' AND status <> $3",
+    )
+    .bind(provider)
+    .bind(provider_id)
+    .bind(status)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Local status of a YooKassa payment status; None while it's open.
+pub fn yookassa_status(status: &str) -> Option<&'static str> {
+    match status {
+        "succeeded" => Some(STATUS_PAID),
+        "canceled" => Some(STATUS_CANCELED),
+        _ => None,
+    }
+}
+
+/// `(invoice_id, local status)` of the resolved invoices in a CryptoPay
+/// `getInvoices` response.
+pub fn cryptopay_statuses(v: &Value) -> Vec<(i64, &'static str)> {
+    v["result"]["items"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|inv| {
+            let status = match inv["status"].as_str()? {
+                "paid" => STATUS_PAID,
+                "expired" => STATUS_EXPIRED,
+                _ => return None,
+            };
+            Some((inv["invoice_id"].as_i64()?, status))
+        })
+        .collect()
+}
+
+#[derive(sqlx::FromRow)]
+struct Open {
+    provider: String,
+    provider_id: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct GivenUp {
+    provider: String,
+    provider_id: String,
+    unconfirmed: bool,
+}
+
+/// Poll the provider for open rows not checked recently.
+async fn sync_open(pool: &PgPool) {
+    let rows = match sqlx::query_as::<_, Open>(
+        "UPDATE pending_payments SET checked_at = NOW() WHERE id IN ( \
+             SELECT id FROM pending_payments WHERE status = 'pending' AND create_request IS NULL \
+             AND (checked_at IS NULL OR checked_at < NOW() - make_interval(secs => $1)) \
+             ORDER BY checked_at NULLS FIRST LIMIT $2) \
+         RETURNING provider, provider_id",
+    )
+    .bind(RECHECK_SECS)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("[pending] open query failed: {}", e);
+            return;
+        }
+    };
+
+    let (web_id, web_secret) = crate::web_handlers::web_yookassa_creds();
+    let shop = yookassa::Shop { name: yookassa::SHOP_WEB, shop_id: web_id, secret: web_secret };
+    confirm_created(pool, &shop).await;
+    let mut invoices = Vec::new();
+    for r in rows {
+        if r.provider == PROVIDER_CRYPTO {
+            invoices.push(r.provider_id);
+        } else {
+            sync_yookassa(pool, &shop, &r.provider_id).await;
+        }
+    }
+    sync_cryptopay(pool, &invoices).await;
+
+    // Past OPEN_HOURS a checkout stops being polled. Only a status the
+    // provider confirms gives anything back; the rest is marked expired
+    // and its promo hold and wallet part are left for a late webhook or
+    // support.
+    let given_up = match sqlx::query_as::<_, GivenUp>(
+        "SELECT provider, provider_id, create_request IS NOT NULL AS unconfirmed FROM pending_payments \
+         WHERE status = 'pending' AND created_at < NOW() - make_interval(hours => $1) \
+         ORDER BY created_at LIMIT $2",
+    )
+    .bind(OPEN_HOURS as i32)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("[pending] given-up query failed: {}", e);
+            return;
+        }
+    };
+    let mut invoices = Vec::new();
+    let mut unresolved = Vec::new();
+    for r in given_up {
+        if r.unconfirmed {
+            error!("[pending] checkout {} never confirmed by YooKassa, its wallet part and promo hold need a manual check", r.provider_id);
+            unresolved.push((r.provider, r.provider_id));
+        } else if r.provider == PROVIDER_CRYPTO {
+            invoices.push(r.provider_id);
+        } else if sync_yookassa(pool, &shop, &r.provider_id).await.is_none() {
+            unresolved.push((r.provider, r.provider_id));
+        }
+    }
+    let resolved = sync_cryptopay(pool, &invoices).await;
+    unresolved.extend(
+        invoices
+            .into_iter()
+            .filter(|id| !resolved.contains(id))
+            .map(|id| (PROVIDER_CRYPTO.to_string(), id)),
+    );
+    if !unresolved.is_empty() {
+        info!("[pending] {} checkouts expired unresolved", unresolved.len());
+    }
+    for (provider, provider_id) in unresolved {
+        if let Err(e) = resolve(pool, &provider, &provider_id, STATUS_EXPIRED).await {
+            error!("[pending] expiring {} failed: {}", provider_id, e);
+        }
+    }
+}
+
+/// Poll one YooKassa payment. A canceled one gives back its checkout.
+/// Returns the local status it resolved to; None while it's open or when
+/// YooKassa couldn't be asked.
+async fn sync_yookassa(pool: &PgPool, shop: &yookassa::Shop, payment_id: &str) -> Option<&'static str> {
+    let status = match yookassa::fetch_payment(&HTTP, shop, payment_id).await {
+        Ok(p) => p["status"].as_str().and_then(yookassa_status)?,
+        Err(e) => {
+            warn!("[pending] fetch {} failed: {}", payment_id, e);
+            return None;
+        }
+    };
+    if let Err(e) = resolve(pool, PROVIDER_YOOKASSA, payment_id, status).await {
+        error!("[pending] resolve {} failed: {}", payment_id, e);
+    }
+    if status == STATUS_CANCELED {
+        release_checkout(pool, PROVIDER_YOOKASSA, payment_id).await;
+    }
+    Some(status)
+}
+
+/// Poll CryptoPay invoices. An expired one gives back its checkout.
+/// Returns the ids that resolved.
+async fn sync_cryptopay(pool: &PgPool, invoices: &[String]) -> Vec<String> {
+    let token = std::env::var("CRYPTO_BOT_TOKEN").unwrap_or_default();
+    if invoices.is_empty() || token.is_empty() {
+        return Vec::new();
+    }
+    let resp = HTTP
+        .get("https://pay.crypt.bot/api/getInvoices")
+        .header("Crypto-Pay-API-Token", &token)
+        .query(&[("invoice_ids", invoices.join(","))])
+        .send()
+        .await;
+    let v = match resp {
+        Ok(r) => match r.json::<Value>().await {
+            Ok(v) if v["ok"] == true => v,
+            Ok(v) => {
+                warn!("[pending] getInvoices: {}", v["error"]);
+                return Vec::new();
+            }
+            Err(e) => {
+                warn!("[pending] getInvoices: {}", e);
+                return Vec::new();
+            }
+        },
+        Err(e) => {
+            warn!("[pending] getInvoices: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut resolved = Vec::new();
+    for (id, status) in cryptopay_statuses(&v) {
+        let id = id.to_string();
+        if let Err(e) = resolve(pool, PROVIDER_CRYPTO, &id, status).await {
+            error!("[pending] resolve invoice {} failed: {}", id, e);
+        }
+        if status == STATUS_EXPIRED {
+            release_checkout(pool, PROVIDER_CRYPTO, &id).await;
+        }
+        resolved.push(id);
+    }
+    resolved
+}
+
+#[derive(sqlx::FromRow)]
+struct Unconfirmed {
+    provider_id: String,
+    create_request: String,
+}
+
+/// Re-send the create call of checkouts recorded by [`record_unconfirmed`]
+/// with their key. YooKassa answers with the payment it made for the key,
+/// which the row, the wallet spend and the promo hold are then tied to; a
+/// rejection means there is no payment, and both are given back. Keys
+/// older than KEY_VALID_HOURS aren't re-sent: YooKassa would make a new
+/// payment for them.
+async fn confirm_created(pool: &PgPool, shop: &yookassa::Shop) {
+    let rows = match sqlx::query_as::<_, Unconfirmed>(
+        "SELECT provider_id, create_request::text AS create_request FROM pending_payments \
+         WHERE status = 'pending' AND create_request IS NOT NULL \
+           AND created_at > NOW() - make_interval(secs => $1) \
+         ORDER BY created_at LIMIT $2",
+    )
+    .bind(KEY_VALID_HOURS * 3600.0)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("[pending] unconfirmed query failed: {}", e);
+            return;
+        }
+    };
+
+    for r in rows {
+        let key = r.provider_id;
+        let request: Value = match serde_json::from_str(&r.create_request) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("[pending] stored request of {} unreadable: {}", key, e);
+                continue;
+            }
+        };
+        let reservation = request["metadata"]["promo_reservation"].as_str().and_then(|s| s.parse::<i64>().ok());
+        match yookassa::create_payment(&HTTP, shop, &key, &request).await {
+            Ok(p) => {
+                let Some(payment_id) = p["id"].as_str() else {
+                    warn!("[pending] create answer for {} has no id", key);
+                    continue;
+                };
+                match tie_created(pool, &key, payment_id, reservation).await {
+                    Ok(()) => info!("[pending] checkout {} is payment {}", key, payment_id),
+                    Err(e) => error!("[pending] tying checkout {} to {} failed: {}", key, payment_id, e),
+                }
+            }
+            Err(yookassa::CreateError::Rejected(e)) => {
+                warn!("[pending] checkout {} rejected on resend, giving it back: {}", key, e);
+                if let Some(id) = reservation {
+                    if let Err(e) = promo::release(pool, id).await {
+                        error!("[pending] releasing promo hold {} failed: {}", id, e);
+                    }
+                }
+                match wallet::spend_by_key(pool, &wallet::spend_key(&key)).await {
+                    Ok(Some(spend_id)) => {
+                        if let Err(e) = wallet::reverse(pool, spend_id).await {
+                            error!("[pending] reversing wallet spend {} failed: {}", spend_id, e);
+                            continue;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("[pending] wallet spend of {} lookup failed: {}", key, e);
+                        continue;
+                    }
+                }
+                if let Err(e) = resolve(pool, PROVIDER_YOOKASSA, &key, STATUS_CANCELED).await {
+                    error!("[pending] resolve {} failed: {}", key, e);
+                }
+            }
+            Err(yookassa::CreateError::Transient(e)) => warn!("[pending] resend of {} failed: {}", key, e),
+        }
+    }
+}
+
+/// Point an unconfirmed checkout at its payment: the promo hold, the wallet
+/// spend, then the row, which the regular poll takes over from there.
+async fn tie_created(pool: &PgPool, key: &str, payment_id: &str, reservation: Option<i64>) -> Result<(), sqlx::Error> {
+    if let Some(id) = reservation {
+        promo::attach(pool, id, payment_id, None).await?;
+    }
+    if let Some(spend_id) = wallet::spend_by_key(pool, &wallet::spend_key(key)).await? {
+        wallet::attach(pool, spend_id, &format!("yookassa:{}", payment_id)).await?;
+    }
+    sqlx::query(
+        "UPDATE pending_payments SET provider_id = $2, create_request = NULL, checked_at = NULL \
+         WHERE provider = $3 AND provider_id = $1",
+    )
+    .bind(key)
+    .bind(payment_id)
+    .bind(PROVIDER_YOOKASSA)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Give back what a checkout that won't be paid was holding: its promo use
+/// and, for YooKassa, the wallet part. Both are no-ops when there is none.
+async fn release_checkout(pool: &PgPool, provider: &str, provider_id: &str) {
+    let promo_provider = if provider == PROVIDER_CRYPTO { promo::PROVIDER_CRYPTO } else { promo::PROVIDER_YOOKASSA };
+    if let Err(e) = promo::release_payment(pool, promo_provider, provider_id).await {
+        error!("[pending] releasing promo hold of {} failed: {}", provider_id, e);
+    }
+    if provider == PROVIDER_YOOKASSA {
+        if let Err(e) = wallet::reverse_reference(pool, &format!("yookassa:{}", provider_id)).await {
+            error!("[pending] reversing wallet spend of {} failed: {}", provider_id, e);
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Abandoned {
+    telegram_id: i64,
+    tariff: Option<String>,
+    duration: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+/// Reminder text for an abandoned subscription checkout.
+pub fn reminder_text(tariff: Option<&str>, duration: Option<&str>) -> String {
+    let plan = tariff
+        .and_then(|t| crate::plans::catalog().get(t).map(|p| p.display_name.clone()))
+        .unwrap_or_else(|| "подписки".to_string());
+    let duration = duration.and_then(crate::prices::duration_name).map(|d| format!(", {}", d)).unwrap_or_default();
+    format!(
+        "Вы начали оформление «{}{}», но оплата не завершилась. Продолжить можно на сайте: https://svoiweb.ru",
+        plan, duration
+    )
+}
+
+/// One reminder per user for their latest subscription checkout that is
+/// still unpaid after `after_hours`, unless they paid anything since.
+async fn remind_abandoned(pool: &PgPool, after_hours: f64) {
+    let rows = match sqlx::query_as::<_, Abandoned>(
+        "SELECT DISTINCT ON (pp.telegram_id) pp.telegram_id, pp.tariff, pp.duration, pp.created_at \
+         FROM pending_payments pp \
+         WHERE pp.kind = 'subscription' AND pp.status <> 'paid' AND pp.reminded_at IS NULL \
+           AND pp.created_at < NOW() - make_interval(secs => $1) \
+           AND pp.created_at > NOW() - make_interval(secs => $2) \
+           AND NOT EXISTS (SELECT 1 FROM pending_payments o WHERE o.telegram_id = pp.telegram_id \
+                           AND (o.status = 'paid' OR o.reminded_at IS NOT NULL) AND o.created_at >= pp.created_at AND o.id <> pp.id) \
+           AND NOT EXISTS (SELECT 1 FROM payments p WHERE p.telegram_id IN (pp.telegram_id, -pp.telegram_id) \
+                           AND p.source IN ('yookassa', 'crypto', 'stars', 'wallet') AND p.created_at > pp.created_at) \
+         ORDER BY pp.telegram_id, pp.created_at DESC LIMIT $3",
+    )
+    .bind(after_hours * 3600.0)
+    .bind(REMIND_WINDOW_HOURS * 3600.0)
+    .bind(BATCH)
+    .fetch_all(pool)
+    .await
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("[pending] abandoned query failed: {}", e);
+            return;
+        }
+    };
+
+    for a in rows {
+        // Marked first: a failed send isn't retried, a double send is worse.
+        let marked = sqlx::query(
+            "UPDATE pending_payments SET reminded_at = NOW() \
+             WHERE telegram_id = $1 AND kind = 'subscription' AND reminded_at IS NULL AND created_at <= $2",
+        )
+        .bind(a.telegram_id)
+        .bind(a.created_at)
+        .execute(pool)
+        .await;
+        if let Err(e) = marked {
+            error!("[pending] marking reminder for {} failed: {}", a.telegram_id, e);
+            continue;
+        }
+        info!("[pending] reminding {} about an unpaid checkout", a.telegram_id);
+        notify(pool, a.telegram_id, &reminder_text(a.tariff.as_deref(), a.duration.as_deref())).await;
+    }
+}
+
+/// Telegram message from the main bot (Telegram users) and web push.
+async fn notify(pool: &PgPool, telegram_id: i64, text: &str) {
+    if telegram_id > 0 {
+        let token = std::env::var("BOT_TOKEN_TG").unwrap_or_default();
+        let sent = HTTP
+            .post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+            .json(&json!({"chat_id": telegram_id, "text": text}))
+            .send()
+            .await;
+        match sent {
+            Ok(r) if r.status().is_success() => {}
+            Ok(r) => warn!("[pending] telegram notify {} failed: {}", telegram_id, r.status()),
+            Err(e) => warn!("[pending] telegram notify {} failed: {}", telegram_id, e),
+        }
+    }
+    push_web::send_to_telegram_id(pool.clone(), telegram_id, "Оплата не завершена".to_string(), text.to_string()).await;
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct FunnelRow {
+    pub provider: String,
+    pub kind: String,
+    pub created: i64,
+    pub paid: i64,
+    pub canceled: i64,
+    pub expired: i64,
+    pub pending: i64,
+    pub reminded: i64,
+    /// Reminded, then paid for anything afterwards.
+    pub recovered: i64,
+    pub created_rub: f64,
+    pub paid_rub: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Funnel {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub created: i64,
+    pub paid: i64,
+    pub conversion_rate: f64,
+    pub abandoned: i64,
+    pub reminded: i64,
+    pub recovered: i64,
+    pub by_provider: Vec<FunnelRow>,
+}
+
+fn ratio(n: i64, d: i64) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        (n as f64 / d as f64 * 10_000.0).round() / 10_000.0
+    }
+}
+
+/// Checkouts created in `[from, to)`, by provider and kind.
+pub async fn funnel(pool: &PgPool, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Funnel, sqlx::Error> {
+    let rows = sqlx::query_as::<_, FunnelRow>(
+        "SELECT pp.provider, pp.kind, COUNT(*) AS created, \
+                COUNT(*) FILTER (WHERE pp.status = 'paid') AS paid, \
+                COUNT(*) FILTER (WHERE pp.status = 'canceled') AS canceled, \
+                COUNT(*) FILTER (WHERE pp.status = 'expired') AS expired, \
+                COUNT(*) FILTER (WHERE pp.status = 'pending') AS pending, \
+                COUNT(*) FILTER (WHERE pp.reminded_at IS NOT NULL) AS reminded, \
+                COUNT(*) FILTER (WHERE pp.reminded_at IS NOT NULL AND EXISTS ( \
+                    SELECT 1 FROM payments p WHERE p.telegram_id IN (pp.telegram_id, -pp.telegram_id) \
+                    AND p.source IN ('yookassa', 'crypto', 'stars', 'wallet') AND p.created_at > pp.reminded_at)) AS recovered, \
+                COALESCE(SUM(pp.amount_rub), 0)::float8 AS created_rub, \
+                COALESCE(SUM(pp.amount_rub) FILTER (WHERE pp.status = 'paid'), 0)::float8 AS paid_rub \
+         FROM pending_payments pp WHERE pp.created_at >= $1 AND pp.created_at < $2 \
+         GROUP BY pp.provider, pp.kind ORDER BY pp.provider, pp.kind",
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let sum = |f: fn(&FunnelRow) -> i64| rows.iter().map(f).sum::<i64>();
+    let (created, paid) = (sum(|r| r.created), sum(|r| r.paid));
+    Ok(Funnel {
+        from,
+        to,
+        created,
+        paid,
+        conversion_rate: ratio(paid, created),
+        abandoned: sum(|r| r.canceled + r.expired),
+        reminded: sum(|r| r.reminded),
+        recovered: sum(|r| r.recovered),
+        by_provider: rows,
+    })
+}
+
+/// Background worker: status sync and abandoned-checkout reminders.
+pub fn spawn(pool: PgPool) {
+    tokio::spawn(async move {
+        loop {
+            sync_open(&pool).await;
+            if let Some(hours) = remind_after_hours() {
+                remind_abandoned(&pool, hours).await;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_provider_statuses() {
+        assert_eq!(yookassa_status("succeeded"), Some(STATUS_PAID));
+        assert_eq!(yookassa_status("canceled"), Some(STATUS_CANCELED));
+        assert_eq!(yookassa_status("waiting_for_capture"), None);
+
+        let v = json!({"ok": true, "result": {"items": [
+            {"invoice_id": 1, "status": "paid"},
+            {"invoice_id": 2, "status": "active"},
+            {"invoice_id": 3, "status": "expired"},
+        ]}});
+        assert_eq!(cryptopay_statuses(&v), vec![(1, STATUS_PAID), (3, STATUS_EXPIRED)]);
+    }
+
+    #[test]
+    fn reminder_names_the_duration() {
+        let text = reminder_text(None, Some("3m"));
+        assert!(text.contains("«подписки, 3 месяца»"), "{}", text);
+        assert!(reminder_text(None, None).contains("«подписки»"));
+    }
+}
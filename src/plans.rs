@@ -0,0 +1,168 @@
+//! Plan catalog: per-tariff squads, inbounds, device/traffic limits, tag and
+//! display name, loaded from the `plans` table (migrations/013_plans.sql).
+//!
+//! The table is read once at startup into an in-memory [`Catalog`] and
+//! re-read after every `/admin/plans` write, so handlers never hit the DB
+//! for plan config. A new tariff is a row, not a redeploy.
+//!
+//! Squad rules (see the migration for `kind`):
+//!   - `paid`  — grants its squads and revokes squads owned by other paid
+//!     plans that it doesn't include (base after bsbase loses БС-обход).
+//!   - `bonus` — grants its squads, never revokes (a trial/milestone extend
+//!     must not strip what the user paid for).
+//!   - `addon` — the PRO squad; toggled separately, never revoked by a plan.
+
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+pub const KIND_PAID: &str = "paid";
+pub const KIND_BONUS: &str = "bonus";
+pub const KIND_ADDON: &str = "addon";
+
+/// Catalog code of the PRO add-on.
+pub const PRO_PLAN: &str = "pro";
+/// Baseline for new users and for plan codes missing from the catalog.
+pub const BASELINE_PLAN: &str = "free";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Plan {
+    pub code: String,
+    pub display_name: String,
+    pub kind: String,
+    pub squads: Vec<String>,
+    pub inbounds: Vec<String>,
+    pub device_limit: i64,
+    pub traffic_limit_bytes: i64,
+    pub tag: String,
+    pub is_active: bool,
+}
+
+impl Plan {
+    pub fn is_paid(&self) -> bool {
+        self.kind == KIND_PAID
+    }
+
+    /// Traffic limit as Remnawave expects it (0 = unlimited).
+    pub fn traffic_limit(&self) -> u64 {
+        self.traffic_limit_bytes.max(0) as u64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    plans: HashMap<String, Plan>,
+}
+
+impl Catalog {
+    pub fn new(plans: Vec<Plan>) -> Self {
+        Catalog { plans: plans.into_iter().map(|p| (p.code.clone(), p)).collect() }
+    }
+
+    pub fn get(&self, code: &str) -> Option<&Plan> {
+        self.plans.get(code)
+    }
+
+    /// The `free` plan — what a freshly created panel user gets.
+    pub fn baseline(&self) -> Plan {
+        self.get(BASELINE_PLAN).cloned().unwrap_or_default()
+    }
+
+    /// Plan config for `code`. Retired (`is_active = false`) plans still
+    /// resolve so existing subscribers keep their squads; unknown codes fall
+    /// back to the baseline's squads/limits with tag UNKNOWN and never revoke.
+    pub fn resolve(&self, code: &str) -> Plan {
+        if let Some(p) = self.get(code) {
+            return p.clone();
+        }
+        let base = self.baseline();
+        Plan {
+            code: code.to_string(),
+            display_name: code.to_string(),
+            kind: KIND_BONUS.to_string(),
+            tag: "UNKNOWN".to_string(),
+            ..base
+        }
+    }
+
+    fn pro_squads(&self) -> &[String] {
+        self.get(PRO_PLAN).map(|p| p.squads.as_slice()).unwrap_or(&[])
+    }
+
+    /// Reconcile the managed squads in `current` for `plan`. Squads no plan
+    /// knows about (admin-granted etc.) are preserved.
+    pub fn entitled_squads(&self, mut current: Vec<String>, plan: &Plan, is_pro: bool) -> Vec<String> {
+        if plan.is_paid() {
+            let addon: HashSet<&str> = self
+                .plans
+                .values()
+                .filter(|p| p.kind == KIND_ADDON)
+                .flat_map(|p| p.squads.iter().map(String::as_str))
+                .collect();
+            let revoked: HashSet<&str> = self
+                .plans
+                .values()
+                .filter(|p| p.is_paid())
+                .flat_map(|p| p.squads.iter().map(String::as_str))
+                .filter(|s| !plan.squads.iter().any(|own| own == s) && !addon.contains(s))
+                .collect();
+            current.retain(|s| !revoked.contains(s.as_str()));
+        }
+        for s in &plan.squads {
+            if !current.contains(s) {
+                current.push(s.clone());
+            }
+        }
+        if is_pro {
+            for s in self.pro_squads() {
+                if !current.contains(s) {
+                    current.push(s.clone());
+                }
+            }
+        }
+        current
+    }
+
+    /// Add or remove only the PRO squad(s), leaving everything else untouched.
+    pub fn with_pro(&self, mut current: Vec<String>, enable: bool) -> Vec<String> {
+        let pro = self.pro_squads();
+        if enable {
+            for s in pro {
+                if !current.contains(s) {
+                    current.push(s.clone());
+                }
+            }
+        } else {
+            current.retain(|s| !pro.contains(s));
+        }
+        current
+    }
+}
+
+static CATALOG: RwLock<Option<Arc<Catalog>>> = RwLock::new(None);
+
+/// Current catalog snapshot. Empty until [`reload`] has run.
+pub fn catalog() -> Arc<Catalog> {
+    CATALOG
+        .read()
+        .ok()
+        .and_then(|g| g.clone())
+        .unwrap_or_default()
+}
+
+/// Re-read the `plans` table into the cache. Returns the number of plans.
+pub async fn reload(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let plans = sqlx::query_as::<_, Plan>(
+        "SELECT code, display_name, kind, squads, inbounds, device_limit, \
+         traffic_limit_bytes, tag, is_active FROM plans",
+    )
+    .fetch_all(pool)
+    .await?;
+    let n = plans.len();
+    if let Ok(mut guard) = CATALOG.write() {
+        *guard = Some(Arc::new(Catalog::new(plans)));
+    }
+    log::info!("[plans] catalog loaded ({} plans)", n);
+    Ok(n)
+}
@@ -0,0 +1,205 @@
+//! Price catalog (migrations/019_prices.sql): versioned RUB prices per
+//! plan and duration.
+//!
+//! Like the plan catalog, every version is read into memory at startup and
+//! after each `/admin/prices` write. The version in effect is picked per
+//! call from `effective_from`, so a scheduled price change takes over on
+//! time without a reload. Payment handlers take one [`PriceList`] up front
+//! and record its `version` with the payment.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// Durations a price can be set for, shortest first.
+pub const DURATIONS: [&str; 4] = ["1m", "3m", "6m", "1y"];
+
+/// Days credited for a duration code.
+pub fn duration_days(duration: &str) -> Option<i32> {
+    match duration {
+        "1m" => Some(30),
+        "3m" => Some(90),
+        "6m" => Some(180),
+        "1y" => Some(365),
+        _ => None,
+    }
+}
+
+/// Russian label, as in payment descriptions and `metadata.plan`.
+pub fn duration_name(duration: &str) -> Option<&'static str> {
+    match duration {
+        "1m" => Some("1 месяц"),
+        "3m" => Some("3 месяца"),
+        "6m" => Some("6 месяцев"),
+        "1y" => Some("1 год"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PriceList {
+    pub version: i32,
+    pub effective_from: DateTime<Utc>,
+    pub note: Option<String>,
+    /// plan -> duration -> RUB
+    pub prices: BTreeMap<String, BTreeMap<String, i64>>,
+}
+
+impl PriceList {
+    pub fn get(&self, plan: &str, duration: &str) -> Option<i64> {
+        self.prices.get(plan).and_then(|d| d.get(duration)).copied()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    /// Ordered by effective_from.
+    versions: Vec<PriceList>,
+}
+
+impl Catalog {
+    pub fn new(mut versions: Vec<PriceList>) -> Self {
+        versions.sort_by_key(|v| v.effective_from);
+        Catalog { versions }
+    }
+
+    /// Version in effect at `at`: the latest one that has started.
+    pub fn at(&self, at: DateTime<Utc>) -> Option<&PriceList> {
+        self.versions.iter().rev().find(|v| v.effective_from <= at)
+    }
+
+    pub fn current(&self) -> Option<&PriceList> {
+        self.at(Utc::now())
+    }
+
+    pub fn versions(&self) -> &[PriceList] {
+        &self.versions
+    }
+}
+
+static CATALOG: RwLock<Option<Arc<Catalog>>> = RwLock::new(None);
+
+/// Current catalog snapshot. Empty until [`reload`] has run.
+pub fn catalog() -> Arc<Catalog> {
+    CATALOG
+        .read()
+        .ok()
+        .and_then(|g| g.clone())
+        .unwrap_or_default()
+}
+
+/// Price list in effect now. Empty (every lookup misses) if no version has
+/// started.
+pub fn current() -> PriceList {
+    catalog().current().cloned().unwrap_or(PriceList {
+        version: 0,
+        effective_from: Utc::now(),
+        note: None,
+        prices: BTreeMap::new(),
+    })
+}
+
+#[derive(sqlx::FromRow)]
+struct Row {
+    version_id: i32,
+    effective_from: DateTime<Utc>,
+    note: Option<String>,
+    plan: String,
+    duration: String,
+    amount_rub: i32,
+}
+
+/// Re-read every price version into the cache. Returns the number of versions.
+pub async fn reload(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let rows = sqlx::query_as::<_, Row>(
+        "SELECT v.id AS version_id, v.effective_from, v.note, p.plan, p.duration, p.amount_rub \
+         FROM price_versions v JOIN prices p ON p.version_id = v.id",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut versions: BTreeMap<i32, PriceList> = BTreeMap::new();
+    for r in rows {
+        versions
+            .entry(r.version_id)
+            .or_insert_with(|| PriceList {
+                version: r.version_id,
+                effective_from: r.effective_from,
+                note: r.note.clone(),
+                prices: BTreeMap::new(),
+            })
+            .prices
+            .entry(r.plan)
+            .or_default()
+            .insert(r.duration, i64::from(r.amount_rub));
+    }
+    let n = versions.len();
+    if let Ok(mut guard) = CATALOG.write() {
+        *guard = Some(Arc::new(Catalog::new(versions.into_values().collect())));
+    }
+    log::info!("[prices] catalog loaded ({} versions)", n);
+    Ok(n)
+}
+
+/// The env vars the prices used to come from. Logged at startup when one is
+/// still set and disagrees with the catalog, so a deploy doesn't silently
+/// change what users pay.
+const LEGACY_ENV: [(&str, &str, &str); 12] = [
+    ("BASE_MONTH", "base", "1m"),
+    ("BASE_3_MONTH", "base", "3m"),
+    ("BASE_YEAR", "base", "1y"),
+    ("FAMILY_MONTH", "family", "1m"),
+    ("FAMILY_3_MONTH", "family", "3m"),
+    ("FAMILY_YEAR", "family", "1y"),
+    ("BSBASE_MONTH", "bsbase", "1m"),
+    ("BSBASE_3_MONTH", "bsbase", "3m"),
+    ("BSBASE_YEAR", "bsbase", "1y"),
+    ("BSFAMILY_MONTH", "bsfamily", "1m"),
+    ("BSFAMILY_3_MONTH", "bsfamily", "3m"),
+    ("BSFAMILY_YEAR", "bsfamily", "1y"),
+];
+
+pub fn warn_legacy_env() {
+    let list = current();
+    for (env, plan, duration) in LEGACY_ENV {
+        let Some(v) = std::env::var(env).ok().and_then(|s| s.parse::<i64>().ok()) else {
+            continue;
+        };
+        if list.get(plan, duration) != Some(v) {
+            log::warn!(
+                "[prices] {}={} is ignored; price version {} has {}_{} = {:?}",
+                env, v, list.version, plan, duration, list.get(plan, duration)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(id: i32, from: &str, base_1m: i64) -> PriceList {
+        let mut prices = BTreeMap::new();
+        prices.insert("base".to_string(), BTreeMap::from([("1m".to_string(), base_1m)]));
+        PriceList {
+            version: id,
+            effective_from: DateTime::parse_from_rfc3339(from).unwrap().with_timezone(&Utc),
+            note: None,
+            prices,
+        }
+    }
+
+    #[test]
+    fn picks_latest_started_version() {
+        let c = Catalog::new(vec![
+            version(2, "2026-06-01T00:00:00Z", 170),
+            version(1, "2020-01-01T00:00:00Z", 150),
+        ]);
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        assert_eq!(c.at(at("2026-05-31T23:59:59Z")).unwrap().version, 1);
+        assert_eq!(c.at(at("2026-06-01T00:00:00Z")).unwrap().get("base", "1m"), Some(170));
+        assert!(c.at(at("2019-01-01T00:00:00Z")).is_none());
+        assert_eq!(c.current().unwrap().get("base", "6m"), None);
+    }
+}
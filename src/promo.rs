@@ -0,0 +1,752 @@
+//! Promo codes (migrations/021_promo_v2.sql): who a code applies to and
+//! what it does to a checkout.
+//!
+//! [`evaluate`] is the single rule set behind `/promos/validate`,
+//! `/web/promo/validate` and the payment handlers, so a code the site shows
+//! as valid is priced the same way when the payment is created.
+//!
+//! Web checkouts don't just check a code, they [`reserve`] one use of it
+//! (migrations/022_promo_reservations.sql). The hold counts against
+//! `max_uses` while the payment is open, is [`commit`]ted by the payment
+//! webhook and released on cancel or expiry.
+//!
+//! Campaigns (migrations/023_promo_campaigns.sql) are batches of
+//! single-use codes sharing one policy, see [`create_campaign`].
+
+use crate::models::{CreatePromoRequest, PromoCode};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use std::time::Duration;
+
+pub const KIND_PERCENT: &str = "percent";
+pub const KIND_FIXED: &str = "fixed";
+pub const KIND_BONUS_DAYS: &str = "bonus_days";
+
+/// A discounted payment is never below this; YooKassa and CryptoPay refuse
+/// zero-amount payments.
+pub const MIN_CHARGE_RUB: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    NotFound,
+    Inactive,
+    Expired,
+    Exhausted,
+    WrongTariff,
+    WrongDuration,
+    AlreadyUsed,
+    NotFirstPurchase,
+    /// The user already holds a use of the code for an unpaid checkout.
+    Held,
+}
+
+impl Rejection {
+    /// Shown to the user as is.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Rejection::NotFound => "Промокод не найден",
+            Rejection::Inactive => "Промокод деактивирован",
+            Rejection::Expired => "Срок действия промокода истёк",
+            Rejection::Exhausted => "Промокод исчерпан",
+            Rejection::WrongTariff => "Промокод не применим к этому тарифу",
+            Rejection::WrongDuration => "Промокод не применим к этому сроку подписки",
+            Rejection::AlreadyUsed => "Вы уже использовали этот промокод",
+            Rejection::NotFirstPurchase => "Промокод действует только на первую покупку",
+            Rejection::Held => "Промокод уже применён к неоплаченному заказу, оплатите его или попробуйте позже",
+        }
+    }
+}
+
+/// What the user is buying, and the facts about them a code can depend on.
+pub struct Checkout<'a> {
+    /// Empty — don't check the tariff (bot's pre-check).
+    pub tariff: &'a str,
+    /// `None` — duration not chosen yet; duration limits aren't checked.
+    pub duration: Option<&'a str>,
+    /// List price; `None` when only validating.
+    pub price_rub: Option<i64>,
+    pub now: DateTime<Utc>,
+    pub already_used: bool,
+    pub has_paid_before: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Effect {
+    /// Price after the code, when a list price was given.
+    pub price_rub: Option<i64>,
+    pub discount_rub: i64,
+    pub bonus_days: i32,
+}
+
+/// Check `promo` against `c` and work out its effect.
+pub fn evaluate(promo: &PromoCode, c: &Checkout) -> Result<Effect, Rejection> {
+    if !promo.is_active {
+        return Err(Rejection::Inactive);
+    }
+    if promo.expires_at.is_some_and(|at| at <= c.now) {
+        return Err(Rejection::Expired);
+    }
+    if promo.current_uses >= promo.max_uses {
+        return Err(Rejection::Exhausted);
+    }
+    if !c.tariff.is_empty() && !promo.applicable_tariffs.iter().any(|t| t == c.tariff) {
+        return Err(Rejection::WrongTariff);
+    }
+    if let Some(d) = c.duration {
+        if !promo.applicable_durations.is_empty() && !promo.applicable_durations.iter().any(|x| x == d) {
+            return Err(Rejection::WrongDuration);
+        }
+    }
+    if c.already_used {
+        return Err(Rejection::AlreadyUsed);
+    }
+    if promo.first_purchase_only && c.has_paid_before {
+        return Err(Rejection::NotFirstPurchase);
+    }
+
+    let (discount_rub, bonus_days) = match (promo.kind.as_str(), c.price_rub) {
+        (KIND_PERCENT, Some(price)) => {
+            let after = (price as f64 * (1.0 - f64::from(promo.discount_percent) / 100.0)).round() as i64;
+            (price - after, 0)
+        }
+        (KIND_FIXED, Some(_)) => (i64::from(promo.amount_rub.unwrap_or(0)), 0),
+        (KIND_BONUS_DAYS, _) => (0, promo.bonus_days.unwrap_or(0)),
+        _ => (0, 0),
+    };
+    let price_rub = c.price_rub.map(|p| (p - discount_rub).max(MIN_CHARGE_RUB).min(p));
+    Ok(Effect {
+        discount_rub: c.price_rub.zip(price_rub).map(|(p, after)| p - after).unwrap_or(0),
+        price_rub,
+        bonus_days,
+    })
+}
+
+pub async fn load(pool: &PgPool, code: &str) -> Result<Option<PromoCode>, sqlx::Error> {
+    sqlx::query_as::<_, PromoCode>("SELECT * FROM promo_codes WHERE code = $1")
+        .bind(code)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Any real-money payment in the ledger, under either sign of the id
+/// (email users). The ledger starts with migrations/005, so earlier payers
+/// only count once they've paid again.
+async fn has_paid_before(conn: &mut PgConnection, telegram_id: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM payments WHERE telegram_id IN ($1, -$1) \
+         AND source IN ('yookassa', 'crypto', 'stars') AND amount_rub > 0)",
+    )
+    .bind(telegram_id)
+    .fetch_one(conn)
+    .await
+}
+
+/// Evaluate `promo` for `telegram_id`, counting other users' live holds as
+/// uses. The user's own holds don't count: a new checkout supersedes them.
+async fn evaluate_for(
+    conn: &mut PgConnection,
+    promo: &PromoCode,
+    telegram_id: i64,
+    tariff: &str,
+    duration: Option<&str>,
+    price_rub: Option<i64>,
+) -> Result<Result<Effect, Rejection>, sqlx::Error> {
+    let held: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM promo_reservations \
+         WHERE promo_code_id = $1 AND status = 'held' AND expires_at > NOW() AND telegram_id <> $2",
+    )
+    .bind(promo.id)
+    .bind(telegram_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    let already_used: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM promo_usages WHERE promo_code_id = $1 AND telegram_id = $2)")
+            .bind(promo.id)
+            .bind(telegram_id)
+            .fetch_one(&mut *conn)
+            .await?;
+    let has_paid_before = promo.first_purchase_only && has_paid_before(&mut *conn, telegram_id).await?;
+
+    let counted = PromoCode {
+        current_uses: promo.current_uses.saturating_add(held as i32),
+        ..promo.clone()
+    };
+    Ok(evaluate(
+        &counted,
+        &Checkout { tariff, duration, price_rub, now: Utc::now(), already_used, has_paid_before },
+    ))
+}
+
+/// Load `code` and evaluate it for `telegram_id`. The outer error is the
+/// database; the inner one why the code doesn't apply.
+pub async fn check(
+    pool: &PgPool,
+    code: &str,
+    telegram_id: i64,
+    tariff: &str,
+    duration: Option<&str>,
+    price_rub: Option<i64>,
+) -> Result<Result<(PromoCode, Effect), Rejection>, sqlx::Error> {
+    let Some(promo) = load(pool, code).await? else {
+        return Ok(Err(Rejection::NotFound));
+    };
+    let mut conn = pool.acquire().await?;
+    let effect = evaluate_for(&mut conn, &promo, telegram_id, tariff, duration, price_rub).await?;
+    Ok(effect.map(|effect| (promo, effect)))
+}
+
+pub const PROVIDER_YOOKASSA: &str = "yookassa";
+pub const PROVIDER_CRYPTO: &str = "crypto";
+pub const PROVIDER_STARS: &str = "stars";
+
+/// How long a YooKassa checkout holds its code (PROMO_RESERVATION_TTL_SECS).
+pub fn reservation_ttl() -> chrono::Duration {
+    let secs = std::env::var("PROMO_RESERVATION_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(3600);
+    chrono::Duration::seconds(secs)
+}
+
+#[derive(Debug)]
+pub struct Reservation {
+    pub id: i64,
+    pub effect: Effect,
+}
+
+/// Check `code` and hold one use of it for `telegram_id` until `ttl`
+/// passes. The code row is locked for the check, so two checkouts can't
+/// both take the last use. One live hold per user and code: while it is
+/// open a second checkout is rejected ([`Rejection::Held`]), so two paid
+/// payments can't share one use.
+#[allow(clippy::too_many_arguments)]
+pub async fn reserve(
+    pool: &PgPool,
+    code: &str,
+    telegram_id: i64,
+    tariff: &str,
+    duration: &str,
+    price_rub: i64,
+    provider: &str,
+    ttl: chrono::Duration,
+) -> Result<Result<Reservation, Rejection>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let promo = sqlx::query_as::<_, PromoCode>("SELECT * FROM promo_codes WHERE code = $1 FOR UPDATE")
+        .bind(code)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(promo) = promo else {
+        return Ok(Err(Rejection::NotFound));
+    };
+    let effect = match evaluate_for(&mut tx, &promo, telegram_id, tariff, Some(duration), Some(price_rub)).await? {
+        Ok(e) => e,
+        Err(rejection) => return Ok(Err(rejection)),
+    };
+
+    let held: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM promo_reservations \
+         WHERE promo_code_id = $1 AND telegram_id = $2 AND status = 'held' AND expires_at > NOW())",
+    )
+    .bind(promo.id)
+    .bind(telegram_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if held {
+        return Ok(Err(Rejection::Held));
+    }
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO promo_reservations (promo_code_id, telegram_id, provider, expires_at) \
+         VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(promo.id)
+    .bind(telegram_id)
+    .bind(provider)
+    .bind(Utc::now() + ttl)
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Ok(Reservation { id, effect }))
+}
+
+/// Tie a hold to its payment once the provider has created it. `expires_at`
+/// overrides the reservation TTL (a CryptoPay invoice expires with its quote).
+pub async fn attach(
+    pool: &PgPool,
+    reservation_id: i64,
+    payment_ref: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE promo_reservations SET payment_ref = $2, expires_at = COALESCE($3, expires_at) WHERE id = $1",
+    )
+    .bind(reservation_id)
+    .bind(payment_ref)
+    .bind(expires_at)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Drop a hold whose payment was never created.
+pub async fn release(pool: &PgPool, reservation_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE promo_reservations SET status = 'released', resolved_at = NOW() WHERE id = $1 AND status = 'held'",
+    )
+    .bind(reservation_id)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// Drop the hold of a canceled payment. No-op when there is none.
+pub async fn release_payment(pool: &PgPool, provider: &str, payment_ref: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE promo_reservations SET status = 'released', resolved_at = NOW() \
+         WHERE provider = $1 AND payment_ref = $2 AND status = 'held'",
+    )
+    .bind(provider)
+    .bind(payment_ref)
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+/// What [`commit`] did with a paid payment's hold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Commit {
+    /// The hold became a use now.
+    Recorded,
+    /// Nothing to do: an earlier delivery committed it, or the payment had
+    /// no hold.
+    Unchanged,
+    /// The hold can't become a use: it was released and the code has no
+    /// use left, or the user has used the code since. The payment doesn't
+    /// get the promo, see [`refused_days`].
+    Refused,
+}
+
+/// Turn the hold of a paid payment into a use: a `promo_usages` row and
+/// `current_uses + 1`. The hold is found by payment, or by `reservation_id`
+/// when the payment carries it (YooKassa metadata) — that also covers a
+/// payment whose id never got attached. Idempotent, a refusal included: the
+/// hold is left uncommitted, so a webhook retry is refused again. A hold
+/// that was already released (expired) is only committed while the code
+/// still has a use free, counting other users' live holds: someone else
+/// may have taken its place in the meantime.
+pub async fn commit(
+    pool: &PgPool,
+    provider: &str,
+    payment_ref: &str,
+    reservation_id: Option<i64>,
+) -> Result<Commit, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let hold: Option<(i64, i32, i64, String)> = sqlx::query_as(
+        "SELECT id, promo_code_id, telegram_id, status FROM promo_reservations \
+         WHERE provider = $1 AND (payment_ref = $2 OR id = $3) AND status <> 'committed' \
+         LIMIT 1 FOR UPDATE",
+    )
+    .bind(provider)
+    .bind(payment_ref)
+    .bind(reservation_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((hold_id, promo_id, telegram_id, status)) = hold else {
+        return Ok(Commit::Unchanged);
+    };
+    let (current_uses, max_uses): (i32, i32) =
+        sqlx::query_as("SELECT current_uses, max_uses FROM promo_codes WHERE id = $1 FOR UPDATE")
+            .bind(promo_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    if status == "released" {
+        let held: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM promo_reservations \
+             WHERE promo_code_id = $1 AND status = 'held' AND expires_at > NOW() AND telegram_id <> $2",
+        )
+        .bind(promo_id)
+        .bind(telegram_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if i64::from(current_uses) + held >= i64::from(max_uses) {
+            log::warn!("[promo] hold {} was released and code {} is exhausted, refused", hold_id, promo_id);
+            return Ok(Commit::Refused);
+        }
+    }
+    let used: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM promo_usages WHERE promo_code_id = $1 AND telegram_id = $2)",
+    )
+    .bind(promo_id)
+    .bind(telegram_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if used {
+        // Stop it counting against max_uses, it will never be committed.
+        sqlx::query(
+            "UPDATE promo_reservations SET status = 'released', resolved_at = NOW() WHERE id = $1 AND status = 'held'",
+        )
+        .bind(hold_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        log::warn!("[promo] {} already used code {}, hold {} refused", telegram_id, promo_id, hold_id);
+        return Ok(Commit::Refused);
+    }
+    sqlx::query(
+        "UPDATE promo_reservations SET status = 'committed', resolved_at = NOW(), \
+         payment_ref = COALESCE(payment_ref, $2) WHERE id = $1",
+    )
+    .bind(hold_id)
+    .bind(payment_ref)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("INSERT INTO promo_usages (promo_code_id, telegram_id) VALUES ($1, $2)")
+        .bind(promo_id)
+        .bind(telegram_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE promo_codes SET current_uses = current_uses + 1 WHERE id = $1")
+        .bind(promo_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Commit::Recorded)
+}
+
+/// Days to credit a payment whose hold was [`Commit::Refused`]: the plain
+/// duration, without a `bonus_days` code's extra days. The discount is
+/// already paid; `metadata` gets `promo_refused` so support can see it.
+pub fn refused_days(duration: &str, days: i32, metadata: &mut serde_json::Value) -> i32 {
+    if let Some(m) = metadata.as_object_mut() {
+        m.insert("promo_refused".into(), true.into());
+    }
+    crate::prices::duration_days(duration).map_or(days, |d| d.min(days))
+}
+
+/// Mark expired holds released. They already stopped counting at
+/// `expires_at`; this keeps the table's status honest.
+pub async fn sweep_expired(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query(
+        "UPDATE promo_reservations SET status = 'released', resolved_at = NOW() \
+         WHERE status = 'held' AND expires_at <= NOW()",
+    )
+    .execute(pool)
+    .await
+    .map(|r| r.rows_affected())
+}
+
+/// Background sweeper, started from main.
+pub fn spawn(pool: PgPool) {
+    tokio::spawn(async move {
+        loop {
+            match sweep_expired(&pool).await {
+                Ok(0) => {}
+                Ok(n) => log::info!("[promo] released {} expired reservations", n),
+                Err(e) => log::error!("[promo] sweep failed: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(300)).await;
+        }
+    });
+}
+
+/// Field checks for a new code. Returns the message for a 400.
+pub fn validate_new(p: &CreatePromoRequest) -> Result<(), String> {
+    match p.kind.as_deref().unwrap_or(KIND_PERCENT) {
+        KIND_PERCENT if (1..=100).contains(&p.discount_percent) => {}
+        KIND_PERCENT => return Err("discount_percent must be 1-100".into()),
+        KIND_FIXED if p.amount_rub.is_some_and(|a| a > 0) => {}
+        KIND_FIXED => return Err("amount_rub must be > 0 for a fixed code".into()),
+        KIND_BONUS_DAYS if p.bonus_days.is_some_and(|d| d > 0) => {}
+        KIND_BONUS_DAYS => return Err("bonus_days must be > 0 for a bonus_days code".into()),
+        other => return Err(format!("unknown kind {:?}", other)),
+    }
+    if let Some(d) = p.applicable_durations.iter().find(|d| crate::prices::duration_days(d).is_none()) {
+        return Err(format!("unknown duration {:?}", d));
+    }
+    if p.max_uses < 1 {
+        return Err("max_uses must be >= 1".into());
+    }
+    Ok(())
+}
+
+// --- Campaigns ---
+
+/// Generated code alphabet: no 0/O or 1/I/L, so codes survive being read
+/// out or retyped from a screenshot.
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+const CODE_RANDOM_LEN: usize = 8;
+pub const MAX_BATCH: i32 = 10_000;
+/// Codes per INSERT.
+const INSERT_CHUNK: usize = 1000;
+
+/// `PREFIX-XXXXXXXX`.
+pub fn generate_code(prefix: &str, rng: &mut impl Rng) -> String {
+    let tail: String = (0..CODE_RANDOM_LEN)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", prefix, tail)
+}
+
+/// 1-16 of A-Z, 0-9 and `_`.
+pub fn valid_prefix(prefix: &str) -> bool {
+    (1..=16).contains(&prefix.len())
+        && prefix.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// A campaign with its redemption stats.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Campaign {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub kind: String,
+    pub discount_percent: i32,
+    pub amount_rub: Option<i32>,
+    pub bonus_days: Option<i32>,
+    pub applicable_tariffs: Vec<String>,
+    pub applicable_durations: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub first_purchase_only: bool,
+    pub codes_count: i32,
+    pub created_at: DateTime<Utc>,
+    /// Codes used at least once (`promo_usages`, including bot redemptions).
+    pub redeemed: i64,
+    /// Ledger payments made with a campaign code.
+    pub paid_orders: i64,
+    pub revenue_rub: f64,
+    pub refunded_rub: f64,
+}
+
+/// Create campaign `name` with `count` single-use codes under `policy.code`
+/// as the prefix. `policy` must have passed [`validate_new`].
+pub async fn create_campaign(
+    pool: &PgPool,
+    name: &str,
+    policy: &CreatePromoRequest,
+    count: i32,
+) -> Result<i32, sqlx::Error> {
+    let kind = policy.kind.as_deref().unwrap_or(KIND_PERCENT);
+    let mut tx = pool.begin().await?;
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO promo_campaigns (name, prefix, kind, discount_percent, amount_rub, bonus_days, \
+         applicable_tariffs, applicable_durations, expires_at, first_purchase_only, codes_count) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id",
+    )
+    .bind(name)
+    .bind(&policy.code)
+    .bind(kind)
+    .bind(if kind == KIND_PERCENT { policy.discount_percent } else { 0 })
+    .bind(policy.amount_rub.filter(|_| kind == KIND_FIXED))
+    .bind(policy.bonus_days.filter(|_| kind == KIND_BONUS_DAYS))
+    .bind(&policy.applicable_tariffs)
+    .bind(&policy.applicable_durations)
+    .bind(policy.expires_at)
+    .bind(policy.first_purchase_only)
+    .bind(count)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // Collisions (with each other or existing codes) are skipped by the
+    // unique index and made up in the next round.
+    let mut created = 0usize;
+    while created < count as usize {
+        let codes: Vec<String> = {
+            let mut rng = rand::thread_rng();
+            (0..(count as usize - created).min(INSERT_CHUNK))
+                .map(|_| generate_code(&policy.code, &mut rng))
+                .collect()
+        };
+        let mut qb = sqlx::QueryBuilder::<sqlx::Postgres>::new(
+            "INSERT INTO promo_codes (code, kind, discount_percent, amount_rub, bonus_days, applicable_tariffs, \
+             applicable_durations, max_uses, expires_at, first_purchase_only, campaign_id) ",
+        );
+        qb.push_values(&codes, |mut b, code| {
+            b.push_bind(code)
+                .push_bind(kind)
+                .push_bind(if kind == KIND_PERCENT { policy.discount_percent } else { 0 })
+                .push_bind(policy.amount_rub.filter(|_| kind == KIND_FIXED))
+                .push_bind(policy.bonus_days.filter(|_| kind == KIND_BONUS_DAYS))
+                .push_bind(&policy.applicable_tariffs)
+                .push_bind(&policy.applicable_durations)
+                .push_bind(1i32)
+                .push_bind(policy.expires_at)
+                .push_bind(policy.first_purchase_only)
+                .push_bind(id);
+        });
+        qb.push(" ON CONFLICT (code) DO NOTHING");
+        created += qb.build().execute(&mut *tx).await?.rows_affected() as usize;
+    }
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Campaigns with stats, newest first; one campaign when `id` is set.
+pub async fn campaigns(pool: &PgPool, id: Option<i32>) -> Result<Vec<Campaign>, sqlx::Error> {
+    sqlx::query_as::<_, Campaign>(
+        "WITH paid AS ( \
+             SELECT pc.campaign_id, p.id, p.amount_rub \
+             FROM promo_reservations r \
+             JOIN promo_codes pc ON pc.id = r.promo_code_id \
+             JOIN payments p ON p.source = r.provider AND p.external_id = r.payment_ref AND p.refund_of IS NULL \
+             WHERE r.status = 'committed' AND pc.campaign_id IS NOT NULL \
+         ) \
+         SELECT c.*, \
+             (SELECT COUNT(DISTINCT u.promo_code_id) FROM promo_usages u \
+              JOIN promo_codes pc ON pc.id = u.promo_code_id WHERE pc.campaign_id = c.id) AS redeemed, \
+             (SELECT COUNT(*) FROM paid WHERE paid.campaign_id = c.id) AS paid_orders, \
+             COALESCE((SELECT SUM(paid.amount_rub) FROM paid WHERE paid.campaign_id = c.id), 0)::float8 AS revenue_rub, \
+             COALESCE((SELECT -SUM(rf.amount_rub) FROM payments rf JOIN paid ON rf.refund_of = paid.id \
+                       WHERE paid.campaign_id = c.id), 0)::float8 AS refunded_rub \
+         FROM promo_campaigns c \
+         WHERE $1::int IS NULL OR c.id = $1 \
+         ORDER BY c.created_at DESC",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(sqlx::FromRow)]
+struct ExportRow {
+    code: String,
+    is_active: bool,
+    current_uses: i32,
+    redeemed_by: Option<i64>,
+    redeemed_at: Option<DateTime<Utc>>,
+}
+
+/// CSV of a campaign's codes, one line per code (and per redemption).
+/// Generated codes need no quoting.
+pub async fn export_csv(pool: &PgPool, campaign_id: i32) -> Result<String, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ExportRow>(
+        "SELECT pc.code, pc.is_active, pc.current_uses, u.telegram_id AS redeemed_by, u.used_at AS redeemed_at \
+         FROM promo_codes pc LEFT JOIN promo_usages u ON u.promo_code_id = pc.id \
+         WHERE pc.campaign_id = $1 ORDER BY pc.id, u.used_at",
+    )
+    .bind(campaign_id)
+    .fetch_all(pool)
+    .await?;
+    let mut csv = String::from("code,is_active,current_uses,redeemed_by,redeemed_at\n");
+    for r in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            r.code,
+            r.is_active,
+            r.current_uses,
+            r.redeemed_by.map(|t| t.to_string()).unwrap_or_default(),
+            r.redeemed_at.map(|t| t.to_rfc3339()).unwrap_or_default(),
+        ));
+    }
+    Ok(csv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn promo(kind: &str) -> PromoCode {
+        PromoCode {
+            id: 1,
+            code: "X".into(),
+            kind: kind.into(),
+            discount_percent: 20,
+            amount_rub: Some(100),
+            bonus_days: Some(7),
+            applicable_tariffs: vec!["base".into(), "family".into()],
+            applicable_durations: vec![],
+            max_uses: 10,
+            current_uses: 0,
+            is_active: true,
+            expires_at: None,
+            first_purchase_only: false,
+            campaign_id: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn checkout(price: i64) -> Checkout<'static> {
+        Checkout {
+            tariff: "base",
+            duration: Some("3m"),
+            price_rub: Some(price),
+            now: Utc::now(),
+            already_used: false,
+            has_paid_before: true,
+        }
+    }
+
+    #[test]
+    fn each_kind_prices_the_checkout() {
+        let e = evaluate(&promo(KIND_PERCENT), &checkout(430)).unwrap();
+        assert_eq!((e.price_rub, e.discount_rub, e.bonus_days), (Some(344), 86, 0));
+
+        let e = evaluate(&promo(KIND_FIXED), &checkout(430)).unwrap();
+        assert_eq!((e.price_rub, e.discount_rub), (Some(330), 100));
+        // Never below the minimum charge.
+        let e = evaluate(&promo(KIND_FIXED), &checkout(80)).unwrap();
+        assert_eq!((e.price_rub, e.discount_rub), (Some(MIN_CHARGE_RUB), 79));
+
+        let e = evaluate(&promo(KIND_BONUS_DAYS), &checkout(430)).unwrap();
+        assert_eq!((e.price_rub, e.discount_rub, e.bonus_days), (Some(430), 0, 7));
+
+        // Validation without a price.
+        let c = Checkout { price_rub: None, duration: None, ..checkout(0) };
+        assert_eq!(evaluate(&promo(KIND_PERCENT), &c).unwrap().price_rub, None);
+    }
+
+    #[test]
+    fn limits_reject_with_reason() {
+        let mut p = promo(KIND_PERCENT);
+        p.expires_at = Some(Utc::now() - chrono::Duration::hours(1));
+        assert_eq!(evaluate(&p, &checkout(150)), Err(Rejection::Expired));
+
+        let mut p = promo(KIND_PERCENT);
+        p.applicable_durations = vec!["1y".into()];
+        assert_eq!(evaluate(&p, &checkout(150)), Err(Rejection::WrongDuration));
+        // Duration not chosen yet: not checked.
+        assert!(evaluate(&p, &Checkout { duration: None, ..checkout(150) }).is_ok());
+
+        let mut p = promo(KIND_PERCENT);
+        p.first_purchase_only = true;
+        assert_eq!(evaluate(&p, &checkout(150)), Err(Rejection::NotFirstPurchase));
+        assert!(evaluate(&p, &Checkout { has_paid_before: false, ..checkout(150) }).is_ok());
+
+        let mut p = promo(KIND_PERCENT);
+        p.current_uses = 10;
+        assert_eq!(evaluate(&p, &checkout(150)), Err(Rejection::Exhausted));
+
+        assert_eq!(
+            evaluate(&promo(KIND_PERCENT), &Checkout { tariff: "bsbase", ..checkout(150) }),
+            Err(Rejection::WrongTariff)
+        );
+        assert_eq!(
+            evaluate(&promo(KIND_PERCENT), &Checkout { already_used: true, ..checkout(150) }),
+            Err(Rejection::AlreadyUsed)
+        );
+    }
+
+    #[test]
+    fn generated_codes_use_prefix_and_unambiguous_alphabet() {
+        let mut rng = rand::thread_rng();
+        let code = generate_code("BLOGGER_1", &mut rng);
+        let tail = code.strip_prefix("BLOGGER_1-").unwrap();
+        assert_eq!(tail.len(), CODE_RANDOM_LEN);
+        assert!(tail.bytes().all(|b| CODE_ALPHABET.contains(&b)));
+
+        assert!(valid_prefix("SPRING24"));
+        assert!(!valid_prefix(""));
+        assert!(!valid_prefix("spring"));
+        assert!(!valid_prefix("A-B"));
+    }
+
+    #[test]
+    fn refused_promo_drops_bonus_days_and_flags_payment() {
+        let mut meta = serde_json::json!({"promo_code": "SPRING"});
+        assert_eq!(refused_days("1m", 37, &mut meta), 30);
+        assert_eq!(meta["promo_refused"], true);
+        assert_eq!(refused_days("nope", 37, &mut meta), 37);
+    }
+}
@@ -0,0 +1,459 @@
+//! RUB -> crypto rates for CryptoPay invoices, and locked quotes
+//! (migrations/020_crypto_quotes.sql).
+//!
+//! Rates are cached in memory and refreshed in the background every
+//! RATE_REFRESH_SECS from the providers in RATE_PROVIDERS, in order: each
+//! provider fills the assets the ones before it couldn't price. Providers
+//! without credentials are skipped. A cached rate older than
+//! RATE_MAX_AGE_SECS is not used — no invoice beats one priced off a stale
+//! rate.
+//!
+//! An invoice is created from a [`Quote`]: the rate, the crypto amount and
+//! an expiry, stored before the invoice and matched to it by invoice_id.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+
+const DEFAULT_PROVIDERS: &str = "cryptopay,coinmarketcap,coingecko";
+const DEFAULT_ASSETS: &str = "USDT,TON,BTC,ETH,LTC,TRX";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn env_secs(name: &str, default: i64) -> i64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|v: &i64| *v > 0)
+        .unwrap_or(default)
+}
+
+/// Assets invoices can be issued in (RATE_ASSETS).
+pub fn assets() -> Vec<String> {
+    std::env::var("RATE_ASSETS")
+        .unwrap_or_else(|_| DEFAULT_ASSETS.to_string())
+        .split(',')
+        .map(|s| s.trim().to_uppercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rate {
+    /// RUB price of one unit.
+    pub rub: f64,
+    pub source: &'static str,
+    pub at: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait RateProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// RUB price of one unit of each asset it knows; unknown ones are left out.
+    async fn fetch(&self, assets: &[String]) -> Result<HashMap<String, f64>, String>;
+}
+
+async fn get_json(req: reqwest::RequestBuilder) -> Result<Value, String> {
+    let resp = req.timeout(REQUEST_TIMEOUT).send().await.map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("HTTP {}", resp.status()));
+    }
+    resp.json().await.map_err(|e| e.to_string())
+}
+
+lazy_static::lazy_static! {
+    static ref HTTP: reqwest::Client = reqwest::Client::new();
+}
+
+/// CryptoPay's own `getExchangeRates` — the rates the invoices settle at.
+pub struct CryptoPay {
+    token: String,
+}
+
+fn parse_cryptopay(v: &Value, assets: &[String]) -> HashMap<String, f64> {
+    let mut out = HashMap::new();
+    for r in v["result"].as_array().into_iter().flatten() {
+        let (Some(source), Some(rate)) = (r["source"].as_str(), r["rate"].as_str()) else { continue };
+        if r["target"] != "RUB" || r["is_valid"] != true || !assets.iter().any(|a| a == source) {
+            continue;
+        }
+        if let Some(rate) = rate.parse::<f64>().ok().filter(|r| *r > 0.0) {
+            out.insert(source.to_string(), rate);
+        }
+    }
+    out
+}
+
+#[async_trait]
+impl RateProvider for CryptoPay {
+    fn name(&self) -> &'static str {
+        "cryptopay"
+    }
+
+    async fn fetch(&self, assets: &[String]) -> Result<HashMap<String, f64>, String> {
+        let v = get_json(
+            HTTP.get("https://pay.crypt.bot/api/getExchangeRates")
+                .header("Crypto-Pay-API-Token", &self.token),
+        )
+        .await?;
+        if v["ok"] != true {
+            return Err(format!("error {}", v["error"]));
+        }
+        Ok(parse_cryptopay(&v, assets))
+    }
+}
+
+/// CoinMarketCap quotes, one call for every asset (CMC_API_KEY).
+pub struct CoinMarketCap {
+    key: String,
+}
+
+fn parse_cmc(v: &Value, assets: &[String]) -> HashMap<String, f64> {
+    assets
+        .iter()
+        .filter_map(|a| {
+            v["data"][a.as_str()]["quote"]["RUB"]["price"]
+                .as_f64()
+                .filter(|r| *r > 0.0)
+                .map(|r| (a.clone(), r))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl RateProvider for CoinMarketCap {
+    fn name(&self) -> &'static str {
+        "coinmarketcap"
+    }
+
+    async fn fetch(&self, assets: &[String]) -> Result<HashMap<String, f64>, String> {
+        let v = get_json(
+            HTTP.get("https://pro-api.coinmarketcap.com/v1/cryptocurrency/quotes/latest")
+                .header("X-CMC_PRO_API_KEY", &self.key)
+                .query(&[("symbol", assets.join(",").as_str()), ("convert", "RUB")]),
+        )
+        .await?;
+        Ok(parse_cmc(&v, assets))
+    }
+}
+
+/// CoinGecko public API — no key, rate-limited; the last resort.
+pub struct CoinGecko;
+
+fn coingecko_id(asset: &str) -> Option<&'static str> {
+    match asset {
+        "USDT" => Some("tether"),
+        "USDC" => Some("usd-coin"),
+        "TON" => Some("the-open-network"),
+        "BTC" => Some("bitcoin"),
+        "ETH" => Some("ethereum"),
+        "LTC" => Some("litecoin"),
+        "TRX" => Some("tron"),
+        "BNB" => Some("binancecoin"),
+        _ => None,
+    }
+}
+
+fn parse_coingecko(v: &Value, assets: &[String]) -> HashMap<String, f64> {
+    assets
+        .iter()
+        .filter_map(|a| {
+            let id = coingecko_id(a)?;
+            v[id]["rub"].as_f64().filter(|r| *r > 0.0).map(|r| (a.clone(), r))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl RateProvider for CoinGecko {
+    fn name(&self) -> &'static str {
+        "coingecko"
+    }
+
+    async fn fetch(&self, assets: &[String]) -> Result<HashMap<String, f64>, String> {
+        let ids: Vec<&str> = assets.iter().filter_map(|a| coingecko_id(a)).collect();
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let v = get_json(
+            HTTP.get("https://api.coingecko.com/api/v3/simple/price")
+                .query(&[("ids", ids.join(",").as_str()), ("vs_currencies", "rub")]),
+        )
+        .await?;
+        Ok(parse_coingecko(&v, assets))
+    }
+}
+
+/// Providers from RATE_PROVIDERS, in order, skipping unconfigured ones.
+fn providers() -> Vec<Box<dyn RateProvider>> {
+    let names = std::env::var("RATE_PROVIDERS").unwrap_or_else(|_| DEFAULT_PROVIDERS.to_string());
+    let mut out: Vec<Box<dyn RateProvider>> = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match name {
+            "cryptopay" => match std::env::var("CRYPTO_BOT_TOKEN") {
+                Ok(token) if !token.is_empty() => out.push(Box::new(CryptoPay { token })),
+                _ => info!("[rates] cryptopay skipped: CRYPTO_BOT_TOKEN not set"),
+            },
+            "coinmarketcap" => match std::env::var("CMC_API_KEY") {
+                Ok(key) if !key.is_empty() => out.push(Box::new(CoinMarketCap { key })),
+                _ => info!("[rates] coinmarketcap skipped: CMC_API_KEY not set"),
+            },
+            "coingecko" => out.push(Box::new(CoinGecko)),
+            other => warn!("[rates] unknown provider {:?} in RATE_PROVIDERS", other),
+        }
+    }
+    out
+}
+
+/// Ask each provider, in order, for the assets still unpriced.
+pub async fn fetch_all(providers: &[Box<dyn RateProvider>], assets: &[String]) -> HashMap<String, Rate> {
+    let mut out: HashMap<String, Rate> = HashMap::new();
+    for p in providers {
+        let missing: Vec<String> = assets.iter().filter(|a| !out.contains_key(*a)).cloned().collect();
+        if missing.is_empty() {
+            break;
+        }
+        match p.fetch(&missing).await {
+            Ok(rates) => {
+                let at = Utc::now();
+                for (asset, rub) in rates {
+                    if missing.contains(&asset) {
+                        out.insert(asset, Rate { rub, source: p.name(), at });
+                    }
+                }
+            }
+            Err(e) => warn!("[rates] {} failed: {}", p.name(), e),
+        }
+    }
+    out
+}
+
+lazy_static::lazy_static! {
+    static ref PROVIDERS: Vec<Box<dyn RateProvider>> = providers();
+    static ref CACHE: RwLock<HashMap<String, Rate>> = RwLock::new(HashMap::new());
+}
+
+/// Fetch every asset and update the cache. An asset no provider could
+/// price keeps its previous rate until that ages out.
+pub async fn refresh() -> usize {
+    let fresh = fetch_all(&PROVIDERS, &assets()).await;
+    let n = fresh.len();
+    if let Ok(mut cache) = CACHE.write() {
+        cache.extend(fresh);
+    }
+    n
+}
+
+fn cached(asset: &str) -> Option<Rate> {
+    let max_age = chrono::Duration::seconds(env_secs("RATE_MAX_AGE_SECS", 600));
+    CACHE
+        .read()
+        .ok()?
+        .get(asset)
+        .filter(|r| Utc::now() - r.at <= max_age)
+        .cloned()
+}
+
+/// Usable rate for `asset`, refreshing once if the cache has none.
+pub async fn rate(asset: &str) -> Option<Rate> {
+    if let Some(r) = cached(asset) {
+        return Some(r);
+    }
+    refresh().await;
+    cached(asset)
+}
+
+/// Every usable cached rate, for display.
+pub fn snapshot() -> HashMap<String, Rate> {
+    assets().into_iter().filter_map(|a| cached(&a).map(|r| (a, r))).collect()
+}
+
+/// Background refresher, started from main.
+pub fn spawn() {
+    let every = Duration::from_secs(env_secs("RATE_REFRESH_SECS", 60) as u64);
+    let names: Vec<&str> = PROVIDERS.iter().map(|p| p.name()).collect();
+    info!("[rates] providers {:?}, refresh every {:?}", names, every);
+    tokio::spawn(async move {
+        loop {
+            let n = refresh().await;
+            if n == 0 {
+                error!("[rates] no provider returned any rate");
+            }
+            tokio::time::sleep(every).await;
+        }
+    });
+}
+
+/// Crypto amount for `rub` at `rate_rub` per unit, rounded up to 8 places
+/// so rounding never undercharges.
+pub fn crypto_amount(rub: f64, rate_rub: f64) -> f64 {
+    (rub / rate_rub * 1e8).ceil() / 1e8
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Quote {
+    pub id: i64,
+    pub asset: String,
+    pub amount_crypto: f64,
+    pub amount_rub: i64,
+    pub rate_rub: f64,
+    pub source: &'static str,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Quote {
+    /// Seconds until expiry, for the invoice's `expires_in`.
+    pub fn expires_in(&self) -> i64 {
+        (self.expires_at - Utc::now()).num_seconds().max(1)
+    }
+}
+
+#[derive(Debug)]
+pub enum QuoteError {
+    UnsupportedAsset,
+    /// No provider has a fresh rate.
+    NoRate,
+    Db(sqlx::Error),
+}
+
+impl fmt::Display for QuoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuoteError::UnsupportedAsset => write!(f, "unsupported asset"),
+            QuoteError::NoRate => write!(f, "no fresh rate"),
+            QuoteError::Db(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+pub struct QuoteRequest<'a> {
+    pub telegram_id: i64,
+    pub tariff: &'a str,
+    pub duration: &'a str,
+    pub price_version: i32,
+    pub amount_rub: i64,
+    pub asset: &'a str,
+}
+
+/// Freeze the current rate for one invoice (CRYPTO_QUOTE_TTL_SECS).
+pub async fn lock_quote(pool: &PgPool, q: &QuoteRequest<'_>) -> Result<Quote, QuoteError> {
+    let asset = q.asset.to_uppercase();
+    if !assets().contains(&asset) {
+        return Err(QuoteError::UnsupportedAsset);
+    }
+    let rate = rate(&asset).await.ok_or(QuoteError::NoRate)?;
+    let amount_crypto = crypto_amount(q.amount_rub as f64, rate.rub);
+    let expires_at = Utc::now() + chrono::Duration::seconds(env_secs("CRYPTO_QUOTE_TTL_SECS", 900));
+
+    let id: i64 = sqlx::query_scalar(
+        "INSERT INTO crypto_quotes (telegram_id, tariff, duration, price_version, amount_rub, asset, \
+                                    rate_rub, amount_crypto, source, rate_at, expires_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING id",
+    )
+    .bind(q.telegram_id)
+    .bind(q.tariff)
+    .bind(q.duration)
+    .bind(Some(q.price_version).filter(|v| *v > 0))
+    .bind(q.amount_rub as f64)
+    .bind(&asset)
+    .bind(rate.rub)
+    .bind(amount_crypto)
+    .bind(rate.source)
+    .bind(rate.at)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await
+    .map_err(QuoteError::Db)?;
+
+    Ok(Quote {
+        id,
+        asset,
+        amount_crypto,
+        amount_rub: q.amount_rub,
+        rate_rub: rate.rub,
+        source: rate.source,
+        expires_at,
+    })
+}
+
+pub async fn attach_invoice(pool: &PgPool, quote_id: i64, invoice_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE crypto_quotes SET invoice_id = $2, status = 'invoiced' WHERE id = $1")
+        .bind(quote_id)
+        .bind(invoice_id)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+
+pub async fn mark_paid(pool: &PgPool, invoice_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE crypto_quotes SET status = 'paid' WHERE invoice_id = $1")
+        .bind(invoice_id)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assets(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_provider_responses() {
+        let want = assets(&["USDT", "TON"]);
+        let cp = json!({"ok": true, "result": [
+            {"is_valid": true, "source": "USDT", "target": "RUB", "rate": "92.50"},
+            {"is_valid": true, "source": "USDT", "target": "USD", "rate": "1.00"},
+            {"is_valid": false, "source": "TON", "target": "RUB", "rate": "300"},
+            {"is_valid": true, "source": "BTC", "target": "RUB", "rate": "9000000"}
+        ]});
+        assert_eq!(parse_cryptopay(&cp, &want), HashMap::from([("USDT".to_string(), 92.5)]));
+
+        let cmc = json!({"data": {"TON": {"quote": {"RUB": {"price": 301.5}}}}});
+        assert_eq!(parse_cmc(&cmc, &want), HashMap::from([("TON".to_string(), 301.5)]));
+
+        let cg = json!({"tether": {"rub": 92.1}, "the-open-network": {"rub": 0}});
+        assert_eq!(parse_coingecko(&cg, &want), HashMap::from([("USDT".to_string(), 92.1)]));
+    }
+
+    #[test]
+    fn amount_rounds_up() {
+        assert_eq!(crypto_amount(150.0, 92.5), 1.62162163);
+        assert_eq!(crypto_amount(185.0, 92.5), 2.0);
+    }
+
+    struct Fixed(&'static str, Result<HashMap<String, f64>, String>);
+
+    #[async_trait]
+    impl RateProvider for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+        async fn fetch(&self, assets: &[String]) -> Result<HashMap<String, f64>, String> {
+            self.1.clone().map(|m| m.into_iter().filter(|(a, _)| assets.contains(a)).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn later_providers_fill_what_earlier_ones_missed() {
+        let providers: Vec<Box<dyn RateProvider>> = vec![
+            Box::new(Fixed("down", Err("timeout".into()))),
+            Box::new(Fixed("first", Ok(HashMap::from([("USDT".to_string(), 92.0)])))),
+            Box::new(Fixed("second", Ok(HashMap::from([("USDT".to_string(), 99.0), ("TON".to_string(), 300.0)])))),
+        ];
+        let got = fetch_all(&providers, &assets(&["USDT", "TON", "BTC"])).await;
+        assert_eq!((got["USDT"].rub, got["USDT"].source), (92.0, "first"));
+        assert_eq!((got["TON"].rub, got["TON"].source), (300.0, "second"));
+        assert!(!got.contains_key("BTC"));
+    }
+}
@@ -0,0 +1,204 @@
+//! The user's own view of the payments ledger (migrations/005): labelled
+//! history rows for `/web/me/payments` and a printable HTML receipt for
+//! rows that moved money.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Entry {
+    pub id: i64,
+    pub source: String,
+    pub amount_rub: Option<f64>,
+    pub plan: String,
+    pub duration: Option<String>,
+    pub days_added: i32,
+    pub external_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Coarse group of a ledger source, for filtering and icons on the site.
+pub fn kind(source: &str) -> &'static str {
+    match source {
+        "yookassa" | "crypto" | "stars" | "wallet" => "paid",
+        "refund" => "refund",
+        "trial" => "trial",
+        s if s.starts_with("ref_") => "referral",
+        _ => "bonus",
+    }
+}
+
+/// Russian label of a ledger source.
+pub fn label(source: &str) -> &'static str {
+    match source {
+        "yookassa" => "Оплата картой",
+        "crypto" => "Оплата криптовалютой",
+        "stars" => "Оплата Telegram Stars",
+        "wallet" => "Оплата с баланса",
+        "refund" => "Возврат",
+        "trial" => "Пробный период",
+        "first_purchase_bonus" => "Бонус за первую покупку",
+        "ref_bonus_child" => "Бонус за регистрацию по приглашению",
+        "ref_bonus_parent" => "Бонус за приглашённого друга",
+        "ref_milestone_5" => "Награда за 5 приглашённых друзей",
+        "ref_milestone_10" => "Награда за 10 приглашённых друзей",
+        "admin_extend" | "admin_compensate" => "Начисление от поддержки",
+        "promo" => "Промокод",
+        _ => "Начисление",
+    }
+}
+
+fn plan_name(plan: &str) -> String {
+    crate::plans::catalog()
+        .get(plan)
+        .map(|p| p.display_name.clone())
+        .unwrap_or_else(|| plan.to_string())
+}
+
+/// JSON shape of a history row.
+pub fn to_json(e: &Entry) -> serde_json::Value {
+    serde_json::json!({
+        "id": e.id,
+        "kind": kind(&e.source),
+        "source": e.source,
+        "label": label(&e.source),
+        "plan": e.plan,
+        "plan_name": plan_name(&e.plan),
+        "duration": e.duration,
+        "duration_name": e.duration.as_deref().and_then(crate::prices::duration_name),
+        "days_added": e.days_added,
+        "amount_rub": e.amount_rub,
+        "external_id": e.external_id,
+        "created_at": e.created_at.to_rfc3339(),
+        "has_receipt": e.amount_rub.is_some(),
+    })
+}
+
+/// Local id the user's rows are credited under: the id itself, or its
+/// negation for an email user paid under the positive id (same rule as
+/// credit.rs). Only one of the two is ever the user's.
+async fn owner(pool: &PgPool, telegram_id: i64) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT telegram_id FROM users WHERE telegram_id = $1 OR ($1 > 0 AND telegram_id = -$1) \
+         ORDER BY telegram_id = $1 DESC LIMIT 1",
+    )
+    .bind(telegram_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// One page of the user's history, newest first, and the total row count.
+pub async fn page(pool: &PgPool, telegram_id: i64, limit: i64, offset: i64) -> Result<(Vec<Entry>, i64), sqlx::Error> {
+    let Some(owner) = owner(pool, telegram_id).await? else {
+        return Ok((Vec::new(), 0));
+    };
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM payments WHERE telegram_id = $1")
+        .bind(owner)
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query_as::<_, Entry>(
+        "SELECT id, source, amount_rub::float8 AS amount_rub, plan, duration, days_added, external_id, created_at \
+         FROM payments WHERE telegram_id = $1 ORDER BY created_at DESC, id DESC LIMIT $2 OFFSET $3",
+    )
+    .bind(owner)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok((rows, total))
+}
+
+/// The user's row `id`, if it is theirs.
+pub async fn entry(pool: &PgPool, telegram_id: i64, id: i64) -> Result<Option<Entry>, sqlx::Error> {
+    let Some(owner) = owner(pool, telegram_id).await? else {
+        return Ok(None);
+    };
+    sqlx::query_as::<_, Entry>(
+        "SELECT id, source, amount_rub::float8 AS amount_rub, plan, duration, days_added, external_id, created_at \
+         FROM payments WHERE id = $2 AND telegram_id = $1",
+    )
+    .bind(owner)
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Printable receipt for a row with an amount. `account` is how the user
+/// is shown (email or @username).
+pub fn render_html(e: &Entry, account: &str) -> String {
+    let amount = e.amount_rub.unwrap_or_default();
+    let duration = e
+        .duration
+        .as_deref()
+        .and_then(crate::prices::duration_name)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{} дн.", e.days_added));
+    let rows = [
+        ("Дата", e.created_at.format("%d.%m.%Y %H:%M UTC").to_string()),
+        ("Аккаунт", account.to_string()),
+        ("Операция", label(&e.source).to_string()),
+        ("Тариф", plan_name(&e.plan)),
+        ("Срок", duration),
+        ("Сумма", format!("{:.2} ₽", amount)),
+        ("ID платежа", e.external_id.clone().unwrap_or_else(|| "—".to_string())),
+    ];
+    let rows_html: String = rows
+        .iter()
+        .map(|(k, v)| format!("<tr><th>{}</th><td>{}</td></tr>", k, escape(v)))
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"ru\"><head><meta charset=\"utf-8\">\
+         <title>Квитанция №{id} — SvoiVPN</title>\
+         <style>body{{font-family:-apple-system,Segoe UI,Roboto,sans-serif;max-width:560px;margin:40px auto;color:#111}}\
+         h1{{font-size:20px}}table{{width:100%;border-collapse:collapse}}\
+         th,td{{text-align:left;padding:8px 0;border-bottom:1px solid #eee}}th{{color:#666;font-weight:normal;width:40%}}\
+         .note{{color:#888;font-size:12px;margin-top:24px}}@media print{{.no-print{{display:none}}}}</style>\
+         </head><body><h1>SvoiVPN — квитанция №{id}</h1><table>{rows}</table>\
+         <p class=\"note\">Фискальный чек направлен платёжной системой. Эта квитанция — подтверждение операции в личном кабинете.</p>\
+         <button class=\"no-print\" onclick=\"window.print()\">Сохранить в PDF</button></body></html>",
+        id = e.id,
+        rows = rows_html,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_map_to_kinds() {
+        assert_eq!(kind("crypto"), "paid");
+        assert_eq!(kind("ref_milestone_5"), "referral");
+        assert_eq!(kind("first_purchase_bonus"), "bonus");
+        assert_eq!(kind("trial"), "trial");
+        assert_eq!(label("something_new"), "Начисление");
+    }
+
+    #[test]
+    fn receipt_escapes_and_shows_payment() {
+        let e = Entry {
+            id: 12,
+            source: "yookassa".into(),
+            amount_rub: Some(430.0),
+            plan: "base".into(),
+            duration: Some("3m".into()),
+            days_added: 90,
+            external_id: Some("2d9c-ext".into()),
+            created_at: Utc::now(),
+        };
+        let html = render_html(&e, "<a@b.ru>");
+        assert!(html.contains("квитанция №12"));
+        assert!(html.contains("430.00 ₽"));
+        assert!(html.contains("3 месяца"));
+        assert!(html.contains("2d9c-ext"));
+        assert!(html.contains("&lt;a@b.ru&gt;"));
+    }
+}
@@ -0,0 +1,389 @@
+//! Drift reconciliation between the local `users` table and Remnawave.
+//!
+//! The two sides drift apart whenever a PATCH fails after the DB write
+//! (`extend_subscription`, `toggle_pro`, admin edits): `expireAt` no longer
+//! matches `subscription_end`, `hwidDeviceLimit` differs from `device_limit`,
+//! squads are missing or left over. [`run`] pages through every panel user
+//! and every local row, diffs them and stores a [`Report`] for
+//! `/admin/reconcile/report`.
+//!
+//! With `fix = true` each drifted user with a live subscription gets its
+//! local entitlement pushed through [`subscription::apply_entitlement`] —
+//! the same squad rules `extend_subscription` uses, with the row's own
+//! `device_limit` (admins can override the plan default).
+//!
+//! Env:
+//!   - `RECONCILE_INTERVAL_MINUTES` — background period, default 360; 0 disables.
+//!   - `RECONCILE_FIX` — `true` lets the background run fix drift (default: report only).
+
+use crate::plans::{Catalog, Plan};
+use crate::remnawave::{RemnawaveApi, RemnawaveUser};
+use crate::subscription::{self, Entitlement};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+
+const PANEL_PAGE: u64 = 500;
+const DB_PAGE: i64 = 1000;
+/// Expiry differences below this are rounding (the panel stores ms, we µs).
+const EXPIRE_TOLERANCE_SECS: i64 = 60;
+/// Report lists are capped so a mass drift doesn't produce a 50 MB response.
+const MAX_ITEMS: usize = 500;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct LocalUser {
+    pub id: i32,
+    pub telegram_id: i64,
+    pub uuid: Uuid,
+    pub plan: String,
+    pub subscription_end: DateTime<Utc>,
+    pub device_limit: i64,
+    pub is_pro: bool,
+}
+
+impl LocalUser {
+    pub const COLUMNS: &'static str = "id, telegram_id, uuid, plan, subscription_end, device_limit, is_pro";
+
+    /// Catalog plan with the row's own device limit (admins can override
+    /// the plan default per user).
+    pub fn effective_plan(&self, catalog: &Catalog) -> Plan {
+        let mut plan = catalog.resolve(&self.plan);
+        plan.device_limit = self.device_limit;
+        plan
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+    pub telegram_id: i64,
+    pub uuid: Uuid,
+    pub plan: String,
+    pub issues: Vec<String>,
+    pub fixed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub fix_mode: bool,
+    pub panel_users: usize,
+    pub checked: usize,
+    pub drifted: usize,
+    pub fixed: usize,
+    pub errors: usize,
+    /// Set when the run aborted (panel or DB unreachable).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aborted: Option<String>,
+    pub items: Vec<Drift>,
+    /// Panel users with no local row.
+    pub orphans: Vec<Uuid>,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static LAST_REPORT: Mutex<Option<Report>> = Mutex::new(None);
+
+/// Clears [`RUNNING`] however the run ends.
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+pub fn last_report() -> Option<Report> {
+    LAST_REPORT.lock().ok().and_then(|g| g.clone())
+}
+
+/// Squads the user should end up with: the plan's rules plus PRO exactly
+/// when `is_pro` (a failed disable leaves the PRO squad behind).
+fn expected_squads(catalog: &Catalog, local: &LocalUser, current: Vec<String>) -> Vec<String> {
+    let plan = catalog.resolve(&local.plan);
+    catalog.entitled_squads(catalog.with_pro(current, local.is_pro), &plan, local.is_pro)
+}
+
+/// Human-readable differences between a local row and its panel user.
+/// Empty means in sync.
+pub fn diff_user(catalog: &Catalog, local: &LocalUser, panel: Option<&RemnawaveUser>) -> Vec<String> {
+    let Some(panel) = panel else {
+        return vec!["missing in panel".to_string()];
+    };
+    let mut issues = Vec::new();
+
+    match panel.expire_at {
+        Some(exp) if (exp - local.subscription_end).num_seconds().abs() <= EXPIRE_TOLERANCE_SECS => {}
+        Some(exp) => issues.push(format!("expire_at: local={} panel={}", local.subscription_end, exp)),
+        None => issues.push(format!("expire_at: local={} panel=none", local.subscription_end)),
+    }
+
+    // 0 on the panel is the temporary "disable device limit" window.
+    match panel.hwid_device_limit {
+        Some(0) => {}
+        Some(n) if n == local.device_limit => {}
+        other => issues.push(format!(
+            "device_limit: local={} panel={}",
+            local.device_limit,
+            other.map(|n| n.to_string()).unwrap_or_else(|| "none".to_string())
+        )),
+    }
+
+    let current = panel.squad_uuids();
+    let expected = expected_squads(catalog, local, current.clone());
+    let missing: Vec<&String> = expected.iter().filter(|s| !current.contains(s)).collect();
+    let extra: Vec<&String> = current.iter().filter(|s| !expected.contains(s)).collect();
+    if !missing.is_empty() || !extra.is_empty() {
+        issues.push(format!("squads: missing={:?} extra={:?}", missing, extra));
+    }
+
+    issues
+}
+
+/// Push the local entitlement for one drifted user.
+async fn fix_user(
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    local: &LocalUser,
+    panel: &RemnawaveUser,
+) -> Result<(), String> {
+    let plan = local.effective_plan(catalog);
+    let entitlement = Entitlement {
+        telegram_id: panel.telegram_id.unwrap_or(local.telegram_id),
+        uuid: local.uuid,
+        plan: &plan,
+        is_pro: local.is_pro,
+        expire_at: local.subscription_end,
+    };
+    let current = catalog.with_pro(panel.squad_uuids(), local.is_pro);
+    subscription::apply_entitlement(api, catalog, &entitlement, current)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn fetch_panel_users(api: &dyn RemnawaveApi) -> Result<HashMap<Uuid, RemnawaveUser>, String> {
+    let mut users = HashMap::new();
+    let mut start = 0;
+    loop {
+        let page = api.list_users(start, PANEL_PAGE).await.map_err(|e| e.to_string())?;
+        let n = page.users.len() as u64;
+        users.extend(page.users.into_iter().map(|u| (u.uuid, u)));
+        start += n;
+        if n == 0 || start >= page.total {
+            break;
+        }
+    }
+    Ok(users)
+}
+
+/// One full pass. Returns `None` if a run is already in progress.
+pub async fn run(pool: &PgPool, api: &dyn RemnawaveApi, catalog: &Catalog, fix: bool) -> Option<Report> {
+    if RUNNING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return None;
+    }
+    let _guard = RunGuard;
+
+    let mut report = Report { started_at: Some(Utc::now()), fix_mode: fix, ..Default::default() };
+    if let Err(e) = reconcile(pool, api, catalog, fix, &mut report).await {
+        log::error!("[reconcile] aborted: {}", e);
+        report.aborted = Some(e);
+    }
+    report.finished_at = Some(Utc::now());
+    log::info!(
+        "[reconcile] checked={} drifted={} fixed={} errors={} orphans={}",
+        report.checked, report.drifted, report.fixed, report.errors, report.orphans.len()
+    );
+
+    if let Ok(mut guard) = LAST_REPORT.lock() {
+        *guard = Some(report.clone());
+    }
+    Some(report)
+}
+
+async fn reconcile(
+    pool: &PgPool,
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    fix: bool,
+    report: &mut Report,
+) -> Result<(), String> {
+    let mut panel = fetch_panel_users(api).await?;
+    report.panel_users = panel.len();
+
+    let now = Utc::now();
+    let mut last_id = 0;
+    loop {
+        let rows = sqlx::query_as::<_, LocalUser>(&format!(
+            "SELECT {} FROM users WHERE id > $1 ORDER BY id LIMIT $2",
+            LocalUser::COLUMNS
+        ))
+        .bind(last_id)
+        .bind(DB_PAGE)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| e.to_string())?;
+        let Some(last) = rows.last() else { break };
+        last_id = last.id;
+
+        for local in &rows {
+            report.checked += 1;
+            let remote = panel.remove(&local.uuid);
+            let issues = diff_user(catalog, local, remote.as_ref());
+            if issues.is_empty() {
+                continue;
+            }
+            report.drifted += 1;
+
+            let mut item = Drift {
+                telegram_id: local.telegram_id,
+                uuid: local.uuid,
+                plan: local.plan.clone(),
+                issues,
+                fixed: false,
+                error: None,
+            };
+            // Expired rows are left to the panel's own expiry handling;
+            // pushing status ACTIVE for them would re-enable access.
+            if let Some(remote) = remote.as_ref().filter(|_| fix && local.subscription_end > now) {
+                match fix_user(api, catalog, local, remote).await {
+                    Ok(()) => {
+                        item.fixed = true;
+                        report.fixed += 1;
+                    }
+                    Err(e) => {
+                        log::warn!("[reconcile] fix failed for {}: {}", local.telegram_id, e);
+                        item.error = Some(e);
+                        report.errors += 1;
+                    }
+                }
+            }
+            if report.items.len() < MAX_ITEMS {
+                report.items.push(item);
+            }
+        }
+    }
+
+    report.orphans = panel.into_keys().take(MAX_ITEMS).collect();
+    Ok(())
+}
+
+/// Background loop, started from main. No-op when
+/// `RECONCILE_INTERVAL_MINUTES=0`.
+pub fn spawn(pool: PgPool) {
+    let minutes: u64 = std::env::var("RECONCILE_INTERVAL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(360);
+    if minutes == 0 {
+        log::info!("[reconcile] background run disabled");
+        return;
+    }
+    let fix = std::env::var("RECONCILE_FIX").map(|v| v == "true").unwrap_or(false);
+    log::info!("[reconcile] every {} min, fix={}", minutes, fix);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(minutes * 60)).await;
+            let catalog = crate::plans::catalog();
+            if run(&pool, crate::remnawave::client(), &catalog, fix).await.is_none() {
+                log::warn!("[reconcile] previous run still in progress, skipping");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remnawave::Squad;
+
+    const DEFAULT: &str = "514a5e22-c599-4f72-81a5-e646f0391db7";
+    const BS: &str = "9e60626e-32a8-4d91-a2f8-2aa3fecf7b23";
+    const PRO: &str = "b6a4e86b-b769-4c86-a2d9-f31bbe645029";
+
+    fn catalog() -> Catalog {
+        let plan = |code: &str, kind: &str, squads: &[&str]| Plan {
+            code: code.into(),
+            kind: kind.into(),
+            squads: squads.iter().map(|s| s.to_string()).collect(),
+            device_limit: 2,
+            ..Default::default()
+        };
+        Catalog::new(vec![
+            plan("base", "paid", &[DEFAULT]),
+            plan("bsbase", "paid", &[DEFAULT, BS]),
+            plan("free", "bonus", &[DEFAULT]),
+            plan("pro", "addon", &[PRO]),
+        ])
+    }
+
+    fn local(plan: &str, is_pro: bool) -> LocalUser {
+        LocalUser {
+            id: 1,
+            telegram_id: 42,
+            uuid: Uuid::nil(),
+            plan: plan.into(),
+            subscription_end: "2026-07-01T12:00:00Z".parse().unwrap(),
+            device_limit: 2,
+            is_pro,
+        }
+    }
+
+    fn panel(squads: &[&str], expire_at: &str, limit: i64) -> RemnawaveUser {
+        RemnawaveUser {
+            uuid: Uuid::nil(),
+            username: None,
+            status: Some("ACTIVE".into()),
+            subscription_url: None,
+            expire_at: Some(expire_at.parse().unwrap()),
+            telegram_id: Some(42),
+            hwid_device_limit: Some(limit),
+            tag: None,
+            first_connected_at: None,
+            active_internal_squads: squads.iter().map(|s| Squad { uuid: s.to_string(), name: None }).collect(),
+        }
+    }
+
+    #[test]
+    fn in_sync_user_has_no_issues() {
+        let p = panel(&[DEFAULT], "2026-07-01T12:00:00.000Z", 2);
+        assert!(diff_user(&catalog(), &local("base", false), Some(&p)).is_empty());
+    }
+
+    #[test]
+    fn reports_expiry_limit_and_squad_drift() {
+        let p = panel(&[DEFAULT, BS, PRO], "2026-06-01T00:00:00.000Z", 10);
+        let issues = diff_user(&catalog(), &local("base", false), Some(&p));
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        assert!(issues[0].starts_with("expire_at"));
+        assert!(issues[1].starts_with("device_limit"));
+        assert!(issues[2].contains(BS) && issues[2].contains(PRO));
+    }
+
+    #[test]
+    fn missing_pro_squad_and_missing_user_are_drift() {
+        let p = panel(&[DEFAULT], "2026-07-01T12:00:00.000Z", 2);
+        let issues = diff_user(&catalog(), &local("base", true), Some(&p));
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].contains(PRO));
+
+        assert_eq!(diff_user(&catalog(), &local("base", false), None), vec!["missing in panel"]);
+    }
+
+    #[test]
+    fn temp_disabled_device_limit_is_not_drift() {
+        let p = panel(&[DEFAULT], "2026-07-01T12:00:00.000Z", 0);
+        assert!(diff_user(&catalog(), &local("base", false), Some(&p)).is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+//! Refunds of YooKassa payments: `POST /admin/payments/{id}/refund`.
+//!
+//! A refund goes to the shop that took the payment, then — in one
+//! transaction — takes the proportional days off `subscription_end`, writes
+//! a negative `payments` row (source 'refund', migrations/018) and queues
+//! the panel sync through the outbox, like a credit in reverse.
+
+use crate::{outbox, plans, remnawave, yookassa};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use std::fmt;
+
+pub struct Refunded {
+    pub refund_id: String,
+    pub status: String,
+    pub telegram_id: i64,
+    pub amount_rub: f64,
+    pub days_removed: i32,
+    pub subscription_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub enum RefundError {
+    NotFound,
+    /// Not a YooKassa payment, already a refund, or fully refunded.
+    NotRefundable(String),
+    BadAmount(String),
+    /// YooKassa refused or couldn't be reached.
+    Provider(String),
+    Db(sqlx::Error),
+}
+
+impl fmt::Display for RefundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefundError::NotFound => write!(f, "payment not found"),
+            RefundError::NotRefundable(e) | RefundError::BadAmount(e) => write!(f, "{}", e),
+            RefundError::Provider(e) => write!(f, "yookassa: {}", e),
+            RefundError::Db(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefundError {
+    fn from(e: sqlx::Error) -> Self {
+        RefundError::Db(e)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct Paid {
+    id: i64,
+    telegram_id: i64,
+    source: String,
+    amount_rub: Option<f64>,
+    plan: String,
+    duration: Option<String>,
+    days_added: i32,
+    external_id: Option<String>,
+    refund_of: Option<i64>,
+    refunded_rub: f64,
+    refunded_days: i64,
+    refunds: i64,
+}
+
+/// Days to take back for refunding `amount` of a `paid` payment that added
+/// `days`. Refunding the whole remainder takes back every day not taken yet,
+/// so partial refunds always add up to the payment.
+pub fn refund_days(days: i32, paid: f64, refunded_rub: f64, refunded_days: i32, amount: f64) -> i32 {
+    let left = (days - refunded_days).max(0);
+    if amount + 0.005 >= paid - refunded_rub {
+        return left;
+    }
+    ((f64::from(days) * amount / paid).round() as i32).min(left)
+}
+
+/// Refund `amount_rub` (the whole remainder when `None`) of ledger row
+/// `payment_id`.
+pub async fn refund(
+    pool: &PgPool,
+    http: &reqwest::Client,
+    payment_id: i64,
+    amount_rub: Option<f64>,
+    reason: Option<&str>,
+) -> Result<Refunded, RefundError> {
+    // The row lock serialises refunds of one payment for the whole call,
+    // including the YooKassa request.
+    let mut tx = pool.begin().await?;
+    let paid = sqlx::query_as::<_, Paid>(
+        "SELECT p.id, p.telegram_id, p.source, p.amount_rub::float8 AS amount_rub, p.plan, p.duration, \
+                p.days_added, COALESCE(p.external_id, p.idempotency_key) AS external_id, p.refund_of, \
+                COALESCE((SELECT -SUM(r.amount_rub) FROM payments r WHERE r.refund_of = p.id), 0)::float8 AS refunded_rub, \
+                COALESCE((SELECT -SUM(r.days_added) FROM payments r WHERE r.refund_of = p.id), 0)::int8 AS refunded_days, \
+                (SELECT COUNT(*) FROM payments r WHERE r.refund_of = p.id) AS refunds \
+         FROM payments p WHERE p.id = $1 FOR UPDATE OF p",
+    )
+    .bind(payment_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(RefundError::NotFound)?;
+
+    if paid.source != "yookassa" || paid.refund_of.is_some() {
+        return Err(RefundError::NotRefundable(format!("{} payments can't be refunded", paid.source)));
+    }
+    let (Some(total), Some(external_id)) = (paid.amount_rub.filter(|a| *a > 0.0), paid.external_id.as_deref()) else {
+        return Err(RefundError::NotRefundable("payment has no amount or YooKassa id".to_string()));
+    };
+    let remaining = ((total - paid.refunded_rub) * 100.0).round() / 100.0;
+    if remaining <= 0.0 {
+        return Err(RefundError::NotRefundable("payment is already fully refunded".to_string()));
+    }
+    let amount = match amount_rub {
+        Some(a) => (a * 100.0).round() / 100.0,
+        None => remaining,
+    };
+    if amount <= 0.0 || amount > remaining {
+        return Err(RefundError::BadAmount(format!("amount must be in (0, {:.2}]", remaining)));
+    }
+    let days = refund_days(paid.days_added, total, paid.refunded_rub, paid.refunded_days as i32, amount);
+
+    // The ledger doesn't say which shop took it — ask both.
+    let mut shop = None;
+    for s in yookassa::shops_for(None, crate::web_handlers::web_yookassa_creds()) {
+        match yookassa::fetch_payment(http, &s, external_id).await {
+            Ok(_) => {
+                shop = Some(s);
+                break;
+            }
+            Err(yookassa::FetchError::NotFound) => continue,
+            Err(e) => return Err(RefundError::Provider(e.to_string())),
+        }
+    }
+    let shop = shop.ok_or_else(|| RefundError::Provider(format!("payment {} not found in any shop", external_id)))?;
+
+    let catalog = plans::catalog();
+    let tariff_name = catalog
+        .get(&paid.plan)
+        .map(|p| p.display_name.clone())
+        .unwrap_or_else(|| "Подписка".to_string());
+    let description = format!("Возврат: SvoiVPN {}", tariff_name);
+    let receipt_email = std::env::var("RECEIPT_EMAIL").unwrap_or_else(|_| "receipt@svoi-connect.ru".to_string());
+    let value = json!({"value": format!("{:.2}", amount), "currency": "RUB"});
+    let body = json!({
+        "payment_id": external_id,
+        "amount": value,
+        "description": reason.unwrap_or(&description),
+        "receipt": {
+            "customer": {"email": receipt_email},
+            "items": [{
+                "description": description,
+                "quantity": "1.00",
+                "amount": value,
+                "vat_code": 1,
+                "payment_subject": "service",
+                "payment_mode": "full_payment"
+            }]
+        }
+    });
+    // Keyed by how many refunds exist so far: a retry of this call gets the
+    // same refund back, the next partial refund gets a new one.
+    let key = format!("refund:{}:{}", paid.id, paid.refunds);
+    let resp: Value = yookassa::create_refund(http, &shop, &key, &body)
+        .await
+        .map_err(|e| RefundError::Provider(e.to_string()))?;
+    let status = resp["status"].as_str().unwrap_or_default().to_string();
+    if status == "canceled" {
+        let why = resp["cancellation_details"]["reason"].as_str().unwrap_or("canceled");
+        return Err(RefundError::Provider(format!("refund canceled: {}", why)));
+    }
+    let refund_id = resp["id"].as_str().unwrap_or_default().to_string();
+    info!("[refund] payment #{} ({}): refund {} {}₽, -{} days, {} via {} shop",
+        paid.id, external_id, refund_id, amount, days, status, shop.name);
+
+    let subscription_end: Option<DateTime<Utc>> = sqlx::query_scalar(
+        "UPDATE users SET subscription_end = subscription_end - $1 * INTERVAL '1 day' \
+         WHERE telegram_id = $2 RETURNING subscription_end",
+    )
+    .bind(f64::from(days))
+    .bind(paid.telegram_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if subscription_end.is_none() {
+        warn!("[refund] user {} is gone, recording refund {} only", paid.telegram_id, refund_id);
+    }
+
+    sqlx::query(
+        "INSERT INTO payments (telegram_id, source, amount_rub, plan, duration, days_added, \
+                               external_id, metadata, idempotency_key, refund_of) \
+         VALUES ($1, 'refund', $2, $3, $4, $5, $6, $7, $8, $9)",
+    )
+    .bind(paid.telegram_id)
+    .bind(-amount)
+    .bind(&paid.plan)
+    .bind(paid.duration.as_deref())
+    .bind(-days)
+    .bind(&refund_id)
+    .bind(json!({"payment_id": external_id, "status": status, "reason": reason, "shop": shop.name}))
+    .bind(format!("refund:{}", refund_id))
+    .bind(paid.id)
+    .execute(&mut *tx)
+    .await?;
+
+    let outbox_id = match subscription_end {
+        Some(_) => Some(outbox::enqueue(&mut tx, paid.telegram_id, "refund").await?),
+        None => None,
+    };
+    tx.commit().await?;
+
+    if let Some(id) = outbox_id {
+        if let Err(e) = outbox::deliver_now(pool, remnawave::client(), &catalog, id).await {
+            warn!("[refund] Remnawave sync for {} deferred to outbox (row {}): {}", paid.telegram_id, id, e);
+        }
+    }
+
+    Ok(Refunded {
+        refund_id,
+        status,
+        telegram_id: paid.telegram_id,
+        amount_rub: amount,
+        days_removed: days,
+        subscription_end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_refunds_add_up_to_the_payment() {
+        // 430₽ for 90 days.
+        assert_eq!(refund_days(90, 430.0, 0.0, 0, 430.0), 90);
+        let first = refund_days(90, 430.0, 0.0, 0, 100.0);
+        assert_eq!(first, 21);
+        let second = refund_days(90, 430.0, 100.0, first, 100.0);
+        assert_eq!(second, 21);
+        // The rest takes whatever is left, rounding included.
+        assert_eq!(refund_days(90, 430.0, 200.0, first + second, 230.0), 48);
+        assert_eq!(refund_days(90, 430.0, 429.99, 90, 0.01), 0);
+    }
+}
@@ -0,0 +1,358 @@
+//! Typed client for the Remnawave panel API.
+//!
+//! Every handler used to build its own `reqwest` call, repeat the Bearer +
+//! `X-Forwarded-*` headers and dig through `json["response"][0]` by hand.
+//! This module owns all of that:
+//!   - [`RemnawaveApi`] — the trait handlers and subscription logic talk to.
+//!   - [`RemnawaveClient`] — the real HTTP implementation (timeout, retry
+//!     with backoff on 5xx / transport errors for idempotent calls).
+//!   - [`client`] — process-wide instance built from `REMNAWAVE_API_BASE` /
+//!     `REMNAWAVE_API_KEY` on first use.
+//!
+//! Tests point a `RemnawaveClient` at a wiremock server instead of the panel
+//! (see tests/remnawave_test.rs).
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+use uuid::Uuid;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+const MAX_ATTEMPTS: u32 = 3;
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+
+// === Types ===
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Squad {
+    pub uuid: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemnawaveUser {
+    pub uuid: Uuid,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub subscription_url: Option<String>,
+    #[serde(default)]
+    pub expire_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub telegram_id: Option<i64>,
+    #[serde(default)]
+    pub hwid_device_limit: Option<i64>,
+    #[serde(default)]
+    pub tag: Option<String>,
+    #[serde(default)]
+    pub first_connected_at: Option<String>,
+    #[serde(default)]
+    pub active_internal_squads: Vec<Squad>,
+}
+
+impl RemnawaveUser {
+    /// UUIDs of the user's active internal squads, in panel order.
+    pub fn squad_uuids(&self) -> Vec<String> {
+        self.active_internal_squads.iter().map(|s| s.uuid.clone()).collect()
+    }
+}
+
+/// One HWID device. Handlers pass these straight through to the bot and the
+/// web app, which read Remnawave's camelCase keys — unknown fields are kept
+/// in `extra` so the JSON round-trips unchanged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HwidDevice {
+    pub hwid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceList {
+    #[serde(default)]
+    pub devices: Vec<HwidDevice>,
+    #[serde(default)]
+    pub total: u64,
+}
+
+/// One page of `GET /users`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserPage {
+    #[serde(default)]
+    pub users: Vec<RemnawaveUser>,
+    #[serde(default)]
+    pub total: u64,
+}
+
+/// Body of `POST /users`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUser {
+    pub username: String,
+    pub status: String,
+    pub traffic_limit_bytes: u64,
+    pub traffic_limit_strategy: String,
+    #[serde(serialize_with = "ser_ts")]
+    pub expire_at: DateTime<Utc>,
+    #[serde(serialize_with = "ser_ts")]
+    pub created_at: DateTime<Utc>,
+    pub telegram_id: i64,
+    pub hwid_device_limit: i64,
+    pub active_internal_squads: Vec<String>,
+}
+
+/// Body of `PATCH /users`. Only the fields that are `Some` are sent, so a
+/// caller touching the device limit doesn't clobber squads or expiry.
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdate {
+    pub uuid: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traffic_limit_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traffic_limit_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_user_inbounds: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_internal_squads: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "ser_opt_ts")]
+    pub expire_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub telegram_id: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hwid_device_limit: Option<i64>,
+}
+
+impl UserUpdate {
+    pub fn new(uuid: Uuid) -> Self {
+        UserUpdate { uuid, ..Default::default() }
+    }
+}
+
+/// The panel wants millisecond precision with a literal `Z`.
+fn ser_ts<S: Serializer>(ts: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&ts.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+}
+
+fn ser_opt_ts<S: Serializer>(ts: &Option<DateTime<Utc>>, s: S) -> Result<S::Ok, S::Error> {
+    match ts {
+        Some(t) => ser_ts(t, s),
+        None => s.serialize_none(),
+    }
+}
+
+// === Errors ===
+
+#[derive(Debug)]
+pub enum RemnawaveError {
+    /// Connection refused, timeout, TLS — the request never got an answer.
+    Transport(reqwest::Error),
+    /// Non-2xx from the panel, with the response body for logging.
+    Status(StatusCode, String),
+    /// 2xx but the body didn't match the expected shape.
+    Decode(String),
+}
+
+impl RemnawaveError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, RemnawaveError::Status(code, _) if *code == StatusCode::NOT_FOUND)
+    }
+}
+
+impl fmt::Display for RemnawaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemnawaveError::Transport(e) => write!(f, "remnawave transport error: {}", e),
+            RemnawaveError::Status(code, body) => write!(f, "remnawave {}: {}", code, body),
+            RemnawaveError::Decode(e) => write!(f, "remnawave decode error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RemnawaveError {}
+
+// === Trait ===
+
+#[async_trait]
+pub trait RemnawaveApi: Send + Sync {
+    /// `GET /users/by-telegram-id/{id}` — first match, `None` on 404 / empty.
+    async fn get_user_by_telegram_id(&self, telegram_id: i64) -> Result<Option<RemnawaveUser>, RemnawaveError>;
+    /// `GET /users?start=&size=` — one page of all panel users.
+    async fn list_users(&self, start: u64, size: u64) -> Result<UserPage, RemnawaveError>;
+    async fn create_user(&self, user: &CreateUser) -> Result<RemnawaveUser, RemnawaveError>;
+    async fn update_user(&self, update: &UserUpdate) -> Result<RemnawaveUser, RemnawaveError>;
+    async fn delete_user(&self, uuid: Uuid) -> Result<(), RemnawaveError>;
+    async fn list_devices(&self, user_uuid: Uuid) -> Result<DeviceList, RemnawaveError>;
+    async fn delete_device(&self, user_uuid: Uuid, hwid: &str) -> Result<(), RemnawaveError>;
+}
+
+// === HTTP implementation ===
+
+#[derive(Deserialize)]
+struct Envelope<T> {
+    response: T,
+}
+
+pub struct RemnawaveClient {
+    http: reqwest::Client,
+    base: String,
+    api_key: String,
+}
+
+impl RemnawaveClient {
+    pub fn new(base: impl Into<String>, api_key: impl Into<String>) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build Remnawave HTTP client");
+        RemnawaveClient {
+            http,
+            base: base.into().trim_end_matches('/').to_string(),
+            api_key: api_key.into(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let base = std::env::var("REMNAWAVE_API_BASE")
+            .unwrap_or_else(|_| "http://localhost:3000/api".to_string());
+        let key = std::env::var("REMNAWAVE_API_KEY").expect("REMNAWAVE_API_KEY must be set");
+        Self::new(base, key)
+    }
+
+    /// Send one request and return the raw 2xx body. Idempotent calls are
+    /// retried on 5xx and transport errors with exponential backoff; `POST
+    /// /users` is not, since a retried create after a lost response would
+    /// hit "already exists".
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        idempotent: bool,
+    ) -> Result<String, RemnawaveError> {
+        let url = format!("{}{}", self.base, path);
+        let attempts = if idempotent { MAX_ATTEMPTS } else { 1 };
+        let mut last_err = None;
+
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                tokio::time::sleep(BACKOFF_BASE * 2u32.pow(attempt - 1)).await;
+            }
+            let mut rb = self
+                .http
+                .request(method.clone(), &url)
+                .bearer_auth(&self.api_key)
+                .header("X-Forwarded-For", "127.0.0.1")
+                .header("X-Forwarded-Proto", "https");
+            if let Some(b) = body {
+                rb = rb.json(b);
+            }
+            match rb.send().await {
+                Ok(resp) => {
+                    let status = resp.status();
+                    let text = resp.text().await.unwrap_or_default();
+                    if status.is_success() {
+                        return Ok(text);
+                    }
+                    let err = RemnawaveError::Status(status, text);
+                    if !status.is_server_error() {
+                        return Err(err);
+                    }
+                    log::warn!("[remnawave] {} {} attempt {}/{}: {}", method, path, attempt + 1, attempts, err);
+                    last_err = Some(err);
+                }
+                Err(e) => {
+                    log::warn!("[remnawave] {} {} attempt {}/{}: {}", method, path, attempt + 1, attempts, e);
+                    last_err = Some(RemnawaveError::Transport(e));
+                }
+            }
+        }
+        Err(last_err.expect("at least one attempt"))
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+        idempotent: bool,
+    ) -> Result<T, RemnawaveError> {
+        let text = self.send(method, path, body, idempotent).await?;
+        serde_json::from_str::<Envelope<T>>(&text)
+            .map(|e| e.response)
+            .map_err(|e| RemnawaveError::Decode(e.to_string()))
+    }
+
+    fn to_body<T: Serialize>(value: &T) -> Result<serde_json::Value, RemnawaveError> {
+        serde_json::to_value(value).map_err(|e| RemnawaveError::Decode(e.to_string()))
+    }
+}
+
+#[async_trait]
+impl RemnawaveApi for RemnawaveClient {
+    async fn get_user_by_telegram_id(&self, telegram_id: i64) -> Result<Option<RemnawaveUser>, RemnawaveError> {
+        let path = format!("/users/by-telegram-id/{}", telegram_id);
+        match self.call::<Vec<RemnawaveUser>>(Method::GET, &path, None, true).await {
+            Ok(users) => Ok(users.into_iter().next()),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn list_users(&self, start: u64, size: u64) -> Result<UserPage, RemnawaveError> {
+        let path = format!("/users?start={}&size={}", start, size);
+        self.call(Method::GET, &path, None, true).await
+    }
+
+    async fn create_user(&self, user: &CreateUser) -> Result<RemnawaveUser, RemnawaveError> {
+        let body = Self::to_body(user)?;
+        self.call(Method::POST, "/users", Some(&body), false).await
+    }
+
+    async fn update_user(&self, update: &UserUpdate) -> Result<RemnawaveUser, RemnawaveError> {
+        let body = Self::to_body(update)?;
+        self.call(Method::PATCH, "/users", Some(&body), true).await
+    }
+
+    async fn delete_user(&self, uuid: Uuid) -> Result<(), RemnawaveError> {
+        self.send(Method::DELETE, &format!("/users/{}", uuid), None, true).await.map(|_| ())
+    }
+
+    async fn list_devices(&self, user_uuid: Uuid) -> Result<DeviceList, RemnawaveError> {
+        self.call(Method::GET, &format!("/hwid/devices/{}", user_uuid), None, true).await
+    }
+
+    async fn delete_device(&self, user_uuid: Uuid, hwid: &str) -> Result<(), RemnawaveError> {
+        let body = serde_json::json!({ "userUuid": user_uuid, "hwid": hwid });
+        self.send(Method::POST, "/hwid/devices/delete", Some(&body), true).await.map(|_| ())
+    }
+}
+
+static CLIENT: OnceLock<RemnawaveClient> = OnceLock::new();
+
+/// Process-wide client, built from the environment on first use.
+pub fn client() -> &'static RemnawaveClient {
+    CLIENT.get_or_init(RemnawaveClient::from_env)
+}
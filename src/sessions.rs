@@ -0,0 +1,246 @@
+//! Revocable web sessions (migrations/027_sessions.sql,
+//! 028_refresh_tokens.sql).
+//!
+//! Each JWT issued by jwt::issue_token names its session row in `jti`;
+//! jwt::authenticate rejects tokens whose row is missing or revoked and
+//! bumps `last_used_at` (at most every TOUCH_INTERVAL_SECS, so it isn't a
+//! write per request). Tokens issued before sessions existed have no row;
+//! they are checked against the user's `session_cutoffs` instead.
+//!
+//! Access tokens are short-lived; the session's refresh token gets new ones
+//! ([`refresh`]) and is replaced on every use. Only its hash is stored.
+
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const REVOKED_BY_USER: &str = "user";
+pub const REVOKED_BY_ADMIN: &str = "admin";
+/// A rotated-out refresh token came back: someone holds a copy.
+pub const REVOKED_BY_REUSE: &str = "refresh_reuse";
+
+const TOUCH_INTERVAL_SECS: i64 = 5 * 60;
+const MAX_USER_AGENT: usize = 512;
+/// A token rotated this recently is a second tab refreshing at the same
+/// time, not a stolen copy.
+const REFRESH_REUSE_GRACE_SECS: i64 = 30;
+
+/// Where a request came from, as stored on the session.
+pub struct Client {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// User agent and client IP (X-Forwarded-For / Forwarded from the proxy,
+/// else the peer address).
+pub fn client(req: &HttpRequest) -> Client {
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|v| v.to_str().ok())
+        .map(|ua| ua.chars().take(MAX_USER_AGENT).collect());
+    let ip = req.connection_info().realip_remote_addr().map(str::to_string);
+    Client { user_agent, ip }
+}
+
+fn new_refresh_token() -> String {
+    use rand::RngCore;
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+fn refresh_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A session just opened or refreshed: what its tokens are signed with.
+pub struct Grant {
+    pub telegram_id: i64,
+    pub jti: Uuid,
+    pub refresh_token: String,
+}
+
+/// New session for `telegram_id`.
+pub async fn create(pool: &PgPool, telegram_id: i64, client: &Client) -> Result<Grant, sqlx::Error> {
+    let jti = Uuid::new_v4();
+    let refresh_token = new_refresh_token();
+    sqlx::query(
+        "INSERT INTO sessions (jti, telegram_id, user_agent, ip, refresh_hash) VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(jti)
+    .bind(telegram_id)
+    .bind(client.user_agent.as_deref())
+    .bind(client.ip.as_deref())
+    .bind(refresh_hash(&refresh_token))
+    .execute(pool)
+    .await?;
+    Ok(Grant { telegram_id, jti, refresh_token })
+}
+
+pub enum Refreshed {
+    Rotated(Grant),
+    /// Rotated moments ago by a concurrent request; the caller should pick
+    /// up the tokens that one got.
+    Raced,
+    /// Unknown, revoked, or a reused old token (the session is revoked now).
+    Invalid,
+}
+
+/// Swap a refresh token for the next one.
+pub async fn refresh(pool: &PgPool, token: &str, client: &Client) -> Result<Refreshed, sqlx::Error> {
+    let old = refresh_hash(token);
+    let next = new_refresh_token();
+    let rotated: Option<(i64, Uuid)> = sqlx::query_as(
+        "UPDATE sessions SET refresh_hash = $2, prev_refresh_hash = refresh_hash, refreshed_at = NOW(), \
+                last_used_at = NOW(), ip = COALESCE($3, ip), user_agent = COALESCE($4, user_agent) \
+         WHERE refresh_hash = $1 AND revoked_at IS NULL RETURNING telegram_id, jti",
+    )
+    .bind(&old)
+    .bind(refresh_hash(&next))
+    .bind(client.ip.as_deref())
+    .bind(client.user_agent.as_deref())
+    .fetch_optional(pool)
+    .await?;
+    if let Some((telegram_id, jti)) = rotated {
+        return Ok(Refreshed::Rotated(Grant { telegram_id, jti, refresh_token: next }));
+    }
+
+    let reused: Option<(i64, bool)> = sqlx::query_as(
+        "SELECT id, refreshed_at > NOW() - make_interval(secs => $2) FROM sessions \
+         WHERE prev_refresh_hash = $1 AND revoked_at IS NULL",
+    )
+    .bind(&old)
+    .bind(REFRESH_REUSE_GRACE_SECS as f64)
+    .fetch_optional(pool)
+    .await?;
+    match reused {
+        None => Ok(Refreshed::Invalid),
+        Some((_, true)) => Ok(Refreshed::Raced),
+        Some((id, false)) => {
+            sqlx::query("UPDATE sessions SET revoked_at = NOW(), revoked_by = $2 WHERE id = $1 AND revoked_at IS NULL")
+                .bind(id)
+                .bind(REVOKED_BY_REUSE)
+                .execute(pool)
+                .await?;
+            log::warn!("[sessions] refresh token reused, session {} revoked", id);
+            Ok(Refreshed::Invalid)
+        }
+    }
+}
+
+/// Session id of a live `jti` issued to `telegram_id`; None when it's
+/// unknown, revoked or someone else's.
+pub async fn check(pool: &PgPool, jti: Uuid, telegram_id: i64, client: &Client) -> Result<Option<i64>, sqlx::Error> {
+    let row: Option<(i64, i64, bool, bool)> = sqlx::query_as(
+        "SELECT id, telegram_id, revoked_at IS NOT NULL, last_used_at < NOW() - make_interval(secs => $2) \
+         FROM sessions WHERE jti = $1",
+    )
+    .bind(jti)
+    .bind(TOUCH_INTERVAL_SECS as f64)
+    .fetch_optional(pool)
+    .await?;
+    let Some((id, owner, revoked, stale)) = row else {
+        return Ok(None);
+    };
+    if owner != telegram_id || revoked {
+        return Ok(None);
+    }
+    if stale {
+        sqlx::query(
+            "UPDATE sessions SET last_used_at = NOW(), ip = COALESCE($2, ip), user_agent = COALESCE($3, user_agent) \
+             WHERE id = $1",
+        )
+        .bind(id)
+        .bind(client.ip.as_deref())
+        .bind(client.user_agent.as_deref())
+        .execute(pool)
+        .await?;
+    }
+    Ok(Some(id))
+}
+
+/// Whether a pre-sessions token issued at `issued_at` survived every
+/// "revoke all" of its user.
+pub async fn legacy_allowed(pool: &PgPool, telegram_id: i64, issued_at: DateTime<Utc>) -> Result<bool, sqlx::Error> {
+    let cutoff: Option<DateTime<Utc>> =
+        sqlx::query_scalar("SELECT revoked_before FROM session_cutoffs WHERE telegram_id = $1")
+            .bind(telegram_id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(cutoff.is_none_or(|c| issued_at > c))
+}
+
+/// Whether live session `id` was signed in (not just refreshed) within the
+/// last `secs` seconds.
+pub async fn opened_within(pool: &PgPool, id: i64, secs: i64) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = $1 AND revoked_at IS NULL \
+         AND created_at > NOW() - make_interval(secs => $2))",
+    )
+    .bind(id)
+    .bind(secs as f64)
+    .fetch_one(pool)
+    .await
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+}
+
+/// Live sessions, most recently used first.
+pub async fn list(pool: &PgPool, telegram_id: i64) -> Result<Vec<Session>, sqlx::Error> {
+    sqlx::query_as::<_, Session>(
+        "SELECT id, user_agent, ip, created_at, last_used_at FROM sessions \
+         WHERE telegram_id = $1 AND revoked_at IS NULL ORDER BY last_used_at DESC",
+    )
+    .bind(telegram_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Revoke one of the user's sessions. Returns whether it was live.
+pub async fn revoke(pool: &PgPool, telegram_id: i64, id: i64, by: &str) -> Result<bool, sqlx::Error> {
+    let r = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW(), revoked_by = $3 \
+         WHERE id = $1 AND telegram_id = $2 AND revoked_at IS NULL",
+    )
+    .bind(id)
+    .bind(telegram_id)
+    .bind(by)
+    .execute(pool)
+    .await?;
+    Ok(r.rows_affected() > 0)
+}
+
+/// Revoke every session of the user, pre-sessions tokens included. Email
+/// users' tokens may carry either sign of the id. Returns how many
+/// sessions were live.
+pub async fn revoke_all(pool: &PgPool, telegram_id: i64, by: &str) -> Result<u64, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let r = sqlx::query(
+        "UPDATE sessions SET revoked_at = NOW(), revoked_by = $2 \
+         WHERE telegram_id IN ($1, -$1) AND revoked_at IS NULL",
+    )
+    .bind(telegram_id)
+    .bind(by)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "INSERT INTO session_cutoffs (telegram_id, revoked_before) SELECT id, NOW() FROM unnest(ARRAY[$1, -$1]) AS id \
+         ON CONFLICT (telegram_id) DO UPDATE SET revoked_before = EXCLUDED.revoked_before",
+    )
+    .bind(telegram_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(r.rows_affected())
+}
@@ -0,0 +1,220 @@
+//! Telegram Stars (XTR) payments.
+//!
+//! Invoice links are created through the Bot API (`createInvoiceLink`) with
+//! BOT_TOKEN_TG, the bot the mini-app runs in. Telegram then sends that bot
+//! a `pre_checkout_query` and, once paid, a message with
+//! `successful_payment`; the bot forwards both updates as is to
+//! `POST /internal/payments/stars`.
+//!
+//! Prices are the RUB catalog prices (prices.rs) converted at
+//! STARS_RUB_RATE rubles per star, rounded up. The invoice payload carries
+//! everything needed to credit the payment:
+//! `stars:telegram_id:tariff:duration:price_rub:price_version:days:stars[:promo_reservation]`.
+
+use serde_json::{json, Value};
+
+pub const CURRENCY: &str = "XTR";
+
+/// RUB per star (STARS_RUB_RATE).
+pub fn rub_rate() -> f64 {
+    std::env::var("STARS_RUB_RATE")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v > 0.0)
+        .unwrap_or(1.5)
+}
+
+/// Stars for a RUB price; never rounds in the user's favour, never 0.
+pub fn stars_for(price_rub: i64, rub_per_star: f64) -> i64 {
+    ((price_rub as f64 / rub_per_star).ceil() as i64).max(1)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    pub telegram_id: i64,
+    pub tariff: String,
+    pub duration: String,
+    pub price_rub: i64,
+    pub price_version: i32,
+    pub days: i32,
+    pub stars: i64,
+    pub promo_reservation: Option<i64>,
+}
+
+impl Payload {
+    pub fn encode(&self) -> String {
+        let mut s = format!(
+            "stars:{}:{}:{}:{}:{}:{}:{}",
+            self.telegram_id, self.tariff, self.duration, self.price_rub, self.price_version, self.days, self.stars
+        );
+        if let Some(id) = self.promo_reservation {
+            s.push_str(&format!(":{}", id));
+        }
+        s
+    }
+
+    pub fn decode(s: &str) -> Result<Payload, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() < 8 || parts[0] != "stars" {
+            return Err(format!("bad stars payload {:?}", s));
+        }
+        let num = |i: usize| parts[i].parse::<i64>().map_err(|_| format!("bad field {} in {:?}", i, s));
+        Ok(Payload {
+            telegram_id: num(1)?,
+            tariff: parts[2].to_string(),
+            duration: parts[3].to_string(),
+            price_rub: num(4)?,
+            price_version: num(5)? as i32,
+            days: num(6)? as i32,
+            stars: num(7)?,
+            promo_reservation: parts.get(8).and_then(|p| p.parse::<i64>().ok()),
+        })
+    }
+}
+
+fn bot_url(method: &str) -> String {
+    format!(
+        "https://api.telegram.org/bot{}/{}",
+        std::env::var("BOT_TOKEN_TG").unwrap_or_default(),
+        method
+    )
+}
+
+/// `createInvoiceLink` for `p`. Returns the t.me invoice link.
+pub async fn create_invoice_link(http: &reqwest::Client, title: &str, description: &str, p: &Payload) -> Result<String, String> {
+    let resp = http
+        .post(bot_url("createInvoiceLink"))
+        .json(&json!({
+            "title": title,
+            "description": description,
+            "payload": p.encode(),
+            "currency": CURRENCY,
+            "prices": [{"label": title, "amount": p.stars}],
+        }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let body: Value = resp.json().await.map_err(|e| e.to_string())?;
+    match body["result"].as_str() {
+        Some(link) if body["ok"] == true => Ok(link.to_string()),
+        _ => Err(format!("createInvoiceLink: {}", body["description"].as_str().unwrap_or("unknown error"))),
+    }
+}
+
+/// `answerPreCheckoutQuery`; Telegram cancels the payment unless this
+/// arrives within 10 seconds.
+pub async fn answer_pre_checkout(http: &reqwest::Client, query_id: &str, error: Option<&str>) -> Result<(), String> {
+    let mut body = json!({"pre_checkout_query_id": query_id, "ok": error.is_none()});
+    if let Some(msg) = error {
+        body["error_message"] = json!(msg);
+    }
+    let resp = http.post(bot_url("answerPreCheckoutQuery")).json(&body).send().await.map_err(|e| e.to_string())?;
+    let body: Value = resp.json().await.map_err(|e| e.to_string())?;
+    if body["ok"] == true {
+        Ok(())
+    } else {
+        Err(format!("answerPreCheckoutQuery: {}", body["description"].as_str().unwrap_or("unknown error")))
+    }
+}
+
+/// Check a `pre_checkout_query` (or `successful_payment`) against its
+/// payload: Stars, the amount the payload was priced at, a known tariff.
+/// The error is shown to the user.
+pub fn check_order(currency: &str, total_amount: i64, payload: &str) -> Result<Payload, &'static str> {
+    let p = Payload::decode(payload).map_err(|_| "Счёт устарел, создайте новый")?;
+    if currency != CURRENCY || total_amount != p.stars {
+        return Err("Сумма счёта не совпадает, создайте новый");
+    }
+    if crate::prices::duration_days(&p.duration).is_none() || p.days <= 0 {
+        return Err("Тариф недоступен");
+    }
+    Ok(p)
+}
+
+/// A forwarded `successful_payment`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaidStars {
+    pub payload: Payload,
+    pub payer_id: i64,
+    pub charge_id: String,
+    pub metadata: Value,
+}
+
+/// `message.successful_payment` of a forwarded update; `Ok(None)` for
+/// anything else.
+pub fn parse_successful_payment(update: &Value) -> Result<Option<PaidStars>, String> {
+    let msg = &update["message"];
+    let sp = &msg["successful_payment"];
+    if sp.is_null() {
+        return Ok(None);
+    }
+    let currency = sp["currency"].as_str().unwrap_or_default();
+    let total = sp["total_amount"].as_i64().ok_or("total_amount missing")?;
+    let payload = sp["invoice_payload"].as_str().ok_or("invoice_payload missing")?;
+    let payload = check_order(currency, total, payload).map_err(|e| format!("{} ({:?})", e, payload))?;
+    let charge_id = sp["telegram_payment_charge_id"]
+        .as_str()
+        .filter(|s| !s.is_empty())
+        .ok_or("telegram_payment_charge_id missing")?
+        .to_string();
+    Ok(Some(PaidStars {
+        payer_id: msg["from"]["id"].as_i64().unwrap_or(payload.telegram_id),
+        metadata: json!({
+            "stars": total,
+            "telegram_payment_charge_id": charge_id,
+            "provider_payment_charge_id": sp["provider_payment_charge_id"],
+        }),
+        charge_id,
+        payload,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> Payload {
+        Payload {
+            telegram_id: 42,
+            tariff: "base".into(),
+            duration: "3m".into(),
+            price_rub: 430,
+            price_version: 1,
+            days: 97,
+            stars: stars_for(430, 1.5),
+            promo_reservation: Some(9),
+        }
+    }
+
+    #[test]
+    fn payload_round_trips_and_fits_telegram_limit() {
+        let p = payload();
+        assert_eq!(p.stars, 287);
+        let s = p.encode();
+        assert!(s.len() <= 128);
+        assert_eq!(Payload::decode(&s).unwrap(), p);
+        let plain = Payload { promo_reservation: None, ..p };
+        assert_eq!(Payload::decode(&plain.encode()).unwrap(), plain);
+        assert!(Payload::decode("42:base:1m").is_err());
+    }
+
+    #[test]
+    fn pre_checkout_rejects_mismatched_amount() {
+        let s = payload().encode();
+        assert!(check_order("XTR", 287, &s).is_ok());
+        assert!(check_order("XTR", 286, &s).is_err());
+        assert!(check_order("RUB", 287, &s).is_err());
+    }
+
+    #[test]
+    fn parses_successful_payment() {
+        let update = json!({"message": {"from": {"id": 42}, "successful_payment": {
+            "currency": "XTR", "total_amount": 287, "invoice_payload": payload().encode(),
+            "telegram_payment_charge_id": "stxABC", "provider_payment_charge_id": "",
+        }}});
+        let paid = parse_successful_payment(&update).unwrap().unwrap();
+        assert_eq!(paid.charge_id, "stxABC");
+        assert_eq!(paid.payload.days, 97);
+        assert_eq!(parse_successful_payment(&json!({"message": {"text": "hi"}})).unwrap(), None);
+    }
+}
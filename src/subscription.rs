@@ -0,0 +1,85 @@
+//! Panel-side half of a subscription change: which squads a user ends up
+//! in, and the PATCH that applies plan/expiry/limits to Remnawave.
+//!
+//! Kept free of DB and actix types so it can be driven against a wiremock
+//! panel in tests — the handlers in main.rs / web_handlers.rs own the local
+//! `users` row and call in here for the Remnawave sync. Squad/limit/tag
+//! config comes from the plan catalog (see plans.rs).
+
+use crate::plans::{Catalog, Plan};
+use crate::remnawave::{RemnawaveApi, RemnawaveError, UserUpdate};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+/// Everything `extend_subscription` pushes to the panel for one user.
+pub struct Entitlement<'a> {
+    pub telegram_id: i64,
+    pub uuid: Uuid,
+    pub plan: &'a Plan,
+    pub is_pro: bool,
+    pub expire_at: DateTime<Utc>,
+}
+
+/// Read the user's current squads, reconcile them for the plan and PATCH
+/// the full entitlement. Returns the squad list that was applied.
+pub async fn push_entitlement(
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    e: &Entitlement<'_>,
+) -> Result<Vec<String>, RemnawaveError> {
+    let current = api
+        .get_user_by_telegram_id(e.telegram_id)
+        .await?
+        .map(|u| u.squad_uuids())
+        .unwrap_or_default();
+    apply_entitlement(api, catalog, e, current).await
+}
+
+/// [`push_entitlement`] for a caller that already holds the panel's current
+/// squad list (the reconciler pages through every user and has it in hand).
+pub async fn apply_entitlement(
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    e: &Entitlement<'_>,
+    current: Vec<String>,
+) -> Result<Vec<String>, RemnawaveError> {
+    let squads = catalog.entitled_squads(current, e.plan, e.is_pro);
+
+    let update = UserUpdate {
+        status: Some("ACTIVE".to_string()),
+        traffic_limit_bytes: Some(e.plan.traffic_limit()),
+        traffic_limit_strategy: Some("MONTH".to_string()),
+        active_user_inbounds: Some(e.plan.inbounds.clone()),
+        active_internal_squads: Some(squads.clone()),
+        tag: Some(e.plan.tag.clone()),
+        expire_at: Some(e.expire_at),
+        telegram_id: Some(e.telegram_id),
+        hwid_device_limit: Some(e.plan.device_limit),
+        ..UserUpdate::new(e.uuid)
+    };
+    api.update_user(&update).await?;
+    Ok(squads)
+}
+
+/// Toggle the PRO squad on the panel. Returns the squad list that was applied.
+pub async fn push_pro(
+    api: &dyn RemnawaveApi,
+    catalog: &Catalog,
+    telegram_id: i64,
+    uuid: Uuid,
+    enable: bool,
+) -> Result<Vec<String>, RemnawaveError> {
+    let current = api
+        .get_user_by_telegram_id(telegram_id)
+        .await?
+        .map(|u| u.squad_uuids())
+        .unwrap_or_default();
+    let squads = catalog.with_pro(current, enable);
+
+    let update = UserUpdate {
+        active_internal_squads: Some(squads.clone()),
+        ..UserUpdate::new(uuid)
+    };
+    api.update_user(&update).await?;
+    Ok(squads)
+}
@@ -0,0 +1,378 @@
+//! TOTP second factor for email accounts (migrations/029_totp.sql).
+//!
+//! Opt-in: POST /web/me/totp/setup stores an unconfirmed secret and returns
+//! its otpauth URI and a QR of it; POST /web/me/totp/confirm with a code
+//! from the app turns it on and hands out RECOVERY_CODES one-time recovery
+//! codes. From then on auth_email_login answers with a challenge instead of
+//! tokens, redeemed with a code at POST /web/auth/totp, and
+//! auth_reset_password wants a code as well.
+//!
+//! RFC 6238 with the parameters every authenticator app supports: SHA-1,
+//! 6 digits, 30 s steps. One step of clock drift either way is accepted,
+//! and a step at most once per account. Wherever a code is asked for, a
+//! recovery code works too.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+const ISSUER: &str = "SvoiVPN";
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps of clock drift accepted either way.
+const DRIFT_STEPS: i64 = 1;
+const SECRET_BYTES: usize = 20;
+pub const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+/// Wrong codes in a row before the factor is locked for LOCK_MINUTES.
+const MAX_FAILURES: i32 = 5;
+const LOCK_MINUTES: i64 = 15;
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    use rand::RngCore;
+    let mut bytes = [0u8; N];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn sha256_hex(s: &str) -> String {
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
+
+/// HOTP value (RFC 4226) of `secret` at `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    (value & 0x7fff_ffff) % 10u32.pow(DIGITS)
+}
+
+/// The time step `code` is valid for at `now` (unix seconds), if any.
+fn matching_step(secret_b32: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = data_encoding::BASE32_NOPAD.decode(secret_b32.as_bytes()).ok()?;
+    let code: u32 = code.parse().ok()?;
+    let current = now.div_euclid(STEP_SECS);
+    (current - DRIFT_STEPS..=current + DRIFT_STEPS).find(|&step| step >= 0 && hotp(&secret, step as u64) == code)
+}
+
+/// A six-digit code, as opposed to a recovery code.
+fn is_totp_code(code: &str) -> bool {
+    code.len() == DIGITS as usize && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Recovery codes are shown as `xxxxx-xxxxx`; accept any case and
+/// separators.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+fn new_recovery_code() -> String {
+    use rand::Rng;
+    const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::rngs::OsRng;
+    let code: String = (0..RECOVERY_CODE_LEN)
+        .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..])
+}
+
+/// `otpauth://` URI authenticator apps import (as a QR or a link).
+pub fn otpauth_uri(secret_b32: &str, email: &str) -> String {
+    let label: String = form_urlencoded::byte_serialize(format!("{}:{}", ISSUER, email).as_bytes()).collect();
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret_b32, ISSUER, DIGITS, STEP_SECS
+    )
+}
+
+/// The URI as an SVG QR code.
+pub fn qr_svg(uri: &str) -> Result<String, String> {
+    let code = qrcode::QrCode::new(uri.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(code.render::<qrcode::render::svg::Color>().min_dimensions(200, 200).build())
+}
+
+/// Whether `email` has a confirmed second factor.
+pub async fn enabled(pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+    let row: Option<i64> = sqlx::query_scalar("SELECT 1::BIGINT FROM user_totp WHERE email = $1 AND confirmed_at IS NOT NULL")
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Unused recovery codes left; None when 2FA is off.
+pub async fn recovery_codes_left(pool: &PgPool, email: &str) -> Result<Option<i64>, sqlx::Error> {
+    if !enabled(pool, email).await? {
+        return Ok(None);
+    }
+    let n: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM totp_recovery_codes WHERE email = $1 AND used_at IS NULL")
+        .bind(email)
+        .fetch_one(pool)
+        .await?;
+    Ok(Some(n))
+}
+
+pub enum Setup {
+    /// New secret, base32; shown to the user until confirmed.
+    Started(String),
+    AlreadyEnabled,
+}
+
+/// Start (or restart) enrollment with a fresh secret.
+pub async fn begin_setup(pool: &PgPool, email: &str) -> Result<Setup, sqlx::Error> {
+    let secret = data_encoding::BASE32_NOPAD.encode(&random_bytes::<SECRET_BYTES>());
+    let r = sqlx::query(
+        "INSERT INTO user_totp (email, secret) VALUES ($1, $2) \
+         ON CONFLICT (email) DO UPDATE SET secret = EXCLUDED.secret, last_step = NULL, failures = 0, \
+             locked_until = NULL, created_at = NOW() \
+         WHERE user_totp.confirmed_at IS NULL",
+    )
+    .bind(email)
+    .bind(&secret)
+    .execute(pool)
+    .await?;
+    Ok(if r.rows_affected() > 0 { Setup::Started(secret) } else { Setup::AlreadyEnabled })
+}
+
+pub enum Confirm {
+    /// 2FA is on; the recovery codes, shown once.
+    Enabled(Vec<String>),
+    WrongCode,
+    /// No enrollment in progress (none started, or already confirmed).
+    NotStarted,
+}
+
+/// Turn 2FA on with a first code from the app.
+pub async fn confirm(pool: &PgPool, email: &str, code: &str) -> Result<Confirm, sqlx::Error> {
+    let secret: Option<String> =
+        sqlx::query_scalar("SELECT secret FROM user_totp WHERE email = $1 AND confirmed_at IS NULL")
+            .bind(email)
+            .fetch_optional(pool)
+            .await?;
+    let Some(secret) = secret else {
+        return Ok(Confirm::NotStarted);
+    };
+    let Some(step) = matching_step(&secret, code.trim(), chrono::Utc::now().timestamp()) else {
+        return Ok(Confirm::WrongCode);
+    };
+
+    let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| new_recovery_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|c| sha256_hex(&normalize_recovery_code(c))).collect();
+    let mut tx = pool.begin().await?;
+    let r = sqlx::query(
+        "UPDATE user_totp SET confirmed_at = NOW(), last_step = $3, failures = 0, locked_until = NULL \
+         WHERE email = $1 AND secret = $2 AND confirmed_at IS NULL",
+    )
+    .bind(email)
+    .bind(&secret)
+    .bind(step)
+    .execute(&mut *tx)
+    .await?;
+    if r.rows_affected() == 0 {
+        // Restarted or confirmed by a concurrent request.
+        return Ok(Confirm::NotStarted);
+    }
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE email = $1")
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO totp_recovery_codes (email, code_hash) SELECT $1, unnest($2::TEXT[])")
+        .bind(email)
+        .bind(&hashes)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Confirm::Enabled(codes))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Passed,
+    WrongCode,
+    /// Too many wrong codes; try again later.
+    Locked,
+}
+
+/// Check a second factor (TOTP or recovery code) for `email`, which must
+/// have 2FA on. A code that passes is used up: the TOTP step can't be
+/// replayed and a recovery code is marked used.
+pub async fn verify(pool: &PgPool, email: &str, code: &str) -> Result<Check, sqlx::Error> {
+    let row: Option<(String, bool)> = sqlx::query_as(
+        "SELECT secret, COALESCE(locked_until > NOW(), FALSE) FROM user_totp \
+         WHERE email = $1 AND confirmed_at IS NOT NULL",
+    )
+    .bind(email)
+    .fetch_optional(pool)
+    .await?;
+    let Some((secret, locked)) = row else {
+        return Ok(Check::WrongCode);
+    };
+    if locked {
+        return Ok(Check::Locked);
+    }
+
+    let code = code.trim();
+    let passed = if is_totp_code(code) {
+        match matching_step(&secret, code, chrono::Utc::now().timestamp()) {
+            Some(step) => sqlx::query(
+                "UPDATE user_totp SET last_step = $2, failures = 0 \
+                 WHERE email = $1 AND (last_step IS NULL OR last_step < $2)",
+            )
+            .bind(email)
+            .bind(step)
+            .execute(pool)
+            .await?
+            .rows_affected() > 0,
+            None => false,
+        }
+    } else {
+        let used = sqlx::query(
+            "UPDATE totp_recovery_codes SET used_at = NOW() \
+             WHERE id = (SELECT id FROM totp_recovery_codes WHERE email = $1 AND code_hash = $2 AND used_at IS NULL LIMIT 1)",
+        )
+        .bind(email)
+        .bind(sha256_hex(&normalize_recovery_code(code)))
+        .execute(pool)
+        .await?
+        .rows_affected() > 0;
+        if used {
+            sqlx::query("UPDATE user_totp SET failures = 0 WHERE email = $1")
+                .bind(email)
+                .execute(pool)
+                .await?;
+            log::info!("[totp] recovery code used for {}", email);
+        }
+        used
+    };
+    if passed {
+        return Ok(Check::Passed);
+    }
+
+    sqlx::query(
+        "UPDATE user_totp SET failures = failures + 1, \
+             locked_until = CASE WHEN failures + 1 >= $2 THEN NOW() + make_interval(mins => $3) END \
+         WHERE email = $1",
+    )
+    .bind(email)
+    .bind(MAX_FAILURES)
+    .bind(LOCK_MINUTES as i32)
+    .execute(pool)
+    .await?;
+    Ok(Check::WrongCode)
+}
+
+/// Turn 2FA off (the caller has checked a code). Returns whether it was on.
+pub async fn disable(pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let r = sqlx::query("DELETE FROM user_totp WHERE email = $1")
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE email = $1")
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM totp_challenges WHERE email = $1")
+        .bind(email)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(r.rows_affected() > 0)
+}
+
+/// Challenge token for a login whose password checked out.
+pub async fn create_challenge(pool: &PgPool, email: &str) -> Result<String, sqlx::Error> {
+    let token = hex::encode(random_bytes::<32>());
+    sqlx::query("DELETE FROM totp_challenges WHERE expires_at < NOW()")
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO totp_challenges (token_hash, email, expires_at) \
+         VALUES ($1, $2, NOW() + make_interval(mins => $3))",
+    )
+    .bind(sha256_hex(&token))
+    .bind(email)
+    .bind(CHALLENGE_TTL_MINUTES as i32)
+    .execute(pool)
+    .await?;
+    Ok(token)
+}
+
+pub enum Redeem {
+    /// Second factor passed; the login's email.
+    Passed(String),
+    WrongCode,
+    Locked,
+    /// Unknown, expired or already used challenge.
+    Invalid,
+}
+
+/// Finish a login: check `code` against the challenge's account and use
+/// the challenge up.
+pub async fn redeem_challenge(pool: &PgPool, token: &str, code: &str) -> Result<Redeem, sqlx::Error> {
+    let hash = sha256_hex(token);
+    let email: Option<String> =
+        sqlx::query_scalar("SELECT email FROM totp_challenges WHERE token_hash = $1 AND expires_at > NOW()")
+            .bind(&hash)
+            .fetch_optional(pool)
+            .await?;
+    let Some(email) = email else {
+        return Ok(Redeem::Invalid);
+    };
+    match verify(pool, &email, code).await? {
+        Check::Passed => {}
+        Check::WrongCode => return Ok(Redeem::WrongCode),
+        Check::Locked => return Ok(Redeem::Locked),
+    }
+    let used = sqlx::query("DELETE FROM totp_challenges WHERE token_hash = $1")
+        .bind(&hash)
+        .execute(pool)
+        .await?;
+    Ok(if used.rows_affected() > 0 { Redeem::Passed(email) } else { Redeem::Invalid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1 seed "12345678901234567890", last 6 digits.
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc6238_vectors() {
+        assert_eq!(matching_step(RFC_SECRET, "287082", 59), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, "081804", 1111111109), Some(37037036));
+        assert_eq!(matching_step(RFC_SECRET, "050471", 1111111111), Some(37037037));
+        assert_eq!(matching_step(RFC_SECRET, "005924", 1234567890), Some(41152263));
+    }
+
+    #[test]
+    fn accepts_one_step_of_drift_only() {
+        // 287082 is the code for step 1 (t = 30..59).
+        assert_eq!(matching_step(RFC_SECRET, "287082", 89), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, "287082", 0), Some(1));
+        assert_eq!(matching_step(RFC_SECRET, "287082", 90), None);
+        assert_eq!(matching_step(RFC_SECRET, "28708x", 59), None);
+        assert_eq!(matching_step("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn recovery_codes_normalize() {
+        let code = new_recovery_code();
+        assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+        assert!(!is_totp_code(&code));
+        assert_eq!(normalize_recovery_code(&code.to_uppercase().replace('-', " ")), code.replace('-', ""));
+        assert!(is_totp_code("012345"));
+    }
+
+    #[test]
+    fn otpauth_uri_escapes_label() {
+        let uri = otpauth_uri("ABC", "a+b@x.ru");
+        assert_eq!(uri, "otpauth://totp/SvoiVPN%3Aa%2Bb%40x.ru?secret=ABC&issuer=SvoiVPN&algorithm=SHA1&digits=6&period=30");
+        assert!(qr_svg(&uri).unwrap().starts_with("<?xml"));
+    }
+}
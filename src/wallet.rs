@@ -0,0 +1,352 @@
+//! Ruble wallet (migrations/025_wallet.sql).
+//!
+//! Referral rewards, support compensation and paid top-ups credit it; web
+//! checkouts spend it, fully or partially. The referrer's bonus and the
+//! paid-referral milestones are posted here by the API itself once their
+//! ruble amounts are set ([`reward_referral`]). Every change is a
+//! `wallet_ledger` row posted together with the `wallets` balance, which
+//! can't go negative, so a spend either fits the balance or fails whole.
+//!
+//! A checkout spend is taken before the YooKassa payment is created, under
+//! the same key ([`spend_key`]), and is [`reverse`]d when the payment is
+//! refused or canceled. Whole rubles are
+//! spent, so the rest left to charge is either 0 or a valid amount.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use std::fmt;
+
+pub const KIND_REFERRAL: &str = "referral";
+pub const KIND_COMPENSATION: &str = "compensation";
+pub const KIND_TOPUP: &str = "topup";
+pub const KIND_SPEND: &str = "spend";
+pub const KIND_REVERSAL: &str = "reversal";
+pub const KIND_ADJUSTMENT: &str = "adjustment";
+
+/// Top-up bounds, RUB.
+pub const MIN_TOPUP_RUB: i64 = 50;
+pub const MAX_TOPUP_RUB: i64 = 15_000;
+
+/// One balance change. Positive credits, negative debits.
+pub struct Posting<'a> {
+    pub telegram_id: i64,
+    pub amount_rub: f64,
+    pub kind: &'a str,
+    /// What the change is about: `yookassa:<payment id>`, a referred user, ...
+    pub reference: Option<&'a str>,
+    pub note: Option<&'a str>,
+    pub idempotency_key: Option<&'a str>,
+}
+
+impl<'a> Posting<'a> {
+    pub fn new(telegram_id: i64, amount_rub: f64, kind: &'a str) -> Self {
+        Posting { telegram_id, amount_rub, kind, reference: None, note: None, idempotency_key: None }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Entry {
+    pub id: i64,
+    pub amount_rub: f64,
+    pub kind: String,
+    pub reference: Option<String>,
+    pub note: Option<String>,
+    pub balance_after: f64,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Result of [`post`]; `replayed` when the key was already posted.
+#[derive(Debug)]
+pub struct Posted {
+    pub entry_id: i64,
+    pub balance_rub: f64,
+    pub replayed: bool,
+}
+
+#[derive(Debug)]
+pub enum WalletError {
+    UserNotFound,
+    /// The debit is larger than the balance.
+    Insufficient,
+    /// The idempotency key was posted for another user.
+    KeyConflict,
+    Db(sqlx::Error),
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::UserNotFound => write!(f, "user not found"),
+            WalletError::Insufficient => write!(f, "insufficient wallet balance"),
+            WalletError::KeyConflict => write!(f, "idempotency key already used"),
+            WalletError::Db(e) => write!(f, "db error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for WalletError {
+    fn from(e: sqlx::Error) -> Self {
+        WalletError::Db(e)
+    }
+}
+
+fn round2(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+/// Rubles of `price_rub` to take from a `balance_rub` wallet: as much as
+/// asked for (everything when `requested_rub` is None), capped by the whole
+/// rubles available and the price.
+pub fn split(price_rub: i64, balance_rub: f64, requested_rub: Option<i64>) -> i64 {
+    let available = balance_rub.floor() as i64;
+    requested_rub.unwrap_or(price_rub).min(available).min(price_rub).max(0)
+}
+
+/// Local id the wallet lives under: the id itself, or its negation for
+/// email users (same rule as credit.rs).
+async fn owner(pool: &PgPool, telegram_id: i64) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT telegram_id FROM users WHERE telegram_id IN ($1, -$1) \
+         ORDER BY telegram_id = $1 DESC LIMIT 1",
+    )
+    .bind(telegram_id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn replay(pool: &PgPool, key: &str, owner: i64) -> Result<Option<Posted>, WalletError> {
+    let row: Option<(i64, i64, f64)> = sqlx::query_as(
+        "SELECT id, telegram_id, balance_after::float8 FROM wallet_ledger WHERE idempotency_key = $1",
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    match row {
+        None => Ok(None),
+        Some((_, tg, _)) if tg != owner => Err(WalletError::KeyConflict),
+        Some((id, _, _)) => Ok(Some(Posted { entry_id: id, balance_rub: balance(pool, owner).await?, replayed: true })),
+    }
+}
+
+/// Apply `p` to the balance and the ledger in one transaction.
+pub async fn post(pool: &PgPool, p: &Posting<'_>) -> Result<Posted, WalletError> {
+    let owner = owner(pool, p.telegram_id).await?.ok_or(WalletError::UserNotFound)?;
+    if let Some(key) = p.idempotency_key {
+        if let Some(done) = replay(pool, key, owner).await? {
+            return Ok(done);
+        }
+    }
+    let amount = round2(p.amount_rub);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT INTO wallets (telegram_id) VALUES ($1) ON CONFLICT DO NOTHING")
+        .bind(owner)
+        .execute(&mut *tx)
+        .await?;
+    let balance: Option<f64> = sqlx::query_scalar(
+        "UPDATE wallets SET balance_rub = balance_rub + $2::numeric, updated_at = NOW() \
+         WHERE telegram_id = $1 AND balance_rub + $2::numeric >= 0 RETURNING balance_rub::float8",
+    )
+    .bind(owner)
+    .bind(amount)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(balance) = balance else {
+        return Err(WalletError::Insufficient);
+    };
+    let entry_id: Option<i64> = sqlx::query_scalar(
+        "INSERT INTO wallet_ledger (telegram_id, amount_rub, kind, reference, note, idempotency_key, balance_after) \
+         VALUES ($1, $2::numeric, $3, $4, $5, $6, $7::numeric) \
+         ON CONFLICT (idempotency_key) DO NOTHING RETURNING id",
+    )
+    .bind(owner)
+    .bind(amount)
+    .bind(p.kind)
+    .bind(p.reference)
+    .bind(p.note)
+    .bind(p.idempotency_key)
+    .bind(balance)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(entry_id) = entry_id else {
+        // A concurrent post with the same key won; ours rolls back.
+        drop(tx);
+        let key = p.idempotency_key.unwrap_or_default();
+        return replay(pool, key, owner).await?.ok_or(WalletError::KeyConflict);
+    };
+    tx.commit().await?;
+    Ok(Posted { entry_id, balance_rub: balance, replayed: false })
+}
+
+/// Paid-referral counts that earn a milestone reward, with its source.
+pub const REFERRAL_MILESTONES: [(i64, &str); 2] = [(5, "ref_milestone_5"), (10, "ref_milestone_10")];
+pub const REFERRAL_PARENT: &str = "ref_bonus_parent";
+
+/// Rubles paid for referral reward `source` (REFERRAL_PARENT_RUB,
+/// REFERRAL_MILESTONE_5_RUB, REFERRAL_MILESTONE_10_RUB). None when unset:
+/// that reward is still granted as days by the bot.
+pub fn referral_reward_rub(source: &str) -> Option<f64> {
+    let var = match source {
+        REFERRAL_PARENT => "REFERRAL_PARENT_RUB",
+        "ref_milestone_5" => "REFERRAL_MILESTONE_5_RUB",
+        "ref_milestone_10" => "REFERRAL_MILESTONE_10_RUB",
+        _ => return None,
+    };
+    std::env::var(var)
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v > 0.0 && v.is_finite())
+}
+
+/// Credit referral reward `source` to `telegram_id`, once per `key` (the
+/// referred user for [`REFERRAL_PARENT`], the referrer for a milestone).
+/// None when the reward has no ruble amount set.
+pub async fn reward_referral(
+    pool: &PgPool,
+    telegram_id: i64,
+    source: &str,
+    key: &str,
+) -> Result<Option<Posted>, WalletError> {
+    let Some(amount) = referral_reward_rub(source) else {
+        return Ok(None);
+    };
+    let p = Posting {
+        reference: Some(source),
+        note: Some(crate::receipts::label(source)),
+        idempotency_key: Some(key),
+        ..Posting::new(telegram_id, amount, KIND_REFERRAL)
+    };
+    post(pool, &p).await.map(Some)
+}
+
+/// Idempotency key of the spend for the checkout sent to YooKassa with
+/// `idempotence_key`.
+pub fn spend_key(idempotence_key: &str) -> String {
+    format!("spend:{}", idempotence_key)
+}
+
+/// Take `amount_rub` for the checkout keyed `idempotence_key`; a replay
+/// returns the first spend. The spend's id is its handle for [`attach`]
+/// and [`reverse`].
+pub async fn spend(
+    pool: &PgPool,
+    telegram_id: i64,
+    amount_rub: i64,
+    note: &str,
+    idempotence_key: &str,
+) -> Result<Posted, WalletError> {
+    let key = spend_key(idempotence_key);
+    post(
+        pool,
+        &Posting {
+            note: Some(note),
+            idempotency_key: Some(&key),
+            ..Posting::new(telegram_id, -(amount_rub as f64), KIND_SPEND)
+        },
+    )
+    .await
+}
+
+/// Id of the spend posted under `key` ([`spend_key`]).
+pub async fn spend_by_key(pool: &PgPool, key: &str) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar("SELECT id FROM wallet_ledger WHERE idempotency_key = $1 AND kind = 'spend'")
+        .bind(key)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Tie a spend to the payment it went into once that exists.
+pub async fn attach(pool: &PgPool, spend_id: i64, reference: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE wallet_ledger SET reference = $2 WHERE id = $1 AND kind = 'spend'")
+        .bind(spend_id)
+        .bind(reference)
+        .execute(pool)
+        .await
+        .map(|_| ())
+}
+
+/// Give a spend back. Idempotent per spend.
+pub async fn reverse(pool: &PgPool, spend_id: i64) -> Result<(), WalletError> {
+    let row: Option<(i64, f64, Option<String>)> = sqlx::query_as(
+        "SELECT telegram_id, amount_rub::float8, reference FROM wallet_ledger WHERE id = $1 AND kind = 'spend'",
+    )
+    .bind(spend_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some((telegram_id, amount, reference)) = row else {
+        return Ok(());
+    };
+    let key = format!("reversal:{}", spend_id);
+    post(
+        pool,
+        &Posting {
+            reference: reference.as_deref(),
+            idempotency_key: Some(&key),
+            ..Posting::new(telegram_id, -amount, KIND_REVERSAL)
+        },
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Give back every spend that went into a canceled payment.
+pub async fn reverse_reference(pool: &PgPool, reference: &str) -> Result<(), WalletError> {
+    let ids: Vec<i64> = sqlx::query_scalar("SELECT id FROM wallet_ledger WHERE reference = $1 AND kind = 'spend'")
+        .bind(reference)
+        .fetch_all(pool)
+        .await?;
+    for id in ids {
+        reverse(pool, id).await?;
+    }
+    Ok(())
+}
+
+/// Current balance; 0 for users who never had a wallet change.
+pub async fn balance(pool: &PgPool, telegram_id: i64) -> Result<f64, sqlx::Error> {
+    let Some(owner) = owner(pool, telegram_id).await? else {
+        return Ok(0.0);
+    };
+    let b: Option<f64> = sqlx::query_scalar("SELECT balance_rub::float8 FROM wallets WHERE telegram_id = $1")
+        .bind(owner)
+        .fetch_optional(pool)
+        .await?;
+    Ok(b.unwrap_or(0.0))
+}
+
+/// One page of ledger rows, newest first, and the total row count.
+pub async fn history(pool: &PgPool, telegram_id: i64, limit: i64, offset: i64) -> Result<(Vec<Entry>, i64), sqlx::Error> {
+    let Some(owner) = owner(pool, telegram_id).await? else {
+        return Ok((Vec::new(), 0));
+    };
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM wallet_ledger WHERE telegram_id = $1")
+        .bind(owner)
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query_as::<_, Entry>(
+        "SELECT id, amount_rub::float8 AS amount_rub, kind, reference, note, \
+                balance_after::float8 AS balance_after, created_at \
+         FROM wallet_ledger WHERE telegram_id = $1 ORDER BY id DESC LIMIT $2 OFFSET $3",
+    )
+    .bind(owner)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok((rows, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_takes_whole_rubles_up_to_price() {
+        assert_eq!(split(430, 1000.0, None), 430);
+        assert_eq!(split(430, 120.75, None), 120);
+        assert_eq!(split(430, 120.75, Some(50)), 50);
+        assert_eq!(split(430, 1000.0, Some(999)), 430);
+        assert_eq!(split(430, 0.5, None), 0);
+        assert_eq!(split(430, 100.0, Some(-5)), 0);
+    }
+}
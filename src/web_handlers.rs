@@ -9,7 +9,21 @@ use log::{info, error, warn};
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use crate::cryptopay;
 use crate::jwt;
+use crate::jobs;
+use crate::passkeys;
+use crate::pending;
+use crate::remnawave::{self, RemnawaveApi};
+use crate::plans;
+use crate::prices;
+use crate::promo;
+use crate::reconcile;
+use crate::sessions;
+use crate::subscription;
+use crate::totp;
+use crate::wallet;
+use crate::yookassa;
 use crate::models::{User, SupportChatRequest, InternalSupportChatRequest, InternalSupportEscalateRequest};
 use chrono::Utc;
 use uuid::Uuid;
@@ -112,47 +126,32 @@ async fn auto_register_user(pool: &PgPool, telegram_id: i64, username: Option<St
     info!("[auto_register] Creating user {} ({}) referral={:?}", telegram_id, username, referral_id);
 
     // Create in Remnawave
-    let api_response = HTTP_CLIENT
-        .post(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .headers(remnawave_headers())
-        .json(&json!({
-            "username": username,
-            "status": "DISABLED",
-            "trafficLimitBytes": 0,
-            "trafficLimitStrategy": "MONTH",
-            "expireAt": Utc::now(),
-            "createdAt": Utc::now(),
-            "telegramId": telegram_id,
-            "hwidDeviceLimit": 2,
-            "activeInternalSquads": ["514a5e22-c599-4f72-81a5-e646f0391db7"],
-        }))
-        .send()
+    let baseline = plans::catalog().baseline();
+    let created = remnawave::client()
+        .create_user(&remnawave::CreateUser {
+            username: username.clone(),
+            status: "DISABLED".to_string(),
+            traffic_limit_bytes: 0,
+            traffic_limit_strategy: "MONTH".to_string(),
+            expire_at: Utc::now(),
+            created_at: Utc::now(),
+            telegram_id,
+            hwid_device_limit: baseline.device_limit,
+            active_internal_squads: baseline.squads,
+        })
         .await
         .map_err(|e| {
-            error!("[auto_register] Remnawave API failed for {}: {}", telegram_id, e);
-            { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+            error!("[auto_register] Remnawave error for {}: {}", telegram_id, e);
+            match e {
+                remnawave::RemnawaveError::Status(_, body) if body.contains("already exists") => {
+                    HttpResponse::Conflict().json(json!({"error": "Ошибка регистрации. Попробуйте снова через минуту."}))
+                }
+                _ => HttpResponse::InternalServerError().json(json!({"error": "Ошибка при создании аккаунта. Попробуйте позже."})),
+            }
         })?;
 
-    if !api_response.status().is_success() {
-        let status = api_response.status();
-        let body = api_response.text().await.unwrap_or_default();
-        error!("[auto_register] Remnawave error for {} ({}): {}", telegram_id, status, body);
-        if body.contains("already exists") {
-            return Err(HttpResponse::Conflict().json(json!({"error": "Ошибка регистрации. Попробуйте снова через минуту."})));
-        }
-        return Err(HttpResponse::InternalServerError().json(json!({"error": "Ошибка при создании аккаунта. Попробуйте позже."})));
-    }
-
-    let json_response: serde_json::Value = api_response.json().await.map_err(|e| {
-        error!("[auto_register] Failed to parse Remnawave response: {}", e);
-        { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
-    })?;
-
-    let uuid_str = json_response["response"]["uuid"].as_str()
-        .ok_or_else(|| HttpResponse::InternalServerError().body("Missing uuid in Remnawave response"))?;
-    let uuid = Uuid::parse_str(uuid_str)
-        .map_err(|_| HttpResponse::InternalServerError().body("Invalid uuid"))?;
-    let sub_url = json_response["response"]["subscriptionUrl"].as_str().unwrap_or("").to_string();
+    let uuid = created.uuid;
+    let sub_url = created.subscription_url.unwrap_or_default();
 
     sqlx::query(
         "INSERT INTO users (telegram_id, uuid, subscription_end, is_active, created_at, is_used_trial, game_points, is_used_ref_bonus, game_attempts, username, sub_link, payed_refs, is_pro, referral_id) \
@@ -186,6 +185,7 @@ async fn auto_register_user(pool: &PgPool, telegram_id: i64, username: Option<St
 
 pub async fn auth_telegram(
     pool: web::Data<PgPool>,
+    req: HttpRequest,
     data: web::Json<TelegramAuthRequest>,
 ) -> HttpResponse {
     let telegram_id = match jwt::validate_init_data(&data.init_data) {
@@ -211,12 +211,18 @@ pub async fn auth_telegram(
         Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
     }
 
-    let token = match jwt::create_token(telegram_id) {
+    let token = match jwt::issue_token(pool.get_ref(), &req, telegram_id).await {
         Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."})),
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+        }
     };
 
-    HttpResponse::Ok().json(json!({ "token": token, "telegram_id": telegram_id }))
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
 }
 
 // auth_telegram_login (Widget) — REMOVED, replaced by bot-based flow
@@ -252,6 +258,8 @@ pub struct ResetPasswordRequest {
     email: String,
     code: String,
     new_password: String,
+    /// TOTP or recovery code, for accounts with 2FA on.
+    totp_code: Option<String>,
 }
 
 fn generate_6digit_code() -> String {
@@ -307,11 +315,7 @@ pub async fn auth_email_register(
                 let old_uuid: uuid::Uuid = old_row.get("uuid");
 
                 // Delete from Remnawave
-                let _ = HTTP_CLIENT
-                    .delete(&format!("{}/users/{}", *REMNAWAVE_API_BASE, old_uuid))
-                    .headers(remnawave_headers())
-                    .send()
-                    .await;
+                let _ = remnawave::client().delete_user(old_uuid).await;
                 info!("[auth_email_register] Deleted Remnawave user {} (uuid={})", old_tg_id, old_uuid);
 
                 let _ = sqlx::query("DELETE FROM user_credentials WHERE email = $1 AND email_verified = FALSE")
@@ -407,6 +411,7 @@ pub async fn auth_email_register(
 
 pub async fn auth_verify_email(
     pool: web::Data<PgPool>,
+    req: HttpRequest,
     data: web::Json<VerifyEmailRequest>,
 ) -> HttpResponse {
     let email = data.email.trim().to_lowercase();
@@ -452,17 +457,24 @@ pub async fn auth_verify_email(
     };
 
     let telegram_id: i64 = cred_row.get("telegram_id");
-    let token = match jwt::create_token(telegram_id) {
+    let token = match jwt::issue_token(pool.get_ref(), &req, telegram_id).await {
         Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."})),
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+        }
     };
 
     info!("[auth_verify_email] Email verified: {} (id={})", email, telegram_id);
-    HttpResponse::Ok().json(json!({ "token": token, "telegram_id": telegram_id }))
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
 }
 
 pub async fn auth_email_login(
     pool: web::Data<PgPool>,
+    req: HttpRequest,
     data: web::Json<EmailLoginRequest>,
 ) -> HttpResponse {
     let email = data.email.trim().to_lowercase();
@@ -498,13 +510,262 @@ pub async fn auth_email_login(
         return HttpResponse::Forbidden().json(json!({"error": "Email не подтверждён", "needs_verification": true}));
     }
 
-    let token = match jwt::create_token(telegram_id) {
+    finish_email_login(pool.get_ref(), &req, &email, telegram_id, "auth_email_login").await
+}
+
+/// Tokens for an email login that passed its first factor (password or
+/// emailed code). With 2FA on it only earns a challenge instead; POST
+/// /web/auth/totp trades that and a code for the tokens.
+async fn finish_email_login(pool: &PgPool, req: &HttpRequest, email: &str, telegram_id: i64, ctx: &str) -> HttpResponse {
+    match totp::enabled(pool, email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return match totp::create_challenge(pool, email).await {
+                Ok(challenge) => {
+                    info!("[{}] 2FA challenge for {} (id={})", ctx, email, telegram_id);
+                    HttpResponse::Ok().json(json!({ "totp_required": true, "challenge": challenge }))
+                }
+                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+            };
+        }
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+
+    let token = match jwt::issue_token(pool, req, telegram_id).await {
         Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."})),
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+        }
+    };
+
+    info!("[{}] Email login: {} (id={})", ctx, email, telegram_id);
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct EmailCodeRequest {
+    email: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailCodeVerifyRequest {
+    email: Option<String>,
+    code: Option<String>,
+    /// From the magic link (`?email_login=`), instead of email + code.
+    token: Option<String>,
+}
+
+/// Wrong codes a login code survives.
+const LOGIN_CODE_MAX_ATTEMPTS: i32 = 5;
+
+fn login_link_payload(code_id: i64, email: &str, code: &str) -> String {
+    format!("email-login:{}:{}:{}", code_id, email, code)
+}
+
+/// POST /web/auth/email-code — passwordless login: mail a 6-digit code and
+/// a magic link for it to a verified account. Either one is redeemed at
+/// POST /web/auth/email-code/verify.
+pub async fn auth_email_code(
+    pool: web::Data<PgPool>,
+    data: web::Json<EmailCodeRequest>,
+) -> HttpResponse {
+    let email = data.email.trim().to_lowercase();
+
+    let row = sqlx::query("SELECT email_verified FROM user_credentials WHERE email = $1")
+        .bind(&email)
+        .fetch_optional(pool.get_ref())
+        .await;
+
+    match row {
+        Ok(None) => {
+            info!("[auth_email_code] Email not registered: {}", email);
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Этот email не зарегистрирован. Зарегистрируйтесь на сайте или войдите через Telegram."
+            }));
+        }
+        Ok(Some(r)) => {
+            let verified: bool = r.get("email_verified");
+            if !verified {
+                return HttpResponse::Forbidden().json(json!({"error": "Email не подтверждён", "needs_verification": true}));
+            }
+        }
+        Err(e) => {
+            error!("[auth_email_code] DB error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+    }
+
+    if check_rate_limit(pool.get_ref(), &email).await {
+        return HttpResponse::TooManyRequests().json(json!({
+            "error": "Код уже отправлен. Подождите несколько минут перед повторной отправкой."
+        }));
+    }
+
+    let code = generate_6digit_code();
+    let code_id: i64 = match sqlx::query_scalar(
+        "INSERT INTO email_verification_codes (email, code, purpose, expires_at) VALUES ($1, $2, 'login', NOW() + INTERVAL '10 minutes') RETURNING id"
+    )
+    .bind(&email)
+    .bind(&code)
+    .fetch_one(pool.get_ref())
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    let sig = jwt::sign_link(&login_link_payload(code_id, &email, &code));
+    let link = format!("https://svoiweb.ru/?email_login={}.{}", code_id, sig);
+    if let Err(e) = crate::email::send_login_code(&email, &code, &link).await {
+        error!("[auth_email_code] Failed to send login email: {}", e);
+        return HttpResponse::InternalServerError().json(json!({"error": "Не удалось отправить код на email. Попробуйте позже."}));
+    }
+
+    info!("[auth_email_code] Login code sent to {}", email);
+    HttpResponse::Ok().json(json!({ "message": "Код для входа отправлен на вашу почту" }))
+}
+
+/// POST /web/auth/email-code/verify — redeem a login code (`email` +
+/// `code`) or magic link (`token`) for the same response as
+/// /web/auth/login.
+pub async fn auth_email_code_verify(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<EmailCodeVerifyRequest>,
+) -> HttpResponse {
+    let invalid = || HttpResponse::BadRequest().json(json!({"error": "Неверный или просроченный код"}));
+
+    let code_id: i64 = if let Some(token) = data.token.as_deref() {
+        // The link is signed over its row, so it needs no attempt limit.
+        let Some((id, sig)) = token.trim().split_once('.') else {
+            return invalid();
+        };
+        let Ok(id) = id.parse::<i64>() else {
+            return invalid();
+        };
+        let row: Option<(String, String)> = match sqlx::query_as(
+            "SELECT email, code FROM email_verification_codes WHERE id = $1 AND purpose = 'login' AND used = FALSE AND expires_at > NOW()"
+        )
+        .bind(id)
+        .fetch_optional(pool.get_ref())
+        .await
+        {
+            Ok(r) => r,
+            Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        };
+        match row {
+            Some((email, code)) if jwt::verify_link(&login_link_payload(id, &email, &code), sig) => id,
+            _ => return invalid(),
+        }
+    } else {
+        let (Some(email), Some(code)) = (data.email.as_deref(), data.code.as_deref()) else {
+            return HttpResponse::BadRequest().json(json!({"error": "email and code (or token) required"}));
+        };
+        let email = email.trim().to_lowercase();
+        let found: Option<i64> = match sqlx::query_scalar(
+            "SELECT id FROM email_verification_codes WHERE email = $1 AND code = $2 AND purpose = 'login' AND used = FALSE AND expires_at > NOW() AND attempts < $3 ORDER BY created_at DESC LIMIT 1"
+        )
+        .bind(&email)
+        .bind(code.trim())
+        .bind(LOGIN_CODE_MAX_ATTEMPTS)
+        .fetch_optional(pool.get_ref())
+        .await
+        {
+            Ok(r) => r,
+            Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        };
+        match found {
+            Some(id) => id,
+            None => {
+                let _ = sqlx::query(
+                    "UPDATE email_verification_codes SET attempts = attempts + 1 WHERE email = $1 AND purpose = 'login' AND used = FALSE AND expires_at > NOW()"
+                )
+                .bind(&email)
+                .execute(pool.get_ref())
+                .await;
+                return invalid();
+            }
+        }
+    };
+
+    // Mark code as used; a concurrent redeem of the same code gets nothing.
+    let email: String = match sqlx::query_scalar(
+        "UPDATE email_verification_codes SET used = TRUE WHERE id = $1 AND used = FALSE RETURNING email"
+    )
+    .bind(code_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(Some(email)) => email,
+        Ok(None) => return invalid(),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    let telegram_id: i64 = match sqlx::query_scalar("SELECT telegram_id FROM user_credentials WHERE email = $1")
+        .bind(&email)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"error": "Пользователь не найден"})),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    finish_email_login(pool.get_ref(), &req, &email, telegram_id, "auth_email_code_verify").await
+}
+
+#[derive(Deserialize)]
+pub struct TotpLoginRequest {
+    challenge: String,
+    code: String,
+}
+
+/// POST /web/auth/totp — second step of an email login with 2FA on: the
+/// challenge from auth_email_login plus a TOTP or recovery code.
+pub async fn auth_totp(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<TotpLoginRequest>,
+) -> HttpResponse {
+    let email = match totp::redeem_challenge(pool.get_ref(), data.challenge.trim(), &data.code).await {
+        Ok(totp::Redeem::Passed(email)) => email,
+        Ok(totp::Redeem::WrongCode) => return HttpResponse::Unauthorized().json(json!({"error": "Неверный код"})),
+        Ok(totp::Redeem::Locked) => return HttpResponse::TooManyRequests().json(json!({
+            "error": "Слишком много неверных кодов. Попробуйте через 15 минут."
+        })),
+        Ok(totp::Redeem::Invalid) => return HttpResponse::Unauthorized().json(json!({
+            "error": "Сессия входа истекла. Введите email и пароль ещё раз."
+        })),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    let telegram_id: i64 = match sqlx::query_scalar("SELECT telegram_id FROM user_credentials WHERE email = $1")
+        .bind(&email)
+        .fetch_optional(pool.get_ref())
+        .await
+    {
+        Ok(Some(id)) => id,
+        Ok(None) => return HttpResponse::NotFound().json(json!({"error": "Пользователь не найден"})),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    let token = match jwt::issue_token(pool.get_ref(), &req, telegram_id).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+        }
     };
 
-    info!("[auth_email_login] Email login: {} (id={})", email, telegram_id);
-    HttpResponse::Ok().json(json!({ "token": token, "telegram_id": telegram_id }))
+    info!("[auth_totp] Email login with 2FA: {} (id={})", email, telegram_id);
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
 }
 
 #[derive(serde::Deserialize)]
@@ -523,6 +784,7 @@ pub struct SubSessionRequest {
 /// generically from the imported subscription body.
 pub async fn auth_sub_session(
     pool: web::Data<PgPool>,
+    req: HttpRequest,
     data: web::Json<SubSessionRequest>,
 ) -> HttpResponse {
     let sub_link = data.sub_link.trim();
@@ -549,12 +811,45 @@ pub async fn auth_sub_session(
     };
 
     let telegram_id: i64 = row.get("telegram_id");
-    let token = match jwt::create_token(telegram_id) {
+    let token = match jwt::issue_token(pool.get_ref(), &req, telegram_id).await {
         Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "token error"})),
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "token error"}));
+        }
     };
     info!("[auth_sub_session] sub-session minted for id={}", telegram_id);
-    HttpResponse::Ok().json(json!({ "token": token, "telegram_id": telegram_id }))
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// POST /web/auth/refresh — trade the session's refresh token for a new
+/// access token and the next refresh token (the one sent stops working).
+pub async fn auth_refresh(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<RefreshRequest>,
+) -> HttpResponse {
+    let refresh_token = data.refresh_token.trim();
+    if refresh_token.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "refresh_token required"}));
+    }
+    match jwt::refresh_token(pool.get_ref(), &req, refresh_token).await {
+        Ok(jwt::Refresh::Issued(token)) => HttpResponse::Ok().json(json!({
+            "token": token.access_token, "refresh_token": token.refresh_token,
+            "expires_in": token.expires_in,
+        })),
+        Ok(jwt::Refresh::Raced) => HttpResponse::Conflict().json(json!({"error": "refresh token already used"})),
+        Ok(jwt::Refresh::Invalid) => HttpResponse::Unauthorized().json(json!({"error": "invalid refresh token"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
 }
 
 pub async fn auth_forgot_password(
@@ -642,6 +937,30 @@ pub async fn auth_reset_password(
 
     let code_id: i64 = row.get("id");
 
+    // The emailed code proves the mailbox, not the second factor.
+    match totp::enabled(pool.get_ref(), &email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            let Some(totp_code) = data.totp_code.as_deref().filter(|c| !c.trim().is_empty()) else {
+                return HttpResponse::Forbidden().json(json!({
+                    "error": "Введите код из приложения-аутентификатора или код восстановления",
+                    "totp_required": true,
+                }));
+            };
+            match totp::verify(pool.get_ref(), &email, totp_code).await {
+                Ok(totp::Check::Passed) => {}
+                Ok(totp::Check::WrongCode) => return HttpResponse::BadRequest().json(json!({
+                    "error": "Неверный код двухфакторной аутентификации", "totp_required": true,
+                })),
+                Ok(totp::Check::Locked) => return HttpResponse::TooManyRequests().json(json!({
+                    "error": "Слишком много неверных кодов. Попробуйте через 15 минут."
+                })),
+                Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+            }
+        }
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+
     // Hash new password
     use argon2::{Argon2, PasswordHasher};
     use argon2::password_hash::SaltString;
@@ -681,7 +1000,7 @@ pub async fn auth_telegram_init(pool: web::Data<PgPool>, req: HttpRequest) -> Ht
         .collect();
 
     // If user is already logged in (has JWT), save their current id for account migration
-    let initiated_by: Option<i64> = jwt::extract_telegram_id(&req).ok();
+    let initiated_by: Option<i64> = jwt::extract_telegram_id(&req).await.ok();
 
     let result = sqlx::query(
         "INSERT INTO telegram_auth_codes (code, expires_at, initiated_by) VALUES ($1, NOW() + INTERVAL '5 minutes', $2)"
@@ -702,6 +1021,7 @@ pub async fn auth_telegram_init(pool: web::Data<PgPool>, req: HttpRequest) -> Ht
 
 pub async fn auth_telegram_check(
     pool: web::Data<PgPool>,
+    req: HttpRequest,
     path: web::Path<String>,
 ) -> HttpResponse {
     let code = path.into_inner();
@@ -758,13 +1078,19 @@ pub async fn auth_telegram_check(
                 }
             }
 
-            let token = match jwt::create_token(id) {
+            let token = match jwt::issue_token(pool.get_ref(), &req, id).await {
                 Ok(t) => t,
-                Err(_) => return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."})),
+                Err(e) => {
+                    error!("Internal error: {}", e);
+                    return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+                }
             };
 
             info!("[auth_telegram_check] Auth confirmed for tg_id={}", id);
-            HttpResponse::Ok().json(json!({ "token": token, "telegram_id": id }))
+            HttpResponse::Ok().json(json!({
+                "token": token.access_token, "refresh_token": token.refresh_token,
+                "expires_in": token.expires_in, "telegram_id": id,
+            }))
         }
         None => {
             // Not yet confirmed — still pending
@@ -823,7 +1149,7 @@ pub async fn auth_claim_email(
     req: HttpRequest,
     data: web::Json<EmailLoginRequest>,
 ) -> HttpResponse {
-    let real_tg = match jwt::extract_telegram_id(&req) {
+    let real_tg = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -911,6 +1237,10 @@ pub async fn auth_claim_email(
                 .bind(real_tg).bind(synthetic_tg)
                 .execute(pool.get_ref())
                 .await;
+            let _ = sqlx::query("UPDATE passkeys SET telegram_id = $1 WHERE telegram_id = $2")
+                .bind(real_tg).bind(synthetic_tg)
+                .execute(pool.get_ref())
+                .await;
             return HttpResponse::Ok().json(json!({"status": "claimed", "merged_subscription": false}));
         }
         Err(e) => { error!("[claim_email] db fetch synth: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal"})); }
@@ -956,6 +1286,13 @@ pub async fn auth_claim_email(
         return HttpResponse::InternalServerError().json(json!({"error": "internal"}));
     }
 
+    if let Err(e) = sqlx::query("UPDATE passkeys SET telegram_id = $1 WHERE telegram_id = $2")
+        .bind(real_tg).bind(synthetic_tg)
+        .execute(&mut *tx).await {
+        error!("[claim_email] repoint passkeys: {}", e);
+        return HttpResponse::InternalServerError().json(json!({"error": "internal"}));
+    }
+
     if let Err(e) = sqlx::query("DELETE FROM users WHERE telegram_id = $1")
         .bind(synthetic_tg)
         .execute(&mut *tx).await {
@@ -969,11 +1306,7 @@ pub async fn auth_claim_email(
     }
 
     // 7. Best-effort delete from Remnawave (outside tx — non-critical).
-    let _ = HTTP_CLIENT
-        .delete(&format!("{}/users/{}", *REMNAWAVE_API_BASE, synth_uuid))
-        .headers(remnawave_headers())
-        .send()
-        .await;
+    let _ = remnawave::client().delete_user(synth_uuid).await;
 
     info!(
         "[claim_email] Merged synthetic {} (email={}) into real TG {}; sub_end={}, active={}, dev={}",
@@ -991,7 +1324,7 @@ pub async fn auth_link_email(
     req: HttpRequest,
     data: web::Json<EmailRegisterRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1168,26 +1501,17 @@ pub async fn internal_link_account(
             .await;
 
             // Update winner's Remnawave user telegramId to the real TG id
-            let expire_str = email_sub_end.unwrap().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-            let _ = HTTP_CLIENT
-                .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-                .headers(remnawave_headers())
-                .json(&json!({
-                    "uuid": email_uuid.unwrap().to_string(),
-                    "telegramId": tg_id,
-                    "status": "ACTIVE",
-                    "expireAt": expire_str
-                }))
-                .send()
-                .await;
+            let update = remnawave::UserUpdate {
+                telegram_id: Some(tg_id),
+                status: Some("ACTIVE".to_string()),
+                expire_at: email_sub_end,
+                ..remnawave::UserUpdate::new(email_uuid.unwrap())
+            };
+            let _ = remnawave::client().update_user(&update).await;
 
             // Delete loser's Remnawave user (TG's old one)
             if let Some(loser_uuid) = tg_uuid {
-                let _ = HTTP_CLIENT
-                    .delete(&format!("{}/users/{}", *REMNAWAVE_API_BASE, loser_uuid))
-                    .headers(remnawave_headers())
-                    .send()
-                    .await;
+                let _ = remnawave::client().delete_user(loser_uuid).await;
                 info!("[internal_link_account] Deleted loser Remnawave user (TG) uuid={}", loser_uuid);
             }
 
@@ -1196,11 +1520,7 @@ pub async fn internal_link_account(
     } else {
         // TG account wins — keep its sub_link; delete email's Remnawave user
         if let Some(loser_uuid) = email_uuid {
-            let _ = HTTP_CLIENT
-                .delete(&format!("{}/users/{}", *REMNAWAVE_API_BASE, loser_uuid))
-                .headers(remnawave_headers())
-                .send()
-                .await;
+            let _ = remnawave::client().delete_user(loser_uuid).await;
             info!("[internal_link_account] Deleted loser Remnawave user (email) uuid={}", loser_uuid);
         }
     }
@@ -1231,7 +1551,7 @@ pub async fn internal_link_account(
 // === User info ===
 
 pub async fn web_get_me(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1257,6 +1577,11 @@ pub async fn web_get_me(pool: web::Data<PgPool>, req: HttpRequest) -> HttpRespon
 
     let email: Option<String> = email_row.map(|r| r.get("email"));
 
+    let wallet_balance = match wallet::balance(pool.get_ref(), telegram_id).await {
+        Ok(b) => b,
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
     match user {
         Ok(Some(row)) => {
             let referrals: Option<Vec<i64>> = row.get("referrals");
@@ -1297,6 +1622,7 @@ pub async fn web_get_me(pool: web::Data<PgPool>, req: HttpRequest) -> HttpRespon
                 "email": email,
                 "first_purchase_bonus_eligible": bonus_eligible,
                 "first_purchase_bonus_days_left": bonus_days_left,
+                "wallet_balance_rub": wallet_balance,
             }))
         }
         Ok(None) => HttpResponse::NotFound().body("User not found"),
@@ -1308,8 +1634,6 @@ pub async fn web_get_me(pool: web::Data<PgPool>, req: HttpRequest) -> HttpRespon
 
 lazy_static::lazy_static! {
     static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
-    static ref REMNAWAVE_API_BASE: String = std::env::var("REMNAWAVE_API_BASE").unwrap_or_else(|_| "http://localhost:3000/api".to_string());
-    static ref REMNAWAVE_API_KEY: String = std::env::var("REMNAWAVE_API_KEY").expect("REMNAWAVE_API_KEY must be set");
     static ref PROXYAPI_KEY: String = std::env::var("PROXYAPI_KEY")
         .expect("PROXYAPI_KEY must be set");
     static ref PROXYAPI_BASE_URL: String = std::env::var("PROXYAPI_BASE_URL")
@@ -1323,16 +1647,7 @@ lazy_static::lazy_static! {
         .collect();
 }
 
-fn remnawave_headers() -> reqwest::header::HeaderMap {
-    let mut h = reqwest::header::HeaderMap::new();
-    h.insert("Authorization", format!("Bearer {}", *REMNAWAVE_API_KEY).parse().unwrap());
-    h.insert("Content-Type", "application/json".parse().unwrap());
-    h.insert("X-Forwarded-For", "127.0.0.1".parse().unwrap());
-    h.insert("X-Forwarded-Proto", "https".parse().unwrap());
-    h
-}
-
-async fn get_user_uuid(pool: &PgPool, telegram_id: i64) -> Result<String, HttpResponse> {
+async fn get_user_uuid(pool: &PgPool, telegram_id: i64) -> Result<Uuid, HttpResponse> {
     let row = sqlx::query("SELECT uuid FROM users WHERE telegram_id = $1")
         .bind(telegram_id)
         .fetch_optional(pool)
@@ -1340,12 +1655,12 @@ async fn get_user_uuid(pool: &PgPool, telegram_id: i64) -> Result<String, HttpRe
         .map_err(|e| { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) })?
         .ok_or_else(|| HttpResponse::NotFound().body("User not found"))?;
 
-    Ok(row.get::<uuid::Uuid, _>("uuid").to_string())
+    Ok(row.get::<uuid::Uuid, _>("uuid"))
 }
 
 /// Authed: personal proxy link for the logged-in website user.
 pub async fn web_get_proxy(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1372,7 +1687,7 @@ pub async fn web_get_proxy(pool: web::Data<PgPool>, req: HttpRequest) -> HttpRes
 }
 
 pub async fn web_get_devices(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1382,29 +1697,14 @@ pub async fn web_get_devices(pool: web::Data<PgPool>, req: HttpRequest) -> HttpR
         Err(resp) => return resp,
     };
 
-    let resp = HTTP_CLIENT
-        .get(&format!("{}/hwid/devices/{}", *REMNAWAVE_API_BASE, uuid))
-        .headers(remnawave_headers())
-        .send()
-        .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => {
-                    let devices = json["response"]["devices"].clone();
-                    HttpResponse::Ok().json(json!({ "devices": devices }))
-                }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
-            }
-        }
-        Ok(r) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
+    match remnawave::client().list_devices(uuid).await {
+        Ok(list) => HttpResponse::Ok().json(json!({ "devices": list.devices })),
         Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
     }
 }
 
 pub async fn web_delete_device(pool: web::Data<PgPool>, req: HttpRequest, hwid: web::Path<String>) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1414,16 +1714,8 @@ pub async fn web_delete_device(pool: web::Data<PgPool>, req: HttpRequest, hwid:
         Err(resp) => return resp,
     };
 
-    let resp = HTTP_CLIENT
-        .post(&format!("{}/hwid/devices/delete", *REMNAWAVE_API_BASE))
-        .headers(remnawave_headers())
-        .json(&json!({ "userUuid": uuid, "hwid": hwid.into_inner() }))
-        .send()
-        .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => HttpResponse::Ok().json(json!({"status": "ok"})),
-        Ok(r) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
+    match remnawave::client().delete_device(uuid, &hwid.into_inner()).await {
+        Ok(()) => HttpResponse::Ok().json(json!({"status": "ok"})),
         Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
     }
 }
@@ -1431,7 +1723,7 @@ pub async fn web_delete_device(pool: web::Data<PgPool>, req: HttpRequest, hwid:
 // === Connection check ===
 
 pub async fn web_check_connection(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1441,64 +1733,33 @@ pub async fn web_check_connection(pool: web::Data<PgPool>, req: HttpRequest) ->
         Err(resp) => return resp,
     };
 
-    let resp = HTTP_CLIENT
-        .get(&format!("{}/hwid/devices/{}", *REMNAWAVE_API_BASE, uuid))
-        .headers(remnawave_headers())
-        .send()
-        .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => {
-                    let total = json["response"]["total"].as_u64().unwrap_or(0);
-                    HttpResponse::Ok().json(json!({ "connected": total > 0 }))
-                }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
-            }
-        }
-        Ok(_) => HttpResponse::Ok().json(json!({ "connected": false })),
-        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
-    }
-}
+    match remnawave::client().list_devices(uuid).await {
+        Ok(list) => HttpResponse::Ok().json(json!({ "connected": list.total > 0 })),
+        Err(remnawave::RemnawaveError::Status(..)) => HttpResponse::Ok().json(json!({ "connected": false })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
 
 // === Subscription prices ===
 
+/// GET /web/subscription/prices — the price list in effect now (src/prices.rs), as
+/// `{ "<plan>": { "<duration>": rub } }` plus `version` and `bs_month_only`.
 pub async fn web_get_prices() -> HttpResponse {
     let bs_month_only = std::env::var("BS_MONTH_ONLY")
         .unwrap_or_else(|_| "false".to_string())
         .eq_ignore_ascii_case("true");
-    let prices = json!({
-        "bs_month_only": bs_month_only,
-        "base": {
-            "1m": std::env::var("BASE_MONTH").unwrap_or_else(|_| "150".to_string()).parse::<i64>().unwrap_or(150),
-            "3m": std::env::var("BASE_3_MONTH").unwrap_or_else(|_| "430".to_string()).parse::<i64>().unwrap_or(430),
-            "1y": std::env::var("BASE_YEAR").unwrap_or_else(|_| "1500".to_string()).parse::<i64>().unwrap_or(1500),
-        },
-        "family": {
-            "1m": std::env::var("FAMILY_MONTH").unwrap_or_else(|_| "250".to_string()).parse::<i64>().unwrap_or(250),
-            "3m": std::env::var("FAMILY_3_MONTH").unwrap_or_else(|_| "700".to_string()).parse::<i64>().unwrap_or(700),
-            "1y": std::env::var("FAMILY_YEAR").unwrap_or_else(|_| "2200".to_string()).parse::<i64>().unwrap_or(2200),
-        },
-        "bsbase": {
-            "1m": std::env::var("BSBASE_MONTH").unwrap_or_else(|_| "450".to_string()).parse::<i64>().unwrap_or(450),
-            "3m": std::env::var("BSBASE_3_MONTH").unwrap_or_else(|_| "1250".to_string()).parse::<i64>().unwrap_or(1250),
-            "1y": std::env::var("BSBASE_YEAR").unwrap_or_else(|_| "4500".to_string()).parse::<i64>().unwrap_or(4500),
-        },
-        "bsfamily": {
-            "1m": std::env::var("BSFAMILY_MONTH").unwrap_or_else(|_| "750".to_string()).parse::<i64>().unwrap_or(750),
-            "3m": std::env::var("BSFAMILY_3_MONTH").unwrap_or_else(|_| "2100".to_string()).parse::<i64>().unwrap_or(2100),
-            "1y": std::env::var("BSFAMILY_YEAR").unwrap_or_else(|_| "7500".to_string()).parse::<i64>().unwrap_or(7500),
-        }
-    });
+    let list = prices::current();
+    let mut body = json!(list.prices);
+    body["version"] = json!(list.version);
+    body["bs_month_only"] = json!(bs_month_only);
 
-    HttpResponse::Ok().json(prices)
+    HttpResponse::Ok().json(body)
 }
 
 // === Trial ===
 
 pub async fn web_activate_trial(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1540,20 +1801,17 @@ pub async fn web_activate_trial(pool: web::Data<PgPool>, req: HttpRequest) -> Ht
     let uuid = row.get::<uuid::Uuid, _>("uuid");
     let new_expire = chrono::Utc::now() + duration;
 
-    let _ = HTTP_CLIENT
-        .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .headers(remnawave_headers())
-        .json(&json!({
-            "uuid": uuid.to_string(),
-            "status": "ACTIVE",
-            "trafficLimitBytes": 0,
-            "trafficLimitStrategy": "NO_RESET",
-            "expireAt": new_expire.to_rfc3339(),
-            "hwidDeviceLimit": 2,
-            "tag": "TRIAL",
-        }))
-        .send()
-        .await;
+    let trial_plan = plans::catalog().resolve("trial");
+    let update = remnawave::UserUpdate {
+        status: Some("ACTIVE".to_string()),
+        traffic_limit_bytes: Some(trial_plan.traffic_limit()),
+        traffic_limit_strategy: Some("NO_RESET".to_string()),
+        expire_at: Some(new_expire),
+        hwid_device_limit: Some(trial_plan.device_limit),
+        tag: Some(trial_plan.tag.clone()),
+        ..remnawave::UserUpdate::new(uuid)
+    };
+    let _ = remnawave::client().update_user(&update).await;
 
     HttpResponse::Ok().json(json!({"status": "ok"}))
 }
@@ -1566,10 +1824,18 @@ pub struct CreatePaymentRequest {
     duration: String,
     promo_code: Option<String>,
     save_payment_method: Option<bool>,
+    /// Pay what the wallet covers, the rest by card.
+    use_wallet: Option<bool>,
+    /// Take exactly this many rubles from the wallet (capped by the
+    /// balance and the price); implies `use_wallet`.
+    wallet_rub: Option<i64>,
 }
 
+/// Tries at POST /v3/payments while YooKassa's answer is lost.
+const CREATE_PAYMENT_ATTEMPTS: u32 = 3;
+
 pub async fn web_create_payment(pool: web::Data<PgPool>, req: HttpRequest, data: web::Json<CreatePaymentRequest>) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1588,41 +1854,72 @@ pub async fn web_create_payment(pool: web::Data<PgPool>, req: HttpRequest, data:
     }
 
     // Get price
-    let prices = get_price_map();
-    let key = format!("{}_{}", data.tariff, data.duration);
-    let mut price = match prices.get(&key) {
-        Some(p) => *p,
+    let price_list = prices::current();
+    let mut price = match price_list.get(&data.tariff, &data.duration) {
+        Some(p) => p,
         None => return HttpResponse::BadRequest().body("Invalid tariff/duration combination"),
     };
 
-    // Apply promo discount
-    if let Some(ref code) = data.promo_code {
-        let promo = sqlx::query(
-            "SELECT discount_percent FROM promo_codes WHERE code = $1 AND is_active = true"
-        )
-        .bind(code)
-        .fetch_optional(pool.get_ref())
-        .await;
+    // Apply promo: one use is held until the webhook commits it. A discount
+    // lowers the price, bonus days go to the webhook through metadata.days.
+    let mut days = prices::duration_days(&data.duration).unwrap_or_default();
+    let mut reservation = None;
+    if let Some(code) = data.promo_code.as_deref().filter(|c| !c.is_empty()) {
+        let ttl = promo::reservation_ttl();
+        match promo::reserve(pool.get_ref(), code, telegram_id, &data.tariff, &data.duration, price, promo::PROVIDER_YOOKASSA, ttl).await {
+            Ok(Ok(r)) => {
+                price = r.effect.price_rub.unwrap_or(price);
+                days += r.effect.bonus_days;
+                reservation = Some(r.id);
+            }
+            Ok(Err(rejection)) => return HttpResponse::BadRequest().json(json!({"error": rejection.reason()})),
+            Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        }
+    }
+
+    // One key for the whole checkout: the wallet spend is posted under it
+    // and the YooKassa payment is created with it.
+    let idempotence_key = Uuid::new_v4().to_string();
 
-        if let Ok(Some(row)) = promo {
-            let discount: i32 = row.get("discount_percent");
-            price = (price as f64 * (1.0 - discount as f64 / 100.0)).round() as i64;
+    // Wallet part is taken now and given back if the payment is refused or
+    // canceled; YooKassa charges the rest.
+    let mut spend = None;
+    if data.use_wallet.unwrap_or(false) || data.wallet_rub.is_some() {
+        let wallet_rub = match wallet::balance(pool.get_ref(), telegram_id).await {
+            Ok(balance) => wallet::split(price, balance, data.wallet_rub),
+            Err(e) => {
+                error!("Internal error: {}", e);
+                release_promo_hold(pool.get_ref(), reservation).await;
+                return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+            }
+        };
+        if wallet_rub > 0 {
+            let note = format!("{} {}", data.tariff, data.duration);
+            match wallet::spend(pool.get_ref(), telegram_id, wallet_rub, &note, &idempotence_key).await {
+                Ok(posted) => spend = Some((posted.entry_id, wallet_rub)),
+                Err(e) => {
+                    release_promo_hold(pool.get_ref(), reservation).await;
+                    if let wallet::WalletError::Insufficient = e {
+                        return HttpResponse::BadRequest().json(json!({"error": "Недостаточно средств на балансе"}));
+                    }
+                    error!("Internal error: {}", e);
+                    return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+                }
+            }
         }
     }
+    if let Some((spend_id, wallet_rub)) = spend {
+        if wallet_rub == price {
+            return pay_from_wallet(pool.get_ref(), telegram_id, &data, days, price_list.version, spend_id, wallet_rub, reservation).await;
+        }
+        price -= wallet_rub;
+    }
 
-    let tariff_name = match data.tariff.as_str() {
-        "base" => "Базовый",
-        "family" => "Семейный",
-        "bsbase" => "Обход БС (Базовый)",
-        "bsfamily" => "Обход БС (Семейный)",
-        _ => "Подписка",
-    };
-    let duration_name = match data.duration.as_str() {
-        "1m" => "1 месяц",
-        "3m" => "3 месяца",
-        "1y" => "1 год",
-        _ => "",
-    };
+    let tariff_name = plans::catalog()
+        .get(&data.tariff)
+        .map(|p| p.display_name.clone())
+        .unwrap_or_else(|| "Подписка".to_string());
+    let duration_name = prices::duration_name(&data.duration).unwrap_or_default();
 
     // Create YooKassa payment (в магазине сайта, не бота)
     let (yookassa_shop_id, yookassa_secret) = web_yookassa_creds();
@@ -1644,13 +1941,8 @@ pub async fn web_create_payment(pool: web::Data<PgPool>, req: HttpRequest, data:
         .unwrap_or(false);
     let save_method = save_method && web_recurring;
 
-    // Map duration code to Russian plan name (must match webhook's subscription_mapping)
-    let plan_name = match data.duration.as_str() {
-        "1m" => "1 месяц",
-        "3m" => "3 месяца",
-        "1y" => "1 год",
-        _ => "1 месяц",
-    };
+    // Russian plan name (must match webhook's subscription_mapping)
+    let plan_name = duration_name;
 
     // Get username and email for receipt
     let username = sqlx::query_scalar::<_, Option<String>>("SELECT username FROM users WHERE telegram_id = $1")
@@ -1712,117 +2004,1175 @@ pub async fn web_create_payment(pool: web::Data<PgPool>, req: HttpRequest, data:
             "plan": plan_name,
             "duration": data.duration,
             "promo_code": data.promo_code.clone().unwrap_or_default(),
+            "price_version": price_list.version.to_string(),
+            "days": days.to_string(),
+            "promo_reservation": reservation.map(|id| id.to_string()).unwrap_or_default(),
+            "wallet_rub": spend.map(|(_, rub)| rub.to_string()).unwrap_or_default(),
+        }
+    });
+
+    // A lost answer (network, 5xx, unreadable body) is asked for again
+    // with the same key: YooKassa hands back the payment it already made
+    // instead of a second one.
+    let shop = yookassa::Shop { name: yookassa::SHOP_WEB, shop_id: yookassa_shop_id, secret: yookassa_secret };
+    let mut created = yookassa::create_payment(&HTTP_CLIENT, &shop, &idempotence_key, &payment_body).await;
+    for attempt in 1..CREATE_PAYMENT_ATTEMPTS {
+        match &created {
+            Err(yookassa::CreateError::Transient(e)) => {
+                warn!("[web_create_payment] YooKassa attempt {} failed, resending: {}", attempt, e);
+                tokio::time::sleep(std::time::Duration::from_millis(500 * u64::from(attempt))).await;
+                created = yookassa::create_payment(&HTTP_CLIENT, &shop, &idempotence_key, &payment_body).await;
+            }
+            _ => break,
+        }
+    }
+
+    match created {
+        Ok(json) => {
+            let payment_url = json["confirmation"]["confirmation_url"]
+                .as_str()
+                .unwrap_or("")
+                .to_string();
+            let payment_id = json["id"]
+                .as_str()
+                .unwrap_or("")
+                .to_string();
+            if let Some(id) = reservation {
+                if let Err(e) = promo::attach(pool.get_ref(), id, &payment_id, None).await {
+                    error!("[web_create_payment] promo hold {} -> {}: {}", id, payment_id, e);
+                }
+            }
+            let recorded = pending::record(pool.get_ref(), &pending::NewPending {
+                provider: pending::PROVIDER_YOOKASSA,
+                provider_id: &payment_id,
+                telegram_id,
+                kind: pending::KIND_SUBSCRIPTION,
+                tariff: Some(&data.tariff),
+                duration: Some(&data.duration),
+                amount_rub: price as f64,
+            }).await;
+            if let Err(e) = recorded {
+                error!("[web_create_payment] recording pending {}: {}", payment_id, e);
+            }
+            if let Some((spend_id, _)) = spend {
+                let reference = format!("yookassa:{}", payment_id);
+                if let Err(e) = wallet::attach(pool.get_ref(), spend_id, &reference).await {
+                    error!("[web_create_payment] wallet spend {} -> {}: {}", spend_id, payment_id, e);
+                }
+            }
+            HttpResponse::Ok().json(json!({
+                "payment_url": payment_url,
+                "payment_id": payment_id,
+                "wallet_rub": spend.map(|(_, rub)| rub).unwrap_or(0),
+                "amount_rub": price,
+            }))
+        }
+        Err(yookassa::CreateError::Rejected(e)) => {
+            error!("[web_create_payment] YooKassa error {}", e);
+            // Rejected outright: no payment exists that could use the hold
+            // or the wallet part.
+            release_promo_hold(pool.get_ref(), reservation).await;
+            reverse_unattached_spend(pool.get_ref(), spend).await;
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+        Err(yookassa::CreateError::Transient(e)) => {
+            // The payment may still exist, so nothing is given back here.
+            // The checkout is recorded under its key; the pending poller
+            // re-sends the request to learn the payment, then ties the hold
+            // and the wallet part to it or gives them back.
+            error!("[web_create_payment] YooKassa unreachable (key {}): {}", idempotence_key, e);
+            let recorded = pending::record_unconfirmed(pool.get_ref(), &pending::NewPending {
+                provider: pending::PROVIDER_YOOKASSA,
+                provider_id: &idempotence_key,
+                telegram_id,
+                kind: pending::KIND_SUBSCRIPTION,
+                tariff: Some(&data.tariff),
+                duration: Some(&data.duration),
+                amount_rub: price as f64,
+            }, &payment_body).await;
+            if let Err(e) = recorded {
+                error!("[web_create_payment] recording unconfirmed checkout {}: {}", idempotence_key, e);
+            }
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+    }
+}
+
+async fn reverse_unattached_spend(pool: &PgPool, spend: Option<(i64, i64)>) {
+    if let Some((spend_id, _)) = spend {
+        if let Err(e) = wallet::reverse(pool, spend_id).await {
+            error!("[web_create_payment] reversing wallet spend {}: {}", spend_id, e);
+        }
+    }
+}
+
+async fn release_promo_hold(pool: &PgPool, reservation: Option<i64>) {
+    if let Some(id) = reservation {
+        if let Err(e) = promo::release(pool, id).await {
+            error!("releasing promo hold {}: {}", id, e);
+        }
+    }
+}
+
+/// Checkout covered entirely by the wallet: credited right away, logged as
+/// a `wallet` row without an amount. The spend is given back if the credit
+/// fails.
+#[allow(clippy::too_many_arguments)]
+async fn pay_from_wallet(
+    pool: &PgPool,
+    telegram_id: i64,
+    data: &CreatePaymentRequest,
+    days: i32,
+    price_version: i32,
+    spend_id: i64,
+    wallet_rub: i64,
+    reservation: Option<i64>,
+) -> HttpResponse {
+    let reference = format!("wallet:{}", spend_id);
+    if let Err(e) = wallet::attach(pool, spend_id, &reference).await {
+        error!("[web_create_payment] wallet spend {} -> {}: {}", spend_id, reference, e);
+    }
+    let c = crate::credit::Credit {
+        idempotency_key: Some(&reference),
+        duration: Some(&data.duration),
+        external_id: Some(&reference),
+        price_version: Some(price_version),
+        metadata: Some(json!({
+            "wallet_rub": wallet_rub,
+            "promo_code": data.promo_code.clone().unwrap_or_default(),
+        })),
+        ..crate::credit::Credit::new(telegram_id, days, &data.tariff, "wallet")
+    };
+    let credited = match crate::credit::credit(pool, &c).await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("[web_create_payment] wallet checkout {} failed: {}", reference, e);
+            if let Err(e) = wallet::reverse(pool, spend_id).await {
+                error!("[web_create_payment] reversing wallet spend {}: {}", spend_id, e);
+            }
+            release_promo_hold(pool, reservation).await;
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+    };
+    if let Some(id) = reservation {
+        // Reserved a moment ago under the code's lock, and the checkout is
+        // already paid: a refusal is only logged.
+        match promo::commit(pool, promo::PROVIDER_YOOKASSA, &reference, Some(id)).await {
+            Ok(promo::Commit::Refused) => warn!("[web_create_payment] promo hold {} of {} refused", id, reference),
+            Ok(_) => {}
+            Err(e) => error!("[web_create_payment] committing promo hold {}: {}", id, e),
+        }
+    }
+    info!("[web_create_payment] {} paid {}₽ from wallet for {} {}", telegram_id, wallet_rub, data.tariff, data.duration);
+    HttpResponse::Ok().json(json!({
+        "paid_from_wallet": true,
+        "wallet_rub": wallet_rub,
+        "amount_rub": 0,
+        "subscription_end": credited.body["subscription_end"],
+    }))
+}
+
+/// Креды магазина ЮКассы для платежей С САЙТА. ЮКасса требует отдельный
+/// магазин на каждый канал продаж (бот и svoiweb.ru — разные магазины);
+/// пока YOOKASSA_WEB_* не заданы — фолбэк на ботовский магазин.
+pub(crate) fn web_yookassa_creds() -> (String, String) {
+    let shop_id = std::env::var("YOOKASSA_WEB_SHOP_ID")
+        .ok()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| std::env::var("YOOKASSA_SHOP_ID").unwrap_or_default());
+    let secret = std::env::var("YOOKASSA_WEB_SECRET_KEY")
+        .ok()
+        .filter(|s| !s.trim().is_empty())
+        .unwrap_or_else(|| std::env::var("YOOKASSA_SECRET_KEY").unwrap_or_default());
+    (shop_id, secret)
+}
+
+// === Payment status ===
+
+pub async fn web_payment_status(pool: web::Data<PgPool>, payment_id: web::Path<String>, req: HttpRequest) -> HttpResponse {
+    // Verify auth
+    if jwt::extract_telegram_id(&req).await.is_err() {
+        return HttpResponse::Unauthorized().body("Unauthorized");
+    }
+    let payment_id = payment_id.into_inner();
+
+    let (yookassa_shop_id, yookassa_secret) = web_yookassa_creds();
+
+    let resp = HTTP_CLIENT
+        .get(format!("https://api.yookassa.ru/v3/payments/{}", payment_id))
+        .basic_auth(&yookassa_shop_id, Some(&yookassa_secret))
+        .send()
+        .await;
+
+    match resp {
+        Ok(r) if r.status().is_success() => {
+            match r.json::<serde_json::Value>().await {
+                Ok(json) => {
+                    let status = json["status"].as_str().unwrap_or("unknown").to_string();
+                    // The site polls this while the user is on the payment
+                    // page, so the checkout row is usually settled here first.
+                    if let Some(local) = pending::yookassa_status(&status) {
+                        if let Err(e) = pending::resolve(pool.get_ref(), pending::PROVIDER_YOOKASSA, &payment_id, local).await {
+                            error!("[web_payment_status] marking {} {}: {}", payment_id, local, e);
+                        }
+                    }
+                    HttpResponse::Ok().json(json!({ "status": status }))
+                }
+                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+            }
+        }
+        Ok(r) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+// === Payment history ===
+
+#[derive(Deserialize)]
+pub struct PaymentHistoryQuery {
+    pub page: Option<i64>,
+    pub page_size: Option<i64>,
+}
+
+/// GET /web/me/payments — the user's ledger rows (payments, bonuses, trial,
+/// referral rewards), newest first.
+pub async fn web_my_payments(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    q: web::Query<PaymentHistoryQuery>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    // page_size <= 100, so the offset can't overflow.
+    let page = q.page.unwrap_or(1).clamp(1, i64::MAX / 100);
+    let page_size = q.page_size.unwrap_or(20).clamp(1, 100);
+
+    match crate::receipts::page(pool.get_ref(), telegram_id, page_size, (page - 1) * page_size).await {
+        Ok((rows, total)) => HttpResponse::Ok().json(json!({
+            "items": rows.iter().map(crate::receipts::to_json).collect::<Vec<_>>(),
+            "total": total, "page": page, "page_size": page_size,
+        })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// GET /web/me/payments/{id}/receipt — printable HTML receipt for a row
+/// that moved money.
+pub async fn web_my_payment_receipt(pool: web::Data<PgPool>, req: HttpRequest, id: web::Path<i64>) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    let entry = match crate::receipts::entry(pool.get_ref(), telegram_id, id.into_inner()).await {
+        Ok(Some(e)) if e.amount_rub.is_some() => e,
+        Ok(_) => return HttpResponse::NotFound().json(json!({"error": "receipt not found"})),
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+
+    let account = sqlx::query_as::<_, (Option<String>, Option<String>)>(
+        "SELECT uc.email, u.username FROM users u \
+         LEFT JOIN user_credentials uc ON uc.telegram_id = u.telegram_id WHERE u.telegram_id = $1",
+    )
+    .bind(telegram_id)
+    .fetch_optional(pool.get_ref())
+    .await
+    .ok()
+    .flatten();
+    let account = match account {
+        Some((Some(email), _)) => email,
+        Some((None, Some(username))) => format!("@{}", username),
+        _ => telegram_id.to_string(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(crate::receipts::render_html(&entry, &account))
+}
+
+// === Wallet ===
+
+/// GET /web/me/wallet — balance and ledger rows, newest first.
+pub async fn web_my_wallet(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    q: web::Query<PaymentHistoryQuery>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    // page_size <= 100, so the offset can't overflow.
+    let page = q.page.unwrap_or(1).clamp(1, i64::MAX / 100);
+    let page_size = q.page_size.unwrap_or(20).clamp(1, 100);
+
+    let balance = match wallet::balance(pool.get_ref(), telegram_id).await {
+        Ok(b) => b,
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    match wallet::history(pool.get_ref(), telegram_id, page_size, (page - 1) * page_size).await {
+        Ok((rows, total)) => HttpResponse::Ok().json(json!({
+            "balance_rub": balance,
+            "items": rows, "total": total, "page": page, "page_size": page_size,
+        })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WalletTopupRequest {
+    pub amount_rub: i64,
+}
+
+/// POST /web/wallet/topup — YooKassa payment (site shop) that credits the
+/// wallet when the webhook sees it succeed.
+pub async fn web_wallet_topup(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    body: web::Json<WalletTopupRequest>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    let amount = body.amount_rub;
+    if !(wallet::MIN_TOPUP_RUB..=wallet::MAX_TOPUP_RUB).contains(&amount) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Сумма пополнения — от {} до {} ₽", wallet::MIN_TOPUP_RUB, wallet::MAX_TOPUP_RUB)
+        }));
+    }
+    info!("[web_wallet_topup] telegram_id={}, amount={}", telegram_id, amount);
+
+    let exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM users WHERE telegram_id IN ($1, -$1))")
+        .bind(telegram_id)
+        .fetch_one(pool.get_ref())
+        .await;
+    match exists {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().json(json!({"error": "user not found"})),
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+
+    let description = "SvoiVPN: пополнение баланса [Сайт]";
+    let receipt_email = std::env::var("RECEIPT_EMAIL").unwrap_or_else(|_| "receipt@svoi-connect.ru".to_string());
+    let payment_body = json!({
+        "amount": {"value": format!("{}.00", amount), "currency": "RUB"},
+        "confirmation": {"type": "redirect", "return_url": "https://svoiweb.ru/?payment_status=success"},
+        "capture": true,
+        "description": description,
+        "receipt": {
+            "customer": {"email": receipt_email},
+            "items": [{
+                "description": description,
+                "quantity": "1.00",
+                "amount": {"value": format!("{}.00", amount), "currency": "RUB"},
+                "vat_code": 1,
+                "payment_subject": "payment",
+                "payment_mode": "full_prepayment"
+            }]
+        },
+        "metadata": {
+            "telegram_id": telegram_id.to_string(),
+            "kind": yookassa::KIND_WALLET_TOPUP,
+        }
+    });
+
+    let (shop_id, secret) = web_yookassa_creds();
+    let shop = yookassa::Shop { name: yookassa::SHOP_WEB, shop_id, secret };
+    match yookassa::create_payment(&HTTP_CLIENT, &shop, &Uuid::new_v4().to_string(), &payment_body).await {
+        Ok(p) => {
+            let payment_id = p["id"].as_str().unwrap_or_default();
+            let recorded = pending::record(pool.get_ref(), &pending::NewPending {
+                provider: pending::PROVIDER_YOOKASSA,
+                provider_id: payment_id,
+                telegram_id,
+                kind: pending::KIND_WALLET_TOPUP,
+                tariff: None,
+                duration: None,
+                amount_rub: amount as f64,
+            }).await;
+            if let Err(e) = recorded {
+                error!("[web_wallet_topup] recording pending {}: {}", payment_id, e);
+            }
+            HttpResponse::Ok().json(json!({
+                "payment_url": p["confirmation"]["confirmation_url"].as_str().unwrap_or_default(),
+                "payment_id": payment_id,
+            }))
+        }
+        Err(e) => {
+            error!("[web_wallet_topup] YooKassa error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+    }
+}
+
+fn wallet_error_response(ctx: &str, e: wallet::WalletError) -> HttpResponse {
+    match e {
+        wallet::WalletError::UserNotFound => HttpResponse::NotFound().json(json!({"error": "user not found"})),
+        wallet::WalletError::Insufficient => HttpResponse::BadRequest().json(json!({"error": e.to_string()})),
+        wallet::WalletError::KeyConflict => HttpResponse::Conflict().json(json!({"error": e.to_string()})),
+        wallet::WalletError::Db(e) => {
+            error!("[{}] {}", ctx, e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InternalWalletCreditRequest {
+    pub telegram_id: i64,
+    pub amount_rub: f64,
+    /// referral | compensation
+    pub kind: String,
+    /// Referral source (`ref_bonus_parent`, `ref_milestone_5`, ...) or the
+    /// referred user's id.
+    pub reference: Option<String>,
+    pub note: Option<String>,
+    pub idempotency_key: Option<String>,
+}
+
+/// POST /internal/wallet/credit — referral rewards and support
+/// compensation from the bot, in rubles instead of days.
+pub async fn internal_wallet_credit(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    body: web::Json<InternalWalletCreditRequest>,
+) -> HttpResponse {
+    if let Some(resp) = check_internal_key(&req) { return resp; }
+    if body.kind != wallet::KIND_REFERRAL && body.kind != wallet::KIND_COMPENSATION {
+        return HttpResponse::BadRequest().json(json!({"error": "kind must be referral or compensation"}));
+    }
+    if body.amount_rub <= 0.0 || !body.amount_rub.is_finite() {
+        return HttpResponse::BadRequest().json(json!({"error": "amount_rub must be positive"}));
+    }
+    let key = body.idempotency_key.as_deref().filter(|k| !k.is_empty());
+    if key.is_some_and(|k| k.len() > 128) {
+        return HttpResponse::BadRequest().json(json!({"error": "idempotency_key too long"}));
+    }
+    info!("[internal_wallet_credit] tg={} {} +{}₽ ({:?})", body.telegram_id, body.kind, body.amount_rub, body.reference);
+
+    let p = wallet::Posting {
+        reference: body.reference.as_deref(),
+        note: body.note.as_deref(),
+        idempotency_key: key,
+        ..wallet::Posting::new(body.telegram_id, body.amount_rub, &body.kind)
+    };
+    match wallet::post(pool.get_ref(), &p).await {
+        Ok(posted) => HttpResponse::Ok().json(json!({
+            "entry_id": posted.entry_id,
+            "balance_rub": posted.balance_rub,
+            "duplicate": posted.replayed,
+        })),
+        Err(e) => wallet_error_response("internal_wallet_credit", e),
+    }
+}
+
+// === Sessions ===
+
+/// GET /web/me/sessions — live sessions, most recently used first; the
+/// caller's own one is marked `current`.
+pub async fn web_my_sessions(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let auth = match jwt::authenticate(&req).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    match sessions::list(pool.get_ref(), auth.telegram_id).await {
+        Ok(rows) => {
+            let items: Vec<_> = rows
+                .into_iter()
+                .map(|s| json!({
+                    "id": s.id,
+                    "user_agent": s.user_agent,
+                    "ip": s.ip,
+                    "created_at": s.created_at,
+                    "last_used_at": s.last_used_at,
+                    "current": auth.session_id == Some(s.id),
+                }))
+                .collect();
+            HttpResponse::Ok().json(json!({ "items": items }))
+        }
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// DELETE /web/me/sessions/{id} — sign one session out (the current one
+/// included).
+pub async fn web_revoke_session(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    let id = path.into_inner();
+    match sessions::revoke(pool.get_ref(), telegram_id, id, sessions::REVOKED_BY_USER).await {
+        Ok(true) => {
+            info!("[web_revoke_session] telegram_id={}, session={}", telegram_id, id);
+            HttpResponse::Ok().json(json!({"status": "ok"}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"error": "session not found"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RevokeAllSessionsQuery {
+    /// Also remove the passkeys added in the sessions being ended.
+    delete_passkeys: Option<bool>,
+}
+
+/// DELETE /web/me/sessions — sign out everywhere, this device included.
+/// `passkeys` are the ones added in the sessions just ended; the site lists
+/// them beforehand (GET /web/me/passkeys, `session_id`) and offers to drop
+/// them along with the sessions via `?delete_passkeys=true`.
+pub async fn web_revoke_all_sessions(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    q: web::Query<RevokeAllSessionsQuery>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    let added = match passkeys::added_in_live_sessions(pool.get_ref(), telegram_id).await {
+        Ok(p) => p,
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    let n = match sessions::revoke_all(pool.get_ref(), telegram_id, sessions::REVOKED_BY_USER).await {
+        Ok(n) => n,
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    let delete_passkeys = q.delete_passkeys.unwrap_or(false);
+    if delete_passkeys {
+        for p in &added {
+            if let Err(e) = passkeys::delete(pool.get_ref(), telegram_id, p.id).await {
+                error!("[web_revoke_all_sessions] deleting passkey {}: {}", p.id, e);
+                return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+            }
+        }
+    }
+    info!("[web_revoke_all_sessions] telegram_id={}, revoked={}, passkeys={} (deleted: {})",
+        telegram_id, n, added.len(), delete_passkeys);
+    HttpResponse::Ok().json(json!({
+        "status": "ok", "revoked": n,
+        "passkeys": added, "passkeys_deleted": delete_passkeys,
+    }))
+}
+
+// === Two-factor auth ===
+
+/// Email of the caller's email/password login; 2FA protects that login,
+/// so accounts without one get a 400.
+async fn totp_email(pool: &PgPool, req: &HttpRequest) -> Result<String, HttpResponse> {
+    let telegram_id = jwt::extract_telegram_id(req).await?;
+    match sqlx::query_scalar::<_, String>("SELECT email FROM user_credentials WHERE telegram_id = $1")
+        .bind(telegram_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(Some(email)) => Ok(email),
+        Ok(None) => Err(HttpResponse::BadRequest().json(json!({
+            "error": "Двухфакторная аутентификация доступна для входа по email. Сначала привяжите email."
+        }))),
+        Err(e) => { error!("Internal error: {}", e); Err(HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))) },
+    }
+}
+
+/// GET /web/me/totp — whether 2FA is on and how many recovery codes are
+/// left.
+pub async fn web_totp_status(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    let email = match totp_email(pool.get_ref(), &req).await {
+        Ok(e) => e,
+        Err(resp) => return resp,
+    };
+    match totp::recovery_codes_left(pool.get_ref(), &email).await {
+        Ok(left) => HttpResponse::Ok().json(json!({ "enabled": left.is_some(), "recovery_codes_left": left })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// POST /web/me/totp/setup — new secret for the authenticator app, as an
+/// otpauth URI and its QR (SVG). Nothing changes until it's confirmed;
+/// calling again replaces an unconfirmed secret.
+pub async fn web_totp_setup(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    let email = match totp_email(pool.get_ref(), &req).await {
+        Ok(e) => e,
+        Err(resp) => return resp,
+    };
+    let secret = match totp::begin_setup(pool.get_ref(), &email).await {
+        Ok(totp::Setup::Started(secret)) => secret,
+        Ok(totp::Setup::AlreadyEnabled) => return HttpResponse::Conflict().json(json!({
+            "error": "Двухфакторная аутентификация уже включена"
+        })),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    let uri = totp::otpauth_uri(&secret, &email);
+    let qr_svg = match totp::qr_svg(&uri) {
+        Ok(svg) => svg,
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    HttpResponse::Ok().json(json!({ "secret": secret, "otpauth_uri": uri, "qr_svg": qr_svg }))
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    code: String,
+}
+
+/// POST /web/me/totp/confirm — first code from the app; turns 2FA on and
+/// returns the recovery codes (shown only here).
+pub async fn web_totp_confirm(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<TotpCodeRequest>,
+) -> HttpResponse {
+    let email = match totp_email(pool.get_ref(), &req).await {
+        Ok(e) => e,
+        Err(resp) => return resp,
+    };
+    match totp::confirm(pool.get_ref(), &email, &data.code).await {
+        Ok(totp::Confirm::Enabled(codes)) => {
+            info!("[web_totp_confirm] 2FA enabled for {}", email);
+            HttpResponse::Ok().json(json!({ "status": "ok", "recovery_codes": codes }))
+        }
+        Ok(totp::Confirm::WrongCode) => HttpResponse::BadRequest().json(json!({"error": "Неверный код"})),
+        Ok(totp::Confirm::NotStarted) => HttpResponse::Conflict().json(json!({
+            "error": "Сначала начните настройку двухфакторной аутентификации"
+        })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// POST /web/me/totp/disable — turn 2FA off; takes a TOTP or recovery
+/// code, so a stolen session alone can't do it.
+pub async fn web_totp_disable(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<TotpCodeRequest>,
+) -> HttpResponse {
+    let email = match totp_email(pool.get_ref(), &req).await {
+        Ok(e) => e,
+        Err(resp) => return resp,
+    };
+    match totp::enabled(pool.get_ref(), &email).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Conflict().json(json!({"error": "Двухфакторная аутентификация не включена"})),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+    match totp::verify(pool.get_ref(), &email, &data.code).await {
+        Ok(totp::Check::Passed) => {}
+        Ok(totp::Check::WrongCode) => return HttpResponse::BadRequest().json(json!({"error": "Неверный код"})),
+        Ok(totp::Check::Locked) => return HttpResponse::TooManyRequests().json(json!({
+            "error": "Слишком много неверных кодов. Попробуйте через 15 минут."
+        })),
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+    match totp::disable(pool.get_ref(), &email).await {
+        Ok(_) => {
+            info!("[web_totp_disable] 2FA disabled for {}", email);
+            HttpResponse::Ok().json(json!({"status": "ok"}))
+        }
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+// === Passkeys ===
+
+fn passkey_error_response(ctx: &str, e: passkeys::PasskeyError) -> HttpResponse {
+    match e {
+        passkeys::PasskeyError::Invalid(reason) => {
+            warn!("[{}] rejected: {}", ctx, reason);
+            HttpResponse::BadRequest().json(json!({"error": "Не удалось проверить ключ доступа"}))
+        }
+        passkeys::PasskeyError::ChallengeExpired => HttpResponse::BadRequest().json(json!({
+            "error": "Время ожидания истекло. Попробуйте ещё раз."
+        })),
+        passkeys::PasskeyError::Duplicate => HttpResponse::Conflict().json(json!({"error": "Этот ключ доступа уже добавлен"})),
+        passkeys::PasskeyError::UnknownCredential => HttpResponse::Unauthorized().json(json!({
+            "error": "Ключ доступа не найден. Войдите другим способом."
+        })),
+        passkeys::PasskeyError::Db(e) => {
+            error!("[{}] {}", ctx, e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+    }
+}
+
+/// A session may add a passkey without a 2FA code this long after signing
+/// in.
+const PASSKEY_STEP_UP_SECS: i64 = 10 * 60;
+
+#[derive(Deserialize)]
+pub struct PasskeyStepUpRequest {
+    /// Current TOTP code; required when 2FA is on.
+    totp_code: Option<String>,
+}
+
+/// A passkey is a new way in, so a token alone doesn't get to add one:
+/// with 2FA on the current code is asked for, otherwise the session must
+/// have signed in (by any method, an email code included) within
+/// PASSKEY_STEP_UP_SECS. `step_up` in the 403 tells the site which to ask
+/// for. None when the caller may go on.
+async fn passkey_step_up(pool: &PgPool, auth: &jwt::Auth, totp_code: Option<&str>) -> Option<HttpResponse> {
+    let email = match sqlx::query_scalar::<_, String>("SELECT email FROM user_credentials WHERE telegram_id = $1")
+        .bind(auth.telegram_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(e) => e,
+        Err(e) => return Some({ error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }),
+    };
+    let totp_email = match email {
+        Some(email) => match totp::enabled(pool, &email).await {
+            Ok(true) => Some(email),
+            Ok(false) => None,
+            Err(e) => return Some({ error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }),
+        },
+        None => None,
+    };
+    if let Some(email) = totp_email {
+        let Some(code) = totp_code.filter(|c| !c.trim().is_empty()) else {
+            return Some(HttpResponse::Forbidden().json(json!({
+                "error": "Введите код из приложения-аутентификатора", "step_up": "totp",
+            })));
+        };
+        return match totp::verify(pool, &email, code).await {
+            Ok(totp::Check::Passed) => None,
+            Ok(totp::Check::WrongCode) => Some(HttpResponse::BadRequest().json(json!({"error": "Неверный код"}))),
+            Ok(totp::Check::Locked) => Some(HttpResponse::TooManyRequests().json(json!({
+                "error": "Слишком много неверных кодов. Попробуйте через 15 минут."
+            }))),
+            Err(e) => Some({ error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }),
+        };
+    }
+    let recent = match auth.session_id {
+        Some(id) => match sessions::opened_within(pool, id, PASSKEY_STEP_UP_SECS).await {
+            Ok(r) => r,
+            Err(e) => return Some({ error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }),
+        },
+        None => false,
+    };
+    if recent {
+        None
+    } else {
+        Some(HttpResponse::Forbidden().json(json!({
+            "error": "Чтобы добавить ключ доступа, войдите в аккаунт заново", "step_up": "login",
+        })))
+    }
+}
+
+/// POST /web/auth/passkey/register/options — creation options for
+/// `navigator.credentials.create()`; the caller must be signed in and pass
+/// [`passkey_step_up`]. The challenge is bound to the user and used once,
+/// so it is what lets POST /web/auth/passkey/register through.
+pub async fn web_passkey_register_options(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: Option<web::Json<PasskeyStepUpRequest>>,
+) -> HttpResponse {
+    let auth = match jwt::authenticate(&req).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    let totp_code = data.as_ref().and_then(|d| d.totp_code.as_deref());
+    if let Some(resp) = passkey_step_up(pool.get_ref(), &auth, totp_code).await {
+        return resp;
+    }
+    let telegram_id = auth.telegram_id;
+    // What the browser shows for the account: email, else @username.
+    let user_name: Option<String> = match sqlx::query_scalar(
+        "SELECT COALESCE((SELECT email FROM user_credentials WHERE telegram_id = $1),                          (SELECT '@' || username FROM users WHERE telegram_id = $1))"
+    )
+    .bind(telegram_id)
+    .fetch_one(pool.get_ref())
+    .await
+    {
+        Ok(name) => name,
+        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    let user_name = user_name.unwrap_or_else(|| format!("id{}", telegram_id));
+    match passkeys::registration_options(pool.get_ref(), telegram_id, &user_name).await {
+        Ok(options) => HttpResponse::Ok().json(json!({ "publicKey": options })),
+        Err(e) => passkey_error_response("web_passkey_register_options", e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegisterRequest {
+    credential: passkeys::RegistrationCredential,
+    /// Shown in the passkey list, e.g. "iPhone".
+    name: Option<String>,
+}
+
+/// POST /web/auth/passkey/register — store the passkey just created,
+/// against the challenge from the step-up checked options call.
+pub async fn web_passkey_register(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<PasskeyRegisterRequest>,
+) -> HttpResponse {
+    let auth = match jwt::authenticate(&req).await {
+        Ok(a) => a,
+        Err(resp) => return resp,
+    };
+    let telegram_id = auth.telegram_id;
+    let name = data.name.as_deref().unwrap_or("");
+    match passkeys::register(pool.get_ref(), telegram_id, auth.session_id, &data.credential, name).await {
+        Ok(passkey) => {
+            info!("[web_passkey_register] telegram_id={}, passkey={}", telegram_id, passkey.id);
+            HttpResponse::Ok().json(passkey)
+        }
+        Err(e) => passkey_error_response("web_passkey_register", e),
+    }
+}
+
+/// POST /web/auth/passkey/login/options — request options for
+/// `navigator.credentials.get()`.
+pub async fn auth_passkey_login_options(pool: web::Data<PgPool>) -> HttpResponse {
+    match passkeys::login_options(pool.get_ref()).await {
+        Ok(options) => HttpResponse::Ok().json(json!({ "publicKey": options })),
+        Err(e) => passkey_error_response("auth_passkey_login_options", e),
+    }
+}
+
+/// POST /web/auth/passkey/login — sign in with a passkey assertion; same
+/// response as the other logins. No TOTP step: the assertion carries user
+/// verification (passkeys.rs), which makes it two factors on its own.
+pub async fn auth_passkey_login(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<passkeys::AssertionCredential>,
+) -> HttpResponse {
+    let telegram_id = match passkeys::authenticate(pool.get_ref(), &data).await {
+        Ok(id) => id,
+        Err(e) => return passkey_error_response("auth_passkey_login", e),
+    };
+    let token = match jwt::issue_token(pool.get_ref(), &req, telegram_id).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Internal error: {}", e);
+            return HttpResponse::InternalServerError().json(json!({"error": "Ошибка авторизации. Попробуйте позже."}));
+        }
+    };
+    info!("[auth_passkey_login] Passkey login: id={}", telegram_id);
+    HttpResponse::Ok().json(json!({
+        "token": token.access_token, "refresh_token": token.refresh_token,
+        "expires_in": token.expires_in, "telegram_id": telegram_id,
+    }))
+}
+
+/// GET /web/me/passkeys — the caller's passkeys, newest first.
+pub async fn web_my_passkeys(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    match passkeys::list(pool.get_ref(), telegram_id).await {
+        Ok(items) => HttpResponse::Ok().json(json!({ "items": items })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// DELETE /web/me/passkeys/{id} — remove a passkey.
+pub async fn web_delete_passkey(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    path: web::Path<i64>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    let id = path.into_inner();
+    match passkeys::delete(pool.get_ref(), telegram_id, id).await {
+        Ok(true) => {
+            info!("[web_delete_passkey] telegram_id={}, passkey={}", telegram_id, id);
+            HttpResponse::Ok().json(json!({"status": "ok"}))
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({"error": "passkey not found"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+// === Payment webhooks ===
+
+/// POST /webhooks/yookassa — `payment.succeeded` and `payment.canceled`
+/// notifications from both shops. The body is only used for the payment id:
+/// the payment is re-fetched from YooKassa and acted on from that copy;
+/// credits are keyed by payment
+/// id so retries and the bot's own handling credit it once. Non-2xx makes
+/// YooKassa retry, so transient failures return 500 and final ones 200.
+pub async fn yookassa_webhook(pool: web::Data<PgPool>, body: web::Json<serde_json::Value>) -> HttpResponse {
+    let event = body["event"].as_str().unwrap_or_default();
+    let payment_id = match body["object"]["id"].as_str() {
+        Some(id) if !id.is_empty() => id.to_string(),
+        _ => return HttpResponse::BadRequest().json(json!({"error": "object.id required"})),
+    };
+    if event == "payment.canceled" {
+        // The body is unsigned: the cancel is confirmed with YooKassa before
+        // the promo hold and the wallet part of the checkout go back.
+        match confirm_canceled(body["object"]["recipient"]["account_id"].as_str(), &payment_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("[yookassa_webhook] cancel of {} not confirmed, ignored", payment_id);
+                return HttpResponse::Ok().json(json!({"status": "ignored"}));
+            }
+            Err(resp) => return resp,
+        }
+        if let Err(e) = promo::release_payment(pool.get_ref(), promo::PROVIDER_YOOKASSA, &payment_id).await {
+            error!("[yookassa_webhook] releasing promo hold of {}: {}", payment_id, e);
+        }
+        if let Err(e) = pending::resolve(pool.get_ref(), pending::PROVIDER_YOOKASSA, &payment_id, pending::STATUS_CANCELED).await {
+            error!("[yookassa_webhook] marking {} canceled: {}", payment_id, e);
+        }
+        if let Err(e) = wallet::reverse_reference(pool.get_ref(), &format!("yookassa:{}", payment_id)).await {
+            error!("[yookassa_webhook] reversing wallet spend of {}: {}", payment_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+        return HttpResponse::Ok().json(json!({"status": "ok"}));
+    }
+    if event != "payment.succeeded" {
+        info!("[yookassa_webhook] ignoring {} for {}", event, payment_id);
+        return HttpResponse::Ok().json(json!({"status": "ignored"}));
+    }
+
+    let account_id = body["object"]["recipient"]["account_id"].as_str();
+    let mut verified = None;
+    for shop in yookassa::shops_for(account_id, web_yookassa_creds()) {
+        match yookassa::fetch_payment(&HTTP_CLIENT, &shop, &payment_id).await {
+            Ok(p) => {
+                verified = Some((shop.name, p));
+                break;
+            }
+            Err(yookassa::FetchError::NotFound) => continue,
+            Err(e) => {
+                error!("[yookassa_webhook] fetch {} from {} shop failed: {}", payment_id, shop.name, e);
+                return HttpResponse::InternalServerError().json(json!({"error": "verification failed"}));
+            }
+        }
+    }
+    let (shop, payment) = match verified {
+        Some(v) => v,
+        None => {
+            warn!("[yookassa_webhook] payment {} not found in any shop", payment_id);
+            return HttpResponse::BadRequest().json(json!({"error": "unknown payment"}));
+        }
+    };
+    // Status of the verified copy, not the event: a "succeeded" body for a
+    // payment that isn't leaves the checkout open.
+    if let Some(local) = payment["status"].as_str().and_then(pending::yookassa_status) {
+        if let Err(e) = pending::resolve(pool.get_ref(), pending::PROVIDER_YOOKASSA, &payment_id, local).await {
+            error!("[yookassa_webhook] marking {} {}: {}", payment_id, local, e);
+        }
+    }
+    match yookassa::parse_topup(&payment) {
+        Ok(Some(topup)) => return credit_topup(pool.get_ref(), &topup).await,
+        Ok(None) => {}
+        Err(e) => {
+            warn!("[yookassa_webhook] not crediting top-up {}: {}", payment_id, e);
+            return HttpResponse::Ok().json(json!({"status": "ignored"}));
+        }
+    }
+    let order = match yookassa::parse_paid_order(&payment) {
+        Ok(o) => o,
+        Err(e) => {
+            warn!("[yookassa_webhook] not crediting {}: {}", payment_id, e);
+            return HttpResponse::Ok().json(json!({"status": "ignored"}));
+        }
+    };
+    info!("[yookassa_webhook] {} ({} shop): tg={} {} {} {}₽",
+        payment_id, shop, order.telegram_id, order.tariff, order.duration, order.amount_rub);
+
+    // The promo goes first: a refused hold means no bonus days.
+    let mut days = order.days;
+    let mut metadata = order.metadata.clone();
+    match promo::commit(pool.get_ref(), promo::PROVIDER_YOOKASSA, &payment_id, order.promo_reservation).await {
+        Ok(promo::Commit::Refused) => {
+            warn!("[yookassa_webhook] promo hold of {} refused, crediting without it", payment_id);
+            days = promo::refused_days(&order.duration, days, &mut metadata);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("[yookassa_webhook] committing promo hold of {}: {}", payment_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
         }
-    });
+    }
+    let c = crate::credit::Credit {
+        idempotency_key: Some(&order.payment_id),
+        amount_rub: Some(order.amount_rub),
+        duration: Some(&order.duration),
+        external_id: Some(&order.payment_id),
+        price_version: order.price_version,
+        metadata: Some(metadata),
+        ..crate::credit::Credit::new(order.telegram_id, days, &order.tariff, "yookassa")
+    };
+    let credited = match crate::credit::credit(pool.get_ref(), &c).await {
+        Ok(c) => c,
+        Err(crate::credit::CreditError::UserNotFound) => {
+            error!("[yookassa_webhook] paid {} for unknown user {}", payment_id, order.telegram_id);
+            return HttpResponse::Ok().json(json!({"status": "user not found"}));
+        }
+        Err(crate::credit::CreditError::KeyConflict) => {
+            error!("[yookassa_webhook] {} already credited to another user", payment_id);
+            return HttpResponse::Ok().json(json!({"status": "conflict"}));
+        }
+        Err(e) => {
+            error!("[yookassa_webhook] credit {} failed: {}", payment_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+    };
+    if credited.replayed {
+        info!("[yookassa_webhook] {} already credited", payment_id);
+        return HttpResponse::Ok().json(json!({"status": "ok", "duplicate": true}));
+    }
 
-    let resp = HTTP_CLIENT
-        .post("https://api.yookassa.ru/v3/payments")
-        .basic_auth(&yookassa_shop_id, Some(&yookassa_secret))
-        .header("Idempotence-Key", uuid::Uuid::new_v4().to_string())
-        .header("Content-Type", "application/json")
-        .json(&payment_body)
-        .send()
+    // Card saved for auto-renew: remember which shop issued it, only that
+    // shop can charge it later.
+    if let Some(m) = &order.saved_method {
+        let resolved_id = credited.body["telegram_id"].as_i64().unwrap_or(order.telegram_id);
+        let saved = sqlx::query(
+            "UPDATE users SET payment_method_id = $1, card_last4 = $2, payment_method_shop = $3, \
+             auto_renew_plan = $4, auto_renew_duration = $5 WHERE telegram_id = $6",
+        )
+        .bind(&m.id)
+        .bind(m.card_last4.as_deref())
+        .bind(shop)
+        .bind(&order.tariff)
+        .bind(&order.duration)
+        .bind(resolved_id)
+        .execute(pool.get_ref())
         .await;
-
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => {
-                    let payment_url = json["confirmation"]["confirmation_url"]
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-                    let payment_id = json["id"]
-                        .as_str()
-                        .unwrap_or("")
-                        .to_string();
-                    HttpResponse::Ok().json(json!({
-                        "payment_url": payment_url,
-                        "payment_id": payment_id,
-                    }))
-                }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
-            }
-        }
-        Ok(r) => {
-            let status = r.status();
-            let body = r.text().await.unwrap_or_default();
-            error!("[web_create_payment] YooKassa error {}: {}", status, body);
-            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        if let Err(e) = saved {
+            error!("[yookassa_webhook] saving payment method for {} failed: {}", resolved_id, e);
         }
-        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(serde_json::json!({"error": "internal server error"})) },
     }
-}
 
-/// Креды магазина ЮКассы для платежей С САЙТА. ЮКасса требует отдельный
-/// магазин на каждый канал продаж (бот и svoiweb.ru — разные магазины);
-/// пока YOOKASSA_WEB_* не заданы — фолбэк на ботовский магазин.
-fn web_yookassa_creds() -> (String, String) {
-    let shop_id = std::env::var("YOOKASSA_WEB_SHOP_ID")
-        .ok()
-        .filter(|s| !s.trim().is_empty())
-        .unwrap_or_else(|| std::env::var("YOOKASSA_SHOP_ID").unwrap_or_default());
-    let secret = std::env::var("YOOKASSA_WEB_SECRET_KEY")
-        .ok()
-        .filter(|s| !s.trim().is_empty())
-        .unwrap_or_else(|| std::env::var("YOOKASSA_SECRET_KEY").unwrap_or_default());
-    (shop_id, secret)
+    HttpResponse::Ok().json(json!({"status": "ok"}))
 }
 
-fn get_price_map() -> HashMap<String, i64> {
-    let mut m = HashMap::new();
-    let pairs = [
-        ("base_1m", "BASE_MONTH", 150),
-        ("base_3m", "BASE_3_MONTH", 430),
-        ("base_1y", "BASE_YEAR", 1500),
-        ("family_1m", "FAMILY_MONTH", 250),
-        ("family_3m", "FAMILY_3_MONTH", 700),
-        ("family_1y", "FAMILY_YEAR", 2200),
-        ("bsbase_1m", "BSBASE_MONTH", 450),
-        ("bsbase_3m", "BSBASE_3_MONTH", 1250),
-        ("bsbase_1y", "BSBASE_YEAR", 4500),
-        ("bsfamily_1m", "BSFAMILY_MONTH", 750),
-        ("bsfamily_3m", "BSFAMILY_3_MONTH", 2100),
-        ("bsfamily_1y", "BSFAMILY_YEAR", 7500),
-    ];
-    for (key, env, default) in pairs {
-        let val = std::env::var(env)
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(default);
-        m.insert(key.to_string(), val);
-    }
-    m
+/// Whether YooKassa reports `payment_id` as canceled. `Err` is the
+/// response to send when it couldn't be asked (YooKassa retries on 500).
+async fn confirm_canceled(account_id: Option<&str>, payment_id: &str) -> Result<bool, HttpResponse> {
+    for shop in yookassa::shops_for(account_id, web_yookassa_creds()) {
+        match yookassa::fetch_payment(&HTTP_CLIENT, &shop, payment_id).await {
+            Ok(p) => return Ok(p["status"] == "canceled"),
+            Err(yookassa::FetchError::NotFound) => continue,
+            Err(e) => {
+                error!("[yookassa_webhook] fetch {} from {} shop failed: {}", payment_id, shop.name, e);
+                return Err(HttpResponse::InternalServerError().json(json!({"error": "verification failed"})));
+            }
+        }
+    }
+    Ok(false)
 }
 
-// === Payment status ===
-
-pub async fn web_payment_status(payment_id: web::Path<String>, req: HttpRequest) -> HttpResponse {
-    // Verify auth
-    if jwt::extract_telegram_id(&req).is_err() {
-        return HttpResponse::Unauthorized().body("Unauthorized");
+/// Credit a paid wallet top-up, once per payment.
+async fn credit_topup(pool: &PgPool, topup: &yookassa::Topup) -> HttpResponse {
+    let key = format!("topup:{}", topup.payment_id);
+    let reference = format!("yookassa:{}", topup.payment_id);
+    let p = wallet::Posting {
+        reference: Some(&reference),
+        idempotency_key: Some(&key),
+        ..wallet::Posting::new(topup.telegram_id, topup.amount_rub, wallet::KIND_TOPUP)
+    };
+    match wallet::post(pool, &p).await {
+        Ok(posted) => {
+            info!("[yookassa_webhook] top-up {}: tg={} +{}₽, balance {}₽",
+                topup.payment_id, topup.telegram_id, topup.amount_rub, posted.balance_rub);
+            HttpResponse::Ok().json(json!({"status": "ok", "duplicate": posted.replayed}))
+        }
+        Err(e @ (wallet::WalletError::UserNotFound | wallet::WalletError::KeyConflict)) => {
+            error!("[yookassa_webhook] top-up {} not credited: {}", topup.payment_id, e);
+            HttpResponse::Ok().json(json!({"status": "not credited"}))
+        }
+        Err(e) => {
+            error!("[yookassa_webhook] top-up {} failed: {}", topup.payment_id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
     }
+}
 
-    let (yookassa_shop_id, yookassa_secret) = web_yookassa_creds();
-
-    let resp = HTTP_CLIENT
-        .get(&format!("https://api.yookassa.ru/v3/payments/{}", payment_id.into_inner()))
-        .basic_auth(&yookassa_shop_id, Some(&yookassa_secret))
-        .send()
-        .await;
+/// POST /webhooks/cryptopay — CryptoPay `invoice_paid` updates. Signed
+/// with CRYPTO_BOT_TOKEN; credited once per invoice_id.
+pub async fn cryptopay_webhook(pool: web::Data<PgPool>, req: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let token = std::env::var("CRYPTO_BOT_TOKEN").unwrap_or_default();
+    if token.is_empty() {
+        error!("[cryptopay_webhook] CRYPTO_BOT_TOKEN not set");
+        return HttpResponse::ServiceUnavailable().json(json!({"error": "crypto payments not configured"}));
+    }
+    let signature = req
+        .headers()
+        .get(cryptopay::SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if !cryptopay::verify_signature(&token, &body, signature) {
+        warn!("[cryptopay_webhook] bad signature");
+        return HttpResponse::Unauthorized().json(json!({"error": "invalid signature"}));
+    }
 
-    match resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => {
-                    let status = json["status"].as_str().unwrap_or("unknown").to_string();
-                    HttpResponse::Ok().json(json!({ "status": status }))
-                }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    let update: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(v) => v,
+        Err(_) => return HttpResponse::BadRequest().json(json!({"error": "invalid json"})),
+    };
+    let invoice = match cryptopay::parse_update(&update) {
+        Ok(Some(inv)) => inv,
+        Ok(None) => return HttpResponse::Ok().json(json!({"status": "ignored"})),
+        Err(e) => {
+            error!("[cryptopay_webhook] unusable update: {}", e);
+            return HttpResponse::Ok().json(json!({"status": "ignored"}));
+        }
+    };
+    info!("[cryptopay_webhook] invoice {}: tg={} {} {}",
+        invoice.invoice_id, invoice.telegram_id, invoice.tariff, invoice.duration);
+
+    let key = format!("cryptopay:{}", invoice.invoice_id);
+    let external_id = invoice.invoice_id.to_string();
+    let mut days = invoice.days;
+    let mut metadata = invoice.metadata.clone();
+    match promo::commit(pool.get_ref(), promo::PROVIDER_CRYPTO, &external_id, None).await {
+        Ok(promo::Commit::Refused) => {
+            warn!("[cryptopay_webhook] promo hold of invoice {} refused, crediting without it", invoice.invoice_id);
+            days = promo::refused_days(&invoice.duration, days, &mut metadata);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("[cryptopay_webhook] committing promo hold of invoice {}: {}", invoice.invoice_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+    }
+    let c = crate::credit::Credit {
+        idempotency_key: Some(&key),
+        amount_rub: invoice.amount_rub,
+        duration: Some(&invoice.duration),
+        external_id: Some(&external_id),
+        price_version: invoice.price_version,
+        metadata: Some(metadata),
+        ..crate::credit::Credit::new(invoice.telegram_id, days, &invoice.tariff, "crypto")
+    };
+    match crate::credit::credit(pool.get_ref(), &c).await {
+        Ok(credited) => {
+            if let Err(e) = crate::rates::mark_paid(pool.get_ref(), invoice.invoice_id).await {
+                error!("[cryptopay_webhook] marking quote for invoice {} paid failed: {}", invoice.invoice_id, e);
             }
+            if let Err(e) = pending::resolve(pool.get_ref(), pending::PROVIDER_CRYPTO, &external_id, pending::STATUS_PAID).await {
+                error!("[cryptopay_webhook] marking invoice {} paid failed: {}", invoice.invoice_id, e);
+            }
+            HttpResponse::Ok().json(json!({"status": "ok", "duplicate": credited.replayed}))
+        }
+        Err(e @ (crate::credit::CreditError::UserNotFound | crate::credit::CreditError::KeyConflict)) => {
+            error!("[cryptopay_webhook] invoice {} not credited: {}", invoice.invoice_id, e);
+            HttpResponse::Ok().json(json!({"status": "not credited"}))
+        }
+        Err(e) => {
+            error!("[cryptopay_webhook] credit for invoice {} failed: {}", invoice.invoice_id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
         }
-        Ok(r) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
-        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
     }
 }
 
@@ -1841,7 +3191,7 @@ pub async fn web_create_crypto_payment(
     req: HttpRequest,
     data: web::Json<CreateCryptoPaymentRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -1858,35 +3208,59 @@ pub async fn web_create_crypto_payment(
         return HttpResponse::BadRequest().body("Bypass tariffs are temporarily available only for 1 month");
     }
 
-    let prices = get_price_map();
-    let key = format!("{}_{}", data.tariff, data.duration);
-    let mut price_rub = match prices.get(&key) {
-        Some(p) => *p,
+    let price_list = prices::current();
+    let mut price_rub = match price_list.get(&data.tariff, &data.duration) {
+        Some(p) => p,
         None => return HttpResponse::BadRequest().body("Invalid tariff/duration"),
     };
 
-    if let Some(ref code) = data.promo_code {
-        let promo = sqlx::query("SELECT discount_percent FROM promo_codes WHERE code = $1 AND is_active = true")
-            .bind(code)
-            .fetch_optional(pool.get_ref())
-            .await;
-        if let Ok(Some(row)) = promo {
-            let discount: i32 = row.get("discount_percent");
-            price_rub = (price_rub as f64 * (1.0 - discount as f64 / 100.0)).round() as i64;
-        }
-    }
-
     let crypto_bot_token = std::env::var("CRYPTO_BOT_TOKEN").unwrap_or_default();
     if crypto_bot_token.is_empty() {
         return HttpResponse::InternalServerError().body("Crypto payments not configured");
     }
 
-    // Convert RUB to crypto via CoinMarketCap
-    let cmc_key = std::env::var("CMC_API_KEY").unwrap_or_default();
-    let crypto_amount = convert_rub_to_crypto(price_rub as f64, &data.currency, &cmc_key).await;
-    let crypto_amount = match crypto_amount {
-        Some(a) => a,
-        None => return HttpResponse::InternalServerError().body("Failed to convert currency"),
+    // Promo hold; its expiry is moved to the quote's once the invoice exists.
+    let mut days = prices::duration_days(&data.duration).unwrap_or_default();
+    let mut reservation = None;
+    if let Some(code) = data.promo_code.as_deref().filter(|c| !c.is_empty()) {
+        let ttl = promo::reservation_ttl();
+        match promo::reserve(pool.get_ref(), code, telegram_id, &data.tariff, &data.duration, price_rub, promo::PROVIDER_CRYPTO, ttl).await {
+            Ok(Ok(r)) => {
+                price_rub = r.effect.price_rub.unwrap_or(price_rub);
+                days += r.effect.bonus_days;
+                reservation = Some(r.id);
+            }
+            Ok(Err(rejection)) => return HttpResponse::BadRequest().json(json!({"error": rejection.reason()})),
+            Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        }
+    }
+
+    // Freeze the rate: the invoice is for exactly the quoted amount and
+    // expires with the quote.
+    let quote = match crate::rates::lock_quote(pool.get_ref(), &crate::rates::QuoteRequest {
+        telegram_id,
+        tariff: &data.tariff,
+        duration: &data.duration,
+        price_version: price_list.version,
+        amount_rub: price_rub,
+        asset: &data.currency,
+    }).await {
+        Ok(q) => q,
+        Err(e) => {
+            if let Some(id) = reservation {
+                if let Err(e) = promo::release(pool.get_ref(), id).await {
+                    error!("[web_create_crypto_payment] releasing promo hold {}: {}", id, e);
+                }
+            }
+            return match e {
+                crate::rates::QuoteError::UnsupportedAsset => HttpResponse::BadRequest().body("Unsupported currency"),
+                crate::rates::QuoteError::NoRate => {
+                    error!("[web_create_crypto_payment] no fresh {} rate", data.currency);
+                    HttpResponse::ServiceUnavailable().body("Crypto rates temporarily unavailable")
+                }
+                e => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+            };
+        }
     };
 
     // Create CryptoPay invoice
@@ -1895,10 +3269,11 @@ pub async fn web_create_crypto_payment(
         .header("Crypto-Pay-API-Token", &crypto_bot_token)
         .json(&json!({
             "currency_type": "crypto",
-            "asset": data.currency,
-            "amount": format!("{:.8}", crypto_amount),
+            "asset": quote.asset,
+            "amount": format!("{:.8}", quote.amount_crypto),
+            "expires_in": quote.expires_in(),
             "description": format!("SvoiVPN {} {} [Сайт]", data.tariff, data.duration),
-            "payload": format!("{}:{}:{}", telegram_id, data.tariff, data.duration),
+            "payload": cryptopay::invoice_payload(telegram_id, &data.tariff, &data.duration, price_rub, price_list.version, days),
         }))
         .send()
         .await;
@@ -1910,51 +3285,257 @@ pub async fn web_create_crypto_payment(
                     if json["ok"].as_bool() == Some(true) {
                         let invoice_url = json["result"]["bot_invoice_url"].as_str().unwrap_or("").to_string();
                         let invoice_id = json["result"]["invoice_id"].as_i64().unwrap_or(0);
+                        if let Err(e) = crate::rates::attach_invoice(pool.get_ref(), quote.id, invoice_id).await {
+                            error!("[web_create_crypto_payment] quote {} -> invoice {}: {}", quote.id, invoice_id, e);
+                        }
+                        let recorded = pending::record(pool.get_ref(), &pending::NewPending {
+                            provider: pending::PROVIDER_CRYPTO,
+                            provider_id: &invoice_id.to_string(),
+                            telegram_id,
+                            kind: pending::KIND_SUBSCRIPTION,
+                            tariff: Some(&data.tariff),
+                            duration: Some(&data.duration),
+                            amount_rub: price_rub as f64,
+                        }).await;
+                        if let Err(e) = recorded {
+                            error!("[web_create_crypto_payment] recording pending invoice {}: {}", invoice_id, e);
+                        }
+                        if let Some(id) = reservation {
+                            let attached = promo::attach(pool.get_ref(), id, &invoice_id.to_string(), Some(quote.expires_at)).await;
+                            if let Err(e) = attached {
+                                error!("[web_create_crypto_payment] promo hold {} -> invoice {}: {}", id, invoice_id, e);
+                            }
+                        }
                         HttpResponse::Ok().json(json!({
                             "invoice_url": invoice_url,
                             "invoice_id": invoice_id.to_string(),
+                            "quote": quote,
                         }))
                     } else {
+                        error!("[web_create_crypto_payment] CryptoPay rejected invoice: {}", json);
+                        release_promo_hold(pool.get_ref(), reservation).await;
                         HttpResponse::InternalServerError().body("CryptoPay error")
                     }
                 }
-                Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+                Err(e) => {
+                    error!("Internal error: {}", e);
+                    release_promo_hold(pool.get_ref(), reservation).await;
+                    HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+                }
             }
         }
-        Ok(r) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
-        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        Ok(r) => {
+            error!("[web_create_crypto_payment] CryptoPay returned {}", r.status());
+            release_promo_hold(pool.get_ref(), reservation).await;
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+        Err(e) => {
+            error!("Internal error: {}", e);
+            release_promo_hold(pool.get_ref(), reservation).await;
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
     }
 }
 
-async fn convert_rub_to_crypto(rub: f64, currency: &str, cmc_key: &str) -> Option<f64> {
-    if cmc_key.is_empty() {
-        return None;
+// === Telegram Stars ===
+
+#[derive(Deserialize)]
+pub struct CreateStarsPaymentRequest {
+    tariff: String,
+    duration: String,
+    promo_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct InternalStarsInvoiceRequest {
+    telegram_id: i64,
+    tariff: String,
+    duration: String,
+    promo_code: Option<String>,
+}
+
+/// Shared by the mini-app and bot endpoints: price from the catalog, apply
+/// and hold the promo, create the invoice link.
+async fn create_stars_invoice(
+    pool: &PgPool,
+    telegram_id: i64,
+    tariff: &str,
+    duration: &str,
+    promo_code: Option<&str>,
+) -> HttpResponse {
+    let bs_month_only = std::env::var("BS_MONTH_ONLY")
+        .unwrap_or_else(|_| "false".to_string())
+        .eq_ignore_ascii_case("true");
+    if bs_month_only && (tariff == "bsbase" || tariff == "bsfamily") && duration != "1m" {
+        return HttpResponse::BadRequest().body("Bypass tariffs are temporarily available only for 1 month");
     }
 
-    // Get USD/RUB rate
-    let resp = HTTP_CLIENT
-        .get("https://pro-api.coinmarketcap.com/v1/tools/price-conversion")
-        .header("X-CMC_PRO_API_KEY", cmc_key)
-        .query(&[("amount", "1"), ("symbol", "USD"), ("convert", "RUB")])
-        .send()
-        .await
-        .ok()?;
+    let price_list = prices::current();
+    let mut price_rub = match price_list.get(tariff, duration) {
+        Some(p) => p,
+        None => return HttpResponse::BadRequest().body("Invalid tariff/duration"),
+    };
+    let mut days = prices::duration_days(duration).unwrap_or_default();
+    let mut reservation = None;
+    if let Some(code) = promo_code.filter(|c| !c.is_empty()) {
+        let ttl = promo::reservation_ttl();
+        match promo::reserve(pool, code, telegram_id, tariff, duration, price_rub, promo::PROVIDER_STARS, ttl).await {
+            Ok(Ok(r)) => {
+                price_rub = r.effect.price_rub.unwrap_or(price_rub);
+                days += r.effect.bonus_days;
+                reservation = Some(r.id);
+            }
+            Ok(Err(rejection)) => return HttpResponse::BadRequest().json(json!({"error": rejection.reason()})),
+            Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+        }
+    }
+
+    let payload = crate::stars::Payload {
+        telegram_id,
+        tariff: tariff.to_string(),
+        duration: duration.to_string(),
+        price_rub,
+        price_version: price_list.version,
+        days,
+        stars: crate::stars::stars_for(price_rub, crate::stars::rub_rate()),
+        promo_reservation: reservation,
+    };
+    let tariff_name = plans::catalog()
+        .get(tariff)
+        .map(|p| p.display_name.clone())
+        .unwrap_or_else(|| "Подписка".to_string());
+    let title = format!("SvoiVPN {}", tariff_name);
+    let description = format!("Подписка {} на {}", tariff_name, prices::duration_name(duration).unwrap_or_default());
+
+    match crate::stars::create_invoice_link(&HTTP_CLIENT, &title, &description, &payload).await {
+        Ok(link) => HttpResponse::Ok().json(json!({
+            "invoice_link": link,
+            "stars": payload.stars,
+            "price_rub": price_rub,
+            "days": days,
+        })),
+        Err(e) => {
+            error!("[create_stars_invoice] tg={}: {}", telegram_id, e);
+            if let Some(id) = reservation {
+                if let Err(e) = promo::release(pool, id).await {
+                    error!("[create_stars_invoice] releasing promo hold {}: {}", id, e);
+                }
+            }
+            HttpResponse::BadGateway().json(json!({"error": "stars invoice failed"}))
+        }
+    }
+}
 
-    let json = resp.json::<serde_json::Value>().await.ok()?;
-    let usd_to_rub = json["data"]["quote"]["RUB"]["price"].as_f64()?;
-    let usd_amount = rub / usd_to_rub;
+/// POST /web/payment/stars — Stars invoice link for the mini-app
+/// (`Telegram.WebApp.openInvoice`).
+pub async fn web_create_stars_payment(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<CreateStarsPaymentRequest>,
+) -> HttpResponse {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
+        Ok(id) => id,
+        Err(resp) => return resp,
+    };
+    info!("[web_create_stars_payment] telegram_id={}, tariff={}, duration={}", telegram_id, data.tariff, data.duration);
+    create_stars_invoice(pool.get_ref(), telegram_id, &data.tariff, &data.duration, data.promo_code.as_deref()).await
+}
 
-    // Get crypto/USD rate
-    let resp = HTTP_CLIENT
-        .get("https://pro-api.coinmarketcap.com/v1/tools/price-conversion")
-        .header("X-CMC_PRO_API_KEY", cmc_key)
-        .query(&[("amount", &format!("{}", usd_amount)), ("symbol", &"USD".to_string()), ("convert", &currency.to_string())])
-        .send()
-        .await
-        .ok()?;
+/// POST /internal/payments/stars/invoice — the same for the bot.
+pub async fn internal_create_stars_invoice(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    data: web::Json<InternalStarsInvoiceRequest>,
+) -> HttpResponse {
+    if let Some(resp) = check_internal_key(&req) { return resp; }
+    info!("[internal_create_stars_invoice] telegram_id={}, tariff={}, duration={}", data.telegram_id, data.tariff, data.duration);
+    create_stars_invoice(pool.get_ref(), data.telegram_id, &data.tariff, &data.duration, data.promo_code.as_deref()).await
+}
+
+/// POST /internal/payments/stars — Telegram updates the bot forwards as is:
+/// `pre_checkout_query` is checked and answered here, `successful_payment`
+/// is credited once per telegram_payment_charge_id. Non-2xx tells the bot
+/// to retry the forward.
+pub async fn internal_stars_update(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    update: web::Json<serde_json::Value>,
+) -> HttpResponse {
+    if let Some(resp) = check_internal_key(&req) { return resp; }
+
+    let q = &update["pre_checkout_query"];
+    if !q.is_null() {
+        let query_id = q["id"].as_str().unwrap_or_default();
+        let verdict = crate::stars::check_order(
+            q["currency"].as_str().unwrap_or_default(),
+            q["total_amount"].as_i64().unwrap_or_default(),
+            q["invoice_payload"].as_str().unwrap_or_default(),
+        );
+        if let Err(reason) = verdict {
+            warn!("[internal_stars_update] rejecting pre-checkout {}: {}", query_id, reason);
+        }
+        let error = verdict.as_ref().err().copied();
+        if let Err(e) = crate::stars::answer_pre_checkout(&HTTP_CLIENT, query_id, error).await {
+            error!("[internal_stars_update] answering pre-checkout {}: {}", query_id, e);
+            return HttpResponse::BadGateway().json(json!({"error": "answerPreCheckoutQuery failed"}));
+        }
+        return HttpResponse::Ok().json(json!({"ok": error.is_none(), "error_message": error}));
+    }
+
+    let paid = match crate::stars::parse_successful_payment(&update) {
+        Ok(Some(p)) => p,
+        Ok(None) => return HttpResponse::Ok().json(json!({"status": "ignored"})),
+        Err(e) => {
+            error!("[internal_stars_update] unusable successful_payment: {}", e);
+            return HttpResponse::Ok().json(json!({"status": "ignored"}));
+        }
+    };
+    let p = &paid.payload;
+    info!("[internal_stars_update] {}: tg={} (payer {}) {} {} {}⭐",
+        paid.charge_id, p.telegram_id, paid.payer_id, p.tariff, p.duration, p.stars);
+
+    let key = format!("stars:{}", paid.charge_id);
+    let mut days = p.days;
+    let mut metadata = paid.metadata.clone();
+    match promo::commit(pool.get_ref(), promo::PROVIDER_STARS, &paid.charge_id, p.promo_reservation).await {
+        Ok(promo::Commit::Refused) => {
+            warn!("[internal_stars_update] promo hold of {} refused, crediting without it", paid.charge_id);
+            days = promo::refused_days(&p.duration, days, &mut metadata);
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!("[internal_stars_update] committing promo hold of {}: {}", paid.charge_id, e);
+            return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+        }
+    }
+    let c = crate::credit::Credit {
+        idempotency_key: Some(&key),
+        amount_rub: Some(p.price_rub as f64),
+        duration: Some(&p.duration),
+        external_id: Some(&paid.charge_id),
+        price_version: Some(p.price_version).filter(|v| *v > 0),
+        metadata: Some(metadata),
+        ..crate::credit::Credit::new(p.telegram_id, days, &p.tariff, "stars")
+    };
+    match crate::credit::credit(pool.get_ref(), &c).await {
+        Ok(credited) => {
+            HttpResponse::Ok().json(json!({"status": "ok", "duplicate": credited.replayed}))
+        }
+        Err(e @ (crate::credit::CreditError::UserNotFound | crate::credit::CreditError::KeyConflict)) => {
+            error!("[internal_stars_update] {} not credited: {}", paid.charge_id, e);
+            HttpResponse::Ok().json(json!({"status": "not credited"}))
+        }
+        Err(e) => {
+            error!("[internal_stars_update] credit for {} failed: {}", paid.charge_id, e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"}))
+        }
+    }
+}
 
-    let json = resp.json::<serde_json::Value>().await.ok()?;
-    json["data"]["quote"][currency]["price"].as_f64()
+/// GET /web/payment/crypto/rates — cached RUB rates per asset, for display. The
+/// amount actually charged is the quote returned with the invoice.
+pub async fn web_crypto_rates() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "rates": crate::rates::snapshot() }))
 }
 
 // === Promo validation ===
@@ -1963,6 +3544,8 @@ async fn convert_rub_to_crypto(rub: f64, currency: &str, cmc_key: &str) -> Optio
 pub struct WebValidatePromoRequest {
     code: String,
     tariff: String,
+    /// When set, duration limits are checked and the prices are returned.
+    duration: Option<String>,
 }
 
 pub async fn web_validate_promo(
@@ -1970,57 +3553,28 @@ pub async fn web_validate_promo(
     req: HttpRequest,
     data: web::Json<WebValidatePromoRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
 
-    let promo = sqlx::query(
-        "SELECT id, discount_percent, applicable_tariffs, max_uses, current_uses, is_active \
-         FROM promo_codes WHERE code = $1"
-    )
-    .bind(&data.code)
-    .fetch_optional(pool.get_ref())
-    .await;
-
-    let row = match promo {
-        Ok(Some(r)) => r,
-        Ok(None) => return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод не найден"})),
-        Err(e) => return { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
-    };
-
-    let is_active: bool = row.get("is_active");
-    if !is_active {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод деактивирован"}));
-    }
-
-    let max_uses: i32 = row.get("max_uses");
-    let current_uses: i32 = row.get("current_uses");
-    if current_uses >= max_uses {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод исчерпан"}));
-    }
-
-    let applicable: Vec<String> = row.get("applicable_tariffs");
-    if !data.tariff.is_empty() && !applicable.contains(&data.tariff) {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Промокод не применим к этому тарифу"}));
-    }
-
-    let promo_id: i32 = row.get("id");
-    let already_used: Option<(i32,)> = sqlx::query_as(
-        "SELECT id FROM promo_usages WHERE promo_code_id = $1 AND telegram_id = $2"
-    )
-    .bind(promo_id)
-    .bind(telegram_id)
-    .fetch_optional(pool.get_ref())
-    .await
-    .unwrap_or(None);
-
-    if already_used.is_some() {
-        return HttpResponse::Ok().json(json!({"valid": false, "reason": "Вы уже использовали этот промокод"}));
+    let price = data.duration.as_deref().and_then(|d| prices::current().get(&data.tariff, d));
+    match promo::check(pool.get_ref(), &data.code, telegram_id, &data.tariff, data.duration.as_deref(), price).await {
+        Ok(Ok((promo, effect))) => HttpResponse::Ok().json(json!({
+            "valid": true,
+            "kind": promo.kind,
+            "discount_percent": promo.discount_percent,
+            "amount_rub": promo.amount_rub,
+            "bonus_days": promo.bonus_days,
+            "applicable_durations": promo.applicable_durations,
+            "expires_at": promo.expires_at,
+            "original_price": price,
+            "final_price": effect.price_rub,
+            "discount_rub": effect.discount_rub,
+        })),
+        Ok(Err(rejection)) => HttpResponse::Ok().json(json!({"valid": false, "reason": rejection.reason()})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
     }
-
-    let discount: i32 = row.get("discount_percent");
-    HttpResponse::Ok().json(json!({"valid": true, "discount_percent": discount}))
 }
 
 // === Settings ===
@@ -2037,7 +3591,7 @@ pub async fn web_toggle_auto_renew(
     req: HttpRequest,
     data: web::Json<WebToggleAutoRenewRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2075,7 +3629,7 @@ pub async fn web_toggle_pro(
     req: HttpRequest,
     data: web::Json<WebToggleProRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2092,46 +3646,11 @@ pub async fn web_toggle_pro(
     };
 
     let uuid: uuid::Uuid = row.get("uuid");
-    let pro_squad = "b6a4e86b-b769-4c86-a2d9-f31bbe645029";
-
-    // Get current squads from Remnawave
-    let get_resp = HTTP_CLIENT
-        .get(&format!("{}/users/by-telegram-id/{}", *REMNAWAVE_API_BASE, telegram_id))
-        .headers(remnawave_headers())
-        .send()
-        .await;
-
-    let mut squad_list: Vec<String> = match get_resp {
-        Ok(r) if r.status().is_success() => {
-            match r.json::<serde_json::Value>().await {
-                Ok(json) => json["response"][0]["activeInternalSquads"]
-                    .as_array()
-                    .map(|arr| arr.iter().filter_map(|s| s["uuid"].as_str().map(|v| v.to_string())).collect())
-                    .unwrap_or_default(),
-                Err(_) => vec![],
-            }
-        }
-        _ => vec![],
-    };
-
-    if data.is_pro {
-        if !squad_list.contains(&pro_squad.to_string()) {
-            squad_list.push(pro_squad.to_string());
-        }
-    } else {
-        squad_list.retain(|s| s != pro_squad);
-    }
 
     // Update Remnawave
-    let _ = HTTP_CLIENT
-        .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-        .headers(remnawave_headers())
-        .json(&json!({
-            "uuid": uuid.to_string(),
-            "activeInternalSquads": squad_list,
-        }))
-        .send()
-        .await;
+    if let Err(e) = subscription::push_pro(remnawave::client(), &plans::catalog(), telegram_id, uuid, data.is_pro).await {
+        error!("[web_toggle_pro] Remnawave squad update failed for {}: {}", telegram_id, e);
+    }
 
     // Update DB
     let _ = sqlx::query("UPDATE users SET is_pro = $1 WHERE telegram_id = $2")
@@ -2144,7 +3663,7 @@ pub async fn web_toggle_pro(
 }
 
 pub async fn web_unbind_card(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2165,7 +3684,7 @@ pub async fn web_unbind_card(pool: web::Data<PgPool>, req: HttpRequest) -> HttpR
 // === Referral info ===
 
 pub async fn web_referral_info(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2226,7 +3745,7 @@ pub async fn web_referral_info(pool: web::Data<PgPool>, req: HttpRequest) -> Htt
 // === AI Support endpoints ===
 
 pub async fn web_support_history(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2352,7 +3871,7 @@ pub async fn web_support_chat(
     body: web::Json<SupportChatRequest>,
     system_prompt: web::Data<Arc<String>>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2852,7 +4371,7 @@ pub async fn push_subscribe(
     req: HttpRequest,
     body: web::Json<AuthedPushSubscribeRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -2890,7 +4409,7 @@ pub async fn web_support_escalate(
     pool: web::Data<PgPool>,
     req: HttpRequest,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -3034,7 +4553,7 @@ pub async fn app_support_message(
     req: HttpRequest,
     mut payload: Multipart,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -3435,7 +4954,7 @@ pub async fn app_support_attachment_upload(
     req: HttpRequest,
     mut payload: Multipart,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -3629,7 +5148,7 @@ pub async fn app_support_attachment_get(
     req: HttpRequest,
     path: web::Path<i64>,
 ) -> HttpResponse {
-    let owner = match jwt::extract_telegram_id(&req) {
+    let owner = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -3963,7 +5482,7 @@ pub async fn app_register_device(
     req: HttpRequest,
     body: web::Json<RegisterDeviceRequest>,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -5299,21 +6818,10 @@ pub async fn admin_list_users(
 
 /// Fetches the user's HWID device list from Remnawave. Non-fatal:
 /// on any failure returns an empty list so the detail page still renders.
-async fn fetch_remnawave_devices(uuid: &str) -> serde_json::Value {
-    let resp = HTTP_CLIENT
-        .get(&format!("{}/hwid/devices/{}", *REMNAWAVE_API_BASE, uuid))
-        .headers(remnawave_headers())
-        .send()
-        .await;
-    match resp {
-        Ok(r) if r.status().is_success() => match r.json::<serde_json::Value>().await {
-            Ok(j) => json!({
-                "devices": j["response"]["devices"].clone(),
-                "total": j["response"]["total"].as_u64().unwrap_or(0),
-            }),
-            Err(_) => json!({ "devices": [], "total": 0 }),
-        },
-        _ => json!({ "devices": [], "total": 0 }),
+async fn fetch_remnawave_devices(uuid: Uuid) -> serde_json::Value {
+    match remnawave::client().list_devices(uuid).await {
+        Ok(list) => json!({ "devices": list.devices, "total": list.total }),
+        Err(_) => json!({ "devices": [], "total": 0 }),
     }
 }
 
@@ -5373,7 +6881,7 @@ pub async fn admin_get_user(
     .ok()
     .flatten();
 
-    let devices = fetch_remnawave_devices(&user.uuid.to_string()).await;
+    let devices = fetch_remnawave_devices(user.uuid).await;
     let referrals = fetch_referral_list(pool.get_ref(), &user).await;
 
     let days_left = (user.subscription_end - Utc::now()).num_days();
@@ -5395,6 +6903,8 @@ pub struct AdminUserPatch {
     pub is_pro: Option<bool>,
     pub plan: Option<String>,
     pub auto_renew: Option<bool>,
+    /// `true` signs the user out of every session.
+    pub revoke_sessions: Option<bool>,
 }
 
 /// PATCH /admin/users/{telegram_id} — partial update of admin-safe fields.
@@ -5424,26 +6934,18 @@ pub async fn admin_update_user(
     // 1. Remnawave: block / unblock.
     if let Some(active) = p.is_active {
         let status = if active { "ACTIVE" } else { "DISABLED" };
-        let r = HTTP_CLIENT
-            .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-            .headers(remnawave_headers())
-            .json(&json!({ "uuid": user.uuid, "status": status }))
-            .send().await;
-        if !matches!(r, Ok(ref resp) if resp.status().is_success()) {
-            error!("[admin_update_user] Remnawave status update failed for {}", telegram_id);
+        let update = remnawave::UserUpdate { status: Some(status.to_string()), ..remnawave::UserUpdate::new(user.uuid) };
+        if let Err(e) = remnawave::client().update_user(&update).await {
+            error!("[admin_update_user] Remnawave status update failed for {}: {}", telegram_id, e);
             return HttpResponse::BadGateway().json(json!({"error": "remnawave status update failed"}));
         }
     }
 
     // 2. Remnawave: device limit.
     if let Some(dl) = p.device_limit {
-        let r = HTTP_CLIENT
-            .patch(&format!("{}/users", *REMNAWAVE_API_BASE))
-            .headers(remnawave_headers())
-            .json(&json!({ "uuid": user.uuid, "hwidDeviceLimit": dl }))
-            .send().await;
-        if !matches!(r, Ok(ref resp) if resp.status().is_success()) {
-            error!("[admin_update_user] Remnawave device limit update failed for {}", telegram_id);
+        let update = remnawave::UserUpdate { hwid_device_limit: Some(dl), ..remnawave::UserUpdate::new(user.uuid) };
+        if let Err(e) = remnawave::client().update_user(&update).await {
+            error!("[admin_update_user] Remnawave device limit update failed for {}: {}", telegram_id, e);
             return HttpResponse::BadGateway().json(json!({"error": "remnawave device limit update failed"}));
         }
     }
@@ -5486,10 +6988,606 @@ pub async fn admin_update_user(
         }
     }
 
+    // 5. Sign out everywhere.
+    if p.revoke_sessions == Some(true) {
+        match sessions::revoke_all(pool.get_ref(), telegram_id, sessions::REVOKED_BY_ADMIN).await {
+            Ok(n) => info!("[admin_update_user] revoked {} sessions of {}", n, telegram_id),
+            Err(e) => {
+                error!("[admin_update_user] session revoke error: {}", e);
+                return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+            }
+        }
+    }
+
     info!("[admin_update_user] updated user {}", telegram_id);
     HttpResponse::Ok().json(json!({ "status": "ok" }))
 }
 
+// === Plan catalog (admin) ===
+
+#[derive(serde::Deserialize)]
+pub struct AdminPlanCreate {
+    pub code: String,
+    pub display_name: String,
+    pub kind: Option<String>,
+    pub squads: Vec<String>,
+    pub inbounds: Option<Vec<String>>,
+    pub device_limit: i64,
+    pub traffic_limit_bytes: Option<i64>,
+    pub tag: Option<String>,
+    pub is_active: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AdminPlanPatch {
+    pub display_name: Option<String>,
+    pub kind: Option<String>,
+    pub squads: Option<Vec<String>>,
+    pub inbounds: Option<Vec<String>>,
+    pub device_limit: Option<i64>,
+    pub traffic_limit_bytes: Option<i64>,
+    pub tag: Option<String>,
+    pub is_active: Option<bool>,
+}
+
+/// Shared field checks for create/patch. Squads and inbounds go straight
+/// into Remnawave PATCH bodies, so a typo here would 400 every extend.
+fn validate_plan_fields(
+    kind: Option<&str>,
+    squads: Option<&[String]>,
+    inbounds: Option<&[String]>,
+    device_limit: Option<i64>,
+    traffic_limit_bytes: Option<i64>,
+) -> Result<(), HttpResponse> {
+    if let Some(k) = kind {
+        if ![plans::KIND_PAID, plans::KIND_BONUS, plans::KIND_ADDON].contains(&k) {
+            return Err(HttpResponse::BadRequest().json(json!({"error": "kind must be paid, bonus or addon"})));
+        }
+    }
+    for id in squads.unwrap_or(&[]).iter().chain(inbounds.unwrap_or(&[])) {
+        if Uuid::parse_str(id).is_err() {
+            return Err(HttpResponse::BadRequest().json(json!({"error": format!("invalid uuid: {}", id)})));
+        }
+    }
+    if matches!(device_limit, Some(n) if n < 0) || matches!(traffic_limit_bytes, Some(n) if n < 0) {
+        return Err(HttpResponse::BadRequest().json(json!({"error": "limits must be >= 0"})));
+    }
+    Ok(())
+}
+
+/// Refresh the in-memory catalog after a write. The row is already
+/// committed, so a failed reload is logged rather than surfaced.
+async fn reload_plans(pool: &PgPool, ctx: &str) {
+    if let Err(e) = plans::reload(pool).await {
+        error!("[{}] plan catalog reload failed: {}", ctx, e);
+    }
+}
+
+const PLAN_COLUMNS: &str = "code, display_name, kind, squads, inbounds, device_limit, \
+                            traffic_limit_bytes, tag, is_active";
+
+/// GET /admin/plans — full catalog, including retired plans.
+pub async fn admin_list_plans(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    match sqlx::query_as::<_, plans::Plan>(&format!("SELECT {} FROM plans ORDER BY code", PLAN_COLUMNS))
+        .fetch_all(pool.get_ref())
+        .await
+    {
+        Ok(rows) => HttpResponse::Ok().json(json!({ "items": rows })),
+        Err(e) => { error!("[admin_list_plans] db error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+/// POST /admin/plans — add a tariff. 409 if the code already exists.
+pub async fn admin_create_plan(
+    pool: web::Data<PgPool>,
+    body: web::Json<AdminPlanCreate>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let p = body.into_inner();
+
+    let code = p.code.trim().to_lowercase();
+    if code.is_empty() || code.len() > 16
+        || !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return HttpResponse::BadRequest().json(json!({"error": "code must be 1-16 chars [a-z0-9_]"}));
+    }
+    if let Err(resp) = validate_plan_fields(
+        p.kind.as_deref(), Some(&p.squads), p.inbounds.as_deref(),
+        Some(p.device_limit), p.traffic_limit_bytes,
+    ) {
+        return resp;
+    }
+
+    let result = sqlx::query_as::<_, plans::Plan>(&format!(
+        "INSERT INTO plans (code, display_name, kind, squads, inbounds, device_limit, traffic_limit_bytes, tag, is_active) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) \
+         ON CONFLICT (code) DO NOTHING RETURNING {}", PLAN_COLUMNS
+    ))
+    .bind(&code)
+    .bind(&p.display_name)
+    .bind(p.kind.as_deref().unwrap_or(plans::KIND_PAID))
+    .bind(&p.squads)
+    .bind(p.inbounds.unwrap_or_default())
+    .bind(p.device_limit)
+    .bind(p.traffic_limit_bytes.unwrap_or(0))
+    .bind(p.tag.as_deref().unwrap_or("PAID"))
+    .bind(p.is_active.unwrap_or(true))
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(plan)) => {
+            reload_plans(pool.get_ref(), "admin_create_plan").await;
+            info!("[admin_create_plan] created plan {}", plan.code);
+            HttpResponse::Ok().json(plan)
+        }
+        Ok(None) => HttpResponse::Conflict().json(json!({"error": "plan already exists"})),
+        Err(e) => { error!("[admin_create_plan] db error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+/// PATCH /admin/plans/{code} — partial update. Affects the next extend /
+/// toggle for every user on the plan; existing panel state is not touched.
+pub async fn admin_update_plan(
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+    body: web::Json<AdminPlanPatch>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let code = path.into_inner();
+    let p = body.into_inner();
+
+    if let Err(resp) = validate_plan_fields(
+        p.kind.as_deref(), p.squads.as_deref(), p.inbounds.as_deref(),
+        p.device_limit, p.traffic_limit_bytes,
+    ) {
+        return resp;
+    }
+
+    let mut qb = sqlx::QueryBuilder::<sqlx::Postgres>::new("UPDATE plans SET updated_at = NOW()");
+    if let Some(v) = p.display_name { qb.push(", display_name = ").push_bind(v); }
+    if let Some(v) = p.kind { qb.push(", kind = ").push_bind(v); }
+    if let Some(v) = p.squads { qb.push(", squads = ").push_bind(v); }
+    if let Some(v) = p.inbounds { qb.push(", inbounds = ").push_bind(v); }
+    if let Some(v) = p.device_limit { qb.push(", device_limit = ").push_bind(v); }
+    if let Some(v) = p.traffic_limit_bytes { qb.push(", traffic_limit_bytes = ").push_bind(v); }
+    if let Some(v) = p.tag { qb.push(", tag = ").push_bind(v); }
+    if let Some(v) = p.is_active { qb.push(", is_active = ").push_bind(v); }
+    qb.push(" WHERE code = ").push_bind(&code);
+    qb.push(format!(" RETURNING {}", PLAN_COLUMNS));
+
+    match qb.build_query_as::<plans::Plan>().fetch_optional(pool.get_ref()).await {
+        Ok(Some(plan)) => {
+            reload_plans(pool.get_ref(), "admin_update_plan").await;
+            info!("[admin_update_plan] updated plan {}", code);
+            HttpResponse::Ok().json(plan)
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({"error": "plan not found"})),
+        Err(e) => { error!("[admin_update_plan] db error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+/// POST /admin/plans/reload — re-read the table after a manual SQL edit.
+pub async fn admin_reload_plans(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    match plans::reload(pool.get_ref()).await {
+        Ok(n) => HttpResponse::Ok().json(json!({ "status": "ok", "plans": n })),
+        Err(e) => { error!("[admin_reload_plans] reload failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+// === Price catalog (admin) ===
+
+#[derive(serde::Deserialize)]
+pub struct AdminPriceVersionCreate {
+    /// Defaults to now. A future date schedules the change.
+    pub effective_from: Option<chrono::DateTime<Utc>>,
+    pub note: Option<String>,
+    /// plan -> duration -> RUB. A full list: plans/durations left out are
+    /// not sold under this version.
+    pub prices: HashMap<String, HashMap<String, i64>>,
+}
+
+/// GET /admin/prices — every version, oldest first, and the one in effect.
+pub async fn admin_list_prices(req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let catalog = prices::catalog();
+    HttpResponse::Ok().json(json!({
+        "current": catalog.current().map(|v| v.version),
+        "items": catalog.versions(),
+    }))
+}
+
+/// POST /admin/prices — add a price version. Versions are never edited, so
+/// every payment's `price_version` keeps pointing at what it was charged.
+pub async fn admin_create_price_version(
+    pool: web::Data<PgPool>,
+    body: web::Json<AdminPriceVersionCreate>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let v = body.into_inner();
+
+    let catalog = plans::catalog();
+    let mut rows = Vec::new();
+    for (plan, durations) in &v.prices {
+        if !catalog.get(plan).is_some_and(|p| p.is_paid()) {
+            return HttpResponse::BadRequest().json(json!({"error": format!("{} is not a paid plan", plan)}));
+        }
+        for (duration, amount) in durations {
+            if !prices::DURATIONS.contains(&duration.as_str()) {
+                return HttpResponse::BadRequest().json(json!({"error": format!("unknown duration {}", duration)}));
+            }
+            if *amount <= 0 || *amount > i64::from(i32::MAX) {
+                return HttpResponse::BadRequest().json(json!({"error": format!("bad amount for {}_{}", plan, duration)}));
+            }
+            rows.push((plan.clone(), duration.clone(), *amount as i32));
+        }
+    }
+    if rows.is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "prices is empty"}));
+    }
+
+    let result: Result<i32, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        let id: i32 = sqlx::query_scalar(
+            "INSERT INTO price_versions (effective_from, note) VALUES (COALESCE($1, NOW()), $2) RETURNING id",
+        )
+        .bind(v.effective_from)
+        .bind(&v.note)
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut qb = sqlx::QueryBuilder::<sqlx::Postgres>::new(
+            "INSERT INTO prices (version_id, plan, duration, amount_rub) ",
+        );
+        qb.push_values(&rows, |mut b, (plan, duration, amount)| {
+            b.push_bind(id).push_bind(plan).push_bind(duration).push_bind(amount);
+        });
+        qb.build().execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(id)
+    }
+    .await;
+
+    match result {
+        Ok(id) => {
+            if let Err(e) = prices::reload(pool.get_ref()).await {
+                error!("[admin_create_price_version] price catalog reload failed: {}", e);
+            }
+            info!("[admin_create_price_version] created version {} ({} prices)", id, rows.len());
+            HttpResponse::Ok().json(json!({ "version": id }))
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            HttpResponse::Conflict().json(json!({"error": "a version with this effective_from already exists"}))
+        }
+        Err(e) => { error!("[admin_create_price_version] db error: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+/// POST /admin/prices/reload — re-read the catalog after a manual SQL edit.
+pub async fn admin_reload_prices(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    match prices::reload(pool.get_ref()).await {
+        Ok(n) => HttpResponse::Ok().json(json!({ "status": "ok", "versions": n })),
+        Err(e) => { error!("[admin_reload_prices] reload failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) }
+    }
+}
+
+// === Drift reconciliation (admin) ===
+
+#[derive(Deserialize)]
+pub struct ReconcileRunQuery {
+    pub fix: Option<bool>,
+}
+
+/// GET /admin/reconcile/report — last finished run, and whether one is in progress.
+pub async fn admin_reconcile_report(req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    HttpResponse::Ok().json(json!({
+        "running": reconcile::is_running(),
+        "report": reconcile::last_report(),
+    }))
+}
+
+/// POST /admin/reconcile/run?fix=true — start a pass in the background.
+/// A full pass pages the whole panel, so it doesn't fit in one request.
+pub async fn admin_reconcile_run(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    query: web::Query<ReconcileRunQuery>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    if reconcile::is_running() {
+        return HttpResponse::Conflict().json(json!({"error": "reconcile already running"}));
+    }
+    let fix = query.fix.unwrap_or(false);
+    let pool = pool.get_ref().clone();
+    tokio::spawn(async move {
+        let catalog = plans::catalog();
+        reconcile::run(&pool, remnawave::client(), &catalog, fix).await;
+    });
+    info!("[admin_reconcile_run] started, fix={}", fix);
+    HttpResponse::Accepted().json(json!({ "status": "started", "fix": fix }))
+}
+
+// === Refunds (admin) ===
+
+#[derive(Deserialize)]
+pub struct RefundPaymentRequest {
+    /// Omitted — refund whatever is left of the payment.
+    pub amount_rub: Option<f64>,
+    pub reason: Option<String>,
+}
+
+/// POST /admin/payments/{id}/refund — full or partial refund of a YooKassa
+/// payment; takes the proportional days back (see refund.rs).
+pub async fn admin_refund_payment(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    id: web::Path<i64>,
+    body: web::Json<RefundPaymentRequest>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let id = id.into_inner();
+    info!("[admin_refund_payment] payment #{} amount={:?}", id, body.amount_rub);
+
+    match crate::refund::refund(pool.get_ref(), &HTTP_CLIENT, id, body.amount_rub, body.reason.as_deref()).await {
+        Ok(r) => HttpResponse::Ok().json(json!({
+            "refund_id": r.refund_id,
+            "status": r.status,
+            "telegram_id": r.telegram_id,
+            "amount_rub": r.amount_rub,
+            "days_removed": r.days_removed,
+            "subscription_end": r.subscription_end,
+        })),
+        Err(crate::refund::RefundError::NotFound) => HttpResponse::NotFound().json(json!({"error": "payment not found"})),
+        Err(e @ crate::refund::RefundError::NotRefundable(_)) | Err(e @ crate::refund::RefundError::BadAmount(_)) => {
+            HttpResponse::BadRequest().json(json!({"error": e.to_string()}))
+        }
+        Err(e @ crate::refund::RefundError::Provider(_)) => {
+            error!("[admin_refund_payment] #{}: {}", id, e);
+            HttpResponse::BadGateway().json(json!({"error": e.to_string()}))
+        }
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+// === Wallet (admin) ===
+
+#[derive(Deserialize)]
+pub struct AdminWalletRequest {
+    /// Negative only for `adjustment`.
+    pub amount_rub: f64,
+    /// compensation (default) | adjustment
+    pub kind: Option<String>,
+    pub note: Option<String>,
+    pub idempotency_key: Option<String>,
+}
+
+/// GET /admin/users/{telegram_id}/wallet — balance and the last 100 rows.
+pub async fn admin_get_wallet(pool: web::Data<PgPool>, req: HttpRequest, path: web::Path<i64>) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let telegram_id = path.into_inner();
+    let balance = match wallet::balance(pool.get_ref(), telegram_id).await {
+        Ok(b) => b,
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    match wallet::history(pool.get_ref(), telegram_id, 100, 0).await {
+        Ok((rows, total)) => HttpResponse::Ok().json(json!({"balance_rub": balance, "items": rows, "total": total})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// POST /admin/users/{telegram_id}/wallet — compensation or a correction
+/// of the user's balance.
+pub async fn admin_post_wallet(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    path: web::Path<i64>,
+    body: web::Json<AdminWalletRequest>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let telegram_id = path.into_inner();
+    let kind = body.kind.as_deref().unwrap_or(wallet::KIND_COMPENSATION);
+    let valid = match kind {
+        wallet::KIND_COMPENSATION => body.amount_rub > 0.0 && body.amount_rub.is_finite(),
+        wallet::KIND_ADJUSTMENT => body.amount_rub != 0.0 && body.amount_rub.is_finite(),
+        _ => return HttpResponse::BadRequest().json(json!({"error": "kind must be compensation or adjustment"})),
+    };
+    if !valid {
+        return HttpResponse::BadRequest().json(json!({"error": "bad amount_rub"}));
+    }
+    let key = body.idempotency_key.as_deref().filter(|k| !k.is_empty());
+    if key.is_some_and(|k| k.len() > 128) {
+        return HttpResponse::BadRequest().json(json!({"error": "idempotency_key too long"}));
+    }
+    info!("[admin_post_wallet] tg={} {} {}₽ note={:?}", telegram_id, kind, body.amount_rub, body.note);
+
+    let p = wallet::Posting {
+        note: body.note.as_deref(),
+        idempotency_key: key,
+        ..wallet::Posting::new(telegram_id, body.amount_rub, kind)
+    };
+    match wallet::post(pool.get_ref(), &p).await {
+        Ok(posted) => HttpResponse::Ok().json(json!({
+            "entry_id": posted.entry_id,
+            "balance_rub": posted.balance_rub,
+            "duplicate": posted.replayed,
+        })),
+        Err(e) => wallet_error_response("admin_post_wallet", e),
+    }
+}
+
+// === Promo campaigns (admin) ===
+
+/// Policy fields are the same as for POST /promos.
+#[derive(Deserialize)]
+pub struct PromoBatchRequest {
+    pub name: String,
+    pub prefix: String,
+    pub count: i32,
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub discount_percent: i32,
+    pub amount_rub: Option<i32>,
+    pub bonus_days: Option<i32>,
+    pub applicable_tariffs: Vec<String>,
+    #[serde(default)]
+    pub applicable_durations: Vec<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub first_purchase_only: bool,
+}
+
+/// POST /admin/promos/batch — create a campaign and `count` single-use codes
+/// `<prefix>-XXXXXXXX` sharing one policy.
+pub async fn admin_create_promo_batch(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    body: web::Json<PromoBatchRequest>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let body = body.into_inner();
+    info!("[admin_create_promo_batch] name={:?} prefix={} count={}", body.name, body.prefix, body.count);
+
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json(json!({"error": "name required"}));
+    }
+    if !promo::valid_prefix(&body.prefix) {
+        return HttpResponse::BadRequest().json(json!({"error": "prefix must be 1-16 of A-Z, 0-9, _"}));
+    }
+    if !(1..=promo::MAX_BATCH).contains(&body.count) {
+        return HttpResponse::BadRequest().json(json!({"error": format!("count must be 1-{}", promo::MAX_BATCH)}));
+    }
+    let policy = crate::models::CreatePromoRequest {
+        code: body.prefix,
+        kind: body.kind,
+        discount_percent: body.discount_percent,
+        amount_rub: body.amount_rub,
+        bonus_days: body.bonus_days,
+        applicable_tariffs: body.applicable_tariffs,
+        applicable_durations: body.applicable_durations,
+        max_uses: 1,
+        expires_at: body.expires_at,
+        first_purchase_only: body.first_purchase_only,
+    };
+    if let Err(msg) = promo::validate_new(&policy) {
+        return HttpResponse::BadRequest().json(json!({"error": msg}));
+    }
+
+    let id = match promo::create_campaign(pool.get_ref(), body.name.trim(), &policy, body.count).await {
+        Ok(id) => id,
+        Err(e) => { error!("Internal error: {}", e); return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    };
+    match promo::campaigns(pool.get_ref(), Some(id)).await {
+        Ok(mut c) if !c.is_empty() => HttpResponse::Ok().json(c.remove(0)),
+        Ok(_) => HttpResponse::InternalServerError().json(json!({"error": "internal server error"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// GET /admin/promos/campaigns — every campaign with redeemed count and
+/// revenue from the payments ledger.
+pub async fn admin_list_promo_campaigns(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    match promo::campaigns(pool.get_ref(), None).await {
+        Ok(c) => HttpResponse::Ok().json(json!({ "campaigns": c })),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// GET /admin/promos/campaigns/{id}
+pub async fn admin_get_promo_campaign(pool: web::Data<PgPool>, req: HttpRequest, id: web::Path<i32>) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    match promo::campaigns(pool.get_ref(), Some(id.into_inner())).await {
+        Ok(mut c) if !c.is_empty() => HttpResponse::Ok().json(c.remove(0)),
+        Ok(_) => HttpResponse::NotFound().json(json!({"error": "campaign not found"})),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+/// GET /admin/promos/campaigns/{id}/codes.csv — the campaign's codes and who
+/// redeemed them.
+pub async fn admin_export_promo_campaign(pool: web::Data<PgPool>, req: HttpRequest, id: web::Path<i32>) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let id = id.into_inner();
+    match promo::export_csv(pool.get_ref(), id).await {
+        Ok(csv) => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header(("Content-Disposition", format!("attachment; filename=\"campaign-{}.csv\"", id)))
+            .body(csv),
+        Err(e) => { error!("Internal error: {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+// === Analytics (admin) ===
+
+#[derive(Deserialize)]
+pub struct RevenueAnalyticsQuery {
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    /// day | week | month
+    pub granularity: Option<String>,
+}
+
+/// GET /admin/analytics/revenue — revenue by source and plan, MRR, ARPU,
+/// trial→paid conversion, churn and signup-month retention (analytics.rs).
+pub async fn admin_revenue_analytics(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    q: web::Query<RevenueAnalyticsQuery>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let Some(granularity) = crate::analytics::Granularity::parse(q.granularity.as_deref()) else {
+        return HttpResponse::BadRequest().json(json!({"error": "granularity must be day, week or month"}));
+    };
+    let (default_from, default_to) = crate::analytics::default_window();
+    let (from, to) = (q.from.unwrap_or(default_from), q.to.unwrap_or(default_to));
+    if from >= to {
+        return HttpResponse::BadRequest().json(json!({"error": "from must be before to"}));
+    }
+
+    match crate::analytics::report(pool.get_ref(), from, to, granularity).await {
+        Ok(r) => HttpResponse::Ok().json(r),
+        Err(e) => { error!("[admin_revenue_analytics] {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CheckoutFunnelQuery {
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// GET /admin/analytics/checkouts — site checkouts created vs paid,
+/// abandoned, reminded and recovered (pending.rs).
+pub async fn admin_checkout_funnel(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    q: web::Query<CheckoutFunnelQuery>,
+) -> HttpResponse {
+    if let Some(resp) = check_admin_key(&req) { return resp; }
+    let (default_from, default_to) = crate::analytics::default_window();
+    let (from, to) = (q.from.unwrap_or(default_from), q.to.unwrap_or(default_to));
+    if from >= to {
+        return HttpResponse::BadRequest().json(json!({"error": "from must be before to"}));
+    }
+
+    match pending::funnel(pool.get_ref(), from, to).await {
+        Ok(f) => HttpResponse::Ok().json(f),
+        Err(e) => { error!("[admin_checkout_funnel] {}", e); HttpResponse::InternalServerError().json(json!({"error": "internal server error"})) },
+    }
+}
+
 /// GET /admin/stats — aggregate dashboard metrics.
 pub async fn admin_stats(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
     if let Some(resp) = check_admin_key(&req) { return resp; }
@@ -5636,21 +7734,53 @@ pub async fn admin_broadcast(
             return HttpResponse::InternalServerError().json(json!({"error": "internal server error"})); }
     };
 
-    // Background FCM fan-out: the admin's request returns immediately.
-    let pool2 = pool.clone();
-    tokio::spawn(async move {
-        let delivered = crate::push::blast_tokens(&pool2, tokens, &title, &text).await as i32;
-        let _ = sqlx::query("UPDATE broadcasts SET delivered = $1, status = 'sent' WHERE id = $2")
-            .bind(delivered)
+    // FCM fan-out runs as a job: the admin's request returns immediately and
+    // the broadcast survives a restart.
+    if let Err(e) = jobs::schedule(pool.get_ref(), &jobs::Job::SendBroadcast { broadcast_id: id }, Utc::now()).await {
+        error!("[admin_broadcast] failed to schedule #{}: {}", id, e);
+        let _ = sqlx::query("UPDATE broadcasts SET status = 'failed' WHERE id = $1")
             .bind(id)
-            .execute(pool2.get_ref())
+            .execute(pool.get_ref())
             .await;
-        info!("[admin_broadcast] #{} delivered {}/{}", id, delivered, recipients);
-    });
+        return HttpResponse::InternalServerError().json(json!({"error": "internal server error"}));
+    }
 
     HttpResponse::Ok().json(json!({"id": id, "recipients": recipients, "status": "sending"}))
 }
 
+/// Job body for [`jobs::Job::SendBroadcast`]: re-resolves the segment and
+/// fans out. Runs at most once (see [`jobs::Job::retryable`]); an
+/// already-sent broadcast is skipped as well.
+pub(crate) async fn run_broadcast(pool: &PgPool, id: i64) -> Result<(), String> {
+    let row: Option<(String, String, serde_json::Value, String)> = sqlx::query_as(
+        "SELECT title, body, segment, status FROM broadcasts WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| format!("DB query failed: {}", e))?;
+    let (title, text, segment, status) = match row {
+        Some(r) => r,
+        None => return Err(format!("broadcast #{} not found", id)),
+    };
+    if status == "sent" {
+        return Ok(());
+    }
+
+    let tokens = resolve_segment_tokens(pool, &segment).await;
+    let recipients = tokens.len() as i32;
+    let delivered = crate::push::blast_tokens(pool, tokens, &title, &text).await as i32;
+    sqlx::query("UPDATE broadcasts SET recipients = $1, delivered = $2, status = 'sent' WHERE id = $3")
+        .bind(recipients)
+        .bind(delivered)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("DB update failed: {}", e))?;
+    info!("[admin_broadcast] #{} delivered {}/{}", id, delivered, recipients);
+    Ok(())
+}
+
 /// GET /admin/broadcasts — broadcast history, newest first.
 pub async fn admin_list_broadcasts(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
     if let Some(resp) = check_admin_key(&req) { return resp; }
@@ -5843,14 +7973,12 @@ pub async fn internal_save_news(pool: web::Data<PgPool>, body: web::Json<serde_j
 
     match result {
         Ok(res) if res.rows_affected() > 0 => {
-            // Fire-and-forget email blast to verified subscribers
-            let pool_clone = pool.clone();
-            let text_owned = text.to_string();
-            tokio::spawn(async move {
-                if let Err(e) = blast_news_email(&pool_clone, &text_owned).await {
-                    error!("[news_blast] failed: {}", e);
-                }
-            });
+            // Email blast to verified subscribers, as a persistent job so a
+            // restart mid-blast doesn't drop it.
+            let job = jobs::Job::SendEmailBatch { news_text: text.to_string() };
+            if let Err(e) = jobs::schedule(pool.get_ref(), &job, Utc::now()).await {
+                error!("[news_blast] failed to schedule: {}", e);
+            }
             // Mobile push to every news-opted device. Same headline/body
             // split as the email blast so the two channels read alike.
             let pool_push = pool.clone();
@@ -5878,7 +8006,7 @@ fn unsubscribe_url(token: &uuid::Uuid, kind: &str) -> String {
     format!("https://svoiweb.ru/api/web/unsubscribe/{}?type={}", token, kind)
 }
 
-async fn blast_news_email(pool: &PgPool, news_text: &str) -> Result<(), String> {
+pub(crate) async fn blast_news_email(pool: &PgPool, news_text: &str) -> Result<(), String> {
     // First line as headline, rest as body
     let mut lines = news_text.splitn(2, '\n');
     let headline = lines.next().unwrap_or("Новости SvoiVPN").trim();
@@ -6103,7 +8231,10 @@ pub async fn admin_test_email(path: web::Path<String>, req: HttpRequest) -> Http
 }
 
 /// POST /internal/payments — append-only ledger of every subscription extension event.
-/// Body: { telegram_id, source, amount_rub?, plan, duration?, days_added, external_id?, metadata? }
+/// Body: { telegram_id, source, amount_rub?, plan, duration?, days_added, external_id?, metadata?, idempotency_key?, price_version? }
+/// A paid row without price_version is stamped with the version in effect.
+/// With an idempotency_key the row written by the keyed extend is completed
+/// (amount, external_id, ...) instead of a second row being added.
 /// Fire-and-forget — callers should not depend on this succeeding.
 pub async fn internal_log_payment(
     pool: web::Data<PgPool>,
@@ -6133,10 +8264,24 @@ pub async fn internal_log_payment(
     let duration = body.get("duration").and_then(|v| v.as_str()).map(|s| s.to_string());
     let external_id = body.get("external_id").and_then(|v| v.as_str()).map(|s| s.to_string());
     let metadata = body.get("metadata").cloned();
+    let idempotency_key = body.get("idempotency_key").and_then(|v| v.as_str()).filter(|s| !s.is_empty());
+    let price_version = body
+        .get("price_version")
+        .and_then(|v| v.as_i64())
+        .map(|v| v as i32)
+        .or_else(|| amount_rub.map(|_| prices::current().version).filter(|v| *v > 0));
 
     let result = sqlx::query(
-        "INSERT INTO payments (telegram_id, source, amount_rub, plan, duration, days_added, external_id, metadata) \
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id"
+        "INSERT INTO payments (telegram_id, source, amount_rub, plan, duration, days_added, external_id, metadata, idempotency_key, price_version) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+         ON CONFLICT (idempotency_key) WHERE idempotency_key IS NOT NULL DO UPDATE SET \
+            source = EXCLUDED.source, \
+            amount_rub = COALESCE(EXCLUDED.amount_rub, payments.amount_rub), \
+            duration = COALESCE(EXCLUDED.duration, payments.duration), \
+            external_id = COALESCE(EXCLUDED.external_id, payments.external_id), \
+            metadata = COALESCE(EXCLUDED.metadata, payments.metadata), \
+            price_version = COALESCE(payments.price_version, EXCLUDED.price_version) \
+         RETURNING id"
     )
     .bind(telegram_id)
     .bind(&source)
@@ -6146,6 +8291,8 @@ pub async fn internal_log_payment(
     .bind(days_added)
     .bind(external_id.as_deref())
     .bind(metadata)
+    .bind(idempotency_key)
+    .bind(price_version)
     .fetch_one(pool.get_ref())
     .await;
 
@@ -6164,7 +8311,7 @@ pub async fn internal_log_payment(
 
 /// GET /web/me/notifications — current email notification preferences (JWT)
 pub async fn web_get_notifications(pool: web::Data<PgPool>, req: HttpRequest) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -6205,7 +8352,7 @@ pub async fn web_update_notifications(
     body: web::Json<serde_json::Value>,
     req: HttpRequest,
 ) -> HttpResponse {
-    let telegram_id = match jwt::extract_telegram_id(&req) {
+    let telegram_id = match jwt::extract_telegram_id(&req).await {
         Ok(id) => id,
         Err(resp) => return resp,
     };
@@ -0,0 +1,322 @@
+//! YooKassa helpers for `POST /webhooks/yookassa`.
+//!
+//! A notification body is never trusted: the handler re-fetches the payment
+//! by id from the shop that issued it ([`fetch_payment`]) and credits from
+//! that copy. There are two shops — the bot's (`YOOKASSA_SHOP_ID`) and the
+//! site's (`YOOKASSA_WEB_*`, see `web_yookassa_creds`); the notification's
+//! `recipient.account_id` says which one to ask first.
+
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+/// `users.payment_method_shop` values (migrations/011).
+pub const SHOP_BOT: &str = "bot";
+pub const SHOP_WEB: &str = "web";
+
+const API_BASE: &str = "https://api.yookassa.ru/v3";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone)]
+pub struct Shop {
+    pub name: &'static str,
+    pub shop_id: String,
+    pub secret: String,
+}
+
+/// Configured shops, the one matching `account_id` first. When the site
+/// shop isn't configured it falls back to the bot's credentials — that's
+/// the same shop, so it's listed once.
+pub fn shops_for(account_id: Option<&str>, web: (String, String)) -> Vec<Shop> {
+    let bot = Shop {
+        name: SHOP_BOT,
+        shop_id: std::env::var("YOOKASSA_SHOP_ID").unwrap_or_default(),
+        secret: std::env::var("YOOKASSA_SECRET_KEY").unwrap_or_default(),
+    };
+    let web = Shop { name: SHOP_WEB, shop_id: web.0, secret: web.1 };
+    let mut shops: Vec<Shop> = if web.shop_id == bot.shop_id { vec![bot] } else { vec![web, bot] };
+    shops.retain(|s| !s.shop_id.is_empty());
+    if let Some(id) = account_id {
+        shops.sort_by_key(|s| s.shop_id != id);
+    }
+    shops
+}
+
+/// Shop that charges a saved card, by `users.payment_method_shop` (NULL is
+/// the bot's). With the site shop unconfigured both names are the same shop.
+pub fn shop_named(name: &str, web: (String, String)) -> Option<Shop> {
+    let shops = shops_for(None, web);
+    if shops.len() == 1 {
+        return shops.into_iter().next();
+    }
+    shops.into_iter().find(|s| s.name == name)
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// This shop doesn't know the payment — try the other one.
+    NotFound,
+    Other(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::NotFound => write!(f, "payment not found"),
+            FetchError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// `GET /v3/payments/{id}` with the shop's credentials.
+pub async fn fetch_payment(http: &reqwest::Client, shop: &Shop, payment_id: &str) -> Result<Value, FetchError> {
+    let resp = http
+        .get(format!("{}/payments/{}", API_BASE, payment_id))
+        .basic_auth(&shop.shop_id, Some(&shop.secret))
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| FetchError::Other(e.to_string()))?;
+    match resp.status() {
+        s if s.is_success() => resp.json().await.map_err(|e| FetchError::Other(e.to_string())),
+        reqwest::StatusCode::NOT_FOUND => Err(FetchError::NotFound),
+        s => Err(FetchError::Other(format!("{}: {}", s, resp.text().await.unwrap_or_default()))),
+    }
+}
+
+#[derive(Debug)]
+pub enum CreateError {
+    /// 4xx — the request itself was refused (card unusable, recurring off
+    /// for the shop, ...). Sending it again won't help.
+    Rejected(String),
+    /// Network error or 5xx; safe to resend with the same Idempotence-Key.
+    Transient(String),
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateError::Rejected(e) | CreateError::Transient(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// `POST /v3/payments`. YooKassa keeps the answer for an Idempotence-Key
+/// for 24h, so a resend with the same key returns the same payment.
+pub async fn create_payment(
+    http: &reqwest::Client,
+    shop: &Shop,
+    idempotence_key: &str,
+    body: &Value,
+) -> Result<Value, CreateError> {
+    post(http, shop, "payments", idempotence_key, body).await
+}
+
+/// `POST /v3/refunds` — same Idempotence-Key rules as [`create_payment`].
+pub async fn create_refund(
+    http: &reqwest::Client,
+    shop: &Shop,
+    idempotence_key: &str,
+    body: &Value,
+) -> Result<Value, CreateError> {
+    post(http, shop, "refunds", idempotence_key, body).await
+}
+
+async fn post(
+    http: &reqwest::Client,
+    shop: &Shop,
+    path: &str,
+    idempotence_key: &str,
+    body: &Value,
+) -> Result<Value, CreateError> {
+    let resp = http
+        .post(format!("{}/{}", API_BASE, path))
+        .basic_auth(&shop.shop_id, Some(&shop.secret))
+        .header("Idempotence-Key", idempotence_key)
+        .timeout(REQUEST_TIMEOUT)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| CreateError::Transient(e.to_string()))?;
+    match resp.status() {
+        s if s.is_success() => resp.json().await.map_err(|e| CreateError::Transient(e.to_string())),
+        s if s.is_client_error() => Err(CreateError::Rejected(format!("{}: {}", s, resp.text().await.unwrap_or_default()))),
+        s => Err(CreateError::Transient(format!("{}: {}", s, resp.text().await.unwrap_or_default()))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedMethod {
+    pub id: String,
+    pub card_last4: Option<String>,
+}
+
+/// What a succeeded payment credits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaidOrder {
+    pub payment_id: String,
+    pub telegram_id: i64,
+    pub tariff: String,
+    pub duration: String,
+    pub days: i32,
+    pub amount_rub: f64,
+    pub saved_method: Option<SavedMethod>,
+    /// `metadata.price_version`, set by payments created here (prices.rs).
+    pub price_version: Option<i32>,
+    /// `metadata.promo_reservation`: the promo hold to commit (promo.rs).
+    pub promo_reservation: Option<i64>,
+    pub metadata: Value,
+}
+
+/// Read a payment object (as re-fetched from the API). Errors describe why
+/// it can't be credited: not succeeded, not RUB, or metadata missing.
+pub fn parse_paid_order(p: &Value) -> Result<PaidOrder, String> {
+    let payment_id = p["id"].as_str().ok_or("payment has no id")?.to_string();
+    if p["status"] != "succeeded" || p["paid"] != true {
+        return Err(format!("payment {} is {}", payment_id, p["status"]));
+    }
+    if p["amount"]["currency"] != "RUB" {
+        return Err(format!("payment {} is in {}", payment_id, p["amount"]["currency"]));
+    }
+    let amount_rub = p["amount"]["value"]
+        .as_str()
+        .and_then(|v| v.parse::<f64>().ok())
+        .ok_or("bad amount")?;
+
+    let meta = &p["metadata"];
+    // YooKassa metadata values are strings.
+    let telegram_id = meta["telegram_id"]
+        .as_str()
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or("metadata.telegram_id missing")?;
+    let tariff = meta["tariff"].as_str().filter(|s| !s.is_empty()).ok_or("metadata.tariff missing")?;
+    let duration = meta["duration"].as_str().unwrap_or_default();
+    let days = meta["days"]
+        .as_str()
+        .and_then(|v| v.parse::<i32>().ok())
+        .or_else(|| crate::prices::duration_days(duration))
+        .ok_or_else(|| format!("unknown duration {:?}", duration))?;
+
+    let price_version = meta["price_version"].as_str().and_then(|v| v.parse::<i32>().ok());
+    let promo_reservation = meta["promo_reservation"].as_str().and_then(|v| v.parse::<i64>().ok());
+
+    let pm = &p["payment_method"];
+    let saved_method = match (pm["saved"].as_bool(), pm["id"].as_str()) {
+        (Some(true), Some(id)) => Some(SavedMethod {
+            id: id.to_string(),
+            card_last4: pm["card"]["last4"].as_str().map(str::to_string),
+        }),
+        _ => None,
+    };
+
+    Ok(PaidOrder {
+        payment_id,
+        telegram_id,
+        tariff: tariff.to_string(),
+        duration: duration.to_string(),
+        days,
+        amount_rub,
+        saved_method,
+        price_version,
+        promo_reservation,
+        metadata: meta.clone(),
+    })
+}
+
+/// `metadata.kind` of a wallet top-up payment (wallet.rs).
+pub const KIND_WALLET_TOPUP: &str = "wallet_topup";
+
+/// A succeeded wallet top-up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topup {
+    pub payment_id: String,
+    pub telegram_id: i64,
+    pub amount_rub: f64,
+}
+
+/// The top-up a payment pays for; `Ok(None)` for subscription payments.
+pub fn parse_topup(p: &Value) -> Result<Option<Topup>, String> {
+    if p["metadata"]["kind"] != KIND_WALLET_TOPUP {
+        return Ok(None);
+    }
+    let payment_id = p["id"].as_str().ok_or("payment has no id")?.to_string();
+    if p["status"] != "succeeded" || p["paid"] != true {
+        return Err(format!("payment {} is {}", payment_id, p["status"]));
+    }
+    if p["amount"]["currency"] != "RUB" {
+        return Err(format!("payment {} is in {}", payment_id, p["amount"]["currency"]));
+    }
+    let amount_rub = p["amount"]["value"]
+        .as_str()
+        .and_then(|v| v.parse::<f64>().ok())
+        .ok_or("bad amount")?;
+    let telegram_id = p["metadata"]["telegram_id"]
+        .as_str()
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or("metadata.telegram_id missing")?;
+    Ok(Some(Topup { payment_id, telegram_id, amount_rub }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn payment() -> Value {
+        json!({
+            "id": "2d9c1f3a-000f-5000-9000-1b2c3d4e5f60",
+            "status": "succeeded",
+            "paid": true,
+            "amount": {"value": "430.00", "currency": "RUB"},
+            "payment_method": {"type": "bank_card", "id": "pm-1", "saved": true, "card": {"last4": "4242"}},
+            "metadata": {"telegram_id": "42", "tariff": "base", "duration": "3m", "plan": "3 месяца", "price_version": "2"}
+        })
+    }
+
+    #[test]
+    fn parses_succeeded_payment() {
+        let order = parse_paid_order(&payment()).unwrap();
+        assert_eq!(order.telegram_id, 42);
+        assert_eq!(order.tariff, "base");
+        assert_eq!(order.days, 90);
+        assert_eq!(order.amount_rub, 430.0);
+        assert_eq!(order.price_version, Some(2));
+        assert_eq!(
+            order.saved_method,
+            Some(SavedMethod { id: "pm-1".into(), card_last4: Some("4242".into()) })
+        );
+    }
+
+    #[test]
+    fn rejects_unpaid_and_incomplete_payments() {
+        let mut p = payment();
+        p["status"] = json!("canceled");
+        assert!(parse_paid_order(&p).is_err());
+
+        let mut p = payment();
+        p["metadata"]["duration"] = json!("6m");
+        assert_eq!(parse_paid_order(&p).unwrap().days, 180);
+
+        let mut p = payment();
+        p["metadata"]["duration"] = json!("2w");
+        assert!(parse_paid_order(&p).is_err());
+
+        let mut p = payment();
+        p["payment_method"]["saved"] = json!(false);
+        assert_eq!(parse_paid_order(&p).unwrap().saved_method, None);
+    }
+
+    #[test]
+    fn parses_wallet_topup() {
+        assert_eq!(parse_topup(&payment()).unwrap(), None);
+
+        let mut p = payment();
+        p["metadata"] = json!({"telegram_id": "-42", "kind": "wallet_topup"});
+        let t = parse_topup(&p).unwrap().unwrap();
+        assert_eq!((t.telegram_id, t.amount_rub), (-42, 430.0));
+        assert!(parse_paid_order(&p).is_err());
+
+        p["status"] = json!("pending");
+        assert!(parse_topup(&p).is_err());
+    }
+}
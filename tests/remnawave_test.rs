@@ -0,0 +1,282 @@
+//! Integration tests for the Remnawave client and the subscription sync
+//! built on it. Uses wiremock as a fake panel.
+
+use serde_json::json;
+use uuid::Uuid;
+use vpn_api::plans::{Catalog, Plan};
+use vpn_api::remnawave::{CreateUser, RemnawaveApi, RemnawaveClient, RemnawaveError};
+use vpn_api::subscription::{self, Entitlement};
+use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const USER_UUID: &str = "6f1d3c9e-0b2a-4c1e-9a57-3f2b8e6d1c40";
+const CUSTOM_SQUAD: &str = "11111111-2222-3333-4444-555555555555";
+const DEFAULT_SQUAD: &str = "514a5e22-c599-4f72-81a5-e646f0391db7";
+const BS_SQUAD: &str = "9e60626e-32a8-4d91-a2f8-2aa3fecf7b23";
+const PRO_SQUAD: &str = "b6a4e86b-b769-4c86-a2d9-f31bbe645029";
+const INBOUND: &str = "d92c68b5-41e9-47d0-b7ee-89e7c8640a59";
+
+fn plan(code: &str, kind: &str, squads: &[&str], device_limit: i64, tag: &str) -> Plan {
+    Plan {
+        code: code.into(),
+        display_name: code.into(),
+        kind: kind.into(),
+        squads: squads.iter().map(|s| s.to_string()).collect(),
+        inbounds: vec![INBOUND.into()],
+        device_limit,
+        traffic_limit_bytes: 0,
+        tag: tag.into(),
+        is_active: true,
+    }
+}
+
+/// Same rows as the 013_plans.sql seed.
+fn seed_plans() -> Vec<Plan> {
+    vec![
+        plan("base", "paid", &[DEFAULT_SQUAD], 2, "PAID"),
+        plan("family", "paid", &[DEFAULT_SQUAD], 10, "PAID"),
+        plan("bsbase", "paid", &[DEFAULT_SQUAD, BS_SQUAD], 2, "PAID"),
+        plan("bsfamily", "paid", &[DEFAULT_SQUAD, BS_SQUAD], 10, "PAID"),
+        plan("trial", "bonus", &[DEFAULT_SQUAD], 2, "TRIAL"),
+        plan("free", "bonus", &[DEFAULT_SQUAD], 2, "FREE"),
+        plan("pro", "addon", &[PRO_SQUAD], 0, "PAID"),
+    ]
+}
+
+fn seed_catalog() -> Catalog {
+    Catalog::new(seed_plans())
+}
+
+fn panel_user(squads: &[&str]) -> serde_json::Value {
+    json!({
+        "response": [{
+            "uuid": USER_UUID,
+            "username": "user_42",
+            "status": "ACTIVE",
+            "subscriptionUrl": "https://sub.example/abc",
+            "expireAt": "2026-06-01T00:00:00.000Z",
+            "telegramId": 42,
+            "hwidDeviceLimit": 2,
+            "firstConnectedAt": null,
+            "activeInternalSquads": squads.iter().map(|s| json!({"uuid": s, "name": "sq"})).collect::<Vec<_>>(),
+        }]
+    })
+}
+
+#[tokio::test]
+async fn get_user_sends_auth_headers_and_parses() {
+    let mock = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/users/by-telegram-id/42"))
+        .and(header("Authorization", "Bearer test-key"))
+        .and(header("X-Forwarded-Proto", "https"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(panel_user(&[DEFAULT_SQUAD])))
+        .expect(1)
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "test-key");
+    let user = client.get_user_by_telegram_id(42).await.unwrap().unwrap();
+    assert_eq!(user.uuid, Uuid::parse_str(USER_UUID).unwrap());
+    assert_eq!(user.subscription_url.as_deref(), Some("https://sub.example/abc"));
+    assert_eq!(user.squad_uuids(), vec![DEFAULT_SQUAD.to_string()]);
+    assert!(user.first_connected_at.is_none());
+}
+
+#[tokio::test]
+async fn get_user_maps_404_to_none() {
+    let mock = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    assert!(client.get_user_by_telegram_id(7).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn get_user_retries_on_5xx() {
+    let mock = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .expect(2)
+        .mount(&mock)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(panel_user(&[])))
+        .expect(1)
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    assert!(client.get_user_by_telegram_id(42).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn create_user_is_not_retried() {
+    let mock = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/users"))
+        .respond_with(ResponseTemplate::new(500).set_body_string("boom"))
+        .expect(1)
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    let res = client
+        .create_user(&CreateUser {
+            username: "user_42".into(),
+            status: "DISABLED".into(),
+            traffic_limit_bytes: 0,
+            traffic_limit_strategy: "MONTH".into(),
+            expire_at: chrono::Utc::now(),
+            created_at: chrono::Utc::now(),
+            telegram_id: 42,
+            hwid_device_limit: 2,
+            active_internal_squads: vec![DEFAULT_SQUAD.into()],
+        })
+        .await;
+    match res {
+        Err(RemnawaveError::Status(code, body)) => {
+            assert_eq!(code.as_u16(), 500);
+            assert_eq!(body, "boom");
+        }
+        other => panic!("expected Status error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn list_devices_round_trips_unknown_fields() {
+    let mock = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path(format!("/hwid/devices/{}", USER_UUID)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "response": {
+                "total": 1,
+                "devices": [{"hwid": "abc", "platform": "Android", "createdAt": "2026-01-01T00:00:00Z"}]
+            }
+        })))
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    let list = client.list_devices(Uuid::parse_str(USER_UUID).unwrap()).await.unwrap();
+    assert_eq!(list.total, 1);
+    let dev = serde_json::to_value(&list.devices[0]).unwrap();
+    assert_eq!(dev["hwid"], "abc");
+    assert_eq!(dev["platform"], "Android");
+    assert_eq!(dev["createdAt"], "2026-01-01T00:00:00Z");
+}
+
+#[tokio::test]
+async fn list_users_pages_with_start_and_size() {
+    let mock = MockServer::start().await;
+    let mut page = panel_user(&[DEFAULT_SQUAD]);
+    let users = page["response"].take();
+    Mock::given(method("GET"))
+        .and(path("/users"))
+        .and(query_param("start", "500"))
+        .and(query_param("size", "500"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "response": { "users": users, "total": 501 }
+        })))
+        .expect(1)
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    let page = client.list_users(500, 500).await.unwrap();
+    assert_eq!(page.total, 501);
+    assert_eq!(page.users.len(), 1);
+    assert_eq!(page.users[0].telegram_id, Some(42));
+}
+
+#[tokio::test]
+async fn extend_to_regular_plan_revokes_bs_and_keeps_unmanaged() {
+    let mock = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/users/by-telegram-id/42"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(panel_user(&[DEFAULT_SQUAD, BS_SQUAD, CUSTOM_SQUAD])),
+        )
+        .mount(&mock)
+        .await;
+    Mock::given(method("PATCH"))
+        .and(path("/users"))
+        .and(body_partial_json(json!({
+            "uuid": USER_UUID,
+            "status": "ACTIVE",
+            "tag": "PAID",
+            "hwidDeviceLimit": 10,
+            "activeUserInbounds": [INBOUND],
+            "expireAt": "2026-07-01T12:00:00.000Z",
+            "activeInternalSquads": [DEFAULT_SQUAD, CUSTOM_SQUAD, PRO_SQUAD],
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"response": {"uuid": USER_UUID}})))
+        .expect(1)
+        .mount(&mock)
+        .await;
+
+    let client = RemnawaveClient::new(mock.uri(), "k");
+    let catalog = seed_catalog();
+    let family = catalog.resolve("family");
+    let expire_at = chrono::DateTime::parse_from_rfc3339("2026-07-01T12:00:00Z")
+        .unwrap()
+        .with_timezone(&chrono::Utc);
+    let squads = subscription::push_entitlement(
+        &client,
+        &catalog,
+        &Entitlement {
+            telegram_id: 42,
+            uuid: Uuid::parse_str(USER_UUID).unwrap(),
+            plan: &family,
+            is_pro: true,
+            expire_at,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(squads, vec![DEFAULT_SQUAD, CUSTOM_SQUAD, PRO_SQUAD]);
+}
+
+#[test]
+fn bonus_extend_leaves_bs_alone() {
+    let catalog = seed_catalog();
+    let current = vec![BS_SQUAD.to_string()];
+    let squads = catalog.entitled_squads(current, &catalog.resolve("trial"), false);
+    assert_eq!(squads, vec![BS_SQUAD, DEFAULT_SQUAD]);
+}
+
+#[test]
+fn new_paid_plan_from_catalog_revokes_other_paid_squads() {
+    // "bsplus" launched as a row: its own squad, and applying it drops BS.
+    const PLUS_SQUAD: &str = "aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee";
+    let mut plans = vec![plan("bsplus", "paid", &[DEFAULT_SQUAD, PLUS_SQUAD], 5, "PAID")];
+    plans.extend(seed_plans());
+    let catalog = Catalog::new(plans);
+
+    let current = vec![DEFAULT_SQUAD.to_string(), BS_SQUAD.to_string(), PRO_SQUAD.to_string()];
+    let squads = catalog.entitled_squads(current, &catalog.resolve("bsplus"), false);
+    assert_eq!(squads, vec![DEFAULT_SQUAD, PRO_SQUAD, PLUS_SQUAD]);
+}
+
+#[test]
+fn unknown_plan_falls_back_to_baseline() {
+    let catalog = seed_catalog();
+    let p = catalog.resolve("legacy");
+    assert_eq!(p.tag, "UNKNOWN");
+    assert_eq!(p.device_limit, 2);
+    let squads = catalog.entitled_squads(vec![BS_SQUAD.to_string()], &p, false);
+    assert_eq!(squads, vec![BS_SQUAD, DEFAULT_SQUAD]);
+}
+
+#[test]
+fn pro_toggle_only_touches_pro_squad() {
+    let catalog = seed_catalog();
+    let on = catalog.with_pro(vec![CUSTOM_SQUAD.to_string()], true);
+    assert_eq!(on, vec![CUSTOM_SQUAD, PRO_SQUAD]);
+    let off = catalog.with_pro(on, false);
+    assert_eq!(off, vec![CUSTOM_SQUAD]);
+}